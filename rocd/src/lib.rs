@@ -3,28 +3,476 @@
 
 use serde::{Deserialize, Serialize};
 use quinn::Connection;
-use serde_json::Value;
+use serde_json::Value as JsonValue;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as TokioBufReader};
 use std::path::{Path, PathBuf};
 use std::fs;
 use anyhow::Result;
 use directories::UserDirs;
 
+pub mod value;
+pub mod error;
+pub use value::{Value, Versioned, parse_value, parse_value_prefix};
+pub use error::{QuotaKind, StoreError};
+
 pub type UserId = String;
 
+/// When a key should expire (mirrors the server's `Expiry`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Expiry {
+    AfterMs(u64),
+    AtMs(u64),
+}
+
+/// What an `Aggregate` command computes (mirrors the server's `Aggregate`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+/// The keys an `Aggregate` command runs over (mirrors the server's `KeyScope`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum KeyScope {
+    #[default]
+    All,
+    Prefix(String),
+    /// From `start` to `end`, inclusive.
+    Range { start: String, end: String },
+}
+
+/// The keys a `Watch` covers (mirrors the server's `WatchScope`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WatchScope {
+    Key(String),
+    Prefix(String),
+    /// From `start` to `end`, inclusive.
+    Range { start: String, end: String },
+}
+
+/// What happened to a watched key (mirrors the server's `ChangeKind`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChangeKind {
+    Set,
+    Update,
+    Delete,
+}
+
+/// One change to a watched key, as streamed by the server (mirrors the server's `WatchEvent`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub key: String,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+    pub version: u64,
+}
+
+/// A message on a subscribed channel (mirrors the server's `PubSubMessage`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PubSubMessage {
+    pub channel: String,
+    /// The pattern the channel matched, for `PSubscribe`.
+    pub pattern: Option<String>,
+    pub message: Value,
+    /// Messages the server dropped for this subscriber since the previous one.
+    pub missed: u64,
+}
+
+/// One committed mutation from a CDC feed (mirrors the server's `CdcEvent`). `op` is the
+/// server's WAL record, kept as JSON.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CdcEvent {
+    pub shard: usize,
+    pub seq: u64,
+    pub ts: u64,
+    pub op: JsonValue,
+}
+
+/// Which values a `Query` matches (mirrors the server's `ValueFilter`). Range bounds are
+/// inclusive; a missing bound is open.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ValueFilter {
+    Eq(Value),
+    Range { min: Option<Value>, max: Option<Value> },
+}
+
 /// The wire-format for user-accessible commands. Only user commands included.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "command", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum WireCommand {
     Hi { user_id: Option<UserId> },
     Ping { user_id: UserId },
-    Set { user_id: UserId, key: String, value: usize },
-    Get { user_id: UserId, key: String },
+    Set {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    Get {
+        user_id: UserId,
+        key: String,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
     Del { user_id: UserId, key: String },
-    Update { user_id: UserId, key: String, value: usize },
-    Range { user_id: UserId, start: String, end: String },
-    List { user_id: UserId },
+    MGet {
+        user_id: UserId,
+        keys: Vec<String>,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
+    MSet { user_id: UserId, pairs: Vec<(String, Value)> },
+    MDel { user_id: UserId, keys: Vec<String> },
+    Cas {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        version: u64,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    SetNx {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    DelIfVersion { user_id: UserId, key: String, version: u64 },
+    Update {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    Upsert {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    Incr { user_id: UserId, key: String },
+    Decr { user_id: UserId, key: String },
+    IncrBy { user_id: UserId, key: String, by: i64 },
+    DecrBy { user_id: UserId, key: String, by: i64 },
+    Range {
+        user_id: UserId,
+        start: String,
+        end: String,
+        #[serde(default)]
+        start_exclusive: bool,
+        #[serde(default)]
+        end_exclusive: bool,
+        #[serde(default)]
+        reverse: bool,
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
+    List {
+        user_id: UserId,
+        #[serde(default)]
+        prefix: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
     Exit { user_id: UserId },
+    Ttl { user_id: UserId, key: String },
+    Expire { user_id: UserId, key: String, expiry: Expiry },
+    Persist { user_id: UserId, key: String },
+    Begin { user_id: UserId },
+    Commit { user_id: UserId },
+    Rollback { user_id: UserId },
+    Aggregate {
+        user_id: UserId,
+        function: Aggregate,
+        #[serde(default)]
+        scope: KeyScope,
+    },
+    CreateIndex { user_id: UserId },
+    DropIndex { user_id: UserId },
+    Query { user_id: UserId, filter: ValueFilter },
+    CreateKeyspace { user_id: UserId, name: String },
+    ListKeyspaces { user_id: UserId },
+    SelectKeyspace { user_id: UserId, name: String },
+    DropKeyspace { user_id: UserId, name: String },
+    /// Admin commands carry the server's admin token (`ROCS_ADMIN_TOKEN`).
+    Snapshot {
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    ClearWal {
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    Stats,
+    /// `quota: None` goes back to the default quota.
+    SetQuota {
+        user_id: UserId,
+        #[serde(default)]
+        quota: Option<Quota>,
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    Info { user_id: UserId },
+    Watch { user_id: UserId, scope: WatchScope },
+    Unwatch { user_id: UserId, watch_id: u64 },
+    OpenReadSnapshot { user_id: UserId },
+    CloseReadSnapshot { user_id: UserId, snapshot_id: u64 },
+    Publish { user_id: UserId, channel: String, message: Value },
+    Subscribe { user_id: UserId, channels: Vec<String> },
+    #[serde(rename = "PSUBSCRIBE")]
+    PSubscribe { user_id: UserId, patterns: Vec<String> },
+    Unsubscribe { user_id: UserId, subscription_id: u64 },
+    Cdc {
+        #[serde(default)]
+        from: Vec<u64>,
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+}
+
+impl WireCommand {
+    /// Points a `List` or `Range` at the page after the one `cursor` was returned with.
+    pub fn set_cursor(&mut self, next: String) {
+        if let WireCommand::List { cursor, .. } | WireCommand::Range { cursor, .. } = self {
+            *cursor = Some(next);
+        }
+    }
+}
+
+/// One page of a `List` or `Range` (mirrors the server's `Page`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Page {
+    pub items: Vec<((String, String), Value)>,
+    /// Continues the scan after this page; `None` on the last page.
+    pub cursor: Option<String>,
+}
+
+/// A read snapshot opened on the server (mirrors the server's `ReadSnapshot`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadSnapshot {
+    pub id: u64,
+    /// The last WAL record the snapshot sees.
+    pub seq: u64,
+    /// When the snapshot was opened, in milliseconds since the unix epoch.
+    pub time: u64,
+}
+
+/// Memory use and eviction counters of the server (mirrors the server's `MemoryStats`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub keys: u64,
+    pub used_memory_bytes: u64,
+    /// `0` means no limit.
+    pub max_memory_bytes: u64,
+    /// `0` means no limit.
+    pub max_memory_per_user_bytes: u64,
+    pub eviction_policy: String,
+    pub evicted_keys: u64,
+    pub rejected_writes: u64,
+}
+
+/// Limits on what one user may store, `0` meaning no limit (mirrors the server's `Quota`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_keys: u64,
+    pub max_bytes: u64,
+    pub max_key_len: u64,
+}
+
+/// What the server reports about a user (mirrors the server's `UserInfo`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub user_id: String,
+    pub keyspaces: u64,
+    pub keys: u64,
+    pub bytes: u64,
+    pub quota: Quota,
+    pub custom_quota: bool,
+}
+
+/// Parses `[KEYS n] [BYTES n] [KEYLEN n]`, in any order. Limits not given are unlimited.
+pub fn parse_quota(tokens: &[&str]) -> Result<Quota, String> {
+    let mut quota = Quota::default();
+    let mut tokens = tokens.iter();
+    while let Some(token) = tokens.next() {
+        let limit = match token.to_uppercase().as_str() {
+            "KEYS" => &mut quota.max_keys,
+            "BYTES" => &mut quota.max_bytes,
+            "KEYLEN" => &mut quota.max_key_len,
+            other => return Err(format!("unknown limit {other:?}")),
+        };
+        let n = tokens.next().ok_or_else(|| format!("{token} needs a number"))?;
+        *limit = n.parse().map_err(|_| format!("invalid limit {n:?}"))?;
+    }
+    Ok(quota)
+}
+
+/// Entries per page when LIST or GET BETWEEN does not give a LIMIT.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+/// Paging options of LIST and GET BETWEEN.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PageOptions {
+    pub limit: Option<usize>,
+    pub reverse: bool,
+    pub start_exclusive: bool,
+    pub end_exclusive: bool,
+}
+
+/// Parses `[LIMIT n] [REVERSE] [EXCLUSIVE | EXCLUDE-START | EXCLUDE-END]`, in any order.
+pub fn parse_page_options(tokens: &[&str]) -> Result<PageOptions, String> {
+    let mut options = PageOptions::default();
+    let mut tokens = tokens.iter();
+    while let Some(token) = tokens.next() {
+        match token.to_uppercase().as_str() {
+            "LIMIT" => {
+                let n = tokens.next().ok_or("LIMIT needs a page size")?;
+                match n.parse::<usize>() {
+                    Ok(n) if n > 0 => options.limit = Some(n),
+                    _ => return Err(format!("invalid page size {n:?}")),
+                }
+            }
+            "REVERSE" => options.reverse = true,
+            "EXCLUSIVE" => (options.start_exclusive, options.end_exclusive) = (true, true),
+            "EXCLUDE-START" => options.start_exclusive = true,
+            "EXCLUDE-END" => options.end_exclusive = true,
+            other => return Err(format!("unknown option {other:?}")),
+        }
+    }
+    Ok(options)
+}
+
+/// Returns what is left of `input` after its first `n` whitespace-separated tokens.
+///
+/// Used for commands whose last argument is a value literal that may itself contain spaces.
+pub fn rest_after_tokens(input: &str, n: usize) -> &str {
+    let mut rest = input.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+/// Parses `<value literal> [EX secs | PX ms | EXAT unix_secs | PXAT unix_ms]`.
+pub fn parse_value_with_expiry(text: &str) -> Result<(Value, Option<Expiry>), String> {
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    if let [.., option, amount] = tokens.as_slice() {
+        // `"x EX 5"` ends in `5"`, so a quoted literal never looks like an option
+        if let (Some(make), Ok(n)) = (expiry_option(option), amount.parse::<u64>()) {
+            // strip the two option tokens from the end of the literal
+            let literal = text.trim_end();
+            let literal = literal[..literal.len() - amount.len()].trim_end();
+            let literal = literal[..literal.len() - option.len()].trim_end();
+            return Ok((parse_value(literal)?, Some(make(n))));
+        }
+    }
+    Ok((parse_value(text)?, None))
+}
+
+fn expiry_option(option: &str) -> Option<fn(u64) -> Expiry> {
+    match option.to_uppercase().as_str() {
+        "EX" => Some(|secs| Expiry::AfterMs(secs.saturating_mul(1000))),
+        "PX" => Some(Expiry::AfterMs),
+        "EXAT" => Some(|secs| Expiry::AtMs(secs.saturating_mul(1000))),
+        "PXAT" => Some(Expiry::AtMs),
+        _ => None,
+    }
+}
+
+/// Parses the filter of QUERY: `= <value>`, `>= <value>`, `<= <value>` or
+/// `BETWEEN <value> <value>`.
+pub fn parse_value_filter(text: &str) -> Result<ValueFilter, String> {
+    let text = text.trim();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let (op, rest) = text.split_at(end);
+    match op.to_uppercase().as_str() {
+        "=" => Ok(ValueFilter::Eq(parse_value(rest)?)),
+        ">=" => Ok(ValueFilter::Range { min: Some(parse_value(rest)?), max: None }),
+        "<=" => Ok(ValueFilter::Range { min: None, max: Some(parse_value(rest)?) }),
+        "BETWEEN" => {
+            let (min, rest) = parse_value_prefix(rest)?;
+            if !rest.starts_with(char::is_whitespace) {
+                return Err("expected two values".to_string());
+            }
+            Ok(ValueFilter::Range { min: Some(min), max: Some(parse_value(rest)?) })
+        }
+        other => Err(format!("unknown filter {other:?} (expected =, >=, <= or BETWEEN)")),
+    }
+}
+
+/// Parses the arguments of AGG: `COUNT|SUM|MIN|MAX|AVG [PREFIX p | BETWEEN start end]`.
+pub fn parse_aggregate(tokens: &[&str]) -> Result<(Aggregate, KeyScope), String> {
+    let (function, scope) = tokens.split_first().ok_or("missing function")?;
+    let function = match function.to_uppercase().as_str() {
+        "COUNT" => Aggregate::Count,
+        "SUM" => Aggregate::Sum,
+        "MIN" => Aggregate::Min,
+        "MAX" => Aggregate::Max,
+        "AVG" => Aggregate::Avg,
+        other => return Err(format!("unknown function {other:?} (expected COUNT, SUM, MIN, MAX or AVG)")),
+    };
+    let scope = match scope {
+        [] => KeyScope::All,
+        [kw, prefix] if kw.eq_ignore_ascii_case("PREFIX") => KeyScope::Prefix(prefix.to_string()),
+        [kw, start, end] if kw.eq_ignore_ascii_case("BETWEEN") => {
+            KeyScope::Range { start: start.to_string(), end: end.to_string() }
+        }
+        _ => return Err("expected PREFIX p or BETWEEN start end".to_string()),
+    };
+    Ok((function, scope))
+}
+
+/// Parses the arguments of WATCH: `KEY k`, `PREFIX p` or `BETWEEN start end`.
+pub fn parse_watch_scope(tokens: &[&str]) -> Result<WatchScope, String> {
+    match tokens {
+        [kw, key] if kw.eq_ignore_ascii_case("KEY") => Ok(WatchScope::Key(key.to_string())),
+        [kw, prefix] if kw.eq_ignore_ascii_case("PREFIX") => Ok(WatchScope::Prefix(prefix.to_string())),
+        [kw, start, end] if kw.eq_ignore_ascii_case("BETWEEN") => {
+            Ok(WatchScope::Range { start: start.to_string(), end: end.to_string() })
+        }
+        _ => Err("expected KEY k, PREFIX p or BETWEEN start end".to_string()),
+    }
+}
+
+/// Parses `key <value literal> key <value literal> ...` for MSET.
+pub fn parse_pairs(mut text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut pairs = Vec::new();
+    loop {
+        text = text.trim_start();
+        if text.is_empty() {
+            return Ok(pairs);
+        }
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        let key = &text[..end];
+        let (value, rest) = parse_value_prefix(&text[end..]).map_err(|e| format!("{key}: {e}"))?;
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return Err(format!("{key}: expected whitespace after the value"));
+        }
+        pairs.push((key.to_string(), value));
+        text = rest;
+    }
 }
 
 async fn hi_handshake(conn: &Connection) -> Result<String> {
@@ -36,7 +484,7 @@ async fn hi_handshake(conn: &Connection) -> Result<String> {
     let mut reader = TokioBufReader::new(recv);
     let mut response = String::new();
     reader.read_line(&mut response).await?;
-    let v: Value = serde_json::from_str(&response)?;
+    let v: JsonValue = serde_json::from_str(&response)?;
     let user_id = v["user_id"].as_str().ok_or_else(|| anyhow::anyhow!("No user_id in response"))?;
     println!("Assigned user_id: {user_id}");
     Ok(user_id.to_string())
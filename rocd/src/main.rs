@@ -7,13 +7,14 @@ use quinn::{ClientConfig, Connection, Endpoint};
 use rustls::RootCertStore;
 use rustls_pki_types::CertificateDer;
 use rustls_pki_types::pem::PemObject;
-use serde_json::{Value, json};
+use serde::de::DeserializeOwned;
+use serde_json::json;
 use std::fs;
 use std::io::{self, Write};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as TokioBufReader};
-use rocd::{WireCommand, get_user_id};
+use rocd::{Expiry, WireCommand, Value, Versioned, StoreError, MemoryStats, Page, UserInfo, ReadSnapshot, PageOptions, ChangeKind, WatchEvent, PubSubMessage, CdcEvent, DEFAULT_PAGE_SIZE, get_user_id, parse_aggregate, parse_pairs, parse_page_options, parse_quota, parse_value, parse_value_filter, parse_value_with_expiry, parse_watch_scope, rest_after_tokens};
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -26,6 +27,9 @@ struct Args {
     /// enables test-mode
     #[arg(long, default_value_t=false)]
     test_mode: bool,
+    /// Token sent with admin commands; must match the server's ROCS_ADMIN_TOKEN
+    #[arg(long)]
+    admin_token: Option<String>,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
@@ -58,12 +62,12 @@ async fn main() -> Result<()> {
         std::process::exit(1);
     }
 
-	repl(new_conn, user_id).await?;
+	repl(new_conn, user_id, args.admin_token).await?;
 
 	Ok(())
 }
 
-async fn repl(conn: Connection, user_id: String) -> Result<()> {
+async fn repl(conn: Connection, user_id: String, admin_token: Option<String>) -> Result<()> {
 
     // user_id is supposed to properly defined here
     // TODO: maybe add a check if it is a proper UUID
@@ -71,9 +75,18 @@ async fn repl(conn: Connection, user_id: String) -> Result<()> {
 	let stdin = io::stdin();
 	let mut stdout = io::stdout();
 
+	// whether a transaction is open on the server, shown in the prompt
+	let mut in_txn = false;
+	// the keyspace selected on the server, shown in the prompt unless it is the default one
+	let mut keyspace = "default".to_string();
+	// the read snapshot FETCH, MGET, LIST and GET BETWEEN read through, shown in the prompt
+	let mut read_snapshot: Option<u64> = None;
+
 	loop {
 		let mut input = String::new();
-		print!("(roc:client)> ");
+		let ks = if keyspace == "default" { String::new() } else { format!(" {keyspace}") };
+		let snap = read_snapshot.map(|id| format!(" snap:{id}")).unwrap_or_default();
+		print!("(roc:client{ks}{snap}{})> ", if in_txn { " txn" } else { "" });
 		io::stdout().flush().unwrap();
 		io::stdin().read_line(&mut input).unwrap();
 
@@ -88,7 +101,7 @@ async fn repl(conn: Connection, user_id: String) -> Result<()> {
 
 		command_tokens[0] = command_tokens[0].to_uppercase();
 
-		if (command_tokens[0] == "GET") && command_tokens.len() >= 2 {
+		if matches!(command_tokens[0].as_str(), "GET" | "KEYSPACE" | "INDEX" | "QUOTA" | "WATCH" | "READSNAP") && command_tokens.len() >= 2 {
 			command_tokens[1] = command_tokens[1].to_uppercase();
 		}
 
@@ -104,45 +117,361 @@ async fn repl(conn: Connection, user_id: String) -> Result<()> {
             ["PING"] => {
 				WireCommand::Ping { user_id: user_id.clone() }
 			},
-			["STORE", key, value] => {
+            ["SNAPSHOT"] => {
+                WireCommand::Snapshot { admin_token: admin_token.clone() }
+            },
+            ["CLEARWAL"] => {
+                WireCommand::ClearWal { admin_token: admin_token.clone() }
+            },
+            ["STATS"] => {
+                WireCommand::Stats
+            },
+            ["INFO"] => {
+                WireCommand::Info { user_id: user_id.clone() }
+            },
+            ["QUOTA", "SET", user, limits @ ..] => {
+                let quota = match parse_quota(limits) {
+                    Ok(quota) => quota,
+                    Err(e) => {
+                        println!("Failed to parse quota: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::SetQuota { user_id: user.to_string(), quota: Some(quota), admin_token: admin_token.clone() }
+            },
+            ["QUOTA", "RESET", user] => {
+                WireCommand::SetQuota { user_id: user.to_string(), quota: None, admin_token: admin_token.clone() }
+            },
+            ["WATCH", args @ ..] => {
+                let scope = match parse_watch_scope(args) {
+                    Ok(scope) => scope,
+                    Err(e) => {
+                        println!("Failed to parse watch: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::Watch { user_id: user_id.clone(), scope }
+            },
+            ["UNWATCH", id] => {
+                let watch_id = match id.parse::<u64>() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        println!("Failed to parse watch id into integer");
+                        continue;
+                    }
+                };
+                WireCommand::Unwatch { user_id: user_id.clone(), watch_id }
+            },
+            ["READSNAP", "OPEN"] => {
+                if let Some(id) = read_snapshot {
+                    println!("Read snapshot {id} is still open, close it first");
+                    continue;
+                }
+                WireCommand::OpenReadSnapshot { user_id: user_id.clone() }
+            },
+            ["READSNAP", "CLOSE"] => match read_snapshot {
+                Some(snapshot_id) => WireCommand::CloseReadSnapshot { user_id: user_id.clone(), snapshot_id },
+                None => {
+                    println!("No read snapshot open");
+                    continue;
+                }
+            },
+            ["PUBLISH", channel, _, ..] => {
+                let message = match parse_value(rest_after_tokens(input, 2)) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::Publish { user_id: user_id.clone(), channel: channel.to_string(), message }
+            },
+            ["SUBSCRIBE", channels @ ..] if !channels.is_empty() => {
+                WireCommand::Subscribe {
+                    user_id: user_id.clone(),
+                    channels: channels.iter().map(|c| c.to_string()).collect(),
+                }
+            },
+            ["PSUBSCRIBE", patterns @ ..] if !patterns.is_empty() => {
+                WireCommand::PSubscribe {
+                    user_id: user_id.clone(),
+                    patterns: patterns.iter().map(|p| p.to_string()).collect(),
+                }
+            },
+            ["UNSUBSCRIBE", id] => {
+                let subscription_id = match id.parse::<u64>() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        println!("Failed to parse subscription id into integer");
+                        continue;
+                    }
+                };
+                WireCommand::Unsubscribe { user_id: user_id.clone(), subscription_id }
+            },
+            ["CDC", from @ ..] => {
+                // CDC [seq ...]: one sequence number per shard to start after, or none
+                let from = match from.iter().map(|n| n.parse::<u64>()).collect::<Result<Vec<_>, _>>() {
+                    Ok(from) => from,
+                    Err(_) => {
+                        println!("Failed to parse sequence number into integer");
+                        continue;
+                    }
+                };
+                WireCommand::Cdc { from, admin_token: admin_token.clone() }
+            },
+            ["BEGIN"] => {
+                WireCommand::Begin { user_id: user_id.clone() }
+            },
+            ["COMMIT"] => {
+                WireCommand::Commit { user_id: user_id.clone() }
+            },
+            ["ROLLBACK"] => {
+                WireCommand::Rollback { user_id: user_id.clone() }
+            },
+            ["INDEX", "CREATE"] => {
+                WireCommand::CreateIndex { user_id: user_id.clone() }
+            },
+            ["INDEX", "DROP"] => {
+                WireCommand::DropIndex { user_id: user_id.clone() }
+            },
+            ["AGG", args @ ..] => {
+                let (function, scope) = match parse_aggregate(args) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("Failed to parse aggregation: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::Aggregate { user_id: user_id.clone(), function, scope }
+            },
+            ["QUERY", _, _, ..] => {
+                let filter = match parse_value_filter(rest_after_tokens(input, 1)) {
+                    Ok(filter) => filter,
+                    Err(e) => {
+                        println!("Failed to parse filter: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::Query { user_id: user_id.clone(), filter }
+            },
+            ["KEYSPACE", "CREATE", name] => {
+                WireCommand::CreateKeyspace { user_id: user_id.clone(), name: name.to_string() }
+            },
+            ["KEYSPACE", "LIST"] => {
+                WireCommand::ListKeyspaces { user_id: user_id.clone() }
+            },
+            ["KEYSPACE", "SELECT", name] => {
+                WireCommand::SelectKeyspace { user_id: user_id.clone(), name: name.to_string() }
+            },
+            ["KEYSPACE", "DROP", name] => {
+                WireCommand::DropKeyspace { user_id: user_id.clone(), name: name.to_string() }
+            },
+			["STORE", key, _, ..] => {
+                let (value, expiry) = match parse_value_with_expiry(rest_after_tokens(input, 2)) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
                 WireCommand::Set {
                     user_id: user_id.clone(),
                     key: key.to_string(),
-                    value: {
-                        match value.parse::<usize>() {
-                            Ok(v) => v,
-                            Err(e) => {
-                                println!("Failed to parse value into integer");
-                                continue;
-                            }
-                        }
-                    },
+                    value,
+                    expiry,
+                }
+            },
+			["CAS", key, version, _, ..] => {
+                let version = match version.parse::<u64>() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        println!("Failed to parse version into integer");
+                        continue;
+                    }
+                };
+                let (value, expiry) = match parse_value_with_expiry(rest_after_tokens(input, 3)) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::Cas {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                    value,
+                    version,
+                    expiry,
+                }
+            },
+			["SETNX", key, _, ..] => {
+                let (value, expiry) = match parse_value_with_expiry(rest_after_tokens(input, 2)) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
+                WireCommand::SetNx {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                    value,
+                    expiry,
                 }
             },
+			["DELIF", key, version] => {
+                let version = match version.parse::<u64>() {
+                    Ok(v) => v,
+                    Err(_) => {
+                        println!("Failed to parse version into integer");
+                        continue;
+                    }
+                };
+				WireCommand::DelIfVersion {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                    version,
+                }
+			},
+			["MGET", keys @ ..] if !keys.is_empty() => {
+				WireCommand::MGet {
+                    user_id: user_id.clone(),
+                    keys: keys.iter().map(|k| k.to_string()).collect(),
+                    snapshot: read_snapshot,
+                }
+			},
+			["MDEL", keys @ ..] if !keys.is_empty() => {
+				WireCommand::MDel {
+                    user_id: user_id.clone(),
+                    keys: keys.iter().map(|k| k.to_string()).collect(),
+                }
+			},
+			["MSET", _, _, ..] => {
+                let pairs = match parse_pairs(rest_after_tokens(input, 1)) {
+                    Ok(pairs) => pairs,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
+				WireCommand::MSet {
+                    user_id: user_id.clone(),
+                    pairs,
+                }
+			},
 			["FETCH", key] => {
 				WireCommand::Get {
                     user_id: user_id.clone(),
                     key: key.to_string(),
+                    snapshot: read_snapshot,
                 }
 			},
-			["LIST"] => {
+			["LIST", rest @ ..] => {
+                // LIST [prefix] [LIMIT n]
+                let (prefix, rest) = match rest {
+                    [first, rest @ ..] if !first.eq_ignore_ascii_case("LIMIT") => (Some(first.to_string()), rest),
+                    _ => (None, rest),
+                };
+                let limit = match parse_page_options(rest) {
+                    Ok(PageOptions { limit, reverse: false, start_exclusive: false, end_exclusive: false }) => limit,
+                    Ok(_) => {
+                        println!("LIST only takes a prefix and LIMIT");
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("Failed to parse options: {e}");
+                        continue;
+                    }
+                };
 				WireCommand::List {
                     user_id: user_id.clone(),
+                    prefix,
+                    limit: Some(limit.unwrap_or(DEFAULT_PAGE_SIZE)),
+                    cursor: None,
+                    snapshot: read_snapshot,
                 }
 			},
-			["UPDATE", key, value] => {
+			["UPDATE", key, _, ..] => {
+                let (value, expiry) = match parse_value_with_expiry(rest_after_tokens(input, 2)) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
 				WireCommand::Update {
                     user_id: user_id.clone(),
                     key: key.to_string(),
-                    value: {
-                        match value.parse::<usize>() {
-                            Ok(v) => v,
-                            Err(e) => {
-                                println!("Failed to parse value into integer");
-                                continue;
-                            }
-                        }
-                    },
+                    value,
+                    expiry,
+                }
+			},
+			["UPSERT", key, _, ..] => {
+                let (value, expiry) = match parse_value_with_expiry(rest_after_tokens(input, 2)) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        println!("Failed to parse value: {e}");
+                        continue;
+                    }
+                };
+				WireCommand::Upsert {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                    value,
+                    expiry,
+                }
+			},
+			["INCR", key] => {
+				WireCommand::Incr {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                }
+			},
+			["DECR", key] => {
+				WireCommand::Decr {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                }
+			},
+			["INCRBY", key, by] | ["DECRBY", key, by] => {
+                let by = match by.parse::<i64>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        println!("Failed to parse amount into integer");
+                        continue;
+                    }
+                };
+                let (user_id, key) = (user_id.clone(), key.to_string());
+                if command_str[0] == "INCRBY" {
+                    WireCommand::IncrBy { user_id, key, by }
+                } else {
+                    WireCommand::DecrBy { user_id, key, by }
+                }
+			},
+			["TTL", key] => {
+				WireCommand::Ttl {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                }
+			},
+			["PERSIST", key] => {
+				WireCommand::Persist {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                }
+			},
+			["EXPIRE", key, secs] | ["EXPIREAT", key, secs] => {
+                let secs = match secs.parse::<u64>() {
+                    Ok(s) => s.saturating_mul(1000),
+                    Err(_) => {
+                        println!("Failed to parse seconds into integer");
+                        continue;
+                    }
+                };
+				WireCommand::Expire {
+                    user_id: user_id.clone(),
+                    key: key.to_string(),
+                    expiry: if command_str[0] == "EXPIRE" { Expiry::AfterMs(secs) } else { Expiry::AtMs(secs) },
                 }
 			},
 			["DELETE", key] => {
@@ -151,12 +480,25 @@ async fn repl(conn: Connection, user_id: String) -> Result<()> {
                     key: key.to_string(),
                 }
 			},
-			["GET", "BETWEEN", start, end] => {
+			["GET", "BETWEEN", start, end, rest @ ..] => {
+                let options = match parse_page_options(rest) {
+                    Ok(options) => options,
+                    Err(e) => {
+                        println!("Failed to parse options: {e}");
+                        continue;
+                    }
+                };
 				WireCommand::Range {
                     user_id: user_id.clone(),
                     start: start.to_string(),
                     end: end.to_string(),
-                }		
+                    start_exclusive: options.start_exclusive,
+                    end_exclusive: options.end_exclusive,
+                    reverse: options.reverse,
+                    limit: Some(options.limit.unwrap_or(DEFAULT_PAGE_SIZE)),
+                    cursor: None,
+                    snapshot: read_snapshot,
+                }
             },
 			_ => {
 				println!("Invalid command!");
@@ -164,26 +506,267 @@ async fn repl(conn: Connection, user_id: String) -> Result<()> {
 			},
 		};
         
-        // each command by the user opens a new bi-directional connection
-		let (mut send, mut recv) = conn.open_bi().await?;
-
-		let request_str = serde_json::to_string(&request)? + "\n";
-        eprintln!("Sending request: {:?}", request_str);
-		send.write_all(request_str.as_bytes()).await?;
-		send.finish(); // does this close the send stream? Yes it does! We make entirely new connections for each command sent by the user
+		let response = send_request(&conn, &request).await?;
 
-		let mut reader = TokioBufReader::new(recv);
-		let mut response = String::new();
-		reader.read_line(&mut response).await?;
-
-		match serde_json::from_str::<Value>(&response) {
-			Ok(res) => {
+		match &request {
+            WireCommand::Get { .. } => match serde_json::from_str::<Result<Option<Versioned>, String>>(&response) {
+                Ok(Ok(Some(v))) if v.version == 0 => println!("Response: {} (uncommitted)", v.value),
+                Ok(Ok(Some(v))) => println!("Response: {} (version {})", v.value, v.version),
+                Ok(Ok(None)) => println!("Response: (nil)"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Cas { .. } | WireCommand::SetNx { .. } => match serde_json::from_str::<Result<u64, StoreError>>(&response) {
+                Ok(Ok(version)) => println!("Response: OK (version {version})"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Set { .. } | WireCommand::Upsert { .. } => match serde_json::from_str::<Result<(), String>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Incr { .. } | WireCommand::Decr { .. } | WireCommand::IncrBy { .. } | WireCommand::DecrBy { .. } => {
+                match serde_json::from_str::<Result<i64, StoreError>>(&response) {
+                    Ok(Ok(n)) => println!("Response: {n}"),
+                    Ok(Err(e)) => println!("Error: {e}"),
+                    Err(_) => println!("Encountered Error!"),
+                }
+            }
+            WireCommand::MGet { keys, .. } => match serde_json::from_str::<Result<Vec<Option<Versioned>>, String>>(&response) {
+                Ok(Ok(values)) => {
+                    for (key, v) in keys.iter().zip(values) {
+                        match v {
+                            Some(v) if v.version == 0 => println!("  {key} = {} (uncommitted)", v.value),
+                            Some(v) => println!("  {key} = {} (version {})", v.value, v.version),
+                            None => println!("  {key} = (nil)"),
+                        }
+                    }
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::MSet { pairs, .. } => match serde_json::from_str::<Result<Vec<u64>, String>>(&response) {
+                Ok(Ok(versions)) => {
+                    println!("Response: OK, {} key(s) set", versions.len());
+                    for ((key, _), version) in pairs.iter().zip(versions) {
+                        match version {
+                            0 => println!("  {key} (uncommitted)"),
+                            v => println!("  {key} (version {v})"),
+                        }
+                    }
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::MDel { keys, .. } => match serde_json::from_str::<Result<Vec<Option<Value>>, String>>(&response) {
+                Ok(Ok(olds)) => {
+                    println!("Response: {} key(s) deleted", olds.iter().flatten().count());
+                    for (key, old) in keys.iter().zip(olds) {
+                        match old {
+                            Some(old) => println!("  {key}: deleted (was {old})"),
+                            None => println!("  {key}: not found"),
+                        }
+                    }
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Del { .. } => match serde_json::from_str::<Result<Option<Value>, String>>(&response) {
+                Ok(Ok(Some(old))) => println!("Response: deleted (was {old})"),
+                Ok(Ok(None)) => println!("Response: not found, nothing deleted"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Update { .. } | WireCommand::DelIfVersion { .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Ttl { .. } => match serde_json::from_str::<Result<Option<u64>, String>>(&response) {
+                Ok(Ok(Some(ms))) => println!("Response: expires in {:.1}s", ms as f64 / 1000.0),
+                Ok(Ok(None)) => println!("Response: no expiry"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Info { .. } => match serde_json::from_str::<Result<UserInfo, StoreError>>(&response) {
+                Ok(Ok(info)) => {
+                    let limit = |n: u64| if n == 0 { "unlimited".to_string() } else { n.to_string() };
+                    let source = if info.custom_quota { "set by an admin" } else { "default" };
+                    println!("Response:");
+                    println!("  user id:     {}", info.user_id);
+                    println!("  keyspaces:   {} besides default", info.keyspaces);
+                    println!("  keys:        {} of {}", info.keys, limit(info.quota.max_keys));
+                    println!("  bytes:       {} of {}", info.bytes, limit(info.quota.max_bytes));
+                    println!("  key length:  at most {}", limit(info.quota.max_key_len));
+                    println!("  quota:       {source}");
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Watch { .. } => match serde_json::from_str::<Result<u64, StoreError>>(&response) {
+                Ok(Ok(watch_id)) => {
+                    let unwatch = WireCommand::Unwatch { user_id: user_id.clone(), watch_id };
+                    listen(&conn, ("watch_id", watch_id.into()), print_event, Some(unwatch)).await?
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Subscribe { .. } | WireCommand::PSubscribe { .. } => match serde_json::from_str::<Result<u64, String>>(&response) {
+                Ok(Ok(subscription_id)) => {
+                    let unsubscribe = WireCommand::Unsubscribe { user_id: user_id.clone(), subscription_id };
+                    listen(&conn, ("subscription_id", subscription_id.into()), print_message, Some(unsubscribe)).await?
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Cdc { .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => listen(&conn, ("cdc", true.into()), print_change, None).await?,
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Publish { .. } => match serde_json::from_str::<Result<u64, String>>(&response) {
+                Ok(Ok(n)) => println!("Response: delivered to {n} subscription(s)"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Unsubscribe { .. } => match serde_json::from_str::<Result<(), String>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::OpenReadSnapshot { .. } => match serde_json::from_str::<Result<ReadSnapshot, StoreError>>(&response) {
+                Ok(Ok(snapshot)) => {
+                    read_snapshot = Some(snapshot.id);
+                    println!("Response: read snapshot {} open, as of WAL record {}", snapshot.id, snapshot.seq);
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::CloseReadSnapshot { .. } => {
+                // a snapshot the server does not know is gone either way
+                read_snapshot = None;
+                match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                    Ok(Ok(())) => println!("Response: OK"),
+                    Ok(Err(e)) => println!("Error: {e}"),
+                    Err(_) => println!("Encountered Error!"),
+                }
+            }
+            WireCommand::Unwatch { .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::SetQuota { .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Stats => match serde_json::from_str::<Result<MemoryStats, String>>(&response) {
+                Ok(Ok(stats)) => {
+                    let limit = |bytes: u64| if bytes == 0 { "unlimited".to_string() } else { format!("{bytes} bytes") };
+                    println!("Response:");
+                    println!("  keys:               {}", stats.keys);
+                    println!("  used memory:        {} bytes", stats.used_memory_bytes);
+                    println!("  max memory:         {}", limit(stats.max_memory_bytes));
+                    println!("  max memory / user:  {}", limit(stats.max_memory_per_user_bytes));
+                    println!("  eviction policy:    {}", stats.eviction_policy);
+                    println!("  evicted keys:       {}", stats.evicted_keys);
+                    println!("  rejected writes:    {}", stats.rejected_writes);
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::List { .. } | WireCommand::Range { .. } => page_through(&conn, request.clone(), response).await?,
+            WireCommand::Begin { .. } | WireCommand::Rollback { .. } => {
+                match serde_json::from_str::<Result<(), String>>(&response) {
+                    Ok(Ok(())) => {
+                        in_txn = matches!(request, WireCommand::Begin { .. });
+                        println!("Response: OK");
+                    }
+                    Ok(Err(e)) => println!("Error: {e}"),
+                    Err(_) => println!("Encountered Error!"),
+                }
+            }
+            WireCommand::Commit { .. } => {
+                // a failed commit has discarded the transaction on the server as well
+                in_txn = false;
+                match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                    Ok(Ok(())) => println!("Response: OK"),
+                    Ok(Err(e)) => println!("Error: {e}"),
+                    Err(_) => println!("Encountered Error!"),
+                }
+            }
+            WireCommand::Aggregate { .. } => match serde_json::from_str::<Result<Option<Value>, StoreError>>(&response) {
+                Ok(Ok(Some(v))) => println!("Response: {v}"),
+                Ok(Ok(None)) => println!("Response: (nil)"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::CreateIndex { .. } => match serde_json::from_str::<Result<u64, StoreError>>(&response) {
+                Ok(Ok(count)) => println!("Response: OK, {count} key(s) indexed"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::DropIndex { .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::Query { .. } => match serde_json::from_str::<Result<Vec<(String, Value)>, StoreError>>(&response) {
+                Ok(Ok(pairs)) => {
+                    println!("Response: {} key(s)", pairs.len());
+                    for (k, v) in pairs {
+                        println!("  {k} = {v}");
+                    }
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::CreateKeyspace { .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => println!("Response: OK"),
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::SelectKeyspace { name, .. } => match serde_json::from_str::<Result<(), StoreError>>(&response) {
+                Ok(Ok(())) => {
+                    keyspace = name.clone();
+                    println!("Response: OK");
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::DropKeyspace { name, .. } => match serde_json::from_str::<Result<u64, StoreError>>(&response) {
+                Ok(Ok(count)) => {
+                    // the server moves the session back to the default keyspace
+                    if *name == keyspace {
+                        keyspace = "default".to_string();
+                    }
+                    println!("Response: dropped {name} ({count} key(s))");
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            WireCommand::ListKeyspaces { .. } => match serde_json::from_str::<Result<Vec<String>, String>>(&response) {
+                Ok(Ok(names)) => {
+                    println!("Response: {} keyspace(s)", names.len());
+                    for name in names {
+                        let marker = if name == keyspace { " (current)" } else { "" };
+                        println!("  {name}{marker}");
+                    }
+                }
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            },
+            _ => match serde_json::from_str::<serde_json::Value>(&response) {
+                Ok(res) => {
 
-                println!("Response: {:#?}", res);
+                    println!("Response: {:#?}", res);
 
-                // check if exit was sent -- if so then close the connection with proper message
+                    // check if exit was sent -- if so then close the connection with proper message
+                },
+                Err(_) => println!("Encountered Error!"),
             },
-			Err(_) => println!("Encountered Error!"),
 		}
 
         if command_str.as_slice().first().unwrap().eq_ignore_ascii_case("exit") {
@@ -194,3 +777,138 @@ async fn repl(conn: Connection, user_id: String) -> Result<()> {
 
 	Ok(())
 }
+
+/// Sends `request` on a new bi-directional stream and returns the response line.
+async fn send_request(conn: &Connection, request: &WireCommand) -> Result<String> {
+    // each command by the user opens a new bi-directional connection
+	let (mut send, mut recv) = conn.open_bi().await?;
+
+	let request_str = serde_json::to_string(request)? + "\n";
+    eprintln!("Sending request: {:?}", request_str);
+	send.write_all(request_str.as_bytes()).await?;
+	send.finish(); // does this close the send stream? Yes it does! We make entirely new connections for each command sent by the user
+
+	let mut reader = TokioBufReader::new(recv);
+	let mut response = String::new();
+	reader.read_line(&mut response).await?;
+    Ok(response)
+}
+
+/// Prints the page in `response` to a LIST or GET BETWEEN, then fetches and prints the
+/// following pages for as long as the user asks for more.
+async fn page_through(conn: &Connection, mut request: WireCommand, mut response: String) -> Result<()> {
+    let mut shown = 0;
+    loop {
+        let page = match serde_json::from_str::<Result<Page, String>>(&response) {
+            Ok(Ok(page)) => page,
+            Ok(Err(e)) => {
+                println!("Error: {e}");
+                return Ok(());
+            }
+            Err(_) => {
+                println!("Encountered Error!");
+                return Ok(());
+            }
+        };
+        if shown == 0 {
+            println!("Response:");
+        }
+        shown += page.items.len();
+        for ((_, k), v) in page.items {
+            println!("  {k} = {v}");
+        }
+        let Some(cursor) = page.cursor else {
+            println!("{shown} key(s)");
+            return Ok(());
+        };
+
+        print!("-- {shown} key(s) so far; Enter for more, q to stop -- ");
+        io::stdout().flush().unwrap();
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).unwrap();
+        if answer.trim().eq_ignore_ascii_case("q") {
+            return Ok(());
+        }
+        request.set_cursor(cursor);
+        response = send_request(conn, &request).await?;
+    }
+}
+/// Listening mode for WATCH, SUBSCRIBE and CDC: prints what arrives on the stream the server
+/// opened, whose header line must hold `header`, until the user presses Enter or the server ends
+/// the stream. On Enter, `cancel` asks the server to end the stream; without one, the client
+/// stops reading it.
+async fn listen<T: DeserializeOwned + 'static>(
+    conn: &Connection,
+    header: (&str, serde_json::Value),
+    print: fn(&T),
+    cancel: Option<WireCommand>,
+) -> Result<()> {
+    let mut items = TokioBufReader::new(conn.accept_uni().await?);
+    let mut line = String::new();
+    items.read_line(&mut line).await?;
+    let (field, expected) = header;
+    if serde_json::from_str::<serde_json::Value>(&line)?[field] != expected {
+        println!("Error: expected a stream with {field} {expected}, got {}", line.trim());
+        return Ok(());
+    }
+
+    println!("Listening ({field} {expected}); press Enter to stop");
+    let printer = tokio::spawn(async move {
+        let mut line = String::new();
+        while matches!(items.read_line(&mut line).await, Ok(n) if n > 0) {
+            match serde_json::from_str::<T>(&line) {
+                Ok(item) => print(&item),
+                Err(_) => println!("Encountered Error!"),
+            }
+            line.clear();
+        }
+        println!("-- stream ended --");
+    });
+
+    // stdin blocks, so keep it off the runtime threads that drive the connection
+    tokio::task::spawn_blocking(|| io::stdin().read_line(&mut String::new())).await??;
+
+    // if the stream already ended, the server has dropped it and there is nothing to cancel
+    match cancel {
+        Some(cancel) if !printer.is_finished() => {
+            match serde_json::from_str::<Result<(), serde_json::Value>>(&send_request(conn, &cancel).await?) {
+                Ok(Ok(())) => {}
+                // the server dropped it in the meantime
+                Ok(Err(_)) if printer.is_finished() => {}
+                Ok(Err(e)) => println!("Error: {e}"),
+                Err(_) => println!("Encountered Error!"),
+            }
+            // the stream ends once the server has dropped it
+            let _ = printer.await;
+        }
+        // dropping the stream tells the server to stop sending
+        None => printer.abort(),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn print_event(event: &WatchEvent) {
+    let show = |v: &Option<Value>| v.as_ref().map_or("(nil)".to_string(), |v| v.to_string());
+    match event.kind {
+        ChangeKind::Set => println!("  [{}] SET {} = {}", event.version, event.key, show(&event.new)),
+        ChangeKind::Update => {
+            println!("  [{}] UPDATE {} = {} (was {})", event.version, event.key, show(&event.new), show(&event.old))
+        }
+        ChangeKind::Delete => println!("  [{}] DELETE {} (was {})", event.version, event.key, show(&event.old)),
+    }
+}
+
+fn print_message(msg: &PubSubMessage) {
+    if msg.missed > 0 {
+        println!("  ({} message(s) dropped, too slow)", msg.missed);
+    }
+    match &msg.pattern {
+        Some(pattern) => println!("  {} ({pattern}): {}", msg.channel, msg.message),
+        None => println!("  {}: {}", msg.channel, msg.message),
+    }
+}
+
+fn print_change(event: &CdcEvent) {
+    println!("  [shard {} seq {}] {}", event.shard, event.seq, event.op);
+}
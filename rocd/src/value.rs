@@ -0,0 +1,255 @@
+//! in src/value.rs
+//!
+//! Client-side copy of the server's `Value` type, plus the REPL literal syntax:
+//!
+//! ```text
+//! 42  -7             Int
+//! 1.5  -2e3          Float
+//! "alice"            Str   (escapes: \" \\ \n \t)
+//! x"deadbeef"        Bytes (hex)
+//! [1, "two", 3.0]    List
+//! {name: "alice", "full name": "Alice A"}   Map
+//! ```
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+/// A value together with the version of the entry holding it (mirrors the server's `Versioned`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Versioned {
+    pub value: Value,
+    pub version: u64,
+}
+
+/// Parses a complete REPL value literal.
+pub fn parse_value(input: &str) -> Result<Value, String> {
+    let mut parser = Parser { chars: input.trim().chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_ws();
+    if parser.pos != parser.chars.len() {
+        return Err(format!("unexpected trailing input at position {}", parser.pos));
+    }
+    Ok(value)
+}
+
+/// Parses the value literal at the start of `input`, returning it and the unparsed rest.
+pub fn parse_value_prefix(input: &str) -> Result<(Value, &str), String> {
+    let input = input.trim_start();
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    let end = input.char_indices().nth(parser.pos).map_or(input.len(), |(i, _)| i);
+    Ok((value, &input[end..]))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_ws(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_ws();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{c}' at position {}", self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_ws();
+        match self.peek() {
+            Some('"') => self.string().map(Value::Str),
+            Some('x') if self.chars.get(self.pos + 1) == Some(&'"') => {
+                self.pos += 1;
+                self.bytes().map(Value::Bytes)
+            }
+            Some('[') => self.list(),
+            Some('{') => self.map(),
+            Some(c) if c == '-' || c == '+' || c.is_ascii_digit() => self.number(),
+            Some(_) => Err(format!(
+                "unexpected input at position {} (strings must be quoted, e.g. \"alice\")",
+                self.pos
+            )),
+            None => Err("missing value".to_string()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or("unterminated string")?;
+            self.pos += 1;
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let esc = self.peek().ok_or("unterminated escape")?;
+                    self.pos += 1;
+                    out.push(match esc {
+                        'n' => '\n',
+                        't' => '\t',
+                        '"' => '"',
+                        '\\' => '\\',
+                        other => return Err(format!("unknown escape \\{other}")),
+                    });
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let hex = self.string()?;
+        if hex.len() % 2 != 0 {
+            return Err("byte literal needs an even number of hex digits".to_string());
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| format!("invalid hex digits in x\"{hex}\""))
+            })
+            .collect()
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '+' | '.'))
+        {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        if let Ok(i) = text.parse::<i64>() {
+            return Ok(Value::Int(i));
+        }
+        // inf, nan and overflowing literals have no JSON form
+        match text.parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(Value::Float(f)),
+            _ => Err(format!("invalid number '{text}'")),
+        }
+    }
+
+    fn list(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_ws();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Value::List(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(Value::List(items));
+                }
+                _ => return Err(format!("expected ',' or ']' at position {}", self.pos)),
+            }
+        }
+    }
+
+    fn map(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut entries = BTreeMap::new();
+        self.skip_ws();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Value::Map(entries));
+        }
+        loop {
+            self.skip_ws();
+            let key = if self.peek() == Some('"') { self.string()? } else { self.ident()? };
+            self.expect(':')?;
+            let value = self.value()?;
+            entries.insert(key, value);
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Value::Map(entries));
+                }
+                _ => return Err(format!("expected ',' or '}}' at position {}", self.pos)),
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(format!("expected a map key at position {}", self.pos));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+}
+
+/// Prints a value back in REPL literal syntax.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Float(x) if x.fract() == 0.0 && x.is_finite() => write!(f, "{x:.1}"),
+            Value::Float(x) => write!(f, "{x}"),
+            Value::Str(s) => write!(f, "{s:?}"),
+            Value::Bytes(b) => {
+                write!(f, "x\"")?;
+                for byte in b {
+                    write!(f, "{byte:02x}")?;
+                }
+                write!(f, "\"")
+            }
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{k:?}: {v}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
@@ -1,9 +1,23 @@
-use tokio::sync::{mpsc};
+use tokio::sync::{mpsc, oneshot};
 use crate::command::Command;
+use crate::actors::store_actor::StoreCommandHandler;
+use crate::actors::snapshot_actor::{SnapshotCommand, SnapshotCommandHandler};
+use crate::actors::logger_actor::{LogCommand, LoggerCommandHandler};
+use crate::shard::shard_of;
+use crate::storage::bounded::MemoryStats;
+use crate::cdc::{self, ShardFeed};
+use crate::error::StoreError;
 
 pub type AdminCommandHandler = mpsc::Sender<Command>;
 
-pub fn spawn_admin_actor() -> AdminCommandHandler {
+/// The actors making up one shard, as far as the admin actor needs them.
+pub struct ShardHandles {
+    pub store: StoreCommandHandler,
+    pub snapshotter: SnapshotCommandHandler,
+    pub logger: LoggerCommandHandler,
+}
+
+pub fn spawn_admin_actor(shards: Vec<ShardHandles>) -> AdminCommandHandler {
 
     let (tx, mut rx) = mpsc::channel::<Command>(64);
 
@@ -30,15 +44,47 @@ pub fn spawn_admin_actor() -> AdminCommandHandler {
                     respond_to,
                     ..
                 } => {
-                    let _ = respond_to.send(Ok(()));
-                    // TODO: clear the wal 
+                    let mut res = Ok(0);
+                    for shard in &shards {
+                        res = match (res, clear_wal(&shard.snapshotter, &shard.logger).await) {
+                            (Ok(total), Ok(bytes)) => Ok(total + bytes),
+                            (Err(e), _) | (_, Err(e)) => Err(e),
+                        };
+                    }
+                    let _ = respond_to.send(res);
                 }
-                Command::Snapshot {
-                    respond_to,
-                    .. 
-                } => {
-                    let _ = respond_to.send(Ok(()));
-                    // TODO: create a snapshot
+                Command::Snapshot { respond_to } => {
+                    let res = snapshot_all(&shards).await;
+                    let _ = respond_to.send(res);
+                }
+                Command::SetQuota { ref user_id, .. } => {
+                    // the shard holding the user's data enforces their quota
+                    let shard = &shards[shard_of(user_id, shards.len())];
+                    if let Err(e) = shard.store.send(cmd).await {
+                        eprintln!("Failed to send the command to the store actor SetQuota");
+                    }
+                }
+                Command::Cdc { from, events, respond_to } => {
+                    match cdc_all(&shards, from).await {
+                        // reading the WAL is left to the feed's own task
+                        Ok(feeds) => {
+                            tokio::spawn(async move {
+                                let res = cdc::check(&feeds).await;
+                                let started = res.is_ok();
+                                let _ = respond_to.send(res);
+                                if started {
+                                    cdc::merge(feeds, events).await;
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            let _ = respond_to.send(Err(e));
+                        }
+                    }
+                }
+                Command::Stats { respond_to } => {
+                    let res = stats_all(&shards).await;
+                    let _ = respond_to.send(res);
                 }
                 other => {
                     #[cfg(debug_assertions)]
@@ -50,3 +96,102 @@ pub fn spawn_admin_actor() -> AdminCommandHandler {
 
     tx
 } 
+
+/// Snapshots every shard, waiting until all of them are on disk.
+///
+/// Returns the highest snapshot sequence number.
+async fn snapshot_all(shards: &[ShardHandles]) -> Result<u64, String> {
+    // Ask every shard first so the snapshots are taken in parallel.
+    let mut pending = Vec::with_capacity(shards.len());
+    for shard in shards {
+        let (tx, rx) = oneshot::channel();
+        // The store actor hands a copy of its state to the snapshot actor,
+        // which replies once the snapshot is on disk.
+        if let Err(e) = shard.store.send(Command::Snapshot { respond_to: tx }).await {
+            eprintln!("Failed to send the command to the store actor Snapshot");
+            return Err("store actor is not running".to_string());
+        }
+        pending.push(rx);
+    }
+
+    let mut newest = 0;
+    for rx in pending {
+        let seq = rx.await.map_err(|_| "store actor dropped the snapshot request".to_string())??;
+        newest = newest.max(seq);
+    }
+    Ok(newest)
+}
+
+/// The memory stats of every shard, summed up.
+async fn stats_all(shards: &[ShardHandles]) -> Result<MemoryStats, String> {
+    let mut pending = Vec::with_capacity(shards.len());
+    for shard in shards {
+        let (tx, rx) = oneshot::channel();
+        if let Err(e) = shard.store.send(Command::Stats { respond_to: tx }).await {
+            eprintln!("Failed to send the command to the store actor Stats");
+            return Err("store actor is not running".to_string());
+        }
+        pending.push(rx);
+    }
+
+    let mut total = MemoryStats::default();
+    for rx in pending {
+        total.merge(rx.await.map_err(|_| "store actor dropped the stats request".to_string())??);
+    }
+    Ok(total)
+}
+
+/// Starts a CDC feed in every shard, shard `i` after sequence number `from[i]`, or from the
+/// beginning if `from` is empty.
+async fn cdc_all(shards: &[ShardHandles], from: Vec<u64>) -> Result<Vec<ShardFeed>, StoreError> {
+    // sequence numbers are per shard, so a partial position cannot be resumed from
+    if !from.is_empty() && from.len() != shards.len() {
+        return Err(StoreError::Other(format!(
+            "got {} sequence numbers, but the server has {} shard(s): pass one per shard, or none",
+            from.len(),
+            shards.len()
+        )));
+    }
+    let mut pending = Vec::with_capacity(shards.len());
+    for (i, shard) in shards.iter().enumerate() {
+        let (tx, rx) = oneshot::channel();
+        let cmd = Command::ShardCdc { from: from.get(i).copied().unwrap_or(0), respond_to: tx };
+        if let Err(e) = shard.store.send(cmd).await {
+            eprintln!("Failed to send the command to the store actor ShardCdc");
+            return Err(StoreError::Other("store actor is not running".to_string()));
+        }
+        pending.push(rx);
+    }
+
+    let mut feeds = Vec::with_capacity(pending.len());
+    for rx in pending {
+        feeds.push(rx.await.map_err(|_| StoreError::Other("store actor dropped the CDC request".to_string()))??);
+    }
+    Ok(feeds)
+}
+
+/// Deletes the WAL segments already covered by the newest snapshot.
+///
+/// Returns the number of bytes reclaimed.
+async fn clear_wal(
+    snapshot_ah: &SnapshotCommandHandler,
+    logger_ah: &LoggerCommandHandler,
+) -> Result<u64, String> {
+    let (tx, rx) = oneshot::channel();
+    snapshot_ah
+        .send(SnapshotCommand::List { respond_to: tx })
+        .await
+        .map_err(|_| "snapshot actor is not running".to_string())?;
+    let manifest = rx.await.map_err(|_| "snapshot actor dropped the request".to_string())?;
+
+    let newest = manifest
+        .last()
+        .ok_or_else(|| "no snapshot yet, nothing in the WAL can be cleared".to_string())?;
+
+    let (tx, rx) = oneshot::channel();
+    logger_ah
+        .send(LogCommand::Truncate { upto_seq: newest.wal_seq, respond_to: tx })
+        .await
+        .map_err(|_| "logger actor is not running".to_string())?;
+    rx.await.map_err(|_| "logger actor dropped the request".to_string())?
+}
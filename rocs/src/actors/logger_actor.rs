@@ -0,0 +1,352 @@
+//! src/actors/logger_actor.rs
+//!
+//! Contains the Logger Actor, which owns the write-ahead log (WAL).
+//!
+//! Every mutating storage command is turned into a `WalOp` by the store actor and appended
+//! here (and fsync'd) *before* the store actor replies on `respond_to`. On boot the store actor
+//! replays the log on top of `store_state.bin`, so writes acknowledged between two
+//! `persist_state` ticks survive a crash.
+//!
+//! The log lives in `logs/wal/` (one directory per shard, see `crate::shard`) as a series of size-bounded segment files. Each segment is
+//! named after the sequence number of its first record (`wal-<first_seq>.log`) and holds one
+//! JSON `WalRecord` per line. Once a segment grows past `RocsConfig::wal_segment_bytes` a new
+//! one is started. Segments whose records are all contained in a snapshot can be deleted with
+//! `LogCommand::Truncate` (which is what `ClearWal` does).
+//!
+//! Records describe the resulting state of a key (not the command that produced it), so
+//! replaying a record that is already contained in `store_state.bin` is harmless.
+
+use tokio::sync::{mpsc, oneshot};
+use crate::quota::Quota;
+use crate::value::{self, Value};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single state change recorded in the WAL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WalOp {
+    /// A user id was handed out by `Hi`.
+    AddUser { user_id: String },
+    /// `key` now holds `value` and expires at `expires_at` (Set / Update / Expire / Persist).
+    Put {
+        user_id: String,
+        key: String,
+        #[serde(deserialize_with = "value::deserialize_with_legacy")]
+        value: Value,
+        /// Absolute expiry in milliseconds since the unix epoch; `None` for persistent keys.
+        #[serde(default)]
+        expires_at: Option<u64>,
+    },
+    /// `key` was removed (Del).
+    Delete { user_id: String, key: String },
+    /// Several changes that must be applied together (a committed transaction).
+    Batch(Vec<WalOp>),
+    /// `user_id` created the named keyspace `keyspace`.
+    CreateKeyspace { user_id: String, keyspace: String },
+    /// `user_id` dropped the named keyspace `keyspace` and every key in it.
+    DropKeyspace { user_id: String, keyspace: String },
+    /// The values of `user_id` (an owner id) are indexed from now on.
+    CreateIndex { user_id: String },
+    /// The values of `user_id` (an owner id) are no longer indexed.
+    DropIndex { user_id: String },
+    /// An admin set the quota of `user_id`; `None` goes back to the default quota.
+    SetQuota { user_id: String, quota: Option<Quota> },
+}
+
+/// One line of the WAL.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalRecord {
+    /// Monotonically increasing sequence number, starting at 1.
+    pub seq: u64,
+    /// Wall-clock time of the append, in milliseconds since the unix epoch.
+    pub ts: u64,
+    pub op: WalOp,
+}
+
+/// Commands understood by the logger actor.
+#[derive(Debug)]
+pub enum LogCommand {
+    /// Append `op` to the log and fsync it.
+    ///
+    /// # Response
+    /// - Sends `Ok((seq, ts))` with the sequence number and timestamp of the record, or
+    ///   `Err(String)` if the record could not be made durable.
+    Append {
+        op: WalOp,
+        respond_to: oneshot::Sender<Result<(u64, u64), String>>,
+    },
+
+    /// Delete every segment whose records all have a sequence number `<= upto_seq`.
+    ///
+    /// # Response
+    /// - Sends `Ok(bytes)` with the number of bytes reclaimed, or `Err(String)` on error.
+    Truncate {
+        upto_seq: u64,
+        respond_to: oneshot::Sender<Result<u64, String>>,
+    },
+}
+
+/// Type alias for the sender used to communicate with the logger actor.
+pub type LoggerCommandHandler = mpsc::Sender<LogCommand>;
+
+/// A closed or active segment file.
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+}
+
+/// The segmented log owned by the logger actor.
+struct Wal {
+    dir: PathBuf,
+    /// All segments, oldest first. The last one is the active segment.
+    segments: Vec<Segment>,
+    active: File,
+    active_size: u64,
+    next_seq: u64,
+    segment_bytes: u64,
+}
+
+impl Wal {
+    fn open(dir: PathBuf, segment_bytes: u64) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        migrate_legacy_log(&dir);
+
+        let mut segments = list_segments(&dir);
+        let next_seq = next_seq_of(&segments);
+
+        if segments.is_empty() {
+            segments.push(Segment { first_seq: next_seq, path: segment_path(&dir, next_seq) });
+        }
+
+        let active_path = &segments.last().expect("at least one segment").path;
+        let active = OpenOptions::new().create(true).append(true).open(active_path)?;
+        let active_size = active.metadata()?.len();
+
+        Ok(Self { dir, segments, active, active_size, next_seq, segment_bytes })
+    }
+
+    /// Returns the sequence number and timestamp of the new record.
+    fn append(&mut self, op: WalOp) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        if self.active_size >= self.segment_bytes {
+            self.rotate()?;
+        }
+
+        let record = WalRecord { seq: self.next_seq, ts: now_millis(), op };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.active.write_all(&line)?;
+        self.active.sync_data()?; // the record must be on disk before the client hears back
+
+        self.active_size += line.len() as u64;
+        self.next_seq += 1;
+        Ok((record.seq, record.ts))
+    }
+
+    /// Closes the active segment and starts a new one at `next_seq`.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let path = segment_path(&self.dir, self.next_seq);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        sync_dir(&self.dir)?;
+        self.segments.push(Segment { first_seq: self.next_seq, path });
+        self.active = file;
+        self.active_size = 0;
+        Ok(())
+    }
+
+    fn truncate(&mut self, upto_seq: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        // If everything written so far is covered, close the active segment so it can go too.
+        if self.active_size > 0 && self.next_seq - 1 <= upto_seq {
+            self.rotate()?;
+        }
+
+        // Segment i ends right before segment i + 1 begins; the active segment is never removed.
+        let removable = self
+            .segments
+            .windows(2)
+            .take_while(|pair| pair[1].first_seq - 1 <= upto_seq)
+            .count();
+
+        let mut reclaimed = 0;
+        for segment in self.segments.drain(..removable) {
+            reclaimed += fs::metadata(&segment.path).map(|m| m.len()).unwrap_or(0);
+            fs::remove_file(&segment.path)?;
+        }
+        sync_dir(&self.dir)?;
+        Ok(reclaimed)
+    }
+}
+
+/// Spawns the logger actor as a Tokio task and returns its sender.
+///
+/// The actor continues the sequence numbering of whatever is already in the log.
+///
+/// # Arguments
+/// * `dir` - Directory holding the segments of this shard's log.
+/// * `segment_bytes` - Size after which the active segment is closed and a new one started.
+pub fn spawn_logger_actor(dir: PathBuf, segment_bytes: u64) -> LoggerCommandHandler {
+    let (tx, mut rx) = mpsc::channel::<LogCommand>(128);
+
+    let mut wal = Wal::open(dir, segment_bytes).expect("Failed to open the write-ahead log");
+
+    tokio::spawn(async move {
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                LogCommand::Append { op, respond_to } => {
+                    let res = wal.append(op).map_err(|e| e.to_string());
+                    let _ = respond_to.send(res);
+                }
+                LogCommand::Truncate { upto_seq, respond_to } => {
+                    let res = wal.truncate(upto_seq).map_err(|e| e.to_string());
+                    if let Ok(bytes) = &res {
+                        println!("WAL truncated up to seq {upto_seq}, reclaimed {bytes} bytes");
+                    }
+                    let _ = respond_to.send(res);
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+/// Appends `op` through the logger actor and waits until it is durable.
+///
+/// Returns the sequence number and timestamp of the new record.
+pub async fn append(logger: &LoggerCommandHandler, op: WalOp) -> Result<(u64, u64), String> {
+    let (tx, rx) = oneshot::channel();
+    logger
+        .send(LogCommand::Append { op, respond_to: tx })
+        .await
+        .map_err(|_| "logger actor is not running".to_string())?;
+    rx.await.map_err(|_| "logger actor dropped the append".to_string())?
+}
+
+/// Reads every well-formed record in `dir` with a sequence number greater than `after_seq`,
+/// in log order.
+///
+/// Lines that cannot be parsed (e.g. a torn final write after a crash) are skipped with a warning.
+pub fn read_wal(dir: &Path, after_seq: u64) -> Vec<WalRecord> {
+    segments_after(dir, after_seq)
+        .iter()
+        .flat_map(|(_, path)| read_segment(path))
+        .filter(|r| r.seq > after_seq)
+        .collect()
+}
+
+/// The segments of the WAL in `dir` that may hold records with a sequence number greater than
+/// `after_seq`, oldest first, each with the sequence number of its first record.
+pub fn segments_after(dir: &Path, after_seq: u64) -> Vec<(u64, PathBuf)> {
+    let segments = list_segments(dir);
+    // skip segments that end before `after_seq`
+    let skipped = segments.windows(2).take_while(|pair| pair[1].first_seq - 1 <= after_seq).count();
+    segments.into_iter().skip(skipped).map(|s| (s.first_seq, s.path)).collect()
+}
+
+/// The sequence number the next record appended to the WAL in `dir` will get.
+pub fn next_seq(dir: &Path) -> u64 {
+    next_seq_of(&list_segments(dir))
+}
+
+fn next_seq_of(segments: &[Segment]) -> u64 {
+    // Records already on disk win; an empty trailing segment still tells us where
+    // numbering continues after a full truncation.
+    segments
+        .last()
+        .map(|s| {
+            read_segment(&s.path)
+                .last()
+                .map(|r| r.seq + 1)
+                .unwrap_or(s.first_seq)
+        })
+        .unwrap_or(1)
+}
+
+/// Starts an empty WAL in `dir` whose first record will be `next_seq`.
+pub fn create_empty(dir: &Path, next_seq: u64) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    OpenOptions::new().create(true).append(true).open(segment_path(dir, next_seq))?;
+    sync_dir(dir)
+}
+
+/// Reads every well-formed record in the segment at `path`, in log order; a segment that is
+/// gone reads as empty.
+pub fn read_segment(path: &Path) -> Vec<WalRecord> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut records = Vec::new();
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("[logger actor] Stopped reading {} at line {}: {e}", path.display(), lineno + 1);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<WalRecord>(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => eprintln!("[logger actor] Skipping malformed line {} in {}: {e}", lineno + 1, path.display()),
+        }
+    }
+    records
+}
+
+/// All segment files in `dir`, ordered by their first sequence number.
+fn list_segments(dir: &Path) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let name = e.file_name().into_string().ok()?;
+                    let first_seq = name.strip_prefix("wal-")?.strip_suffix(".log")?.parse().ok()?;
+                    Some(Segment { first_seq, path: e.path() })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    segments.sort_by_key(|s| s.first_seq);
+    segments
+}
+
+/// Moves a pre-segmentation `logs/wal.log` into `logs/wal/` as the first segment.
+fn migrate_legacy_log(dir: &Path) {
+    let Some(legacy) = dir.parent().map(|logs| logs.join("wal.log")) else { return };
+    let Ok(meta) = fs::metadata(&legacy) else { return };
+
+    if meta.len() > 0 {
+        let first_seq = read_segment(&legacy).first().map(|r| r.seq).unwrap_or(1);
+        let target = segment_path(dir, first_seq);
+        match fs::rename(&legacy, &target) {
+            Ok(()) => println!("Migrated {} to {}", legacy.display(), target.display()),
+            Err(e) => eprintln!("[logger actor] Failed to migrate {}: {e}", legacy.display()),
+        }
+    } else {
+        let _ = fs::remove_file(&legacy);
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("wal-{first_seq:020}.log"))
+}
+
+/// fsyncs a directory so that file creations/removals in it are durable.
+pub(crate) fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Milliseconds since the unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -3,3 +3,4 @@ pub mod admin_actor;
 pub mod logger_actor;
 pub mod snapshot_actor;
 pub mod user_actor;
+pub mod pubsub_actor;
@@ -0,0 +1,280 @@
+//! src/actors/pubsub_actor.rs
+//!
+//! The pub/sub actor: publish/subscribe channels next to the key-value store.
+//!
+//! Channels are shared by every user and exist only while somebody subscribes to them; nothing
+//! about them is logged or persisted. `Subscribe` covers a list of channels, `PSubscribe` a list
+//! of glob patterns (`*` matches any run of characters, `?` one character, `\` escapes the next
+//! one). Each subscription gets a bounded buffer of `config.pubsub_buffer` messages, which the
+//! connection handler drains into a QUIC unidirectional stream it opens to the client.
+//!
+//! A subscriber whose buffer is full is handled by `config.slow_subscriber`: either the message
+//! is dropped for it (and the next message it does get carries the count in `missed`), or its
+//! subscription ends. Publishers never wait on subscribers.
+
+use crate::command::{Command, UserId};
+use crate::config::{RocsConfig, SlowSubscriberPolicy};
+use crate::value::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+pub type PubSubCommandHandler = mpsc::Sender<Command>;
+
+/// A message as delivered to a subscriber.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PubSubMessage {
+    pub channel: String,
+    /// The pattern the channel matched, for subscriptions made with `PSubscribe`.
+    pub pattern: Option<String>,
+    pub message: Value,
+    /// Messages dropped for this subscriber since the previous one it got, because its buffer
+    /// was full.
+    pub missed: u64,
+}
+
+/// A new subscription: its id and the messages published to it.
+#[derive(Debug)]
+pub struct Subscribed {
+    pub id: u64,
+    pub messages: mpsc::Receiver<PubSubMessage>,
+}
+
+struct Subscription {
+    user_id: UserId,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+    messages: mpsc::Sender<PubSubMessage>,
+    missed: u64,
+}
+
+impl Subscription {
+    /// Whether a message on `channel` is for this subscription, and through which pattern.
+    fn matches(&self, channel: &str) -> Option<Option<&str>> {
+        if self.channels.iter().any(|c| c == channel) {
+            return Some(None);
+        }
+        self.patterns.iter().find(|p| glob_match(p, channel)).map(|p| Some(p.as_str()))
+    }
+}
+
+/// Spawns the pub/sub actor, which handles `Publish`, `Subscribe`, `PSubscribe` and
+/// `Unsubscribe` for every user.
+pub fn spawn_pubsub_actor(config: &RocsConfig) -> PubSubCommandHandler {
+    let (tx, mut rx) = mpsc::channel::<Command>(128);
+    let buffer = config.pubsub_buffer;
+    let policy = config.slow_subscriber;
+
+    tokio::spawn(async move {
+        let mut next_id = 0;
+        let mut subscriptions: BTreeMap<u64, Subscription> = BTreeMap::new();
+
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                Command::Publish { channel, message, respond_to, .. } => {
+                    let mut delivered = 0;
+                    subscriptions.retain(|_, sub| {
+                        let Some(pattern) = sub.matches(&channel) else { return true };
+                        let msg = PubSubMessage {
+                            channel: channel.clone(),
+                            pattern: pattern.map(str::to_string),
+                            message: message.clone(),
+                            missed: sub.missed,
+                        };
+                        match sub.messages.try_send(msg) {
+                            Ok(()) => {
+                                sub.missed = 0;
+                                delivered += 1;
+                                true
+                            }
+                            Err(TrySendError::Full(_)) if policy == SlowSubscriberPolicy::Drop => {
+                                sub.missed += 1;
+                                true
+                            }
+                            // too slow, or the client went away
+                            Err(_) => false,
+                        }
+                    });
+                    let _ = respond_to.send(Ok(delivered));
+                }
+                Command::Subscribe { user_id, channels, respond_to } => {
+                    let res = subscribe(&mut subscriptions, &mut next_id, buffer, user_id, channels, Vec::new());
+                    let _ = respond_to.send(res);
+                }
+                Command::PSubscribe { user_id, patterns, respond_to } => {
+                    let res = subscribe(&mut subscriptions, &mut next_id, buffer, user_id, Vec::new(), patterns);
+                    let _ = respond_to.send(res);
+                }
+                Command::Unsubscribe { user_id, subscription_id, respond_to } => {
+                    let res = match subscriptions.get(&subscription_id) {
+                        Some(sub) if sub.user_id == user_id => {
+                            // dropping the sender finishes the stream
+                            subscriptions.remove(&subscription_id);
+                            Ok(())
+                        }
+                        _ => Err(format!("no subscription {subscription_id}")),
+                    };
+                    let _ = respond_to.send(res);
+                }
+                other => {
+                    #[cfg(debug_assertions)]
+                    eprintln!("[pubsub actor] Ignored non-pub/sub command: {:?}", other);
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+/// Registers a subscription to `channels` and `patterns`, with a buffer of `buffer` messages.
+fn subscribe(
+    subscriptions: &mut BTreeMap<u64, Subscription>,
+    next_id: &mut u64,
+    buffer: usize,
+    user_id: UserId,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+) -> Result<Subscribed, String> {
+    if channels.is_empty() && patterns.is_empty() {
+        return Err("nothing to subscribe to".to_string());
+    }
+    *next_id += 1;
+    let (tx, messages) = mpsc::channel(buffer);
+    subscriptions.insert(*next_id, Subscription { user_id, channels, patterns, messages: tx, missed: 0 });
+    Ok(Subscribed { id: *next_id, messages })
+}
+
+/// Whether `channel` matches the glob `pattern`.
+fn glob_match(pattern: &str, channel: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let channel: Vec<char> = channel.chars().collect();
+    let (mut p, mut c) = (0, 0);
+    // where the last `*` was, and how much of the channel it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+    while c < channel.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, c));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                c += 1;
+                continue;
+            }
+            Some('\\') if pattern.get(p + 1) == Some(&channel[c]) => {
+                p += 2;
+                c += 1;
+                continue;
+            }
+            Some(&ch) if ch != '\\' && ch == channel[c] => {
+                p += 1;
+                c += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // mismatch: let the last `*` take one more character, if there was one
+        match star {
+            Some((star_p, star_c)) => {
+                star = Some((star_p, star_c + 1));
+                p = star_p + 1;
+                c = star_c + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[test]
+    fn globs_match_runs_single_characters_and_escapes() {
+        assert!(glob_match("news.*", "news."));
+        assert!(glob_match("news.*", "news.sport"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*.log.*", "app.log.2024"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("news.*", "new.sport"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h?llo", "hällo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(!glob_match("h?llo", "heello"));
+
+        assert!(glob_match(r"a\*b", "a*b"));
+        assert!(!glob_match(r"a\*b", "aXb"));
+        assert!(glob_match(r"what\?", "what?"));
+        assert!(!glob_match(r"what\?", "whats"));
+        assert!(glob_match(r"back\\slash", r"back\slash"));
+        assert!(!glob_match(r"trailing\", r"trailing\"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    fn config(policy: SlowSubscriberPolicy) -> RocsConfig {
+        RocsConfig { pubsub_buffer: 2, slow_subscriber: policy, ..RocsConfig::default() }
+    }
+
+    async fn subscribe(actor: &PubSubCommandHandler, channel: &str) -> Subscribed {
+        let (respond_to, rx) = oneshot::channel();
+        let cmd = Command::Subscribe { user_id: "u".to_string(), channels: vec![channel.to_string()], respond_to };
+        actor.send(cmd).await.unwrap();
+        rx.await.unwrap().unwrap()
+    }
+
+    async fn publish(actor: &PubSubCommandHandler, channel: &str, n: i64) -> u64 {
+        let (respond_to, rx) = oneshot::channel();
+        let cmd = Command::Publish {
+            user_id: "u".to_string(),
+            channel: channel.to_string(),
+            message: Value::Int(n),
+            respond_to,
+        };
+        actor.send(cmd).await.unwrap();
+        rx.await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_miss_messages_under_the_drop_policy() {
+        let actor = spawn_pubsub_actor(&config(SlowSubscriberPolicy::Drop));
+        let mut sub = subscribe(&actor, "c").await;
+        for n in 0..5 {
+            let delivered = publish(&actor, "c", n).await;
+            assert_eq!(delivered, u64::from(n < 2));
+        }
+        assert_eq!(sub.messages.recv().await.unwrap().message, Value::Int(0));
+        assert_eq!(sub.messages.recv().await.unwrap().message, Value::Int(1));
+
+        assert_eq!(publish(&actor, "c", 5).await, 1);
+        let next = sub.messages.recv().await.unwrap();
+        assert_eq!((next.message, next.missed), (Value::Int(5), 3));
+        assert_eq!(publish(&actor, "c", 6).await, 1);
+        assert_eq!(sub.messages.recv().await.unwrap().missed, 0);
+    }
+
+    #[tokio::test]
+    async fn slow_subscribers_are_dropped_under_the_disconnect_policy() {
+        let actor = spawn_pubsub_actor(&config(SlowSubscriberPolicy::Disconnect));
+        let mut slow = subscribe(&actor, "c").await;
+        let mut fast = subscribe(&actor, "c").await;
+        assert_eq!(publish(&actor, "c", 0).await, 2);
+        assert_eq!(fast.messages.recv().await.unwrap().message, Value::Int(0));
+        assert_eq!(publish(&actor, "c", 1).await, 2);
+        assert_eq!(fast.messages.recv().await.unwrap().message, Value::Int(1));
+
+        // the slow subscriber's buffer of two is full now
+        assert_eq!(publish(&actor, "c", 2).await, 1);
+        assert_eq!(fast.messages.recv().await.unwrap().message, Value::Int(2));
+        assert_eq!(slow.messages.recv().await.unwrap().message, Value::Int(0));
+        assert_eq!(slow.messages.recv().await.unwrap().message, Value::Int(1));
+        assert!(slow.messages.recv().await.is_none(), "the subscription ended");
+        assert_eq!(publish(&actor, "c", 3).await, 1);
+    }
+}
@@ -0,0 +1,230 @@
+//! src/actors/snapshot_actor.rs
+//!
+//! Contains the Snapshot Actor, which writes point-in-time copies of the store to `snaps/` (one
+//! directory per shard, see `crate::shard`).
+//!
+//! The store actor hands over a clone of its `StoreState` and goes straight back to its command
+//! loop; serializing, checksumming and writing the snapshot all happen here. Every snapshot is
+//! recorded in `snaps/snapshots.json` (the manifest), and only the newest
+//! `RocsConfig::snapshot_retention` snapshots are kept on disk. A manifest that cannot be read
+//! is rebuilt from the snapshot files in the directory, so numbering carries on after the newest
+//! one instead of overwriting it.
+
+use crate::actors::logger_actor::{now_millis, sync_dir};
+use crate::actors::store_actor::{self, StoreState};
+use tokio::sync::{mpsc, oneshot};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// One entry of the snapshot manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    /// Snapshot sequence number, increasing by one per snapshot.
+    pub seq: u64,
+    /// Sequence number of the last WAL record contained in the snapshot.
+    pub wal_seq: u64,
+    /// Creation time, in milliseconds since the unix epoch.
+    pub created_at: u64,
+    /// File name of the snapshot, relative to the snapshot directory.
+    pub file: String,
+    /// Size of the snapshot file in bytes.
+    pub size: u64,
+    /// CRC32 of the snapshot file contents.
+    pub checksum: u32,
+}
+
+/// Commands understood by the snapshot actor.
+#[derive(Debug)]
+pub enum SnapshotCommand {
+    /// Write `state` to a new snapshot file and apply the retention policy.
+    ///
+    /// # Response
+    /// - Sends `Ok(seq)` with the sequence number of the new snapshot, or `Err(String)` on error.
+    Take {
+        state: StoreState,
+        respond_to: oneshot::Sender<Result<u64, String>>,
+    },
+
+    /// List the snapshots currently recorded in the manifest, oldest first.
+    List {
+        respond_to: oneshot::Sender<Vec<SnapshotMeta>>,
+    },
+}
+
+/// Type alias for the sender used to communicate with the snapshot actor.
+pub type SnapshotCommandHandler = mpsc::Sender<SnapshotCommand>;
+
+/// Spawns the snapshot actor as a Tokio task and returns its sender.
+///
+/// # Arguments
+/// * `dir` - Directory holding this shard's snapshots and manifest.
+/// * `retention` - Number of snapshots to keep; older ones are deleted after each new snapshot.
+pub fn spawn_snapshot_actor(dir: PathBuf, retention: usize) -> SnapshotCommandHandler {
+    let (tx, mut rx) = mpsc::channel::<SnapshotCommand>(16);
+
+    let (mut manifest, rebuilt) = read_or_rebuild_manifest(&dir);
+    if rebuilt {
+        match save_manifest(&dir, &manifest) {
+            Ok(()) => println!("[snapshot actor] Rebuilt {} from {} snapshot file(s)", manifest_path(&dir).display(), manifest.len()),
+            Err(e) => eprintln!("[snapshot actor] Failed to save the rebuilt manifest: {e}"),
+        }
+    }
+
+    tokio::spawn(async move {
+        while let Some(cmd) = rx.recv().await {
+            match cmd {
+                SnapshotCommand::Take { state, respond_to } => {
+                    let res = take_snapshot(&dir, &mut manifest, &state, retention).map_err(|e| e.to_string());
+                    if let Err(e) = &res {
+                        eprintln!("[snapshot actor] Snapshot failed: {e}");
+                    }
+                    let _ = respond_to.send(res);
+                }
+                SnapshotCommand::List { respond_to } => {
+                    let _ = respond_to.send(manifest.clone());
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+fn take_snapshot(
+    dir: &Path,
+    manifest: &mut Vec<SnapshotMeta>,
+    state: &StoreState,
+    retention: usize,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let seq = manifest.last().map(|m| m.seq + 1).unwrap_or(1);
+    let file = format!("snapshot-{seq:08}.bin");
+    fs::create_dir_all(dir)?;
+    let path = dir.join(&file);
+
+    let bytes = store_actor::encode_state(state)?;
+    write_durable(&path, &bytes)?;
+
+    manifest.push(SnapshotMeta {
+        seq,
+        wal_seq: state.wal_seq,
+        created_at: now_millis(),
+        file,
+        size: bytes.len() as u64,
+        checksum: crc32fast::hash(&bytes),
+    });
+
+    // Retention: drop the oldest snapshots beyond the configured count.
+    let excess = manifest.len().saturating_sub(retention.max(1));
+    let expired: Vec<SnapshotMeta> = manifest.drain(..excess).collect();
+
+    save_manifest(dir, manifest)?;
+
+    // Only delete files once the manifest no longer points at them.
+    for old in expired {
+        if let Err(e) = fs::remove_file(dir.join(&old.file)) {
+            eprintln!("[snapshot actor] Failed to remove expired snapshot {}: {e}", old.file);
+        }
+    }
+
+    println!("Snapshot {seq} written ({} bytes, wal_seq {})", bytes.len(), state.wal_seq);
+    Ok(seq)
+}
+
+/// Reads and verifies the snapshot in `dir` described by `meta`.
+pub fn load_snapshot(dir: &Path, meta: &SnapshotMeta) -> Result<StoreState, String> {
+    let bytes = fs::read(dir.join(&meta.file)).map_err(|e| e.to_string())?;
+    if crc32fast::hash(&bytes) != meta.checksum {
+        return Err(format!("checksum mismatch in {}", meta.file));
+    }
+    let mut state = store_actor::decode_state(&bytes)?;
+    state.wal_seq = meta.wal_seq;
+    Ok(state)
+}
+
+/// Reads `snapshots.json` in `dir`. A missing manifest is treated as empty; an unreadable one is
+/// rebuilt from the snapshot files in `dir`, without writing it back.
+pub fn load_manifest(dir: &Path) -> Vec<SnapshotMeta> {
+    read_or_rebuild_manifest(dir).0
+}
+
+/// The manifest in `dir`, and whether it had to be rebuilt from the snapshot files.
+fn read_or_rebuild_manifest(dir: &Path) -> (Vec<SnapshotMeta>, bool) {
+    let path = manifest_path(dir);
+    let bytes = match fs::read(&path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return (Vec::new(), false),
+        Err(e) => {
+            eprintln!("[snapshot actor] Failed to read manifest {}: {e}; rebuilding it", path.display());
+            return (rebuild_manifest(dir), true);
+        }
+    };
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return (Vec::new(), false);
+    }
+    match serde_json::from_slice(&bytes) {
+        Ok(manifest) => (manifest, false),
+        Err(e) => {
+            eprintln!("[snapshot actor] Unreadable manifest {}: {e}; rebuilding it", path.display());
+            (rebuild_manifest(dir), true)
+        }
+    }
+}
+
+/// Describes every `snapshot-<seq>.bin` in `dir` that decodes, oldest first.
+fn rebuild_manifest(dir: &Path) -> Vec<SnapshotMeta> {
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    let mut manifest: Vec<SnapshotMeta> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|entry| {
+            let file = entry.file_name().to_string_lossy().into_owned();
+            let seq = file.strip_prefix("snapshot-")?.strip_suffix(".bin")?.parse().ok()?;
+            let bytes = fs::read(entry.path()).ok()?;
+            let state = match store_actor::decode_state(&bytes) {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("[snapshot actor] Leaving out {file} from the rebuilt manifest: {e}");
+                    return None;
+                }
+            };
+            let created_at = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_millis() as u64);
+            Some(SnapshotMeta {
+                seq,
+                wal_seq: state.wal_seq,
+                created_at,
+                file,
+                size: bytes.len() as u64,
+                checksum: crc32fast::hash(&bytes),
+            })
+        })
+        .collect();
+    manifest.sort_by_key(|m| m.seq);
+    manifest
+}
+
+fn save_manifest(dir: &Path, manifest: &[SnapshotMeta]) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = serde_json::to_vec_pretty(manifest)?;
+    write_durable(&manifest_path(dir), &bytes)
+}
+
+/// Writes `bytes` to a temporary file, fsyncs it, renames it over `path` and fsyncs the directory.
+fn write_durable(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    sync_dir(path.parent().expect("snapshots live in a directory"))?;
+    Ok(())
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join("snapshots.json")
+}
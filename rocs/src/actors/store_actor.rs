@@ -5,20 +5,513 @@
 //! and processes only storage commands. Non-storage commands (like shutdown, logging, etc.)
 //! should be routed to their respective actors or handlers elsewhere for clear separation of concerns.
 
-use crate::command::Command;
+use crate::command::{Command, ValueFilter, WriteOp};
+use crate::config::{CorruptionPolicy, RocsConfig};
+use crate::value::{Value, Versioned};
+use crate::error::StoreError;
+use crate::actors::logger_actor::{self, LoggerCommandHandler, WalOp, now_millis};
+use crate::actors::snapshot_actor::{self, SnapshotCommand, SnapshotCommandHandler};
+use crate::aggregate::{Accumulator, Aggregate, KeyScope};
+use crate::keyspace;
+use crate::quota::{Quota, UserInfo};
+use crate::page::{self, Page};
+use crate::shard::{ShardDirs, ShardId};
+use crate::storage::{self, EntryIter, StorageEngine, UsageCounters};
+use crate::storage::bounded::Bounded;
+use crate::storage::index::{IndexKey, Indexed};
+use crate::watch::{WatchEvent, Watches};
+use crate::cdc::Feeds;
+use crate::mvcc::{ReadSnapshot, Versions};
 use tokio::sync::mpsc;
 use std::collections::{BTreeMap, BTreeSet};
-use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
 use serde::{Serialize, Deserialize};
 use uuid;
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 
-#[derive(Serialize, Deserialize, Default)]
+/// What is stored under a `(user_id, key)` pair.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub value: Value,
+    /// Absolute expiry in milliseconds since the unix epoch; `None` for persistent keys.
+    pub expires_at: Option<u64>,
+    /// Sequence number of the WAL record that last wrote this entry. Every write moves it
+    /// forward, and it never repeats for a key, even across a delete and re-create.
+    pub version: u64,
+}
+
+impl Entry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct StoreState {
-    pub kv: BTreeMap<(String, String), usize>,
+    pub kv: BTreeMap<(String, String), Entry>,
     pub users: BTreeSet<String>,
+    /// Sequence number of the last WAL record applied to this state.
+    pub wal_seq: u64,
+    /// Users whose values are indexed, see `storage::index`.
+    pub indexed: BTreeSet<String>,
+    /// Quotas set by an admin, see `crate::quota`.
+    pub quotas: BTreeMap<String, Quota>,
+    /// Keys with an expiry, ordered by expiry time. Rebuilt from `kv` on load.
+    #[serde(skip)]
+    expiry_index: BTreeSet<(u64, (String, String))>,
+    /// What every owner holds. Rebuilt from `kv` on load.
+    #[serde(skip)]
+    usage: UsageCounters,
+}
+
+/// Store files and snapshots start with this magic, followed by a little-endian `u32` format
+/// version, a little-endian CRC32 of the rest of the file (from version 5 on) and the
+/// bincode-encoded `StoreState`. Files without the magic predate typed values.
+const STORE_MAGIC: &[u8; 4] = b"ROCS";
+const STORE_FORMAT_VERSION: u32 = 7;
+
+/// Format version 6: before quotas could be set per user.
+#[derive(Deserialize)]
+struct StoreStateV6 {
+    kv: BTreeMap<(String, String), Entry>,
+    users: BTreeSet<String>,
+    wal_seq: u64,
+    indexed: BTreeSet<String>,
+}
+
+/// Format versions 4 and 5: before values could be indexed.
+#[derive(Deserialize)]
+struct StoreStateV5 {
+    kv: BTreeMap<(String, String), Entry>,
+    users: BTreeSet<String>,
+    wal_seq: u64,
+}
+
+/// Format version 3: entries with an expiry but no version.
+#[derive(Deserialize)]
+struct StoreStateV3 {
+    kv: BTreeMap<(String, String), EntryV3>,
+    users: BTreeSet<String>,
+    wal_seq: u64,
+}
+
+#[derive(Deserialize)]
+struct EntryV3 {
+    value: Value,
+    expires_at: Option<u64>,
+}
+
+/// Format version 2: typed values without expiry.
+#[derive(Deserialize)]
+struct StoreStateV2 {
+    kv: BTreeMap<(String, String), Value>,
+    users: BTreeSet<String>,
+    wal_seq: u64,
+}
+
+/// Unversioned layout with `usize` values, written before the WAL sequence was stored.
+#[derive(Deserialize)]
+struct StoreStateV0 {
+    kv: BTreeMap<(String, String), usize>,
+    users: BTreeSet<String>,
+}
+
+/// Unversioned layout with `usize` values and the WAL sequence number.
+#[derive(Deserialize)]
+struct StoreStateV1 {
+    kv: BTreeMap<(String, String), usize>,
+    users: BTreeSet<String>,
+    wal_seq: u64,
+}
+
+impl From<StoreStateV0> for StoreStateV1 {
+    fn from(old: StoreStateV0) -> Self {
+        Self { kv: old.kv, users: old.users, wal_seq: 0 }
+    }
+}
+
+impl From<StoreStateV1> for StoreStateV2 {
+    fn from(old: StoreStateV1) -> Self {
+        Self {
+            kv: old.kv.into_iter().map(|(k, v)| (k, Value::from_legacy(v))).collect(),
+            users: old.users,
+            wal_seq: old.wal_seq,
+        }
+    }
+}
+
+impl From<StoreStateV2> for StoreStateV3 {
+    fn from(old: StoreStateV2) -> Self {
+        Self {
+            kv: old.kv.into_iter().map(|(k, value)| (k, EntryV3 { value, expires_at: None })).collect(),
+            users: old.users,
+            wal_seq: old.wal_seq,
+        }
+    }
+}
+
+impl From<StoreStateV3> for StoreStateV5 {
+    fn from(old: StoreStateV3) -> Self {
+        // Every existing entry was written at or before `wal_seq`, so later writes still move
+        // their version forward.
+        let version = old.wal_seq;
+        Self {
+            kv: old
+                .kv
+                .into_iter()
+                .map(|(k, e)| (k, Entry { value: e.value, expires_at: e.expires_at, version }))
+                .collect(),
+            users: old.users,
+            wal_seq: old.wal_seq,
+        }
+    }
+}
+
+impl From<StoreStateV5> for StoreStateV6 {
+    fn from(old: StoreStateV5) -> Self {
+        Self { kv: old.kv, users: old.users, wal_seq: old.wal_seq, indexed: BTreeSet::new() }
+    }
+}
+
+impl From<StoreStateV6> for StoreState {
+    fn from(old: StoreStateV6) -> Self {
+        Self {
+            kv: old.kv,
+            users: old.users,
+            wal_seq: old.wal_seq,
+            indexed: old.indexed,
+            quotas: BTreeMap::new(),
+            expiry_index: BTreeSet::new(),
+            usage: UsageCounters::default(),
+        }
+    }
+}
+
+/// Encodes `state` in the current store file format.
+pub fn encode_state(state: &StoreState) -> Result<Vec<u8>, bincode::Error> {
+    let body = bincode::serialize(state)?;
+    let mut bytes = Vec::with_capacity(12 + body.len());
+    bytes.extend_from_slice(STORE_MAGIC);
+    bytes.extend_from_slice(&STORE_FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+/// Decodes a store file or snapshot written by this or an older version of the server.
+///
+/// Fails on a checksum mismatch, a truncated file or a format version newer than this server.
+pub fn decode_state(bytes: &[u8]) -> Result<StoreState, String> {
+    let mut state = decode_any_version(bytes)?;
+    state.rebuild_indexes();
+    Ok(state)
+}
+
+fn decode_any_version(bytes: &[u8]) -> Result<StoreState, String> {
+    if let Some(rest) = bytes.strip_prefix(STORE_MAGIC) {
+        let (version, body) = rest.split_at_checked(4).ok_or("truncated store file header")?;
+        let version = u32::from_le_bytes(version.try_into().expect("4 bytes"));
+        return match version {
+            STORE_FORMAT_VERSION => bincode::deserialize(checked_body(body)?).map_err(|e| e.to_string()),
+            6 => bincode::deserialize::<StoreStateV6>(checked_body(body)?)
+                .map(StoreState::from)
+                .map_err(|e| e.to_string()),
+            5 => bincode::deserialize::<StoreStateV5>(checked_body(body)?)
+                .map(|v5| StoreStateV6::from(v5).into())
+                .map_err(|e| e.to_string()),
+            // version 4 had the layout of version 5, only without the checksum
+            4 => bincode::deserialize::<StoreStateV5>(body)
+                .map(|v5| StoreStateV6::from(v5).into())
+                .map_err(|e| e.to_string()),
+            3 => bincode::deserialize::<StoreStateV3>(body)
+                .map(|v3| StoreStateV6::from(StoreStateV5::from(v3)).into())
+                .map_err(|e| e.to_string()),
+            2 => bincode::deserialize::<StoreStateV2>(body)
+                .map(|v2| StoreStateV6::from(StoreStateV5::from(StoreStateV3::from(v2))).into())
+                .map_err(|e| e.to_string()),
+            other if other > STORE_FORMAT_VERSION => {
+                Err(format!("store file format version {other} is newer than this server supports ({STORE_FORMAT_VERSION})"))
+            }
+            other => Err(format!("unsupported store file format version {other}")),
+        };
+    }
+
+    let v1 = match bincode::deserialize::<StoreStateV1>(bytes) {
+        Ok(old) => old,
+        Err(e) => bincode::deserialize::<StoreStateV0>(bytes)
+            .map(StoreStateV1::from)
+            .map_err(|_| e.to_string())?,
+    };
+    Ok(StoreStateV6::from(StoreStateV5::from(StoreStateV3::from(StoreStateV2::from(v1)))).into())
+}
+
+/// Strips the CRC32 in front of `body` after checking it. Used for store files and deltas.
+pub(crate) fn checked_body(body: &[u8]) -> Result<&[u8], String> {
+    let (crc, body) = body.split_at_checked(4).ok_or("truncated header")?;
+    let crc = u32::from_le_bytes(crc.try_into().expect("4 bytes"));
+    let actual = crc32fast::hash(body);
+    if crc != actual {
+        return Err(format!("checksum mismatch (header says {crc:#010x}, contents hash to {actual:#010x})"));
+    }
+    Ok(body)
+}
+
+impl StoreState {
+    pub(crate) fn insert_entry(&mut self, key: (String, String), entry: Entry) {
+        if let Some(at) = entry.expires_at {
+            self.expiry_index.insert((at, key.clone()));
+        }
+        let old = self.kv.insert(key.clone(), entry);
+        self.usage.replace(&key, old.as_ref(), self.kv.get(&key));
+        if let Some(old) = old {
+            self.unindex_expiry(&key, &old);
+        }
+    }
+
+    pub(crate) fn remove_entry(&mut self, key: &(String, String)) -> Option<Entry> {
+        let old = self.kv.remove(key)?;
+        self.usage.replace(key, Some(&old), None);
+        self.unindex_expiry(key, &old);
+        Some(old)
+    }
+
+    pub(crate) fn usage(&self) -> &UsageCounters {
+        &self.usage
+    }
+
+    fn unindex_expiry(&mut self, key: &(String, String), old: &Entry) {
+        if let Some(at) = old.expires_at {
+            // the new entry may have the same expiry, in which case the index row must stay
+            if self.kv.get(key).and_then(|e| e.expires_at) != Some(at) {
+                self.expiry_index.remove(&(at, key.clone()));
+            }
+        }
+    }
+
+    /// Drops every key whose expiry is at or before `now`. Returns the keys removed.
+    ///
+    /// Expired keys are not logged: their expiry is in the WAL, so replay reaches the same result.
+    pub(crate) fn purge_expired(&mut self, now: u64) -> Vec<(String, String)> {
+        let mut removed = Vec::new();
+        while let Some((at, key)) = self.expiry_index.first().cloned() {
+            if at > now {
+                break;
+            }
+            self.expiry_index.pop_first();
+            if let Some(old) = self.kv.remove(&key) {
+                self.usage.replace(&key, Some(&old), None);
+            }
+            removed.push(key);
+        }
+        removed
+    }
+
+    fn rebuild_indexes(&mut self) {
+        self.expiry_index = self
+            .kv
+            .iter()
+            .filter_map(|(k, e)| e.expires_at.map(|at| (at, k.clone())))
+            .collect();
+        self.usage = UsageCounters::default();
+        for (key, entry) in &self.kv {
+            self.usage.replace(key, None, Some(entry));
+        }
+    }
+}
+
+/// Applies the state change logged as record `seq`. Used both for live writes and for WAL replay.
+pub(crate) fn apply(engine: &mut dyn StorageEngine, seq: u64, op: WalOp) -> io::Result<()> {
+    apply_op(engine, seq, op)?;
+    // Only now: an engine that flushes halfway through a batch must not claim the whole record.
+    engine.set_wal_seq(seq);
+    Ok(())
 }
+
+fn apply_op(engine: &mut dyn StorageEngine, seq: u64, op: WalOp) -> io::Result<()> {
+    match op {
+        WalOp::AddUser { user_id } => engine.add_user(user_id),
+        WalOp::Put { user_id, key, value, expires_at } => {
+            engine.put((user_id, key), Entry { value, expires_at, version: seq })?;
+        }
+        WalOp::Delete { user_id, key } => engine.delete(&(user_id, key))?,
+        WalOp::Batch(ops) => {
+            for op in ops {
+                apply_op(engine, seq, op)?;
+            }
+        }
+        WalOp::CreateKeyspace { user_id, keyspace } => engine.add_user(keyspace::owner_id(&user_id, &keyspace)),
+        WalOp::DropKeyspace { user_id, keyspace } => {
+            let owner = keyspace::owner_id(&user_id, &keyspace);
+            let keys = engine
+                .scan(&owner, (Bound::Unbounded, Bound::Unbounded))
+                .map(|r| r.map(|(key, _)| key))
+                .collect::<io::Result<Vec<_>>>()?;
+            for key in keys {
+                engine.delete(&(owner.clone(), key))?;
+            }
+            engine.set_indexed(&owner, false)?;
+            engine.remove_user(&owner);
+        }
+        WalOp::CreateIndex { user_id } => engine.set_indexed(&user_id, true)?,
+        WalOp::DropIndex { user_id } => engine.set_indexed(&user_id, false)?,
+        WalOp::SetQuota { user_id, quota } => engine.set_quota(&user_id, quota)?,
+    }
+    Ok(())
+}
+
+/// The engine of the store actor: the configured engine with value indexes and memory budgets.
+type Engine = Bounded<Indexed>;
+
+/// The entry under `key`, unless it does not exist or has expired.
+fn live(engine: &dyn StorageEngine, key: &(String, String), now: u64) -> Result<Option<Entry>, String> {
+    match engine.get(key) {
+        Ok(entry) => Ok(entry.filter(|e| !e.is_expired(now))),
+        Err(e) => Err(format!("failed to read {:?}: {e}", key.1)),
+    }
+}
+
+/// The live entries of `user_id` in `range`.
+fn live_range(
+    engine: &dyn StorageEngine,
+    user_id: &str,
+    range: (Bound<String>, Bound<String>),
+    now: u64,
+) -> Result<Vec<((String, String), Value)>, String> {
+    engine
+        .scan(user_id, range)
+        .filter(|r| !matches!(r, Ok((_, e)) if e.is_expired(now)))
+        .map(|r| r.map(|(k, e)| ((user_id.to_string(), k), e.value)))
+        .collect::<io::Result<_>>()
+        .map_err(|e| format!("failed to scan: {e}"))
+}
+
+/// One page of the live entries of `user_id` in `range`, as returned by `Range` and `List`.
+///
+/// Seeks to the start of `range` (its end when `reverse`) and stops at the first key failing
+/// `within`, reading one live entry past the page to tell whether a next page exists.
+fn live_page(
+    engine: &dyn StorageEngine,
+    user_id: &str,
+    range: (Bound<String>, Bound<String>),
+    reverse: bool,
+    limit: usize,
+    within: impl Fn(&str) -> bool,
+    now: u64,
+) -> Result<Page, String> {
+    let scan = if reverse { engine.scan_rev(user_id, range) } else { engine.scan(user_id, range) };
+    page_of(scan, user_id, limit, within, now)
+}
+
+/// Like `live_page`, but as of `snapshot` if one is given.
+#[allow(clippy::too_many_arguments)]
+fn read_page(
+    engine: &dyn StorageEngine,
+    versions: &Versions,
+    snapshot: Option<ReadSnapshot>,
+    user_id: &str,
+    range: (Bound<String>, Bound<String>),
+    reverse: bool,
+    limit: usize,
+    within: impl Fn(&str) -> bool,
+) -> Result<Page, String> {
+    match snapshot {
+        Some(snapshot) => {
+            let scan = versions.scan(engine, user_id, range, reverse, &snapshot);
+            page_of(scan, user_id, limit, within, snapshot.time)
+        }
+        None => live_page(engine, user_id, range, reverse, limit, within, now_millis()),
+    }
+}
+
+/// One page out of `scan`, stopping at the first key failing `within` and skipping entries
+/// expired at `now`.
+fn page_of(scan: EntryIter<'_>, user_id: &str, limit: usize, within: impl Fn(&str) -> bool, now: u64) -> Result<Page, String> {
+    let mut items = scan
+        .take_while(|r| !matches!(r, Ok((k, _)) if !within(k)))
+        .filter(|r| !matches!(r, Ok((_, e)) if e.is_expired(now)))
+        .take(limit + 1)
+        .map(|r| r.map(|(k, e)| ((user_id.to_string(), k), e.value)))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| format!("failed to scan: {e}"))?;
+    let cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|((_, k), _)| page::encode_cursor(k))
+    } else {
+        None
+    };
+    Ok(Page { items, cursor })
+}
+
+/// The live entries of `user_id` whose value matches `filter`, looked up in its value index.
+fn query(
+    engine: &Engine,
+    user_id: &str,
+    filter: ValueFilter,
+    now: u64,
+) -> Result<Vec<(String, Value)>, StoreError> {
+    let index_key = |value: Value| {
+        IndexKey::of(&value).ok_or_else(|| StoreError::Other(format!("{} values are not indexed", value.type_name())))
+    };
+    let range = match filter {
+        ValueFilter::Eq(value) => {
+            let key = index_key(value)?;
+            (Bound::Included(key.clone()), Bound::Included(key))
+        }
+        ValueFilter::Range { min, max } => (
+            min.map(index_key).transpose()?.map_or(Bound::Unbounded, Bound::Included),
+            max.map(index_key).transpose()?.map_or(Bound::Unbounded, Bound::Included),
+        ),
+    };
+    let keys = engine.lookup(user_id, range.clone()).ok_or(StoreError::NotIndexed)?;
+    let mut found = Vec::with_capacity(keys.len());
+    for key in keys {
+        // rows of expired keys stay until the next purge, so check each key against the engine
+        if let Some(entry) = live(engine, &(user_id.to_string(), key.clone()), now)? {
+            if IndexKey::of(&entry.value).is_some_and(|v| range.contains(&v)) {
+                found.push((key, entry.value));
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// `function` over the live values of `user_id` in `scope`.
+fn aggregate(
+    engine: &dyn StorageEngine,
+    user_id: &str,
+    function: Aggregate,
+    scope: KeyScope,
+    now: u64,
+) -> Result<Option<Value>, StoreError> {
+    let (range, prefix) = match scope {
+        KeyScope::All => ((Bound::Unbounded, Bound::Unbounded), String::new()),
+        KeyScope::Prefix(prefix) => ((Bound::Included(prefix.clone()), Bound::Unbounded), prefix),
+        KeyScope::Range { start, end } => ((Bound::Included(start), Bound::Included(end)), String::new()),
+    };
+    let mut acc = Accumulator::default();
+    for record in engine.scan(user_id, range) {
+        let (key, entry) = record.map_err(|e| format!("failed to scan: {e}"))?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        if !entry.is_expired(now) {
+            acc.add(&entry.value);
+        }
+    }
+    acc.finish(function)
+}
+
+/// The page size for a request asking for `limit` entries.
+fn page_size(limit: Option<usize>, max_page_size: usize) -> Result<usize, String> {
+    match limit {
+        Some(0) => Err("page size must be at least 1".to_string()),
+        Some(limit) => Ok(limit.min(max_page_size)),
+        None => Ok(max_page_size),
+    }
+}
+
 /// Type alias for the sender used to communicate with the store actor.
 pub type StoreCommandHandler = mpsc::Sender<Command>;
 
@@ -28,29 +521,90 @@ pub type StoreCommandHandler = mpsc::Sender<Command>;
 /// The store actor owns its state and only responds to commands related to data storage
 /// and retrieval (Store, Fetch, Delete, Update, Range, List).
 ///
+/// There is one store actor per shard. It holds the users that `shard` owns, keeps its files in
+/// `shard.dirs()`, and only hands out new user ids that belong to it.
+///
+/// Entries live in the `StorageEngine` selected by `config.storage_engine`. On startup the
+/// engine is opened (or restored from the newest snapshot, if that is more recent) and the
+/// write-ahead log is replayed on top of it. Every mutation is appended to the log through
+/// `logger` before it is applied and acknowledged. The engine is flushed every
+/// `config.persist_interval_ms`, skipping ticks where it has nothing new.
+///
+/// `Snapshot` commands (and the optional snapshot timer, every `config.snapshot_interval_secs`)
+/// export a copy of the state and hand it to `snapshotter`, so the command loop never waits on
+/// snapshot IO.
+///
+/// Expired keys are invisible to every read as soon as their expiry passes. They are removed
+/// when a read runs into them and by a sweep every `config.ttl_sweep_interval_ms`.
+///
+/// The actor also keeps the watches of its users (see `crate::watch`), and hands them the
+/// changes of every write once it is applied. Likewise it keeps their read snapshots and the old
+/// versions those need (see `crate::mvcc`).
+///
 /// # Example
-/// ```rust
-/// let store_sender = spawn_store_actor();
+/// ```rust,ignore
+/// let shard = ShardId { index: 0, count: 1 };
+/// let store_sender = spawn_store_actor(shard, logger_sender, snapshot_sender, &RocsConfig::from_env());
 /// // Use store_sender to send storage commands.
 /// ```
 ///
 /// # Design Note
 /// This actor should **not** handle commands unrelated to storage (such as Shutdown, Crash, etc.).
 /// Route such commands to other actors for better modularity and maintainability.
-pub fn spawn_store_actor() -> StoreCommandHandler {
+pub fn spawn_store_actor(
+    shard: ShardId,
+    logger: LoggerCommandHandler,
+    snapshotter: SnapshotCommandHandler,
+    config: &RocsConfig,
+) -> StoreCommandHandler {
     // Buffer size set to 128 for the mpsc channel.
     let (tx, mut rx) = mpsc::channel::<Command>(128);
 
-    let path = store_path();
-    let mut state = if let Ok(bytes) = fs::read(&path) {
-        bincode::deserialize(&bytes).unwrap_or_default()
-    } else {
-        StoreState::default()
-    };
+    let dirs = shard.dirs();
+    let engine = Indexed::new(open_engine(config, &dirs))
+        .unwrap_or_else(|e| refuse_to_start(&format!("failed to build the value indexes: {e}")));
+    let mut engine = Bounded::new(engine, config, shard.count)
+        .unwrap_or_else(|e| refuse_to_start(&format!("failed to measure the memory use of the store: {e}")));
+
+    let replayed = logger_actor::read_wal(&dirs.wal, engine.wal_seq());
+    if let Some(first) = replayed.first() {
+        if first.seq > engine.wal_seq() + 1 {
+            refuse_to_start(&format!(
+                "the store is at wal_seq {} but the WAL starts at record {}; records in between are missing",
+                engine.wal_seq(),
+                first.seq
+            ));
+        }
+    }
+    if !replayed.is_empty() {
+        println!("Replaying {} WAL records", replayed.len());
+    }
+    for record in replayed {
+        let seq = record.seq;
+        apply(&mut engine, seq, record.op).unwrap_or_else(|e| panic!("Failed to replay WAL record {seq}: {e}"));
+    }
+
+    let max_page_size = config.max_page_size;
+    let snapshot_interval_secs = config.snapshot_interval_secs;
+    let sweep_period = Duration::from_millis(config.ttl_sweep_interval_ms.max(1));
+    let persist_period = Duration::from_millis(config.persist_interval_ms.max(1));
+    let read_snapshot_idle_ms = config.read_snapshot_idle_secs.saturating_mul(1000);
 
     tokio::spawn(async move {
 
-        let mut interval = time::interval(Duration::from_secs(10));
+        let mut watches = Watches::default();
+        let mut feeds = Feeds::new(shard.index);
+        let mut versions = Versions::default();
+
+        let mut persist_interval = time::interval(persist_period);
+
+        // the first snapshot is due one full period after boot, not immediately
+        let mut snapshot_interval = (snapshot_interval_secs > 0).then(|| {
+            let period = Duration::from_secs(snapshot_interval_secs);
+            time::interval_at(Instant::now() + period, period)
+        });
+
+        let mut sweep_interval = time::interval(sweep_period);
 
         loop {
 
@@ -60,53 +614,391 @@ pub fn spawn_store_actor() -> StoreCommandHandler {
 
                     match maybe_cmd {
                         Some(cmd) => {
+                            let engine = &mut engine;
                             match cmd {
                                 Command::Hi { user_id, respond_to } => {
                                     let assigned_id = match user_id {
-                                        Some(id) if state.users.contains(&id) => id,
+                                        // owner ids of named keyspaces are not user ids
+                                        Some(id) if keyspace::is_user_id(&id) && engine.has_user(&id) => id,
                                         _ => {
-                                            let new_id = uuid::Uuid::new_v4().to_string();
-                                            state.users.insert(new_id.clone());
+                                            let new_id = new_user_id(shard);
+                                            let op = WalOp::AddUser { user_id: new_id.clone() };
+                                            if let Err(e) = log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await {
+                                                // the id still works for this run, but without a WAL record it may not survive a restart
+                                                eprintln!("Failed to log new user {new_id}: {e}");
+                                                engine.add_user(new_id.clone());
+                                            }
                                             new_id
                                         }
                                     };
                                     let _ = respond_to.send(assigned_id);
                                 },
-                                Command::Set { user_id, key, value, respond_to } => {
-                                    state.kv.insert((user_id, key), value);
-                                    let _ = respond_to.send(Ok(()));
+                                Command::Set { user_id, key, value, expiry, respond_to } => {
+                                    let expires_at = expiry.map(|e| e.deadline(now_millis()));
+                                    let op = WalOp::Put { user_id, key, value, expires_at };
+                                    let res = log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await.map_err(String::from);
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Get { user_id, key, snapshot: Some(id), respond_to } => {
+                                    let res = versions.at(&user_id, id, now_millis()).map_err(String::from).and_then(|snapshot| {
+                                        let k = (user_id, key);
+                                        let entry = versions.get(engine, &k, &snapshot).map_err(|e| format!("failed to read: {e}"))?;
+                                        if entry.is_some() {
+                                            engine.touch(&k);
+                                        }
+                                        Ok(entry.map(|e| Versioned { value: e.value, version: e.version }))
+                                    });
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Get { user_id, key, snapshot: None, respond_to } => {
+                                    let now = now_millis();
+                                    let k = (user_id, key);
+                                    let res = match engine.get(&k) {
+                                        Ok(Some(entry)) if entry.is_expired(now) => {
+                                            // lazily reclaim the expired key, unless a read snapshot still sees it
+                                            if entry.is_expired(versions.expiry_horizon(now)) {
+                                                if let Err(e) = engine.delete(&k) {
+                                                    eprintln!("Failed to reclaim expired key {:?}: {e}", k.1);
+                                                }
+                                            }
+                                            Ok(None)
+                                        }
+                                        Ok(Some(entry)) => {
+                                            engine.touch(&k);
+                                            Ok(Some(Versioned { value: entry.value, version: entry.version }))
+                                        }
+                                        Ok(None) => Ok(None),
+                                        Err(e) => Err(format!("failed to read {:?}: {e}", k.1)),
+                                    };
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::MGet { user_id, keys, snapshot, respond_to } => {
+                                    let now = now_millis();
+                                    let res = snapshot.map(|id| versions.at(&user_id, id, now)).transpose().map_err(String::from).and_then(|snapshot| {
+                                        keys.into_iter()
+                                            .map(|key| {
+                                                let k = (user_id.clone(), key);
+                                                let entry = match &snapshot {
+                                                    Some(snapshot) => versions.get(engine, &k, snapshot).map_err(|e| format!("failed to read {:?}: {e}", k.1))?,
+                                                    None => live(engine, &k, now)?,
+                                                };
+                                                if entry.is_some() {
+                                                    engine.touch(&k);
+                                                }
+                                                Ok(entry.map(|e| Versioned { value: e.value, version: e.version }))
+                                            })
+                                            .collect()
+                                    });
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::MSet { user_id, pairs, respond_to } => {
+                                    let count = pairs.len();
+                                    let ops = pairs.into_iter().map(|(key, value)| WriteOp::Set { key, value, expiry: None }).collect();
+                                    let res = match plan_batch(engine, &user_id, ops, now_millis()) {
+                                        Ok(planned) if planned.is_empty() => Ok(Vec::new()),
+                                        Ok(planned) => log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::Batch(planned))
+                                            .await
+                                            .map(|_| vec![engine.wal_seq(); count])
+                                            .map_err(String::from),
+                                        Err(e) => Err(e.to_string()),
+                                    };
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::MDel { user_id, keys, respond_to } => {
+                                    let res: Result<_, String> = async {
+                                        let now = now_millis();
+                                        let mut seen = BTreeSet::new();
+                                        let mut olds = Vec::with_capacity(keys.len());
+                                        let mut deletes = Vec::new();
+                                        for key in keys {
+                                            let old = match seen.insert(key.clone()) {
+                                                true => live(engine, &(user_id.clone(), key.clone()), now)?.map(|e| e.value),
+                                                false => None,
+                                            };
+                                            if old.is_some() {
+                                                deletes.push(WalOp::Delete { user_id: user_id.clone(), key });
+                                            }
+                                            olds.push(old);
+                                        }
+                                        if !deletes.is_empty() {
+                                            log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::Batch(deletes)).await?;
+                                        }
+                                        Ok(olds)
+                                    }.await;
+                                    let _ = respond_to.send(res);
                                 },
-                                Command::Get { user_id, key, respond_to } => {
-                                    let val = state.kv.get(&(user_id, key)).cloned();
-                                    let _ = respond_to.send(Ok(val));
+                                Command::Cas { user_id, key, value, version, expiry, respond_to } => {
+                                    let res = async {
+                                        let now = now_millis();
+                                        match live(engine, &(user_id.clone(), key.clone()), now)? {
+                                            Some(entry) if entry.version == version => {
+                                                let expires_at = expiry.map(|e| e.deadline(now)).or(entry.expires_at);
+                                                let op = WalOp::Put { user_id, key, value, expires_at };
+                                                log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await?;
+                                                Ok(engine.wal_seq())
+                                            }
+                                            current => Err(StoreError::VersionMismatch { key, expected: version, actual: current.map(|e| e.version) }),
+                                        }
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::SetNx { user_id, key, value, expiry, respond_to } => {
+                                    let res = async {
+                                        let now = now_millis();
+                                        if let Some(entry) = live(engine, &(user_id.clone(), key.clone()), now)? {
+                                            return Err(StoreError::KeyExists { key, version: entry.version });
+                                        }
+                                        let expires_at = expiry.map(|e| e.deadline(now));
+                                        let op = WalOp::Put { user_id, key, value, expires_at };
+                                        log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await?;
+                                        Ok(engine.wal_seq())
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::DelIfVersion { user_id, key, version, respond_to } => {
+                                    let res = async {
+                                        let actual = live(engine, &(user_id.clone(), key.clone()), now_millis())?.map(|e| e.version);
+                                        if actual != Some(version) {
+                                            return Err(StoreError::DeleteConflict { key, expected: version, actual });
+                                        }
+                                        Ok(log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::Delete { user_id, key }).await?)
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::IncrBy { user_id, key, delta, respond_to } => {
+                                    let res = add_checked(engine, &logger, &mut watches, &mut feeds, &mut versions, user_id, key, |v| v.checked_add(delta)).await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::DecrBy { user_id, key, delta, respond_to } => {
+                                    let res = add_checked(engine, &logger, &mut watches, &mut feeds, &mut versions, user_id, key, |v| v.checked_sub(delta)).await;
+                                    let _ = respond_to.send(res);
                                 },
                                 Command::Del { user_id, key, respond_to } => {
-                                    let _deleted = state.kv.remove(&(user_id, key.clone())).map(|v| (key, v));
-                                    let _ = respond_to.send(Ok(()));
+                                    let res = async {
+                                        match live(engine, &(user_id.clone(), key.clone()), now_millis())? {
+                                            Some(old) => {
+                                                log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::Delete { user_id, key }).await?;
+                                                Ok(Some(old.value))
+                                            }
+                                            None => Ok(None),
+                                        }
+                                    }.await;
+                                    let _ = respond_to.send(res);
                                 },
-                                Command::Update { user_id, key, value, respond_to } => {
-                                    state.kv.insert((user_id, key), value);
-                                    let _ = respond_to.send(Ok(()));
+                                Command::Update { user_id, key, value, expiry, respond_to } => {
+                                    let res = async {
+                                        let now = now_millis();
+                                        let Some(entry) = live(engine, &(user_id.clone(), key.clone()), now)? else {
+                                            return Err(StoreError::NotFound { key });
+                                        };
+                                        let expires_at = expiry.map(|e| e.deadline(now)).or(entry.expires_at);
+                                        let op = WalOp::Put { user_id, key, value, expires_at };
+                                        Ok(log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await?)
+                                    }.await;
+                                    let _ = respond_to.send(res);
                                 },
-                                Command::Range { user_id, start, end, respond_to } => {
-                                    let res = state.kv
-                                        .range((user_id.clone(), start)..=(user_id.clone(), end))
-                                        .map(|((u, k), &v)| ((u.clone(), k.clone()), v))
-                                        .collect();
-                                    let _ = respond_to.send(Ok(res));
+                                Command::Upsert { user_id, key, value, expiry, respond_to } => {
+                                    let res = async {
+                                        let now = now_millis();
+                                        let current = live(engine, &(user_id.clone(), key.clone()), now)?.and_then(|e| e.expires_at);
+                                        let expires_at = expiry.map(|e| e.deadline(now)).or(current);
+                                        let op = WalOp::Put { user_id, key, value, expires_at };
+                                        Ok(log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await?)
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Batch { user_id, ops, respond_to } => {
+                                    let res = match plan_batch(engine, &user_id, ops, now_millis()) {
+                                        Ok(planned) if planned.is_empty() => Ok(()),
+                                        // one WAL record, so a crash can never leave half a batch behind
+                                        Ok(planned) => log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::Batch(planned)).await,
+                                        Err(e) => Err(e),
+                                    };
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Range { user_id, start, end, reverse, limit, cursor, snapshot, respond_to } => {
+                                    let res = page_size(limit, max_page_size).and_then(|limit| {
+                                        let range = page::resume((start, end), reverse, cursor.as_deref())?;
+                                        let snapshot = snapshot.map(|id| versions.at(&user_id, id, now_millis())).transpose()?;
+                                        let page = read_page(engine, &versions, snapshot, &user_id, range, reverse, limit, |_| true)?;
+                                        for (key, _) in &page.items {
+                                            engine.touch(key);
+                                        }
+                                        Ok(page)
+                                    });
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::List { user_id, prefix, limit, cursor, snapshot, respond_to } => {
+                                    let res = page_size(limit, max_page_size).and_then(|limit| {
+                                        let prefix = prefix.unwrap_or_default();
+                                        let range = (Bound::Included(prefix.clone()), Bound::Unbounded);
+                                        let range = page::resume(range, false, cursor.as_deref())?;
+                                        let within = |k: &str| k.starts_with(prefix.as_str());
+                                        let snapshot = snapshot.map(|id| versions.at(&user_id, id, now_millis())).transpose()?;
+                                        read_page(engine, &versions, snapshot, &user_id, range, false, limit, within)
+                                    });
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::CreateKeyspace { user_id, name, respond_to } => {
+                                    let res = async {
+                                        keyspace::validate_name(&name)?;
+                                        if engine.has_user(&keyspace::owner_id(&user_id, &name)) {
+                                            return Err(StoreError::KeyspaceExists { name });
+                                        }
+                                        let op = WalOp::CreateKeyspace { user_id, keyspace: name };
+                                        Ok(log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await?)
+                                    }.await;
+                                    let _ = respond_to.send(res);
                                 },
-                                Command::List { user_id, respond_to } => {
-                                    let res = state.kv
-                                        .iter()
-                                        .filter(|((u, _), _)| *u == user_id)
-                                        .map(|((u, k), &v)| ((u.clone(), k.clone()), v))
+                                Command::ListKeyspaces { user_id, respond_to } => {
+                                    let prefix = keyspace::owner_prefix(&user_id);
+                                    let named = engine.users_with_prefix(&prefix);
+                                    let names = std::iter::once(keyspace::DEFAULT_KEYSPACE.to_string())
+                                        .chain(named.iter().map(|owner| owner[prefix.len()..].to_string()))
                                         .collect();
-                                    let _ = respond_to.send(Ok(res));
+                                    let _ = respond_to.send(Ok(names));
                                 },
-                                Command::Persist { respond_to } => {
-                                    let res = persist_state(&state).map_err(|e| e.to_string());
+                                Command::DropKeyspace { user_id, name, respond_to } => {
+                                    let res = async {
+                                        keyspace::validate_name(&name)?;
+                                        let owner = keyspace::owner_id(&user_id, &name);
+                                        if !engine.has_user(&owner) {
+                                            return Err(StoreError::NoSuchKeyspace { name });
+                                        }
+                                        let range = (Bound::Unbounded, Bound::Unbounded);
+                                        let dropped = live_range(engine, &owner, range, now_millis())?.len() as u64;
+                                        let op = WalOp::DropKeyspace { user_id, keyspace: name };
+                                        log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await?;
+                                        watches.remove_owner(&owner);
+                                        Ok(dropped)
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Aggregate { user_id, function, scope, respond_to } => {
+                                    let res = aggregate(engine, &user_id, function, scope, now_millis());
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::CreateIndex { user_id, respond_to } => {
+                                    let res = async {
+                                        if !engine.is_indexed(&user_id) {
+                                            log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::CreateIndex { user_id: user_id.clone() }).await?;
+                                        }
+                                        Ok(engine.indexed_keys(&user_id) as u64)
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::DropIndex { user_id, respond_to } => {
+                                    let res = async {
+                                        if !engine.is_indexed(&user_id) {
+                                            return Err(StoreError::NotIndexed);
+                                        }
+                                        Ok(log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::DropIndex { user_id }).await?)
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Query { user_id, filter, respond_to } => {
+                                    let _ = respond_to.send(query(engine, &user_id, filter, now_millis()));
+                                },
+                                Command::Ttl { user_id, key, respond_to } => {
+                                    let now = now_millis();
+                                    let res = match live(engine, &(user_id, key), now) {
+                                        Ok(Some(entry)) => Ok(entry.expires_at.map(|at| at - now)),
+                                        Ok(None) => Err("key not found".to_string()),
+                                        Err(e) => Err(e),
+                                    };
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Expire { user_id, key, expiry, respond_to } => {
+                                    let res = async {
+                                        let now = now_millis();
+                                        match live(engine, &(user_id.clone(), key.clone()), now)? {
+                                            Some(entry) => {
+                                                let op = WalOp::Put { user_id, key, value: entry.value, expires_at: Some(expiry.deadline(now)) };
+                                                log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await.map(|_| true).map_err(String::from)
+                                            }
+                                            None => Ok(false),
+                                        }
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::PersistKey { user_id, key, respond_to } => {
+                                    let res = async {
+                                        match live(engine, &(user_id.clone(), key.clone()), now_millis())? {
+                                            Some(entry) if entry.expires_at.is_some() => {
+                                                let op = WalOp::Put { user_id, key, value: entry.value, expires_at: None };
+                                                log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, op).await.map(|_| true).map_err(String::from)
+                                            }
+                                            _ => Ok(false),
+                                        }
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Info { user_id, respond_to } => {
+                                    let usage = engine.usage().user(&user_id);
+                                    let info = UserInfo {
+                                        keyspaces: engine.users_with_prefix(&keyspace::owner_prefix(&user_id)).len() as u64,
+                                        keys: usage.keys,
+                                        bytes: usage.bytes,
+                                        quota: engine.quota_of(&user_id),
+                                        custom_quota: engine.quota(&user_id).is_some(),
+                                        user_id,
+                                    };
+                                    let _ = respond_to.send(Ok(info));
+                                },
+                                Command::SetQuota { user_id, quota, respond_to } => {
+                                    let res = async {
+                                        if !keyspace::is_user_id(&user_id) || !engine.has_user(&user_id) {
+                                            return Err(StoreError::NoSuchUser { user_id });
+                                        }
+                                        log_and_apply(engine, &logger, &mut watches, &mut feeds, &mut versions, WalOp::SetQuota { user_id, quota }).await
+                                    }.await;
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::Watch { user_id, scope, events, respond_to } => {
+                                    let _ = respond_to.send(Ok(watches.add(user_id, scope, events)));
+                                },
+                                Command::Unwatch { user_id, watch_id, respond_to } => {
+                                    let res = match watches.remove(&user_id, watch_id) {
+                                        true => Ok(()),
+                                        false => Err(StoreError::NoSuchWatch { watch_id }),
+                                    };
                                     let _ = respond_to.send(res);
                                 },
+                                Command::OpenReadSnapshot { user_id, respond_to } => {
+                                    let _ = respond_to.send(Ok(versions.open(user_id, engine.wal_seq(), now_millis())));
+                                },
+                                Command::CloseReadSnapshot { user_id, snapshot_id, respond_to } => {
+                                    let res = match versions.close(&user_id, snapshot_id) {
+                                        true => Ok(()),
+                                        false => Err(StoreError::NoSuchReadSnapshot { snapshot_id }),
+                                    };
+                                    let _ = respond_to.send(res);
+                                },
+                                Command::ShardCdc { from, respond_to } => {
+                                    let _ = respond_to.send(feeds.follow(&dirs.wal, from, engine.wal_seq()));
+                                },
+                                Command::Stats { respond_to } => {
+                                    let _ = respond_to.send(Ok(engine.stats()));
+                                },
+                                Command::Persist { respond_to } => {
+                                    let _ = respond_to.send(engine.flush());
+                                },
+                                Command::Snapshot { respond_to } => {
+                                    // The snapshot actor replies once the file is on disk.
+                                    let state = match engine.export() {
+                                        Ok(state) => state,
+                                        Err(e) => {
+                                            let _ = respond_to.send(Err(format!("failed to export the store: {e}")));
+                                            continue;
+                                        }
+                                    };
+                                    let take = SnapshotCommand::Take { state, respond_to };
+                                    if let Err(mpsc::error::SendError(take)) = snapshotter.send(take).await {
+                                        if let SnapshotCommand::Take { respond_to, .. } = take {
+                                            let _ = respond_to.send(Err("snapshot actor is not running".to_string()));
+                                        }
+                                    }
+                                },
                                 // Add shutdown or other commands if needed
                                 _ => unreachable!("Received non-storage command in store actor"),
                             }
@@ -114,10 +1006,26 @@ pub fn spawn_store_actor() -> StoreCommandHandler {
                         None => break, // channel closed, exit actor
                     }
                 },
-                _ = interval.tick() => {
-                    if let Err(e) = persist_state(&state) {
+                _ = persist_interval.tick(), if engine.is_dirty() => {
+                    if let Err(e) = engine.flush() {
                         eprintln!("Failed to persist store state: {e}");
                     }
+                },
+                _ = sweep_interval.tick() => {
+                    let now = now_millis();
+                    versions.close_idle(now, read_snapshot_idle_ms);
+                    // expired keys an open read snapshot still sees stay until it closes
+                    engine.purge_expired(versions.expiry_horizon(now));
+                },
+                _ = tick_opt(&mut snapshot_interval) => {
+                    // Nobody waits on scheduled snapshots; failures are logged by the snapshot actor.
+                    match engine.export() {
+                        Ok(state) => {
+                            let (respond_to, _) = tokio::sync::oneshot::channel();
+                            let _ = snapshotter.send(SnapshotCommand::Take { state, respond_to }).await;
+                        }
+                        Err(e) => eprintln!("Failed to export the store for a scheduled snapshot: {e}"),
+                    }
                 }
             }
         }
@@ -126,19 +1034,250 @@ pub fn spawn_store_actor() -> StoreCommandHandler {
     tx // Return the sender for communicating with the store actor.
 }
 
-fn store_path() -> PathBuf {
-    let mut home = dirs::home_dir().expect("Could not find home directory");
-    home.push(".roc_server");
-    fs::create_dir_all(&home).expect("Failed to create roc_server directory");
-    home.push("store_state.bin");
-    home
+/// Makes `op` durable in the WAL and only then applies it to `engine`.
+///
+/// If the append fails the engine is left untouched and the error is returned to the caller.
+/// If applying fails, the record is still in the WAL and takes effect on the next replay.
+async fn log_and_apply(
+    engine: &mut Engine,
+    logger: &LoggerCommandHandler,
+    watches: &mut Watches,
+    feeds: &mut Feeds,
+    versions: &mut Versions,
+    op: WalOp,
+) -> Result<(), StoreError> {
+    engine.check_quotas(&op)?;
+    let victims = engine.make_room(&op)?;
+    let evicted = victims.len();
+    let op = match victims {
+        victims if victims.is_empty() => op,
+        // one record, so replay evicts exactly what the write evicted
+        victims => WalOp::Batch(
+            victims
+                .into_iter()
+                .map(|(user_id, key)| WalOp::Delete { user_id, key })
+                .chain(std::iter::once(op))
+                .collect(),
+        ),
+    };
+    let changes = watched_changes(engine, watches, &op, now_millis())?;
+    let replaced = versions.replaced(engine, &op).map_err(|e| format!("failed to read the entries the write replaces: {e}"))?;
+    let (seq, ts) = logger_actor::append(logger, op.clone()).await?;
+    feeds.publish(seq, ts, &op);
+    versions.keep(seq, replaced);
+    apply(engine, seq, op).map_err(|e| format!("failed to apply WAL record {seq}: {e}"))?;
+    engine.count_evicted(evicted);
+    for (owner, key, old, new) in changes {
+        watches.publish(&owner, &WatchEvent::new(key, old, new, seq));
+    }
+    Ok(())
+}
+
+/// A change to a watched key: owner, key, old value and new value.
+type Change = (String, String, Option<Value>, Option<Value>);
+
+/// The changes `op` makes to the watched keys, in the order it makes them.
+///
+/// Must run before `op` is applied: old values are read from `engine`, on top of the earlier
+/// ops of a batch. Writes that leave a key as it was absent (deleting a missing or expired key)
+/// are left out.
+fn watched_changes(engine: &dyn StorageEngine, watches: &Watches, op: &WalOp, now: u64) -> Result<Vec<Change>, String> {
+    fn walk(
+        engine: &dyn StorageEngine,
+        watches: &Watches,
+        op: &WalOp,
+        now: u64,
+        overlay: &mut BTreeMap<(String, String), Option<Value>>,
+        changes: &mut Vec<Change>,
+    ) -> Result<(), String> {
+        let mut change = |k: (String, String), new: Option<Value>| -> Result<(), String> {
+            let old = match overlay.get(&k) {
+                Some(value) => value.clone(),
+                None => live(engine, &k, now)?.map(|e| e.value),
+            };
+            overlay.insert(k.clone(), new.clone());
+            if old.is_some() || new.is_some() {
+                changes.push((k.0, k.1, old, new));
+            }
+            Ok(())
+        };
+        match op {
+            WalOp::Put { user_id, key, value, .. } if watches.is_watched(user_id) => {
+                change((user_id.clone(), key.clone()), Some(value.clone()))?;
+            }
+            WalOp::Delete { user_id, key } if watches.is_watched(user_id) => {
+                change((user_id.clone(), key.clone()), None)?;
+            }
+            WalOp::DropKeyspace { user_id, keyspace } => {
+                let owner = keyspace::owner_id(user_id, keyspace);
+                if watches.is_watched(&owner) {
+                    for (k, _) in live_range(engine, &owner, (Bound::Unbounded, Bound::Unbounded), now)? {
+                        change(k, None)?;
+                    }
+                }
+            }
+            WalOp::Batch(ops) => {
+                for op in ops {
+                    walk(engine, watches, op, now, overlay, changes)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    let mut changes = Vec::new();
+    if !watches.is_empty() {
+        walk(engine, watches, op, now, &mut BTreeMap::new(), &mut changes)?;
+    }
+    Ok(changes)
 }
 
-fn persist_state(state: &StoreState) -> Result<(), Box<dyn std::error::Error>> {
-    let path = store_path();
-    let tmp_path = path.with_extension("bin.tmp");
-    let bytes = bincode::serialize(state)?;
-    fs::write(&tmp_path, &bytes)?;
-    fs::rename(tmp_path, path)?; // atomic replace
-    Ok(())
+/// Replaces the integer under `key` with `op(current)`, treating a missing key as 0.
+///
+/// `op` returns `None` on overflow, in which case nothing is written.
+async fn add_checked(
+    engine: &mut Engine,
+    logger: &LoggerCommandHandler,
+    watches: &mut Watches,
+    feeds: &mut Feeds,
+    versions: &mut Versions,
+    user_id: String,
+    key: String,
+    op: impl FnOnce(i64) -> Option<i64>,
+) -> Result<i64, StoreError> {
+    let (current, expires_at) = match live(engine, &(user_id.clone(), key.clone()), now_millis())? {
+        Some(Entry { value: Value::Int(i), expires_at, .. }) => (i, expires_at),
+        Some(entry) => return Err(StoreError::NotAnInteger { key, found: entry.value.type_name().to_string() }),
+        None => (0, None),
+    };
+    let Some(new) = op(current) else {
+        return Err(StoreError::Overflow { key, value: current });
+    };
+    let put = WalOp::Put { user_id, key, value: Value::Int(new), expires_at };
+    log_and_apply(engine, logger, watches, feeds, versions, put).await?;
+    Ok(new)
+}
+
+/// Turns a batch of writes into the WAL ops that describe its result.
+///
+/// The ops are evaluated in order against `engine` plus the effect of the earlier ops in the
+/// batch, so e.g. an `Update` after a `Set` of the same key keeps the `Set`'s expiry.
+///
+/// Fails with `StoreError::NotFound` if an `Update` targets a key that does not exist at that
+/// point of the batch.
+fn plan_batch(engine: &dyn StorageEngine, user_id: &str, ops: Vec<WriteOp>, now: u64) -> Result<Vec<WalOp>, StoreError> {
+    let mut overlay: BTreeMap<String, Option<Entry>> = BTreeMap::new();
+    let mut planned = Vec::with_capacity(ops.len());
+
+    for op in ops {
+        let key = op.key().to_string();
+        let current = match overlay.get(&key) {
+            Some(entry) => entry.clone(),
+            None => live(engine, &(user_id.to_string(), key.clone()), now)?,
+        };
+
+        // versions are assigned when the batch is logged
+        let (wal_op, after) = match op {
+            WriteOp::Set { key, value, expiry } => {
+                let expires_at = expiry.map(|e| e.deadline(now));
+                let entry = Entry { value: value.clone(), expires_at, version: 0 };
+                (Some(WalOp::Put { user_id: user_id.to_string(), key, value, expires_at }), Some(entry))
+            }
+            WriteOp::Update { key, .. } if current.is_none() => return Err(StoreError::NotFound { key }),
+            WriteOp::Update { key, value, expiry } | WriteOp::Upsert { key, value, expiry } => {
+                let expires_at = expiry.map(|e| e.deadline(now)).or(current.and_then(|e| e.expires_at));
+                let entry = Entry { value: value.clone(), expires_at, version: 0 };
+                (Some(WalOp::Put { user_id: user_id.to_string(), key, value, expires_at }), Some(entry))
+            }
+            WriteOp::Del { key } => match current {
+                Some(_) => (Some(WalOp::Delete { user_id: user_id.to_string(), key }), None),
+                None => (None, None),
+            },
+        };
+
+        planned.extend(wal_op);
+        overlay.insert(key, after);
+    }
+    Ok(planned)
+}
+
+/// Ticks `interval` if it is configured, otherwise never completes.
+async fn tick_opt(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Opens the configured storage engine, restoring the newest valid snapshot into it if it is ahead.
+///
+/// `ClearWal` only keeps the WAL segments newer than the newest snapshot, so starting from an
+/// older engine state would leave a gap in the replay.
+///
+/// A corrupt store is handled according to `config.on_corruption`: either the server refuses to
+/// start, or the corrupt files are moved to `~/.roc_server/corrupt/` and a fresh engine is filled
+/// from the newest snapshot that passes its checks.
+fn open_engine(config: &RocsConfig, dirs: &ShardDirs) -> Box<dyn StorageEngine> {
+    let kind = config.storage_engine;
+    let mut engine = match storage::open_engine(config, &dirs.store) {
+        Ok(engine) => engine,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("The {kind} store is corrupt: {e}");
+            if config.on_corruption == CorruptionPolicy::Refuse {
+                refuse_to_start("the store is corrupt and ROCS_ON_CORRUPTION=refuse");
+            }
+            if newest_valid_snapshot(&dirs.snaps, 0).is_none() {
+                refuse_to_start("the store is corrupt and there is no valid snapshot to recover from");
+            }
+            match storage::quarantine(kind, &dirs.store) {
+                Ok(dest) => eprintln!("Moved the corrupt {kind} store to {}", dest.display()),
+                Err(e) => refuse_to_start(&format!("failed to move the corrupt store aside: {e}")),
+            }
+            storage::open_engine(config, &dirs.store)
+                .unwrap_or_else(|e| refuse_to_start(&format!("failed to open a fresh {kind} store: {e}")))
+        }
+        Err(e) => refuse_to_start(&format!("failed to open the {kind} store: {e}")),
+    };
+    println!("Storage engine: {kind} in {} (wal_seq {})", dirs.store.display(), engine.wal_seq());
+
+    if let Some((meta, state)) = newest_valid_snapshot(&dirs.snaps, engine.wal_seq()) {
+        engine.restore(state).expect("Failed to restore the snapshot into the storage engine");
+        println!("Loaded snapshot {} (wal_seq {})", meta.seq, meta.wal_seq);
+    }
+    engine
+}
+
+/// The newest snapshot past `after_seq` that loads and passes its checksum, skipping (and
+/// reporting) damaged ones.
+fn newest_valid_snapshot(dir: &Path, after_seq: u64) -> Option<(snapshot_actor::SnapshotMeta, StoreState)> {
+    snapshot_actor::load_manifest(dir)
+        .into_iter()
+        .rev()
+        .take_while(|meta| meta.wal_seq > after_seq)
+        .find_map(|meta| match snapshot_actor::load_snapshot(dir, &meta) {
+            Ok(state) => Some((meta, state)),
+            Err(e) => {
+                eprintln!("Failed to load snapshot {}: {e}", meta.seq);
+                None
+            }
+        })
+}
+
+/// A fresh user id that belongs to `shard`, so that later commands of the user are routed back
+/// here. Takes `shard.count` attempts on average.
+fn new_user_id(shard: ShardId) -> String {
+    loop {
+        let id = uuid::Uuid::new_v4().to_string();
+        if shard.owns(&id) {
+            return id;
+        }
+    }
+}
+
+fn refuse_to_start(reason: &str) -> ! {
+    eprintln!("Refusing to start: {reason}");
+    std::process::exit(1);
 }
@@ -3,9 +3,24 @@
 //! The user actor acts as the frontend/session proxy for user commands.
 //! It receives commands from the dispatcher, forwards them as needed to the store actors,
 //! and relays responses back to the user. This actor may also be extended with session logic, access control, etc.
+//!
+//! Transactions live here: after `Begin`, writes are buffered in the user actor and reads are
+//! answered from the buffer on top of the committed data (read-your-writes). The committed data
+//! is read through a read snapshot opened at `Begin` (see `crate::mvcc`), so all reads of the
+//! transaction see the store as of one instant. `Commit` hands the buffer to the store actor as a
+//! single `Command::Batch`; `Rollback` drops it. Either closes the snapshot.
+//!
+//! The session's current keyspace lives here too. Every command that touches keys is forwarded
+//! with its `user_id` replaced by the owner id of that keyspace (see `crate::keyspace`).
 
-use tokio::sync::{mpsc};
-use crate::command::Command;
+use tokio::sync::{mpsc, oneshot};
+use std::collections::BTreeMap;
+use crate::command::{Command, WriteOp};
+use crate::value::{Value, Versioned};
+use crate::error::StoreError;
+use crate::keyspace::{self, DEFAULT_KEYSPACE};
+use crate::page::{self, Page};
+use std::ops::Bound;
 
 /// Channel type alias for sending commands to a user actor.
 pub type UserCommandHandler = mpsc::Sender<Command>;
@@ -24,7 +39,28 @@ pub fn spawn_user_actor(store_ah: Sch) -> UserCommandHandler {
     let (tx, mut rx) = mpsc::channel::<Command>(128);
 
     tokio::spawn(async move {
-        while let Some(cmd) = rx.recv().await {
+        // Writes buffered by the open transaction, in the order they were issued.
+        let mut txn: Option<Vec<WriteOp>> = None;
+        // The read snapshot the open transaction reads through.
+        let mut txn_snapshot: Option<u64> = None;
+        // The keyspace selected with `SelectKeyspace`.
+        let mut current = DEFAULT_KEYSPACE.to_string();
+
+        while let Some(mut cmd) = rx.recv().await {
+            if let Some(user_id) = cmd.owner_mut() {
+                *user_id = keyspace::owner_id(user_id, &current);
+            }
+            if let (Some(id), Some(snapshot)) = (txn_snapshot, cmd.snapshot_mut()) {
+                snapshot.get_or_insert(id);
+            }
+            let cmd = match txn.as_mut() {
+                Some(buffer) => match handle_in_txn(cmd, buffer, txn_snapshot, &store_ah).await {
+                    Some(cmd) => cmd,
+                    None => continue,
+                },
+                None => cmd,
+            };
+
             match cmd {
                 // Forward storage commands to the store actor.
                 // We don't really care about the fields here since the store actor does the
@@ -35,6 +71,54 @@ pub fn spawn_user_actor(store_ah: Sch) -> UserCommandHandler {
                         eprintln!("Failed to send the command to the store actor Set");
                     }
                 }
+                Command::Get {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor Get");
+                    }
+                }
+                Command::MGet {..} | Command::MSet {..} | Command::MDel {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (multi-key)");
+                    }
+                }
+                Command::Cas {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor Cas");
+                    }
+                }
+                Command::SetNx {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor SetNx");
+                    }
+                }
+                Command::DelIfVersion {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor DelIfVersion");
+                    }
+                }
+                Command::Update {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor Update");
+                    }
+                }
+                Command::Upsert {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor Upsert");
+                    }
+                }
+                Command::IncrBy {..} | Command::DecrBy {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (counter)");
+                    }
+                }
                 Command::Del {..} => {
                     if let Err(e) = store_ah.send(cmd).await
                     {
@@ -53,6 +137,75 @@ pub fn spawn_user_actor(store_ah: Sch) -> UserCommandHandler {
                         eprintln!("Failed to send the command to the store actor Range");
                     }
                 }
+                Command::Ttl {..} | Command::Expire {..} | Command::PersistKey {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (TTL)");
+                    }
+                }
+                Command::Aggregate {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor Aggregate");
+                    }
+                }
+                Command::CreateIndex {..} | Command::DropIndex {..} | Command::Query {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (index)");
+                    }
+                }
+                Command::Info {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor Info");
+                    }
+                }
+                Command::Watch {..} | Command::Unwatch {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (watch)");
+                    }
+                }
+                Command::CreateKeyspace {..} | Command::ListKeyspaces {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (keyspace)");
+                    }
+                }
+                Command::OpenReadSnapshot {..} | Command::CloseReadSnapshot {..} => {
+                    if let Err(e) = store_ah.send(cmd).await
+                    {
+                        eprintln!("Failed to send the command to the store actor (read snapshot)");
+                    }
+                }
+
+                Command::SelectKeyspace { user_id, name, respond_to } => {
+                    let res = if name == DEFAULT_KEYSPACE {
+                        Ok(())
+                    } else {
+                        let (tx, rx) = oneshot::channel();
+                        let list = Command::ListKeyspaces { user_id, respond_to: tx };
+                        match ask_store(&store_ah, list, rx).await {
+                            Ok(names) if names.contains(&name) => Ok(()),
+                            Ok(_) => Err(StoreError::NoSuchKeyspace { name: name.clone() }),
+                            Err(e) => Err(StoreError::Other(e)),
+                        }
+                    };
+                    if res.is_ok() {
+                        current = name;
+                    }
+                    let _ = respond_to.send(res);
+                }
+                Command::DropKeyspace { user_id, name, respond_to } => {
+                    let (tx, rx) = oneshot::channel();
+                    let drop = Command::DropKeyspace { user_id, name: name.clone(), respond_to: tx };
+                    let res = ask_store(&store_ah, drop, rx).await;
+                    if res.is_ok() && name == current {
+                        current = DEFAULT_KEYSPACE.to_string();
+                    }
+                    let _ = respond_to.send(res);
+                }
 
                 // Respond directly to Ping
                 Command::Ping {respond_to, ..} => {
@@ -68,10 +221,50 @@ pub fn spawn_user_actor(store_ah: Sch) -> UserCommandHandler {
                     // This will help clients automatically discover connection endpoints and improve observability.
                 }
 
+                Command::Begin { user_id, respond_to } => {
+                    let res = match txn {
+                        Some(_) => Err("transaction already in progress".to_string()),
+                        None => {
+                            let (tx, rx) = oneshot::channel();
+                            let open = Command::OpenReadSnapshot { user_id, respond_to: tx };
+                            ask_store(&store_ah, open, rx).await.map_err(|e: StoreError| e.to_string()).map(|snapshot| {
+                                txn = Some(Vec::new());
+                                txn_snapshot = Some(snapshot.id);
+                            })
+                        }
+                    };
+                    let _ = respond_to.send(res);
+                }
+                Command::Commit { user_id, respond_to } => match txn.take() {
+                    Some(ops) => {
+                        let batch = Command::Batch { user_id: user_id.clone(), ops, respond_to };
+                        if let Err(e) = store_ah.send(batch).await
+                        {
+                            eprintln!("Failed to send the command to the store actor Batch");
+                        }
+                        close_snapshot(&store_ah, user_id, txn_snapshot.take()).await;
+                    }
+                    None => {
+                        let _ = respond_to.send(Err(StoreError::Other("no transaction in progress".to_string())));
+                    }
+                },
+                Command::Rollback { user_id, respond_to } => {
+                    let res = match txn.take() {
+                        Some(_) => Ok(()),
+                        None => Err("no transaction in progress".to_string()),
+                    };
+                    close_snapshot(&store_ah, user_id, txn_snapshot.take()).await;
+                    let _ = respond_to.send(res);
+                }
+
                 // Handle session exit
                 Command::Exit { respond_to, user_id } => {
                     println!("EXITING USER with user_id: {}", user_id);
-                    // TODO: Perform session cleanup if needed
+                    if txn.take().is_some() {
+                        println!("Rolled back the open transaction of {}", user_id);
+                    }
+                    close_snapshot(&store_ah, user_id, txn_snapshot.take()).await;
+                    current = DEFAULT_KEYSPACE.to_string();
                     let _ = respond_to.send(Ok(()));
                 }
 
@@ -86,4 +279,241 @@ pub fn spawn_user_actor(store_ah: Sch) -> UserCommandHandler {
     tx
 }
 
+/// Handles `cmd` while a transaction is open.
+///
+/// Returns `None` if the command was fully handled here, or gives it back to be processed as usual.
+/// Committed data is read through read snapshot `snapshot`.
+async fn handle_in_txn(cmd: Command, buffer: &mut Vec<WriteOp>, snapshot: Option<u64>, store_ah: &Sch) -> Option<Command> {
+    match cmd {
+        Command::Set { key, value, expiry, respond_to, .. } => {
+            buffer.push(WriteOp::Set { key, value, expiry });
+            let _ = respond_to.send(Ok(()));
+        }
+        Command::Upsert { key, value, expiry, respond_to, .. } => {
+            buffer.push(WriteOp::Upsert { key, value, expiry });
+            let _ = respond_to.send(Ok(()));
+        }
+        // Update and Del report what the transaction sees now; `Commit` checks Update again.
+        Command::Update { user_id, key, value, expiry, respond_to } => {
+            let res = match txn_get(store_ah, buffer, snapshot, &user_id, &key).await {
+                Ok(Some(_)) => {
+                    buffer.push(WriteOp::Update { key, value, expiry });
+                    Ok(())
+                }
+                Ok(None) => Err(StoreError::NotFound { key }),
+                Err(e) => Err(StoreError::Other(e)),
+            };
+            let _ = respond_to.send(res);
+        }
+        Command::Del { user_id, key, respond_to } => {
+            let res = txn_get(store_ah, buffer, snapshot, &user_id, &key).await;
+            if let Ok(Some(_)) = res {
+                buffer.push(WriteOp::Del { key });
+            }
+            let _ = respond_to.send(res);
+        }
+        Command::Get { user_id, key, snapshot, respond_to } => match buffered(buffer, &key) {
+            Some(value) => {
+                let versioned = value.map(|value| Versioned { value: value.clone(), version: 0 });
+                let _ = respond_to.send(Ok(versioned));
+            }
+            None => return Some(Command::Get { user_id, key, snapshot, respond_to }),
+        },
+        Command::MGet { user_id, keys, snapshot, respond_to } => {
+            let (tx, rx) = oneshot::channel();
+            let forward = Command::MGet { user_id, keys: keys.clone(), snapshot, respond_to: tx };
+            let res = ask_store(store_ah, forward, rx).await.map(|committed| {
+                keys.iter()
+                    .zip(committed)
+                    .map(|(key, committed)| match buffered(buffer, key) {
+                        Some(value) => value.map(|value| Versioned { value: value.clone(), version: 0 }),
+                        None => committed,
+                    })
+                    .collect()
+            });
+            let _ = respond_to.send(res);
+        }
+        Command::MSet { pairs, respond_to, .. } => {
+            let versions = vec![0; pairs.len()];
+            buffer.extend(pairs.into_iter().map(|(key, value)| WriteOp::Set { key, value, expiry: None }));
+            let _ = respond_to.send(Ok(versions));
+        }
+        Command::MDel { user_id, keys, respond_to } => {
+            let mut olds = Vec::with_capacity(keys.len());
+            let mark = buffer.len();
+            for key in keys {
+                match txn_get(store_ah, buffer, snapshot, &user_id, &key).await {
+                    Ok(old) => {
+                        if old.is_some() {
+                            buffer.push(WriteOp::Del { key });
+                        }
+                        olds.push(old);
+                    }
+                    Err(e) => {
+                        // keep the request all-or-nothing
+                        buffer.truncate(mark);
+                        let _ = respond_to.send(Err(e));
+                        return None;
+                    }
+                }
+            }
+            let _ = respond_to.send(Ok(olds));
+        }
+        Command::Range { user_id, start, end, reverse, limit, cursor, snapshot, respond_to } => {
+            let (tx, rx) = oneshot::channel();
+            let forward = Command::Range {
+                user_id: user_id.clone(),
+                start: start.clone(),
+                end: end.clone(),
+                reverse,
+                limit,
+                cursor: cursor.clone(),
+                snapshot,
+                respond_to: tx,
+            };
+            let res = async {
+                let committed = ask_store(store_ah, forward, rx).await?;
+                let range = page::resume((start, end), reverse, cursor.as_deref())?;
+                overlay(committed, buffer, &user_id, reverse, limit, |k| page::contains(&range, k))
+            }.await;
+            let _ = respond_to.send(res);
+        }
+        Command::List { user_id, prefix, limit, cursor, snapshot, respond_to } => {
+            let (tx, rx) = oneshot::channel();
+            let forward = Command::List {
+                user_id: user_id.clone(),
+                prefix: prefix.clone(),
+                limit,
+                cursor: cursor.clone(),
+                snapshot,
+                respond_to: tx,
+            };
+            let res = async {
+                let committed = ask_store(store_ah, forward, rx).await?;
+                let prefix = prefix.unwrap_or_default();
+                let range = page::resume((Bound::Included(prefix.clone()), Bound::Unbounded), false, cursor.as_deref())?;
+                let in_scope = |k: &str| k.starts_with(prefix.as_str()) && page::contains(&range, k);
+                overlay(committed, buffer, &user_id, false, limit, in_scope)
+            }.await;
+            let _ = respond_to.send(res);
+        }
+        // the index only knows committed values
+        Command::Query { respond_to, .. } => {
+            let msg = "queries are not supported inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        Command::Aggregate { respond_to, .. } => {
+            let msg = "aggregations are not supported inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        Command::Expire { respond_to, .. } | Command::PersistKey { respond_to, .. } => {
+            let _ = respond_to.send(Err("EXPIRE/PERSIST are not supported inside a transaction".to_string()));
+        }
+        Command::Cas { respond_to, .. } | Command::SetNx { respond_to, .. } => {
+            let msg = "conditional writes are not supported inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        Command::IncrBy { respond_to, .. } | Command::DecrBy { respond_to, .. } => {
+            let msg = "counters are not supported inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        Command::DelIfVersion { respond_to, .. } => {
+            let msg = "conditional writes are not supported inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        // the transaction's writes belong to the keyspace it started in
+        Command::SelectKeyspace { respond_to, .. } => {
+            let msg = "cannot switch keyspaces inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        Command::DropKeyspace { respond_to, .. } => {
+            let msg = "cannot drop keyspaces inside a transaction".to_string();
+            let _ = respond_to.send(Err(StoreError::Other(msg)));
+        }
+        other => return Some(other),
+    }
+    None
+}
+
+/// The value of `key` as last written by the transaction: `Some(None)` if the transaction
+/// deleted it, `None` if the transaction has not touched it.
+fn buffered<'a>(buffer: &'a [WriteOp], key: &str) -> Option<Option<&'a Value>> {
+    buffer.iter().rev().find(|op| op.key() == key).map(|op| match op {
+        WriteOp::Set { value, .. } | WriteOp::Update { value, .. } | WriteOp::Upsert { value, .. } => Some(value),
+        WriteOp::Del { .. } => None,
+    })
+}
+
+/// The value of `key` as seen by the transaction: its own writes first, then the committed data
+/// as of `snapshot`.
+async fn txn_get(store_ah: &Sch, buffer: &[WriteOp], snapshot: Option<u64>, user_id: &str, key: &str) -> Result<Option<Value>, String> {
+    if let Some(value) = buffered(buffer, key) {
+        return Ok(value.cloned());
+    }
+    let (tx, rx) = oneshot::channel();
+    let get = Command::Get { user_id: user_id.to_string(), key: key.to_string(), snapshot, respond_to: tx };
+    Ok(ask_store(store_ah, get, rx).await?.map(|v| v.value))
+}
+
+/// Closes the read snapshot of a transaction that ended, if it had one.
+async fn close_snapshot(store_ah: &Sch, user_id: String, snapshot: Option<u64>) {
+    let Some(snapshot_id) = snapshot else { return };
+    let (tx, _rx) = oneshot::channel();
+    let close = Command::CloseReadSnapshot { user_id, snapshot_id, respond_to: tx };
+    if let Err(e) = store_ah.send(close).await
+    {
+        eprintln!("Failed to send the command to the store actor CloseReadSnapshot");
+    }
+}
+
+/// Sends `cmd` to the store actor and waits for the reply on `rx`.
+async fn ask_store<T, E: From<String>>(
+    store_ah: &Sch,
+    cmd: Command,
+    rx: oneshot::Receiver<Result<T, E>>,
+) -> Result<T, E> {
+    store_ah.send(cmd).await.map_err(|_| E::from("store actor is not running".to_string()))?;
+    rx.await.map_err(|_| E::from("store actor dropped the request".to_string()))?
+}
 
+/// Applies the buffered writes whose key passes `in_scope` on top of the committed page.
+///
+/// Only writes up to the page's last key are applied, so every buffered write shows up on
+/// exactly one page. A page that grows past `limit` is cut short and continues from its new
+/// last key.
+fn overlay(
+    committed: Page,
+    buffer: &[WriteOp],
+    user_id: &str,
+    reverse: bool,
+    limit: Option<usize>,
+    in_scope: impl Fn(&str) -> bool,
+) -> Result<Page, String> {
+    let last = committed.cursor.as_deref().map(page::decode_cursor).transpose()?;
+    let on_page = |k: &str| match &last {
+        Some(last) if reverse => k >= last.as_str(),
+        Some(last) => k <= last.as_str(),
+        None => true,
+    };
+    let mut merged: BTreeMap<String, Value> = committed.items.into_iter().map(|((_, k), v)| (k, v)).collect();
+    for op in buffer.iter().filter(|op| in_scope(op.key()) && on_page(op.key())) {
+        match op {
+            WriteOp::Set { key, value, .. } | WriteOp::Update { key, value, .. } | WriteOp::Upsert { key, value, .. } => {
+                merged.insert(key.clone(), value.clone());
+            }
+            WriteOp::Del { key } => {
+                merged.remove(key);
+            }
+        }
+    }
+    let mut items: Vec<_> = merged.into_iter().map(|(k, v)| ((user_id.to_string(), k), v)).collect();
+    if reverse {
+        items.reverse();
+    }
+    let mut cursor = committed.cursor;
+    if let Some(limit) = limit.filter(|&limit| items.len() > limit) {
+        items.truncate(limit);
+        cursor = items.last().map(|((_, k), _)| page::encode_cursor(k));
+    }
+    Ok(Page { items, cursor })
+}
@@ -0,0 +1,142 @@
+//! src/aggregate.rs
+//!
+//! Aggregations over the values of a key range, computed in the store actor so that only the
+//! result crosses the wire.
+//!
+//! `Count` counts every live key in scope. The other functions only look at numbers (ints and
+//! floats) and skip every other value. Ints are summed in an `i128`, which cannot overflow for
+//! any number of `i64` values the store can hold, so `Sum` is exact until the very end, where a
+//! total outside the `i64` range is reported as `StoreError::SumOverflow` instead of wrapping.
+//! As soon as a float is involved, sums are computed in `f64`, and a sum or average that goes past
+//! the `f64` range is reported as `SumOverflow` too, as infinity has no JSON form.
+
+use crate::error::StoreError;
+use crate::storage::index::IndexKey;
+use crate::value::Value;
+use serde::{Deserialize, Serialize};
+
+/// What an `Aggregate` command computes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+/// The keys an `Aggregate` command runs over.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum KeyScope {
+    /// Every key.
+    #[default]
+    All,
+    /// The keys starting with the prefix.
+    Prefix(String),
+    /// The keys from `start` to `end`, inclusive.
+    Range { start: String, end: String },
+}
+
+/// Running state of an aggregation.
+#[derive(Debug, Default)]
+pub struct Accumulator {
+    /// Live keys seen, numeric or not.
+    keys: u64,
+    /// Numbers seen.
+    numbers: u64,
+    int_sum: i128,
+    float_sum: f64,
+    saw_float: bool,
+    min: Option<(IndexKey, Value)>,
+    max: Option<(IndexKey, Value)>,
+}
+
+impl Accumulator {
+    pub fn add(&mut self, value: &Value) {
+        self.keys += 1;
+        match value {
+            Value::Int(n) => self.int_sum += i128::from(*n),
+            Value::Float(f) => {
+                self.float_sum += f;
+                self.saw_float = true;
+            }
+            _ => return,
+        }
+        self.numbers += 1;
+        let key = IndexKey::of(value).expect("numbers are indexable");
+        if self.min.as_ref().is_none_or(|(min, _)| key < *min) {
+            self.min = Some((key.clone(), value.clone()));
+        }
+        if self.max.as_ref().is_none_or(|(max, _)| key > *max) {
+            self.max = Some((key, value.clone()));
+        }
+    }
+
+    /// The result of `function` over everything added. `None` for `Min`, `Max` and `Avg` when
+    /// no number was added.
+    pub fn finish(self, function: Aggregate) -> Result<Option<Value>, StoreError> {
+        let float_sum = || finite(self.int_sum as f64 + self.float_sum);
+        Ok(match function {
+            Aggregate::Count => Some(Value::Int(i64::try_from(self.keys).unwrap_or(i64::MAX))),
+            Aggregate::Sum if self.saw_float => Some(Value::Float(float_sum()?)),
+            Aggregate::Sum => Some(Value::Int(i64::try_from(self.int_sum).map_err(|_| StoreError::SumOverflow)?)),
+            Aggregate::Min => self.min.map(|(_, v)| v),
+            Aggregate::Max => self.max.map(|(_, v)| v),
+            Aggregate::Avg if self.numbers == 0 => None,
+            Aggregate::Avg => Some(Value::Float(float_sum()? / self.numbers as f64)),
+        })
+    }
+}
+
+fn finite(f: f64) -> Result<f64, StoreError> {
+    if f.is_finite() { Ok(f) } else { Err(StoreError::SumOverflow) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn over(values: &[Value], function: Aggregate) -> Result<Option<Value>, StoreError> {
+        let mut acc = Accumulator::default();
+        for value in values {
+            acc.add(value);
+        }
+        acc.finish(function)
+    }
+
+    #[test]
+    fn functions_skip_what_is_not_a_number() {
+        let values = [Value::Int(3), Value::Str("x".into()), Value::Float(1.5), Value::Int(-2)];
+        assert_eq!(over(&values, Aggregate::Count), Ok(Some(Value::Int(4))));
+        assert_eq!(over(&values, Aggregate::Sum), Ok(Some(Value::Float(2.5))));
+        assert_eq!(over(&values, Aggregate::Min), Ok(Some(Value::Int(-2))));
+        assert_eq!(over(&values, Aggregate::Max), Ok(Some(Value::Int(3))));
+        assert_eq!(over(&values, Aggregate::Avg), Ok(Some(Value::Float(2.5 / 3.0))));
+        assert_eq!(over(&[Value::Str("x".into())], Aggregate::Avg), Ok(None));
+        assert_eq!(over(&[], Aggregate::Sum), Ok(Some(Value::Int(0))));
+    }
+
+    #[test]
+    fn int_sums_are_exact_until_the_result() {
+        let back_in_range = [Value::Int(i64::MAX), Value::Int(i64::MAX), Value::Int(i64::MIN), Value::Int(i64::MIN)];
+        assert_eq!(over(&back_in_range, Aggregate::Sum), Ok(Some(Value::Int(-2))));
+        assert_eq!(over(&[Value::Int(i64::MAX), Value::Int(1)], Aggregate::Sum), Err(StoreError::SumOverflow));
+        assert_eq!(over(&[Value::Int(i64::MIN), Value::Int(-1)], Aggregate::Sum), Err(StoreError::SumOverflow));
+        assert_eq!(
+            over(&[Value::Int(i64::MAX), Value::Int(i64::MAX)], Aggregate::Avg),
+            Ok(Some(Value::Float(i64::MAX as f64)))
+        );
+    }
+
+    #[test]
+    fn float_sums_past_the_f64_range_overflow() {
+        let huge = [Value::Float(f64::MAX), Value::Float(f64::MAX)];
+        assert_eq!(over(&huge, Aggregate::Sum), Err(StoreError::SumOverflow));
+        assert_eq!(over(&huge, Aggregate::Avg), Err(StoreError::SumOverflow));
+        assert_eq!(over(&[Value::Float(-f64::MAX), Value::Float(-f64::MAX)], Aggregate::Sum), Err(StoreError::SumOverflow));
+        assert_eq!(over(&huge, Aggregate::Max), Ok(Some(Value::Float(f64::MAX))));
+        assert_eq!(over(&[Value::Float(f64::MAX), Value::Float(-f64::MAX)], Aggregate::Sum), Ok(Some(Value::Float(0.0))));
+    }
+}
@@ -0,0 +1,285 @@
+//! src/cdc.rs
+//!
+//! Change data capture: a feed of every committed mutation, for mirroring the store elsewhere.
+//!
+//! Every WAL record is one event, numbered by its sequence number in the WAL of its shard and
+//! stamped with the time the record was appended. With a single shard (the default) that number
+//! is global and increases with every committed mutation. With several shards (`ROCS_SHARDS`)
+//! there is no global sequence number: each shard counts on its own, and the feed interleaves
+//! the shards in no particular order, so a sequence number alone does not say where a feed
+//! stands. The resume token is therefore one sequence number per shard, `from[i]` being the last
+//! `seq` handled of shard `i` (every event carries its `shard`). It is either empty, to start at
+//! the beginning of every shard, or complete: a token with fewer or more entries than there are
+//! shards is rejected rather than guessed at. Sequence numbers increase by one with every record
+//! of a shard and never repeat, so a consumer that reconnects with `Cdc { from }` continues
+//! without gaps or duplicates.
+//!
+//! A feed first replays the WAL after `from` and then continues live. Each shard registers its
+//! live feed and notes the last sequence number it logged in one step of its store actor, so
+//! nothing falls in between; the backlog up to that number is read afterwards by the task
+//! feeding the consumer, one WAL segment at a time, so the store actor never waits on it.
+//! Starting from a sequence number whose records were already deleted by `ClearWal` fails with
+//! `StoreError::Compacted`. Should `ClearWal` delete them after the feed started but before they
+//! were read, the feed is ended instead, and resuming it reports the compaction.
+//!
+//! Live events are buffered `CDC_BUFFER` deep per shard. A consumer that falls further behind
+//! has its feed ended (the stream is finished), and resumes from the last event it got.
+
+use crate::actors::logger_actor::{self, WalOp, WalRecord};
+use crate::error::StoreError;
+use serde::{Deserialize, Serialize};
+use std::future::poll_fn;
+use std::path::{Path, PathBuf};
+use std::task::Poll;
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// Live events a shard buffers for a feed before ending it.
+pub const CDC_BUFFER: usize = 4096;
+
+/// One committed mutation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CdcEvent {
+    pub shard: usize,
+    /// Sequence number of the WAL record, in the WAL of `shard`.
+    pub seq: u64,
+    /// When the record was appended, in milliseconds since the unix epoch.
+    pub ts: u64,
+    pub op: WalOp,
+}
+
+/// The feed of one shard, as handed out by its store actor: where its backlog lies in the WAL,
+/// and the live events that continue it.
+#[derive(Debug)]
+pub struct ShardFeed {
+    pub shard: usize,
+    pub wal_dir: PathBuf,
+    /// The backlog is the records after this sequence number...
+    pub from: u64,
+    /// ...up to and including this one, the last the shard had logged when `live` started.
+    pub until: u64,
+    /// Everything logged since.
+    pub live: mpsc::Receiver<CdcEvent>,
+}
+
+impl ShardFeed {
+    /// Whether the WAL still holds the whole backlog, looking only at which segments are left.
+    async fn check(&self) -> Result<(), StoreError> {
+        if self.from == self.until {
+            return Ok(());
+        }
+        let (dir, from) = (self.wal_dir.clone(), self.from);
+        let segments = task::spawn_blocking(move || logger_actor::segments_after(&dir, from))
+            .await
+            .unwrap_or_default();
+        match segments.first() {
+            Some(&(first_seq, _)) if first_seq <= self.from + 1 => Ok(()),
+            first => Err(StoreError::Compacted {
+                shard: self.shard,
+                requested: self.from,
+                oldest: first.map_or(self.until, |&(first_seq, _)| first_seq - 1),
+            }),
+        }
+    }
+
+    /// Sends the backlog to `out`, reading the WAL one segment at a time.
+    ///
+    /// Returns false if the consumer went away or the backlog no longer starts right after `from`.
+    async fn send_backlog(&self, out: &mpsc::Sender<CdcEvent>) -> bool {
+        let (dir, from) = (self.wal_dir.clone(), self.from);
+        let segments = task::spawn_blocking(move || logger_actor::segments_after(&dir, from))
+            .await
+            .unwrap_or_default();
+        let mut next = self.from + 1;
+        for (_, path) in segments {
+            if next > self.until {
+                break;
+            }
+            let records = task::spawn_blocking(move || logger_actor::read_segment(&path))
+                .await
+                .unwrap_or_default();
+            for WalRecord { seq, ts, op } in records {
+                if seq < next || seq > self.until {
+                    continue;
+                }
+                // only the start of the WAL is ever deleted, so a gap can only come first
+                if next == self.from + 1 && seq != next {
+                    return false;
+                }
+                if out.send(CdcEvent { shard: self.shard, seq, ts, op }).await.is_err() {
+                    return false;
+                }
+                next = seq + 1;
+            }
+        }
+        next > self.until
+    }
+}
+
+/// The live feeds of one shard.
+pub struct Feeds {
+    shard: usize,
+    senders: Vec<mpsc::Sender<CdcEvent>>,
+}
+
+impl Feeds {
+    pub fn new(shard: usize) -> Self {
+        Self { shard, senders: Vec::new() }
+    }
+
+    /// Registers a live feed continuing the backlog after `from` in the WAL in `wal_dir`, which
+    /// is left for the feed's task to read. `wal_seq` is the last sequence number the shard
+    /// logged.
+    pub fn follow(&mut self, wal_dir: &Path, from: u64, wal_seq: u64) -> Result<ShardFeed, StoreError> {
+        let shard = self.shard;
+        if from > wal_seq {
+            return Err(StoreError::SequenceAhead { shard, requested: from, latest: wal_seq });
+        }
+        let (tx, live) = mpsc::channel(CDC_BUFFER);
+        self.senders.push(tx);
+        Ok(ShardFeed { shard, wal_dir: wal_dir.to_path_buf(), from, until: wal_seq, live })
+    }
+
+    /// Hands record `seq`, appended at `ts`, to every feed, ending those that fell too far
+    /// behind or whose consumer went away.
+    pub fn publish(&mut self, seq: u64, ts: u64, op: &WalOp) {
+        if self.senders.is_empty() {
+            return;
+        }
+        let event = CdcEvent { shard: self.shard, seq, ts, op: op.clone() };
+        self.senders.retain(|tx| tx.try_send(event.clone()).is_ok());
+    }
+}
+
+/// Checks that the WAL of every shard still holds the backlog of its feed.
+pub async fn check(feeds: &[ShardFeed]) -> Result<(), StoreError> {
+    for feed in feeds {
+        feed.check().await?;
+    }
+    Ok(())
+}
+
+/// Sends the backlog of every shard to `out`, then the live events of all of them as they come.
+///
+/// Stops, dropping `out`, as soon as any shard ends its feed, its backlog turns out to be
+/// compacted, or the consumer goes away.
+pub async fn merge(feeds: Vec<ShardFeed>, out: mpsc::Sender<CdcEvent>) {
+    let mut live = Vec::with_capacity(feeds.len());
+    for feed in feeds {
+        if !feed.send_backlog(&out).await {
+            return;
+        }
+        live.push(feed.live);
+    }
+
+    // start at a different shard each time so a busy one cannot starve the others
+    let mut start = 0;
+    loop {
+        let next = poll_fn(|cx| {
+            for i in 0..live.len() {
+                let i = (start + i) % live.len();
+                if let Poll::Ready(event) = live[i].poll_recv(cx) {
+                    return Poll::Ready(event);
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        let Some(event) = next else { return };
+        if out.send(event).await.is_err() {
+            return;
+        }
+        start = (start + 1) % live.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn live_events_carry_the_time_of_their_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut feeds = Feeds::new(2);
+        let mut feed = feeds.follow(dir.path(), 0, 0).unwrap();
+        assert_eq!((feed.from, feed.until), (0, 0));
+
+        feeds.publish(1, 1234, &WalOp::AddUser { user_id: "u".into() });
+        let event = feed.live.try_recv().unwrap();
+        assert_eq!((event.shard, event.seq, event.ts), (2, 1, 1234));
+    }
+
+    #[test]
+    fn a_feed_cannot_start_ahead_of_the_shard() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = Feeds::new(0).follow(dir.path(), 5, 3).unwrap_err();
+        assert!(matches!(err, StoreError::SequenceAhead { shard: 0, requested: 5, latest: 3 }));
+    }
+
+    /// A WAL with one record per segment, records 1 to `n`.
+    async fn wal_of(dir: &Path, n: u64) -> logger_actor::LoggerCommandHandler {
+        let logger = logger_actor::spawn_logger_actor(dir.to_path_buf(), 1);
+        for _ in 0..n {
+            logger_actor::append(&logger, WalOp::AddUser { user_id: "u".into() }).await.unwrap();
+        }
+        logger
+    }
+
+    async fn truncate(logger: &logger_actor::LoggerCommandHandler, upto_seq: u64) {
+        let (respond_to, rx) = tokio::sync::oneshot::channel();
+        logger.send(logger_actor::LogCommand::Truncate { upto_seq, respond_to }).await.unwrap();
+        rx.await.unwrap().unwrap();
+    }
+
+    async fn received(mut events: mpsc::Receiver<CdcEvent>) -> Vec<u64> {
+        let mut seqs = Vec::new();
+        while let Some(event) = events.recv().await {
+            seqs.push(event.seq);
+        }
+        seqs
+    }
+
+    #[tokio::test]
+    async fn the_backlog_is_read_by_the_feed_and_continued_live() {
+        let dir = tempfile::tempdir().unwrap();
+        let _logger = wal_of(dir.path(), 5).await;
+        let mut feeds = Feeds::new(0);
+        let feed = feeds.follow(dir.path(), 2, 5).unwrap();
+        check(std::slice::from_ref(&feed)).await.unwrap();
+
+        feeds.publish(6, 0, &WalOp::AddUser { user_id: "v".into() });
+        drop(feeds);
+        let (out, events) = mpsc::channel(16);
+        merge(vec![feed], out).await;
+        assert_eq!(received(events).await, [3, 4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn a_feed_cannot_start_before_the_compacted_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = wal_of(dir.path(), 5).await;
+        truncate(&logger, 3).await;
+        let mut feeds = Feeds::new(0);
+
+        let feed = feeds.follow(dir.path(), 1, 5).unwrap();
+        let err = check(&[feed]).await.unwrap_err();
+        assert!(matches!(err, StoreError::Compacted { shard: 0, requested: 1, oldest: 3 }), "{err:?}");
+
+        let feed = feeds.follow(dir.path(), 3, 5).unwrap();
+        check(&[feed]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_backlog_compacted_before_it_is_read_ends_the_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let logger = wal_of(dir.path(), 5).await;
+        let mut feeds = Feeds::new(0);
+        let feed = feeds.follow(dir.path(), 1, 5).unwrap();
+        check(std::slice::from_ref(&feed)).await.unwrap();
+
+        truncate(&logger, 3).await;
+        let (out, events) = mpsc::channel(16);
+        merge(vec![feed], out).await;
+        assert!(received(events).await.is_empty());
+    }
+}
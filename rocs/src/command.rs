@@ -9,10 +9,73 @@
 //! to the correct client or subsystem. This is the foundation for robust, concurrent, and scalable
 //! async processing in the system.
 
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
+use serde::{Serialize, Deserialize};
+use crate::value::{Value, Versioned};
+use crate::error::StoreError;
+use crate::page::Page;
+use crate::aggregate::{Aggregate, KeyScope};
+use crate::quota::{Quota, UserInfo};
+use crate::storage::bounded::MemoryStats;
+use crate::watch::{EventSender, WatchScope};
+use crate::actors::pubsub_actor::Subscribed;
+use crate::cdc::{CdcEvent, ShardFeed};
+use crate::mvcc::ReadSnapshot;
+use std::ops::Bound;
 
 pub type UserId = String;
 
+/// When a key should expire.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Expiry {
+    /// Expire this many milliseconds after the command is applied.
+    AfterMs(u64),
+    /// Expire at this absolute time, in milliseconds since the unix epoch.
+    AtMs(u64),
+}
+
+/// Which values a `Query` matches. Range bounds are inclusive; a missing bound is open.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ValueFilter {
+    Eq(Value),
+    Range { min: Option<Value>, max: Option<Value> },
+}
+
+/// A single write, as buffered by a transaction and applied by `Command::Batch`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    /// Same semantics as `Command::Set`.
+    Set { key: String, value: Value, expiry: Option<Expiry> },
+    /// Same semantics as `Command::Update`.
+    Update { key: String, value: Value, expiry: Option<Expiry> },
+    /// Same semantics as `Command::Upsert`.
+    Upsert { key: String, value: Value, expiry: Option<Expiry> },
+    /// Same semantics as `Command::Del`.
+    Del { key: String },
+}
+
+impl WriteOp {
+    pub fn key(&self) -> &str {
+        match self {
+            WriteOp::Set { key, .. }
+            | WriteOp::Update { key, .. }
+            | WriteOp::Upsert { key, .. }
+            | WriteOp::Del { key } => key,
+        }
+    }
+}
+
+impl Expiry {
+    /// The absolute expiry time, given the current time `now_ms`.
+    pub fn deadline(self, now_ms: u64) -> u64 {
+        match self {
+            Expiry::AfterMs(ms) => now_ms.saturating_add(ms),
+            Expiry::AtMs(at) => at,
+        }
+    }
+}
+
 /// The set of commands that can be sent to the database actor system.
 ///
 /// Each variant represents an operation that can be performed on the database,
@@ -47,27 +110,142 @@ pub enum Command {
     /// # Arguments
     /// - `key`: The key to insert or update.
     /// - `value`: The value to associate with the key.
+    /// - `expiry`: When the key should expire. `None` makes the key persistent,
+    ///   dropping any TTL it had before.
     ///
     /// # Response
     /// - Sends `Ok(())` if successful, or `Err(String)` with an error message.
     Set {
         user_id: UserId,
         key: String,
-        value: usize,
+        value: Value,
+        expiry: Option<Expiry>,
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 
     /// Get the value associated with a given key.
     ///
+    /// Every entry carries a version that changes on each write to the key (including TTL
+    /// changes). It can be passed to `Cas` and `DelIfVersion`.
+    ///
     /// # Arguments
     /// - `key`: The key to look up.
+    /// - `snapshot`: Read as of this read snapshot (see `crate::mvcc`) instead of now.
     ///
     /// # Response
-    /// - Sends `Ok(Some(value))` if found, `Ok(None)` if not found, or `Err(String)` on error.
+    /// - Sends `Ok(Some(versioned))` if found, `Ok(None)` if not found, or `Err(String)` on error.
+    ///   Inside a transaction, a value written by the transaction itself has version `0`.
     Get {
         user_id: UserId,
         key: String,
-        respond_to: oneshot::Sender<Result<Option<usize>, String>>,
+        snapshot: Option<u64>,
+        respond_to: oneshot::Sender<Result<Option<Versioned>, String>>,
+    },
+
+    /// Get several keys in one request, all read at the same point in time: now, or that of
+    /// read snapshot `snapshot`.
+    ///
+    /// # Response
+    /// - Sends `Ok(values)` with one entry per requested key, in request order (`None` for
+    ///   missing keys), or `Err(String)` on error.
+    MGet {
+        user_id: UserId,
+        keys: Vec<String>,
+        snapshot: Option<u64>,
+        respond_to: oneshot::Sender<Result<Vec<Option<Versioned>>, String>>,
+    },
+
+    /// Set several keys atomically, with `Set` semantics for each pair (any expiry is dropped).
+    /// If a key appears more than once, the last pair wins.
+    ///
+    /// # Response
+    /// - Sends `Ok(versions)` with the new version of each key, in request order,
+    ///   or `Err(String)` if nothing was written.
+    MSet {
+        user_id: UserId,
+        pairs: Vec<(String, Value)>,
+        respond_to: oneshot::Sender<Result<Vec<u64>, String>>,
+    },
+
+    /// Delete several keys atomically.
+    ///
+    /// # Response
+    /// - Sends `Ok(old_values)` with one entry per requested key, in request order: the deleted
+    ///   value, or `None` if the key did not exist (or was already deleted earlier in the same
+    ///   request). Sends `Err(String)` if nothing was deleted because of an error.
+    MDel {
+        user_id: UserId,
+        keys: Vec<String>,
+        respond_to: oneshot::Sender<Result<Vec<Option<Value>>, String>>,
+    },
+
+    /// Compare-and-swap: write `value` only if the key exists and is at `version`.
+    ///
+    /// # Arguments
+    /// - `version`: The version the key must currently have (as returned by `Get`).
+    /// - `expiry`: A new expiry for the key. `None` keeps the current one.
+    ///
+    /// # Response
+    /// - Sends `Ok(new_version)`, `Err(StoreError::VersionMismatch)` if the precondition
+    ///   failed, or `Err(StoreError::Other)` on error.
+    Cas {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        version: u64,
+        expiry: Option<Expiry>,
+        respond_to: oneshot::Sender<Result<u64, StoreError>>,
+    },
+
+    /// Set a key only if it does not exist.
+    ///
+    /// # Response
+    /// - Sends `Ok(new_version)`, `Err(StoreError::KeyExists)` if the key already exists,
+    ///   or `Err(StoreError::Other)` on error.
+    SetNx {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        expiry: Option<Expiry>,
+        respond_to: oneshot::Sender<Result<u64, StoreError>>,
+    },
+
+    /// Delete a key only if it is at `version`.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` if deleted, `Err(StoreError::DeleteConflict)` if the key is missing or
+    ///   at another version, or `Err(StoreError::Other)` on error.
+    DelIfVersion {
+        user_id: UserId,
+        key: String,
+        version: u64,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    /// Atomically add `delta` to the integer stored under `key` (INCR, INCRBY).
+    ///
+    /// A missing key starts at 0 and is created without an expiry; an existing key keeps its
+    /// expiry.
+    ///
+    /// # Response
+    /// - Sends `Ok(new_value)`, `Err(StoreError::NotAnInteger)` if the key holds another type,
+    ///   `Err(StoreError::Overflow)` if the result would not fit in an `i64`,
+    ///   or `Err(StoreError::Other)` on error.
+    IncrBy {
+        user_id: UserId,
+        key: String,
+        delta: i64,
+        respond_to: oneshot::Sender<Result<i64, StoreError>>,
+    },
+
+    /// Atomically subtract `delta` from the integer stored under `key` (DECR, DECRBY).
+    ///
+    /// Same rules and response as `IncrBy`.
+    DecrBy {
+        user_id: UserId,
+        key: String,
+        delta: i64,
+        respond_to: oneshot::Sender<Result<i64, StoreError>>,
     },
 
     /// Delete a key-value pair from the database.
@@ -76,11 +254,12 @@ pub enum Command {
     /// - `key`: The key to remove.
     ///
     /// # Response
-    /// - Sends `Ok(())` if deleted, or `Err(String)` on error.
+    /// - Sends `Ok(Some(old_value))` if the key was deleted, `Ok(None)` if it did not exist,
+    ///   or `Err(String)` on error.
     Del {
         user_id: UserId,
         key: String,
-        respond_to: oneshot::Sender<Result<(), String>>,
+        respond_to: oneshot::Sender<Result<Option<Value>, String>>,
     },
 
     /// Update the value for an existing key.
@@ -88,38 +267,204 @@ pub enum Command {
     /// # Arguments
     /// - `key`: The key to update.
     /// - `value`: The new value to associate with the key.
+    /// - `expiry`: A new expiry for the key. `None` keeps the current one.
     ///
     /// # Response
-    /// - Sends `Ok(())` if updated, or `Err(String)` on failure.
+    /// - Sends `Ok(())` if updated, `Err(StoreError::NotFound)` if the key does not exist,
+    ///   or `Err(StoreError::Other)` on failure.
     Update {
         user_id: UserId,
         key: String,
-        value: usize,
+        value: Value,
+        expiry: Option<Expiry>,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    /// Update the value for a key, inserting it if it does not exist.
+    ///
+    /// Unlike `Set`, an existing key keeps its expiry when `expiry` is `None`.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` if written, or `Err(String)` on failure.
+    Upsert {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        expiry: Option<Expiry>,
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 
-    /// Fetch all key-value pairs within a range of keys (inclusive).
+    /// Get the remaining time to live of a key.
+    ///
+    /// # Response
+    /// - Sends `Ok(Some(ms))` with the milliseconds left, `Ok(None)` if the key has no expiry,
+    ///   or `Err(String)` if the key does not exist.
+    Ttl {
+        user_id: UserId,
+        key: String,
+        respond_to: oneshot::Sender<Result<Option<u64>, String>>,
+    },
+
+    /// Set an expiry on an existing key.
+    ///
+    /// # Response
+    /// - Sends `Ok(true)` if the expiry was set, `Ok(false)` if the key does not exist,
+    ///   or `Err(String)` on error.
+    Expire {
+        user_id: UserId,
+        key: String,
+        expiry: Expiry,
+        respond_to: oneshot::Sender<Result<bool, String>>,
+    },
+
+    /// Remove the expiry of a key, making it persistent (the `PERSIST` wire command).
+    ///
+    /// # Response
+    /// - Sends `Ok(true)` if an expiry was removed, `Ok(false)` if the key does not exist
+    ///   or had no expiry, or `Err(String)` on error.
+    PersistKey {
+        user_id: UserId,
+        key: String,
+        respond_to: oneshot::Sender<Result<bool, String>>,
+    },
+
+    /// Fetch one page of the key-value pairs within a range of keys.
     ///
     /// # Arguments
-    /// - `start`: The start key (inclusive).
-    /// - `end`: The end key (inclusive).
+    /// - `start`: The lower bound of the range.
+    /// - `end`: The upper bound of the range.
+    /// - `reverse`: Walk the range from `end` down to `start`.
+    /// - `limit`: Page size, capped at (and defaulting to) the server's maximum.
+    /// - `cursor`: The cursor of the previous page, to continue after it.
+    /// - `snapshot`: Read as of this read snapshot instead of now. Pages of one scan should all
+    ///   be read through the same snapshot.
     ///
     /// # Response
-    /// - Sends `Ok(Vec<(key, value)>)` if successful, or `Err(String)` on error.
+    /// - Sends `Ok(Page)` if successful, or `Err(String)` on error or for an invalid cursor.
     Range {
         user_id: UserId,
-        start: String,
-        end: String,
-        respond_to: oneshot::Sender<Result<Vec<((String, String), usize)>, String>>,
+        start: Bound<String>,
+        end: Bound<String>,
+        reverse: bool,
+        limit: Option<usize>,
+        cursor: Option<String>,
+        snapshot: Option<u64>,
+        respond_to: oneshot::Sender<Result<Page, String>>,
     },
 
-    /// List all key-value pairs in the database.
+    /// List one page of the key-value pairs in the database, in key order.
+    ///
+    /// # Arguments
+    /// - `prefix`: Only list keys starting with this prefix.
+    /// - `limit`: Page size, capped at (and defaulting to) the server's maximum.
+    /// - `cursor`: The cursor of the previous page, to continue after it.
+    /// - `snapshot`: Read as of this read snapshot instead of now.
     ///
     /// # Response
-    /// - Sends `Ok(Vec<(key, value)>)` if successful, or `Err(String)` on error.
+    /// - Sends `Ok(Page)` if successful, or `Err(String)` on error or for an invalid cursor.
     List {
         user_id: UserId,
-        respond_to: oneshot::Sender<Result<Vec<((String, String), usize)>, String>>,
+        prefix: Option<String>,
+        limit: Option<usize>,
+        cursor: Option<String>,
+        snapshot: Option<u64>,
+        respond_to: oneshot::Sender<Result<Page, String>>,
+    },
+
+    /// Compute `function` over the values of the live keys in `scope`, see `crate::aggregate`.
+    ///
+    /// # Response
+    /// - Sends `Ok(Some(value))` with the result, `Ok(None)` for `Min`, `Max` and `Avg` over no
+    ///   numbers, `Err(StoreError::SumOverflow)` if a `Sum` of ints does not fit in an `i64`, or
+    ///   `Err(StoreError::Other)` on error.
+    Aggregate {
+        user_id: UserId,
+        function: Aggregate,
+        scope: KeyScope,
+        respond_to: oneshot::Sender<Result<Option<Value>, StoreError>>,
+    },
+
+    // Value indexes
+
+    /// Start indexing the values of the user, see `storage::index`. Indexing an indexed user
+    /// changes nothing.
+    ///
+    /// # Response
+    /// - Sends `Ok(count)` with the number of keys in the index, or `Err(StoreError::Other)` on
+    ///   error.
+    CreateIndex {
+        user_id: UserId,
+        respond_to: oneshot::Sender<Result<u64, StoreError>>,
+    },
+
+    /// Stop indexing the values of the user and drop the index.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` if the index was dropped, `Err(StoreError::NotIndexed)` if there was none,
+    ///   or `Err(StoreError::Other)` on error.
+    DropIndex {
+        user_id: UserId,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    /// Find the keys whose value matches `filter`, using the user's value index.
+    ///
+    /// # Response
+    /// - Sends `Ok(Vec<(key, value)>)` ordered by value, then key, `Err(StoreError::NotIndexed)`
+    ///   if the user has no index, or `Err(StoreError::Other)` for a list or map in `filter` or
+    ///   on error.
+    Query {
+        user_id: UserId,
+        filter: ValueFilter,
+        respond_to: oneshot::Sender<Result<Vec<(String, Value)>, StoreError>>,
+    },
+
+    // Keyspaces
+
+    /// Create the named keyspace `name` for the user.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` if it was created, `Err(StoreError::KeyspaceExists)` if the user already
+    ///   has it, `Err(StoreError::InvalidKeyspace)` for a bad name, or `Err(StoreError::Other)`
+    ///   on error.
+    CreateKeyspace {
+        user_id: UserId,
+        name: String,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    /// List the keyspaces of the user, `default` first and the named ones in order.
+    ///
+    /// # Response
+    /// - Sends `Ok(Vec<name>)` if successful, or `Err(String)` on error.
+    ListKeyspaces {
+        user_id: UserId,
+        respond_to: oneshot::Sender<Result<Vec<String>, String>>,
+    },
+
+    /// Make `name` the current keyspace of the session. Every data command that follows reads
+    /// and writes that keyspace. Handled by the user actor.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` if it was selected, `Err(StoreError::NoSuchKeyspace)` if the user has no
+    ///   such keyspace, or `Err(StoreError::Other)` on error (e.g. inside a transaction).
+    SelectKeyspace {
+        user_id: UserId,
+        name: String,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    /// Drop the named keyspace `name` with every key in it. Sessions that had it selected are
+    /// moved back to `default`.
+    ///
+    /// # Response
+    /// - Sends `Ok(count)` with the number of keys dropped, `Err(StoreError::NoSuchKeyspace)` if
+    ///   the user has no such keyspace, `Err(StoreError::InvalidKeyspace)` for `default` or a bad
+    ///   name, or `Err(StoreError::Other)` on error.
+    DropKeyspace {
+        user_id: UserId,
+        name: String,
+        respond_to: oneshot::Sender<Result<u64, StoreError>>,
     },
 
     // Admin
@@ -140,70 +485,226 @@ pub enum Command {
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 
-    /// Trigger a snapshot of the current database state.
+    /// Trigger a snapshot of the current database state, in every shard.
     ///
     /// # Response
-    /// - Sends `Ok(())` if snapshot is successful, or `Err(String)` on error.
+    /// - Sends `Ok(seq)` once every shard's snapshot is on disk, with the sequence number of the
+    ///   new snapshot (the highest one if shards disagree), or `Err(String)` if any shard failed.
     Snapshot {
-        respond_to: oneshot::Sender<Result<(), String>>,
+        respond_to: oneshot::Sender<Result<u64, String>>,
     },
 
-    /// Clear the write-ahead log (WAL).
+    /// Clear the write-ahead log (WAL): delete every segment covered by the newest snapshot,
+    /// in every shard.
     ///
     /// # Response
-    /// - Sends `Ok(())` if WAL is cleared, or `Err(String)` on error.
+    /// - Sends `Ok(bytes)` with the number of bytes reclaimed across all shards, or
+    ///   `Err(String)` on error.
     ClearWal {
-        respond_to: oneshot::Sender<Result<(), String>>,
+        respond_to: oneshot::Sender<Result<u64, String>>,
     },
 
     Persist {
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 
+    /// Stream every committed mutation to `events`: first those after `from` still in the WAL,
+    /// then the live ones. `from` holds a sequence number per shard, or is empty to start at the
+    /// beginning. An admin command, which needs the admin token over the wire.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` once every shard feeds the stream, `Err(StoreError::Compacted)` if a
+    ///   shard no longer has the records after its sequence number, or `Err(StoreError)` on
+    ///   another error.
+    Cdc {
+        from: Vec<u64>,
+        events: mpsc::Sender<CdcEvent>,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    /// The part of a `Cdc` feed from one shard, sent by the admin actor to its store actor.
+    ///
+    /// # Response
+    /// - Sends `Ok(ShardFeed)`, with the backlog left in the WAL, or `Err(StoreError)` if the
+    ///   feed cannot start after `from`.
+    ShardCdc {
+        from: u64,
+        respond_to: oneshot::Sender<Result<ShardFeed, StoreError>>,
+    },
+
     // Introspection/meta
 
-    /// Get statistics about the database or workspace.
+    /// Get the memory use and eviction counters, summed over every shard.
     ///
     /// # Response
-    /// - Sends `Ok(String)` containing stats info, or `Err(String)` on error.
+    /// - Sends `Ok(MemoryStats)`, or `Err(String)` if a shard did not answer.
     Stats {
-        respond_to: oneshot::Sender<Result<String, String>>,
+        respond_to: oneshot::Sender<Result<MemoryStats, String>>,
     },
 
-    /// Get general information about the database or workspace.
+    /// Get what the user holds and the quota that limits it.
     ///
     /// # Response
-    /// - Sends `Ok(String)` containing info, or `Err(String)` on error.
+    /// - Sends `Ok(UserInfo)`, or `Err(StoreError)` on error.
     Info {
-        respond_to: oneshot::Sender<Result<String, String>>,
+        user_id: UserId,
+        respond_to: oneshot::Sender<Result<UserInfo, StoreError>>,
+    },
+
+    /// Set the quota of `user_id` (an admin command, which needs the admin token over the wire);
+    /// `None` goes back to the default quota.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` once the quota is in the WAL, or `Err(StoreError::NoSuchUser)` if the
+    ///   user does not exist.
+    SetQuota {
+        user_id: UserId,
+        quota: Option<Quota>,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    // Watches
+
+    /// Watch the keys in `scope` of the current keyspace: every change the store makes to one
+    /// of them is sent to `events`, until `Unwatch` or until `events` is closed or full.
+    ///
+    /// # Response
+    /// - Sends `Ok(watch_id)` once the watch is registered, or `Err(StoreError)` on error.
+    Watch {
+        user_id: UserId,
+        scope: WatchScope,
+        events: EventSender,
+        respond_to: oneshot::Sender<Result<u64, StoreError>>,
+    },
+
+    /// Cancel watch `watch_id` of the user, in whichever keyspace it was created.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` once the watch is gone (its event stream ends), or
+    ///   `Err(StoreError::NoSuchWatch)` if the user has no such watch.
+    Unwatch {
+        user_id: UserId,
+        watch_id: u64,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    // Read snapshots
+
+    /// Open a read snapshot of the store as it is now, see `crate::mvcc`. It covers every
+    /// keyspace of the user, and stays open until `CloseReadSnapshot` or until it goes unused
+    /// for `config.read_snapshot_idle_secs`.
+    ///
+    /// # Response
+    /// - Sends `Ok(snapshot)` with its id, the last WAL record it sees and its time.
+    OpenReadSnapshot {
+        user_id: UserId,
+        respond_to: oneshot::Sender<Result<ReadSnapshot, StoreError>>,
+    },
+
+    /// Close read snapshot `snapshot_id` of the user, letting go of the old versions it kept.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` once it is closed, or `Err(StoreError::NoSuchReadSnapshot)` if the user
+    ///   has no such snapshot.
+    CloseReadSnapshot {
+        user_id: UserId,
+        snapshot_id: u64,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
+    // Pub/sub
+
+    /// Publish `message` on `channel`, to every subscription covering it.
+    ///
+    /// # Response
+    /// - Sends `Ok(n)` with the number of subscriptions the message was queued for.
+    Publish {
+        user_id: UserId,
+        channel: String,
+        message: Value,
+        respond_to: oneshot::Sender<Result<u64, String>>,
+    },
+
+    /// Subscribe to the messages published on `channels`.
+    ///
+    /// # Response
+    /// - Sends `Ok(Subscribed)` with the subscription id and its messages, or `Err(String)` if
+    ///   `channels` is empty.
+    Subscribe {
+        user_id: UserId,
+        channels: Vec<String>,
+        respond_to: oneshot::Sender<Result<Subscribed, String>>,
+    },
+
+    /// Subscribe to the messages published on every channel matching one of `patterns`.
+    ///
+    /// # Response
+    /// - Sends `Ok(Subscribed)` with the subscription id and its messages, or `Err(String)` if
+    ///   `patterns` is empty.
+    PSubscribe {
+        user_id: UserId,
+        patterns: Vec<String>,
+        respond_to: oneshot::Sender<Result<Subscribed, String>>,
+    },
+
+    /// End subscription `subscription_id` of the user.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` once the subscription is gone (its stream ends), or `Err(String)` if the
+    ///   user has no such subscription.
+    Unsubscribe {
+        user_id: UserId,
+        subscription_id: u64,
+        respond_to: oneshot::Sender<Result<(), String>>,
     },
 
     // Transaction support (optional)
 
     /// Begin a new transaction.
     ///
+    /// Until `Commit` or `Rollback`, the user actor buffers Set/Update/Del instead of forwarding
+    /// them, and answers reads from the buffer on top of the committed data as of `Begin`, read
+    /// through a read snapshot.
+    ///
     /// # Response
     /// - Sends `Ok(())` if transaction begins, or `Err(String)` on error.
     Begin {
+        user_id: UserId,
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 
-    /// Commit the current transaction.
+    /// Commit the current transaction: all buffered writes are applied atomically.
     ///
     /// # Response
-    /// - Sends `Ok(())` if commit is successful, or `Err(String)` on error.
+    /// - Sends `Ok(())` if commit is successful, `Err(StoreError::NotFound)` if an `Update` in
+    ///   the transaction targets a key that no longer exists, or `Err(StoreError::Other)` on
+    ///   error. The transaction is over either way.
     Commit {
-        respond_to: oneshot::Sender<Result<(), String>>,
+        user_id: UserId,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
     },
 
-    /// Rollback the current transaction.
+    /// Rollback the current transaction, discarding the buffered writes.
     ///
     /// # Response
     /// - Sends `Ok(())` if rollback is successful, or `Err(String)` on error.
     Rollback {
+        user_id: UserId,
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 
+    /// Apply `ops` in order as one atomic change: either all of them become durable and
+    /// visible, or none do. Sent by the user actor on `Commit`.
+    ///
+    /// # Response
+    /// - Sends `Ok(())` if the batch was applied, `Err(StoreError::NotFound)` if an `Update`
+    ///   targets a missing key (nothing is applied), or `Err(StoreError::Other)` on error.
+    Batch {
+        user_id: UserId,
+        ops: Vec<WriteOp>,
+        respond_to: oneshot::Sender<Result<(), StoreError>>,
+    },
+
     // User/session
 
     /// Terminates the current user connection gracefully.
@@ -215,3 +716,50 @@ pub enum Command {
         respond_to: oneshot::Sender<Result<(), String>>,
     },
 }
+
+impl Command {
+    /// The id whose keys the command reads or writes, for commands that touch keys.
+    ///
+    /// The user actor points it at the owner id of the session's current keyspace.
+    pub fn owner_mut(&mut self) -> Option<&mut UserId> {
+        match self {
+            Command::Set { user_id, .. }
+            | Command::Get { user_id, .. }
+            | Command::MGet { user_id, .. }
+            | Command::MSet { user_id, .. }
+            | Command::MDel { user_id, .. }
+            | Command::Cas { user_id, .. }
+            | Command::SetNx { user_id, .. }
+            | Command::DelIfVersion { user_id, .. }
+            | Command::IncrBy { user_id, .. }
+            | Command::DecrBy { user_id, .. }
+            | Command::Del { user_id, .. }
+            | Command::Update { user_id, .. }
+            | Command::Upsert { user_id, .. }
+            | Command::Ttl { user_id, .. }
+            | Command::Expire { user_id, .. }
+            | Command::PersistKey { user_id, .. }
+            | Command::Range { user_id, .. }
+            | Command::List { user_id, .. }
+            | Command::CreateIndex { user_id, .. }
+            | Command::DropIndex { user_id, .. }
+            | Command::Query { user_id, .. }
+            | Command::Aggregate { user_id, .. }
+            | Command::Commit { user_id, .. }
+            | Command::Watch { user_id, .. }
+            | Command::Batch { user_id, .. } => Some(user_id),
+            _ => None,
+        }
+    }
+
+    /// The read snapshot of the commands that can read through one.
+    pub fn snapshot_mut(&mut self) -> Option<&mut Option<u64>> {
+        match self {
+            Command::Get { snapshot, .. }
+            | Command::MGet { snapshot, .. }
+            | Command::Range { snapshot, .. }
+            | Command::List { snapshot, .. } => Some(snapshot),
+            _ => None,
+        }
+    }
+}
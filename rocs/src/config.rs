@@ -0,0 +1,244 @@
+//! src/config.rs
+//!
+//! Server configuration.
+//!
+//! Every setting can be overridden with a `ROCS_*` environment variable; anything unset
+//! (or unparsable) falls back to the default below.
+
+use crate::quota::Quota;
+use crate::recovery::{RecoveryMode, RecoveryTarget};
+use crate::storage::StorageEngineKind;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct RocsConfig {
+    /// How many snapshots to keep under `snaps/` before the oldest are deleted.
+    /// (`ROCS_SNAPSHOT_RETENTION`)
+    pub snapshot_retention: usize,
+    /// Take a snapshot automatically every this many seconds. `0` disables the timer.
+    /// (`ROCS_SNAPSHOT_INTERVAL_SECS`)
+    pub snapshot_interval_secs: u64,
+    /// Size in bytes after which the active WAL segment is closed and a new one started.
+    /// (`ROCS_WAL_SEGMENT_BYTES`)
+    pub wal_segment_bytes: u64,
+    /// How often the store actor sweeps expired keys, in milliseconds.
+    /// (`ROCS_TTL_SWEEP_INTERVAL_MS`)
+    pub ttl_sweep_interval_ms: u64,
+    /// Where entries are kept: `memory` or `lsm`. (`ROCS_STORAGE_ENGINE`)
+    pub storage_engine: StorageEngineKind,
+    /// Size in bytes after which the LSM engine writes its memtable out as a table.
+    /// (`ROCS_LSM_MEMTABLE_BYTES`)
+    pub lsm_memtable_bytes: u64,
+    /// How often the store actor persists its engine, in milliseconds. Ticks with nothing new
+    /// to persist are skipped. (`ROCS_PERSIST_INTERVAL_MS`)
+    pub persist_interval_ms: u64,
+    /// How many delta files the memory engine writes before it rewrites `store_state.bin` in
+    /// full and deletes them. (`ROCS_DELTA_MERGE_COUNT`)
+    pub delta_merge_count: usize,
+    /// What to do when the store files fail their checksum or cannot be decoded at startup.
+    /// (`ROCS_ON_CORRUPTION`)
+    pub on_corruption: CorruptionPolicy,
+    /// How many store actors the keyspace is split across, by user id. Cannot be changed once
+    /// data has been written. (`ROCS_SHARDS`)
+    pub shards: usize,
+    /// Most entries one `List` or `Range` page may hold; also the page size when a request does
+    /// not ask for one. (`ROCS_MAX_PAGE_SIZE`)
+    pub max_page_size: usize,
+    /// Memory budget for all entries, in bytes, split evenly across the shards. `0` means no
+    /// limit. (`ROCS_MAX_MEMORY_BYTES`)
+    pub max_memory_bytes: u64,
+    /// Memory budget for the entries of one user, across all of their keyspaces, in bytes. `0`
+    /// means no limit. (`ROCS_MAX_MEMORY_PER_USER_BYTES`)
+    pub max_memory_per_user_bytes: u64,
+    /// What to do with a write that would go over a memory budget. (`ROCS_EVICTION_POLICY`)
+    pub eviction_policy: EvictionPolicy,
+    /// The quota of every user an admin has not set one for. (`ROCS_QUOTA_MAX_KEYS`,
+    /// `ROCS_QUOTA_MAX_BYTES`, `ROCS_QUOTA_MAX_KEY_LEN`; `0` means no limit)
+    pub default_quota: Quota,
+    /// Messages a pub/sub subscriber may fall behind by. (`ROCS_PUBSUB_BUFFER`)
+    pub pubsub_buffer: usize,
+    /// What to do with a subscriber whose buffer is full. (`ROCS_PUBSUB_SLOW_SUBSCRIBER`)
+    pub slow_subscriber: SlowSubscriberPolicy,
+    /// Read snapshots that go unused for this many seconds are closed. (`ROCS_READ_SNAPSHOT_IDLE_SECS`)
+    pub read_snapshot_idle_secs: u64,
+    /// Recover the store to this point before starting, see `crate::recovery`. An invalid value
+    /// refuses to start rather than being ignored. (`ROCS_RECOVER_TO`)
+    pub recover_to: Option<RecoveryTarget>,
+    /// What to do with the recovered state. (`ROCS_RECOVER_MODE`)
+    pub recover_mode: RecoveryMode,
+    /// Where the `write` recovery mode puts the new store file. (`ROCS_RECOVER_OUTPUT`)
+    pub recover_output: PathBuf,
+    /// The token admin commands sent over the wire must carry. Unset, the server refuses them.
+    /// (`ROCS_ADMIN_TOKEN`)
+    pub admin_token: Option<String>,
+}
+
+/// What the pub/sub actor does with a message for a subscriber whose buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowSubscriberPolicy {
+    /// Drop the message for that subscriber; the next message it gets says how many it missed.
+    Drop,
+    /// End the subscription, which finishes its stream.
+    Disconnect,
+}
+
+impl FromStr for SlowSubscriberPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(SlowSubscriberPolicy::Drop),
+            "disconnect" => Ok(SlowSubscriberPolicy::Disconnect),
+            other => Err(format!("unknown slow subscriber policy {other:?} (expected \"drop\" or \"disconnect\")")),
+        }
+    }
+}
+
+/// How the store makes room when a write would go over a memory budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EvictionPolicy {
+    /// Evict nothing; the write fails with an out-of-memory error.
+    NoEviction,
+    /// Evict the least recently used keys.
+    AllKeysLru,
+    /// Evict the least frequently used keys, the least recently used first among equals.
+    AllKeysLfu,
+    /// Evict the keys with an expiry, the ones expiring soonest first. Keys without an expiry
+    /// are never evicted; if those fill the budget, the write fails.
+    VolatileTtl,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            other => Err(format!(
+                "unknown eviction policy {other:?} (expected \"noeviction\", \"allkeys-lru\", \"allkeys-lfu\" or \"volatile-ttl\")"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        })
+    }
+}
+
+/// How the server reacts to a corrupt store on startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Refuse to start and leave the files untouched.
+    Refuse,
+    /// Move the corrupt files aside and start from the newest valid snapshot plus the WAL.
+    /// Refuses to start if there is no valid snapshot.
+    Snapshot,
+}
+
+impl FromStr for CorruptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "refuse" => Ok(CorruptionPolicy::Refuse),
+            "snapshot" => Ok(CorruptionPolicy::Snapshot),
+            other => Err(format!("unknown corruption policy {other:?} (expected \"refuse\" or \"snapshot\")")),
+        }
+    }
+}
+
+impl Default for RocsConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_retention: 7,
+            snapshot_interval_secs: 60 * 60,
+            wal_segment_bytes: 16 * 1024 * 1024,
+            ttl_sweep_interval_ms: 1000,
+            storage_engine: StorageEngineKind::Memory,
+            lsm_memtable_bytes: 4 * 1024 * 1024,
+            persist_interval_ms: 10_000,
+            delta_merge_count: 16,
+            on_corruption: CorruptionPolicy::Snapshot,
+            shards: 1,
+            max_page_size: 1000,
+            max_memory_bytes: 0,
+            max_memory_per_user_bytes: 0,
+            eviction_policy: EvictionPolicy::NoEviction,
+            default_quota: Quota::default(),
+            pubsub_buffer: 1024,
+            slow_subscriber: SlowSubscriberPolicy::Drop,
+            read_snapshot_idle_secs: 5 * 60,
+            recover_to: None,
+            recover_mode: RecoveryMode::DryRun,
+            recover_output: PathBuf::from("recovered"),
+            admin_token: None,
+        }
+    }
+}
+
+impl RocsConfig {
+    /// Builds the config from the environment, starting from `RocsConfig::default()`.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            snapshot_retention: env_or("ROCS_SNAPSHOT_RETENTION", default.snapshot_retention),
+            snapshot_interval_secs: env_or("ROCS_SNAPSHOT_INTERVAL_SECS", default.snapshot_interval_secs),
+            wal_segment_bytes: env_or("ROCS_WAL_SEGMENT_BYTES", default.wal_segment_bytes),
+            ttl_sweep_interval_ms: env_or("ROCS_TTL_SWEEP_INTERVAL_MS", default.ttl_sweep_interval_ms),
+            storage_engine: env_or("ROCS_STORAGE_ENGINE", default.storage_engine),
+            lsm_memtable_bytes: env_or("ROCS_LSM_MEMTABLE_BYTES", default.lsm_memtable_bytes),
+            persist_interval_ms: env_or("ROCS_PERSIST_INTERVAL_MS", default.persist_interval_ms),
+            delta_merge_count: env_or("ROCS_DELTA_MERGE_COUNT", default.delta_merge_count),
+            on_corruption: env_or("ROCS_ON_CORRUPTION", default.on_corruption),
+            shards: env_or("ROCS_SHARDS", default.shards).max(1),
+            max_page_size: env_or("ROCS_MAX_PAGE_SIZE", default.max_page_size).max(1),
+            max_memory_bytes: env_or("ROCS_MAX_MEMORY_BYTES", default.max_memory_bytes),
+            max_memory_per_user_bytes: env_or("ROCS_MAX_MEMORY_PER_USER_BYTES", default.max_memory_per_user_bytes),
+            eviction_policy: env_or("ROCS_EVICTION_POLICY", default.eviction_policy),
+            default_quota: Quota {
+                max_keys: env_or("ROCS_QUOTA_MAX_KEYS", default.default_quota.max_keys),
+                max_bytes: env_or("ROCS_QUOTA_MAX_BYTES", default.default_quota.max_bytes),
+                max_key_len: env_or("ROCS_QUOTA_MAX_KEY_LEN", default.default_quota.max_key_len),
+            },
+            pubsub_buffer: env_or("ROCS_PUBSUB_BUFFER", default.pubsub_buffer).max(1),
+            slow_subscriber: env_or("ROCS_PUBSUB_SLOW_SUBSCRIBER", default.slow_subscriber),
+            read_snapshot_idle_secs: env_or("ROCS_READ_SNAPSHOT_IDLE_SECS", default.read_snapshot_idle_secs),
+            recover_to: env::var("ROCS_RECOVER_TO").ok().map(|raw| {
+                raw.trim().parse().unwrap_or_else(|e| {
+                    eprintln!("Refusing to start: invalid ROCS_RECOVER_TO: {e}");
+                    std::process::exit(1);
+                })
+            }),
+            recover_mode: env_or("ROCS_RECOVER_MODE", default.recover_mode),
+            recover_output: env_or("ROCS_RECOVER_OUTPUT", default.recover_output),
+            admin_token: env::var("ROCS_ADMIN_TOKEN").ok().map(|raw| raw.trim().to_string()).filter(|t| !t.is_empty()),
+        }
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(raw) => match raw.trim().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("Ignoring invalid value for {name}: {raw:?}");
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,124 @@
+//! src/error.rs
+//!
+//! Typed errors for the store commands whose failures clients need to tell apart.
+//!
+//! They travel over the wire as externally tagged JSON, e.g.
+//! `{"Err":{"VersionMismatch":{"key":"a","expected":3,"actual":5}}}`.
+
+use crate::quota::QuotaKind;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StoreError {
+    /// `Cas`: the key is not at the expected version. `actual` is `None` if the key does not exist.
+    VersionMismatch { key: String, expected: u64, actual: Option<u64> },
+    /// `SetNx`: the key already exists, at `version`.
+    KeyExists { key: String, version: u64 },
+    /// `DelIfVersion`: the key is not at the expected version, so it was not deleted.
+    /// `actual` is `None` if the key does not exist.
+    DeleteConflict { key: String, expected: u64, actual: Option<u64> },
+    /// `Update`: the key does not exist.
+    NotFound { key: String },
+    /// `IncrBy`/`DecrBy`: the key holds something other than an integer.
+    NotAnInteger { key: String, found: String },
+    /// `IncrBy`/`DecrBy`: the result does not fit in an `i64`. The key keeps `value`.
+    Overflow { key: String, value: i64 },
+    /// `Aggregate`: the `Sum` of the ints in scope does not fit in an `i64`, or a `Sum` or `Avg`
+    /// involving floats goes past the `f64` range.
+    SumOverflow,
+    /// `CreateKeyspace`/`DropKeyspace`/`SelectKeyspace`: `name` cannot be used as a keyspace.
+    InvalidKeyspace { name: String, reason: String },
+    /// `CreateKeyspace`: the user already has a keyspace called `name`.
+    KeyspaceExists { name: String },
+    /// `DropKeyspace`/`SelectKeyspace`: the user has no keyspace called `name`.
+    NoSuchKeyspace { name: String },
+    /// `Query`/`DropIndex`: the values of the current keyspace are not indexed.
+    NotIndexed,
+    /// The write does not fit in the memory budget of `limit` bytes, and the eviction policy
+    /// could not make room for it.
+    OutOfMemory { limit: u64 },
+    /// The write would take the user past the `kind` limit of their quota, which is `limit`.
+    QuotaExceeded { kind: QuotaKind, limit: u64 },
+    /// `SetQuota`/`Info`: there is no user `user_id`.
+    NoSuchUser { user_id: String },
+    /// `Unwatch`: the user has no watch `watch_id`, or it already ended.
+    NoSuchWatch { watch_id: u64 },
+    /// A read through snapshot `snapshot_id`, or `CloseReadSnapshot`: the user has no such read
+    /// snapshot, e.g. because it was closed for being idle.
+    NoSuchReadSnapshot { snapshot_id: u64 },
+    /// `Cdc`: the WAL of `shard` no longer holds the records after `requested`; the oldest
+    /// sequence number a feed can start from is `oldest`.
+    Compacted { shard: usize, requested: u64, oldest: u64 },
+    /// `Cdc`: `shard` has only logged up to `latest`, so it cannot start after `requested`.
+    SequenceAhead { shard: usize, requested: u64, latest: u64 },
+    /// An admin command came without the server's admin token, with a wrong one, or to a server
+    /// that has none set.
+    Unauthorized,
+    /// Any other failure, e.g. the WAL append failed.
+    Other(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::VersionMismatch { key, expected, actual: Some(actual) } => {
+                write!(f, "version mismatch on {key:?}: expected {expected}, found {actual}")
+            }
+            StoreError::VersionMismatch { key, expected, actual: None } => {
+                write!(f, "version mismatch on {key:?}: expected {expected}, but the key does not exist")
+            }
+            StoreError::KeyExists { key, version } => write!(f, "key {key:?} already exists (version {version})"),
+            StoreError::DeleteConflict { key, expected, actual: Some(actual) } => {
+                write!(f, "not deleted {key:?}: expected version {expected}, found {actual}")
+            }
+            StoreError::DeleteConflict { key, expected, actual: None } => {
+                write!(f, "not deleted {key:?}: expected version {expected}, but the key does not exist")
+            }
+            StoreError::NotFound { key } => write!(f, "key {key:?} not found"),
+            StoreError::NotAnInteger { key, found } => write!(f, "key {key:?} holds a {found}, not an int"),
+            StoreError::Overflow { key, value } => write!(f, "{key:?} would overflow (currently {value})"),
+            StoreError::SumOverflow => f.write_str("the sum is too large to represent"),
+            StoreError::InvalidKeyspace { name, reason } => write!(f, "invalid keyspace {name:?}: {reason}"),
+            StoreError::KeyspaceExists { name } => write!(f, "keyspace {name:?} already exists"),
+            StoreError::NoSuchKeyspace { name } => write!(f, "no keyspace {name:?}"),
+            StoreError::NotIndexed => f.write_str("the values of this keyspace are not indexed (see CREATE_INDEX)"),
+            StoreError::OutOfMemory { limit } => {
+                write!(f, "out of memory: the write does not fit in the memory budget of {limit} bytes")
+            }
+            StoreError::QuotaExceeded { kind: QuotaKind::Keys, limit } => {
+                write!(f, "quota exceeded: at most {limit} keys allowed")
+            }
+            StoreError::QuotaExceeded { kind: QuotaKind::Bytes, limit } => {
+                write!(f, "quota exceeded: at most {limit} bytes of keys and values allowed")
+            }
+            StoreError::QuotaExceeded { kind: QuotaKind::KeyLength, limit } => {
+                write!(f, "quota exceeded: keys may be at most {limit} bytes long")
+            }
+            StoreError::NoSuchUser { user_id } => write!(f, "no user {user_id:?}"),
+            StoreError::NoSuchWatch { watch_id } => write!(f, "no watch {watch_id}"),
+            StoreError::NoSuchReadSnapshot { snapshot_id } => write!(f, "no read snapshot {snapshot_id}"),
+            StoreError::Compacted { shard, requested, oldest } => write!(
+                f,
+                "shard {shard} no longer has the records after sequence {requested} (compacted); the oldest a feed can start from is {oldest}"
+            ),
+            StoreError::SequenceAhead { shard, requested, latest } => {
+                write!(f, "shard {shard} is only at sequence {latest}, cannot start after {requested}")
+            }
+            StoreError::Unauthorized => f.write_str("not authorized: admin commands need the server's admin token"),
+            StoreError::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<StoreError> for String {
+    fn from(e: StoreError) -> Self {
+        e.to_string()
+    }
+}
+
+impl From<String> for StoreError {
+    fn from(msg: String) -> Self {
+        StoreError::Other(msg)
+    }
+}
@@ -4,9 +4,15 @@
 
 use crate::actors::{
     store_actor::spawn_store_actor,
-    admin_actor::spawn_admin_actor,
+    logger_actor::spawn_logger_actor,
+    snapshot_actor::spawn_snapshot_actor,
+    admin_actor::{spawn_admin_actor, ShardHandles},
     user_actor::spawn_user_actor,
+    pubsub_actor::spawn_pubsub_actor,
 };
+use crate::config::RocsConfig;
+use crate::recovery::{self, RecoveryMode};
+use crate::shard::{self, ShardId, Shards};
 
 use std::collections::HashMap;
 use crate::router::ActorChannels;
@@ -15,15 +21,39 @@ use std::sync::{
     Mutex,
 };
 
-pub async fn initialize_system() -> ActorChannels {
+pub async fn initialize_system(config: RocsConfig) -> ActorChannels {
 
-    let store_actor = spawn_store_actor();
-    //let admin_actor = spawn_admin_actor();
+    let dry_run = config.recover_to.is_some() && config.recover_mode == RecoveryMode::DryRun;
+    if let Err(e) = shard::check_layout(config.shards, !dry_run) {
+        eprintln!("Refusing to start: {e}");
+        std::process::exit(1);
+    }
+
+    // Either exits, or leaves the recovered state in place to be served.
+    recovery::run(&config);
+
+    // Every shard gets its own logger, snapshotter and store actor.
+    let mut stores = Vec::with_capacity(config.shards);
+    let mut handles = Vec::with_capacity(config.shards);
+    for index in 0..config.shards {
+        let shard = ShardId { index, count: config.shards };
+        let dirs = shard.dirs();
+        let logger_actor = spawn_logger_actor(dirs.wal, config.wal_segment_bytes);
+        let snapshot_actor = spawn_snapshot_actor(dirs.snaps, config.snapshot_retention);
+        let store_actor = spawn_store_actor(shard, logger_actor.clone(), snapshot_actor.clone(), &config);
+        stores.push(store_actor.clone());
+        handles.push(ShardHandles { store: store_actor, snapshotter: snapshot_actor, logger: logger_actor });
+    }
+    let admin_actor = spawn_admin_actor(handles);
+    let pubsub_actor = spawn_pubsub_actor(&config);
     
     let mut user_actors = Arc::new(Mutex::new(HashMap::new()));
 
     ActorChannels {
         user_actors,
-        store_actor,
+        shards: Shards::new(stores),
+        admin_actor,
+        pubsub_actor,
+        admin_token: config.admin_token.as_deref().map(Arc::from),
     }
 }
@@ -0,0 +1,61 @@
+//! src/keyspace.rs
+//!
+//! Named keyspaces: separate logical databases of one user.
+//!
+//! Every user has the `default` keyspace, which is the flat keyspace users had before keyspaces
+//! existed. Further keyspaces are created by name. Storage does not know about keyspaces: the
+//! entries of keyspace `name` of user `u` are stored under the owner id `u/name` instead of `u`,
+//! and a created keyspace is registered in the store's set of users under that owner id. User
+//! ids are UUIDs and never contain a `/`, so owner ids cannot collide with user ids.
+//!
+//! The user actor keeps the session's current keyspace and rewrites the `user_id` of every
+//! command it forwards to the owner id.
+
+use crate::error::StoreError;
+
+/// The keyspace every user starts in. It always exists and cannot be dropped.
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+const SEPARATOR: char = '/';
+const MAX_NAME_LEN: usize = 64;
+
+/// The owner id under which the entries of `keyspace` of `user_id` are stored.
+pub fn owner_id(user_id: &str, keyspace: &str) -> String {
+    if keyspace == DEFAULT_KEYSPACE {
+        user_id.to_string()
+    } else {
+        format!("{user_id}{SEPARATOR}{keyspace}")
+    }
+}
+
+/// The prefix shared by the owner ids of all named keyspaces of `user_id`.
+pub fn owner_prefix(user_id: &str) -> String {
+    format!("{user_id}{SEPARATOR}")
+}
+
+/// The user id an owner id belongs to.
+pub fn user_of(owner_id: &str) -> &str {
+    owner_id.split(SEPARATOR).next().unwrap_or(owner_id)
+}
+
+/// Whether `id` is a user id rather than the owner id of a named keyspace.
+pub fn is_user_id(id: &str) -> bool {
+    !id.contains(SEPARATOR)
+}
+
+/// Checks that `name` can be used for a named keyspace.
+pub fn validate_name(name: &str) -> Result<(), StoreError> {
+    let invalid = |reason: &str| {
+        Err(StoreError::InvalidKeyspace { name: name.to_string(), reason: reason.to_string() })
+    };
+    if name == DEFAULT_KEYSPACE {
+        return invalid("the default keyspace always exists");
+    }
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return invalid("names must be 1 to 64 characters long");
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return invalid("names may only contain letters, digits, '_' and '-'");
+    }
+    Ok(())
+}
@@ -1,7 +1,20 @@
 #![allow(warnings)]
 pub mod command;
+pub mod config;
 pub mod actors;
 pub mod router;
 pub mod initializer;
 pub mod network;
 pub mod wire_cmd;
+pub mod value;
+pub mod error;
+pub mod storage;
+pub mod shard;
+pub mod keyspace;
+pub mod page;
+pub mod aggregate;
+pub mod quota;
+pub mod watch;
+pub mod cdc;
+pub mod mvcc;
+pub mod recovery;
@@ -4,8 +4,21 @@
 #![allow(warnings)]
 mod actors;
 mod command;
+mod config;
 mod initializer;
 mod router;
+mod value;
+mod error;
+mod storage;
+mod shard;
+mod keyspace;
+mod page;
+mod aggregate;
+mod quota;
+mod watch;
+mod cdc;
+mod mvcc;
+mod recovery;
 
 use anyhow;
 use std::io;
@@ -15,7 +28,8 @@ use quinn::{Endpoint, ServerConfig};
 use rocs::{
     network::connections::handle_connection, 
     router::{ActorChannels, route_cmd}, 
-    initializer::initialize_system
+    initializer::initialize_system,
+    config::RocsConfig,
 };
 use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
 use std::net::SocketAddr;
@@ -24,7 +38,7 @@ use std::fs;
 #[tokio::main(flavor = "multi_thread", worker_threads = 4)]
 async fn main() -> anyhow::Result<()> {
 
-    let system: ActorChannels = initialize_system().await;
+    let system: ActorChannels = initialize_system(RocsConfig::from_env()).await;
 
     let alt_domain_names_for_cert = vec!["localhost".to_string(), "hello.azen".to_string()];
 
@@ -78,13 +92,14 @@ async fn main() -> anyhow::Result<()> {
                     Ok((mut send, mut recv)) => {
 
                         let system = system.clone(); 
+                        let connection = connection.clone();
                         // why do we clone again here?
                         // Because if we use the cloned channels here .. they;ll be moved here ..
                         // we want each stream from the client to use the channels independently
 
                         tokio::spawn(async move {
 
-                            if let Err(e) = handle_connection(send, recv, system).await {
+                            if let Err(e) = handle_connection(send, recv, connection, system).await {
                                 eprintln!("Stream Error: {:?}", e);
                             }
                         });
@@ -0,0 +1,431 @@
+//! src/mvcc.rs
+//!
+//! Read snapshots: reading a shard as of one point in time while writes go on.
+//!
+//! Every entry carries the sequence number of the WAL record that last wrote it, which is its
+//! commit timestamp. `OpenReadSnapshot` pins the shard's current `wal_seq` and clock and hands
+//! out a handle; `Get`, `MGet`, `Range` and `List` run through the handle see the keys exactly
+//! as they were then. Expiry is judged by the time the snapshot was opened too, so keys do not
+//! expire out of a snapshot. Transactions read through a snapshot taken at `Begin`.
+//!
+//! The engine only holds the newest version of each key. While read snapshots are open, the
+//! store actor shows every write to `Versions` before applying it, and the versions it replaces
+//! are kept for as long as an open snapshot may read them: for each snapshot, the first change
+//! of a key after it holds the version the snapshot sees. Versions no open snapshot needs are
+//! dropped when a snapshot closes, and with no snapshot open nothing is kept. Expired keys that
+//! an open snapshot can still see are not swept until it closes. Kept versions are not counted
+//! against memory budgets.
+//!
+//! Snapshots live in memory and end with a restart. A snapshot that goes unused for
+//! `config.read_snapshot_idle_secs` is closed, so a client that goes away cannot pin old
+//! versions forever.
+
+use crate::actors::logger_actor::WalOp;
+use crate::actors::store_actor::Entry;
+use crate::error::StoreError;
+use crate::keyspace;
+use crate::page;
+use crate::storage::{EntryIter, Key, StorageEngine};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::ops::Bound;
+
+/// A read snapshot, as handed to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadSnapshot {
+    pub id: u64,
+    /// The last WAL record the snapshot sees.
+    pub seq: u64,
+    /// When the snapshot was opened, in milliseconds since the unix epoch.
+    pub time: u64,
+}
+
+struct Open {
+    user_id: String,
+    snapshot: ReadSnapshot,
+    last_used: u64,
+}
+
+/// The entries a WAL record replaces: every key it writes, with the entry the key had before
+/// (`None` if there was none).
+pub type Replaced = Vec<(Key, Option<Entry>)>;
+
+/// The read snapshots of one shard, and the old versions they need.
+#[derive(Default)]
+pub struct Versions {
+    next_id: u64,
+    open: BTreeMap<u64, Open>,
+    /// The `seq` of every open snapshot, with how many are open at it.
+    seqs: BTreeMap<u64, usize>,
+    /// For keys changed while snapshots were open: the sequence number of each kept change and
+    /// the entry it replaced, oldest first.
+    history: BTreeMap<Key, Vec<(u64, Option<Entry>)>>,
+}
+
+impl Versions {
+    /// Opens a snapshot for `user_id` of the shard as of record `seq` and time `now`.
+    pub fn open(&mut self, user_id: String, seq: u64, now: u64) -> ReadSnapshot {
+        self.next_id += 1;
+        let snapshot = ReadSnapshot { id: self.next_id, seq, time: now };
+        *self.seqs.entry(seq).or_default() += 1;
+        self.open.insert(snapshot.id, Open { user_id, snapshot, last_used: now });
+        snapshot
+    }
+
+    /// Closes snapshot `id` if it belongs to the user of `owner` (in any of their keyspaces).
+    /// Returns whether there was such a snapshot.
+    pub fn close(&mut self, owner: &str, id: u64) -> bool {
+        match self.open.get(&id) {
+            Some(open) if open.user_id == keyspace::user_of(owner) => {
+                self.forget(id);
+                self.collect_garbage();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Closes the snapshots that were last used more than `idle_ms` before `now`.
+    pub fn close_idle(&mut self, now: u64, idle_ms: u64) {
+        let idle: Vec<u64> = self
+            .open
+            .values()
+            .filter(|open| now.saturating_sub(open.last_used) > idle_ms)
+            .map(|open| open.snapshot.id)
+            .collect();
+        if idle.is_empty() {
+            return;
+        }
+        for id in idle {
+            self.forget(id);
+        }
+        self.collect_garbage();
+    }
+
+    fn forget(&mut self, id: u64) {
+        let Some(open) = self.open.remove(&id) else { return };
+        let seq = open.snapshot.seq;
+        if let Some(count) = self.seqs.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.seqs.remove(&seq);
+            }
+        }
+    }
+
+    /// Snapshot `id`, for a read of the keys of `owner` at `now`.
+    pub fn at(&mut self, owner: &str, id: u64, now: u64) -> Result<ReadSnapshot, StoreError> {
+        match self.open.get_mut(&id) {
+            Some(open) if open.user_id == keyspace::user_of(owner) => {
+                open.last_used = now;
+                Ok(open.snapshot)
+            }
+            _ => Err(StoreError::NoSuchReadSnapshot { snapshot_id: id }),
+        }
+    }
+
+    /// Keys that expired at or before this time are invisible to every open snapshot, and may be
+    /// removed.
+    pub fn expiry_horizon(&self, now: u64) -> u64 {
+        self.open.values().map(|open| open.snapshot.time).fold(now, u64::min)
+    }
+
+    /// The entries `op` replaces, as read from `engine` before it is applied. Empty if no
+    /// snapshot is open.
+    pub fn replaced(&self, engine: &dyn StorageEngine, op: &WalOp) -> io::Result<Replaced> {
+        fn walk(engine: &dyn StorageEngine, op: &WalOp, seen: &mut BTreeSet<Key>, replaced: &mut Replaced) -> io::Result<()> {
+            // only the first write of a key in a batch replaces what a snapshot saw
+            let mut replace = |key: Key| -> io::Result<()> {
+                if seen.insert(key.clone()) {
+                    let before = engine.get(&key)?;
+                    replaced.push((key, before));
+                }
+                Ok(())
+            };
+            match op {
+                WalOp::Put { user_id, key, .. } | WalOp::Delete { user_id, key } => {
+                    replace((user_id.clone(), key.clone()))?;
+                }
+                WalOp::DropKeyspace { user_id, keyspace } => {
+                    let owner = keyspace::owner_id(user_id, keyspace);
+                    let keys = engine
+                        .scan(&owner, (Bound::Unbounded, Bound::Unbounded))
+                        .map(|r| r.map(|(key, _)| key))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    for key in keys {
+                        replace((owner.clone(), key))?;
+                    }
+                }
+                WalOp::Batch(ops) => {
+                    for op in ops {
+                        walk(engine, op, seen, replaced)?;
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        let mut replaced = Vec::new();
+        if !self.open.is_empty() {
+            walk(engine, op, &mut BTreeSet::new(), &mut replaced)?;
+        }
+        Ok(replaced)
+    }
+
+    /// Keeps the entries record `seq` replaced that an open snapshot may read.
+    pub fn keep(&mut self, seq: u64, replaced: Replaced) {
+        let Some(&newest) = self.seqs.keys().next_back() else { return };
+        for (key, before) in replaced {
+            // A change is the first after some snapshot unless every snapshot is older than the
+            // key's previous change, whose kept version those snapshots read instead.
+            let kept = self.history.get(&key).and_then(|changes| changes.last()).map_or(0, |(s, _)| *s);
+            let previous = before.as_ref().map_or(0, |e| e.version).max(kept);
+            if newest >= previous {
+                self.history.entry(key).or_default().push((seq, before));
+            }
+        }
+    }
+
+    /// Drops the kept versions no open snapshot reads: for every snapshot, only the first kept
+    /// change of a key after it is needed.
+    fn collect_garbage(&mut self) {
+        if self.seqs.is_empty() {
+            self.history.clear();
+            return;
+        }
+        let seqs: Vec<u64> = self.seqs.keys().copied().collect();
+        self.history.retain(|_, changes| {
+            let mut next = 0; // the oldest snapshot whose change has not been found yet
+            changes.retain(|(seq, _)| {
+                let needed = seqs.get(next).is_some_and(|s| s < seq);
+                while seqs.get(next).is_some_and(|s| s < seq) {
+                    next += 1;
+                }
+                needed
+            });
+            !changes.is_empty()
+        });
+    }
+
+    /// The entry under `key` as `snapshot` sees it, or `None` if it did not exist or had expired.
+    pub fn get(&self, engine: &dyn StorageEngine, key: &Key, snapshot: &ReadSnapshot) -> io::Result<Option<Entry>> {
+        let entry = match self.seen_by(key, snapshot) {
+            Some(before) => before.clone(),
+            None => engine.get(key)?,
+        };
+        Ok(entry.filter(|e| !e.is_expired(snapshot.time)))
+    }
+
+    /// The entry `snapshot` sees under `key`, if the key changed since.
+    fn seen_by(&self, key: &Key, snapshot: &ReadSnapshot) -> Option<&Option<Entry>> {
+        let changes = self.history.get(key)?;
+        changes.iter().find(|(seq, _)| *seq > snapshot.seq).map(|(_, before)| before)
+    }
+
+    /// The entries of `owner` in `range` as `snapshot` sees them, in key order (reversed if
+    /// `reverse`), expired or not.
+    pub fn scan<'a>(
+        &'a self,
+        engine: &'a dyn StorageEngine,
+        owner: &str,
+        range: (Bound<String>, Bound<String>),
+        reverse: bool,
+        snapshot: &ReadSnapshot,
+    ) -> EntryIter<'a> {
+        let mut changed: Vec<(String, Option<Entry>)> = self
+            .history
+            .range((owner.to_string(), String::new())..)
+            .take_while(|((o, _), _)| o == owner)
+            .filter(|((_, key), _)| page::contains(&range, key))
+            .filter_map(|(k, _)| self.seen_by(k, snapshot).map(|before| (k.1.clone(), before.clone())))
+            .collect();
+        if reverse {
+            changed.reverse();
+        }
+        let skip: BTreeSet<String> = changed.iter().map(|(key, _)| key.clone()).collect();
+
+        let current = if reverse { engine.scan_rev(owner, range) } else { engine.scan(owner, range) };
+        let mut current = current.filter(move |r| !matches!(r, Ok((key, _)) if skip.contains(key))).peekable();
+        let mut then = changed.into_iter().filter_map(|(key, before)| Some((key, before?))).peekable();
+        Box::new(std::iter::from_fn(move || {
+            let then_first = match (current.peek(), then.peek()) {
+                (Some(Ok((a, _))), Some((b, _))) => if reverse { b > a } else { b < a },
+                (None, Some(_)) => true,
+                _ => false,
+            };
+            if then_first { then.next().map(Ok) } else { current.next() }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::store_actor;
+    use crate::storage::memory::MemoryEngine;
+    use crate::value::Value;
+
+    /// A shard whose WAL is at `seq`, with the snapshots open on it.
+    struct Shard {
+        engine: MemoryEngine,
+        versions: Versions,
+        seq: u64,
+    }
+
+    impl Shard {
+        fn new() -> Self {
+            Self { engine: MemoryEngine::detached(), versions: Versions::default(), seq: 0 }
+        }
+
+        /// Logs and applies `op` the way the store actor does.
+        fn write(&mut self, op: WalOp) {
+            self.seq += 1;
+            let replaced = self.versions.replaced(&self.engine, &op).unwrap();
+            store_actor::apply(&mut self.engine, self.seq, op).unwrap();
+            self.versions.keep(self.seq, replaced);
+        }
+
+        fn set(&mut self, key: &str, n: i64) {
+            self.write(WalOp::Put { user_id: "u".into(), key: key.into(), value: Value::Int(n), expires_at: None });
+        }
+
+        fn delete(&mut self, key: &str) {
+            self.write(WalOp::Delete { user_id: "u".into(), key: key.into() });
+        }
+
+        fn open(&mut self) -> ReadSnapshot {
+            self.versions.open("u".into(), self.seq, 1000)
+        }
+
+        fn get(&self, key: &str, snapshot: &ReadSnapshot) -> Option<Value> {
+            let entry = self.versions.get(&self.engine, &("u".into(), key.into()), snapshot).unwrap();
+            entry.map(|e| e.value)
+        }
+
+        fn scan(&self, snapshot: &ReadSnapshot, reverse: bool) -> Vec<(String, Value)> {
+            let range = (Bound::Unbounded, Bound::Unbounded);
+            let scan = self.versions.scan(&self.engine, "u", range, reverse, snapshot);
+            scan.map(|r| r.map(|(key, e)| (key, e.value))).collect::<io::Result<_>>().unwrap()
+        }
+
+        /// How many versions are kept for `key`.
+        fn kept(&self, key: &str) -> usize {
+            self.versions.history.get(&("u".to_string(), key.to_string())).map_or(0, Vec::len)
+        }
+    }
+
+    fn entries(pairs: &[(&str, i64)]) -> Vec<(String, Value)> {
+        pairs.iter().map(|(key, n)| (key.to_string(), Value::Int(*n))).collect()
+    }
+
+    #[test]
+    fn nothing_is_kept_without_an_open_snapshot() {
+        let mut shard = Shard::new();
+        shard.set("a", 1);
+        shard.set("a", 2);
+        shard.delete("a");
+        assert!(shard.versions.history.is_empty());
+    }
+
+    #[test]
+    fn a_snapshot_sees_keys_as_they_were() {
+        let mut shard = Shard::new();
+        shard.set("a", 1);
+        shard.set("b", 1);
+        let snapshot = shard.open();
+        shard.set("a", 2);
+        shard.delete("b");
+        shard.set("c", 2);
+
+        assert_eq!(shard.get("a", &snapshot), Some(Value::Int(1)));
+        assert_eq!(shard.get("b", &snapshot), Some(Value::Int(1)));
+        assert_eq!(shard.get("c", &snapshot), None);
+        assert_eq!(shard.scan(&snapshot, false), entries(&[("a", 1), ("b", 1)]));
+        assert_eq!(shard.scan(&snapshot, true), entries(&[("b", 1), ("a", 1)]));
+
+        let now = shard.open();
+        assert_eq!(shard.scan(&now, false), entries(&[("a", 2), ("c", 2)]));
+    }
+
+    #[test]
+    fn only_the_first_change_after_a_snapshot_is_kept() {
+        let mut shard = Shard::new();
+        shard.set("a", 1);
+        let snapshot = shard.open();
+        for n in 2..=5 {
+            shard.set("a", n);
+        }
+        shard.set("b", 1);
+        shard.set("b", 2);
+
+        assert_eq!((shard.kept("a"), shard.kept("b")), (1, 1));
+        assert_eq!(shard.get("a", &snapshot), Some(Value::Int(1)));
+        assert_eq!(shard.get("b", &snapshot), None);
+    }
+
+    #[test]
+    fn closing_a_snapshot_drops_the_versions_only_it_needed() {
+        let mut shard = Shard::new();
+        shard.set("a", 1);
+        let first = shard.open();
+        shard.set("a", 2);
+        let second = shard.open();
+        shard.set("a", 3);
+        shard.set("a", 4);
+        assert_eq!(shard.kept("a"), 2);
+
+        assert!(shard.versions.close("u", first.id));
+        assert_eq!(shard.kept("a"), 1);
+        assert_eq!(shard.get("a", &second), Some(Value::Int(2)));
+
+        assert!(shard.versions.close("u", second.id));
+        assert!(shard.versions.history.is_empty());
+        assert!(!shard.versions.close("u", second.id), "already closed");
+    }
+
+    #[test]
+    fn versions_a_newer_snapshot_reads_stay_when_an_older_one_closes() {
+        let mut shard = Shard::new();
+        shard.set("a", 1);
+        shard.set("b", 1);
+        let first = shard.open();
+        shard.set("a", 2);
+        let second = shard.open();
+        shard.set("b", 2);
+
+        assert!(shard.versions.close("u", first.id));
+        assert_eq!(shard.kept("a"), 0, "only the first snapshot read the old a");
+        assert_eq!(shard.kept("b"), 1);
+        assert_eq!(shard.scan(&second, false), entries(&[("a", 2), ("b", 1)]));
+    }
+
+    #[test]
+    fn snapshots_belong_to_their_user() {
+        let mut shard = Shard::new();
+        let snapshot = shard.open();
+        assert!(shard.versions.at("v", snapshot.id, 1000).is_err());
+        assert!(!shard.versions.close("v", snapshot.id));
+        assert!(shard.versions.at("u", snapshot.id, 1000).is_ok());
+    }
+
+    #[test]
+    fn idle_snapshots_are_closed() {
+        let mut shard = Shard::new();
+        shard.set("a", 1);
+        let idle = shard.open();
+        let used = shard.open();
+        shard.set("a", 2);
+        shard.versions.at("u", used.id, 5000).unwrap();
+        assert_eq!(shard.versions.expiry_horizon(9000), 1000);
+
+        shard.versions.close_idle(9000, 5000);
+        assert!(shard.versions.at("u", idle.id, 9000).is_err());
+        assert_eq!(shard.get("a", &used), Some(Value::Int(1)), "the version is still needed");
+
+        shard.versions.close_idle(20_000, 5000);
+        assert!(shard.versions.history.is_empty());
+        assert_eq!(shard.versions.expiry_horizon(20_000), 20_000);
+    }
+}
@@ -1,14 +1,20 @@
 use anyhow::Result;
-use quinn::{RecvStream, SendStream};
+use quinn::{Connection, RecvStream, SendStream};
 use crate::router::{ActorChannels, route_cmd};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, AsyncBufReadExt, BufReader};
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 use crate::command::Command;
+use crate::actors::pubsub_actor::Subscribed;
+use serde::Serialize;
 use crate::wire_cmd::{WireCommand, WireResponseReceiver};
 
+/// Handles one bidirectional stream of `connection`: reads JSON commands line by line and
+/// answers each with one JSON line. The streams of watches and subscriptions are opened on
+/// `connection`.
 pub async fn handle_connection(
 	mut send: SendStream,
 	mut recv: RecvStream,
+	connection: Connection,
 	system: ActorChannels,
 ) -> Result<()> {
 
@@ -36,6 +42,18 @@ pub async fn handle_connection(
         };
         //eprintln!("PARSED WIRED COMMAND: {:?}", wire_cmd);
 
+        if let Err(e) = wire_cmd.authorize(system.admin_token.as_deref()) {
+            // refused in the error type the command answers with
+            let response_json = match wire_cmd {
+                WireCommand::Snapshot { .. } | WireCommand::ClearWal { .. } => serde_json::to_string(&Err::<(), _>(e.to_string()))?,
+                _ => serde_json::to_string(&Err::<(), _>(e))?,
+            };
+            send.write_all(response_json.as_bytes()).await?;
+            send.write_all(b"\n").await?;
+            send.flush().await?;
+            continue;
+        }
+
         let (cmd, wire_response_recv) = wire_cmd.clone().into_internal();
 
 
@@ -55,14 +73,107 @@ pub async fn handle_connection(
                 let res = rx.await?;
                 serde_json::to_string(&res)?
             }
-            WireResponseReceiver::ResultOptUsize(rx) => {
+            WireResponseReceiver::ResultOptValue(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultOptVersioned(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultOptVersionedVec(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultU64Vec(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultOptValueVec(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultPage(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultMemoryStats(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultU64(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultOptU64(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultBool(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::ResultStringVec(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::StoreResultU64(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::StoreResultI64(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::StoreResultUnit(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::StoreResultOptValue(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::StoreResultUserInfo(rx) => {
+                let res = rx.await?;
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::StoreResultReadSnapshot(rx) => {
                 let res = rx.await?;
                 serde_json::to_string(&res)?
             }
-            WireResponseReceiver::ResultKvVec(rx) => {
+            WireResponseReceiver::StoreResultPairs(rx) => {
                 let res = rx.await?;
                 serde_json::to_string(&res)?
             }
+            WireResponseReceiver::Watch(rx, events) => {
+                let res = rx.await?;
+                if let Ok(watch_id) = res {
+                    let stream = connection.open_uni().await?;
+                    let header = serde_json::json!({ "watch_id": watch_id });
+                    tokio::spawn(stream_lines(stream, header, events));
+                }
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::Cdc(rx, events) => {
+                let res = rx.await?;
+                if res.is_ok() {
+                    let stream = connection.open_uni().await?;
+                    tokio::spawn(stream_lines(stream, serde_json::json!({ "cdc": true }), events));
+                }
+                serde_json::to_string(&res)?
+            }
+            WireResponseReceiver::Subscribe(rx) => {
+                let res = match rx.await? {
+                    Ok(Subscribed { id, messages }) => {
+                        let stream = connection.open_uni().await?;
+                        let header = serde_json::json!({ "subscription_id": id });
+                        tokio::spawn(stream_lines(stream, header, messages));
+                        Ok(id)
+                    }
+                    Err(e) => Err(e),
+                };
+                serde_json::to_string(&res)?
+            }
         };
 
         send.write_all(response_json.as_bytes()).await?;
@@ -76,3 +187,26 @@ pub async fn handle_connection(
 	
 	Ok(())
 }
+
+/// Writes `header` and then every item received on `items` to `stream`, one JSON line each,
+/// and finishes the stream once the sending actor drops its end (e.g. on `Unwatch`).
+///
+/// If the client goes away, `items` is dropped, which the actor notices on its next send.
+async fn stream_lines<T: Serialize>(mut stream: SendStream, header: serde_json::Value, mut items: mpsc::Receiver<T>) {
+    if stream.write_all(format!("{header}\n").as_bytes()).await.is_err() {
+        return;
+    }
+    while let Some(item) = items.recv().await {
+        let line = match serde_json::to_string(&item) {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("Failed to encode a streamed item ({header}): {e}");
+                continue;
+            }
+        };
+        if stream.write_all(format!("{line}\n").as_bytes()).await.is_err() {
+            return;
+        }
+    }
+    let _ = stream.finish();
+}
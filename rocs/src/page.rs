@@ -0,0 +1,152 @@
+//! src/page.rs
+//!
+//! Paging for `List` and `Range`.
+//!
+//! A scan returns at most one page of entries. When there are more, the page carries a cursor,
+//! and passing that cursor back with the same request continues right after the last entry of
+//! the page. Cursors are opaque to clients: they encode the last key returned, so a cursor stays
+//! valid across writes, and entries written behind it are simply not revisited.
+
+use crate::value::Value;
+use serde::{Deserialize, Serialize};
+use std::ops::Bound;
+
+/// One page of a `List` or `Range`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Page {
+    pub items: Vec<((String, String), Value)>,
+    /// Continues the scan after this page; `None` on the last page.
+    pub cursor: Option<String>,
+}
+
+/// The cursor continuing a scan after `key`.
+pub fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The key a cursor continues after.
+pub fn decode_cursor(cursor: &str) -> Result<String, String> {
+    let invalid = || format!("invalid cursor {cursor:?}");
+    if cursor.len() % 2 != 0 || !cursor.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Narrows `range` to the keys that come after `cursor` in scan order.
+///
+/// A cursor only ever narrows the range, so a forged cursor cannot reach keys outside it.
+pub fn resume(
+    (start, end): (Bound<String>, Bound<String>),
+    reverse: bool,
+    cursor: Option<&str>,
+) -> Result<(Bound<String>, Bound<String>), String> {
+    let Some(cursor) = cursor else { return Ok((start, end)) };
+    let after = decode_cursor(cursor)?;
+    if reverse {
+        let narrows = match &end {
+            Bound::Included(e) | Bound::Excluded(e) => after <= *e,
+            Bound::Unbounded => true,
+        };
+        Ok((start, if narrows { Bound::Excluded(after) } else { end }))
+    } else {
+        let narrows = match &start {
+            Bound::Included(s) | Bound::Excluded(s) => after >= *s,
+            Bound::Unbounded => true,
+        };
+        Ok((if narrows { Bound::Excluded(after) } else { start }, end))
+    }
+}
+
+/// Whether `key` lies in `range`.
+pub fn contains((start, end): &(Bound<String>, Bound<String>), key: &str) -> bool {
+    let above_start = match start {
+        Bound::Included(s) => key >= s.as_str(),
+        Bound::Excluded(s) => key > s.as_str(),
+        Bound::Unbounded => true,
+    };
+    let below_end = match end {
+        Bound::Included(e) => key <= e.as_str(),
+        Bound::Excluded(e) => key < e.as_str(),
+        Bound::Unbounded => true,
+    };
+    above_start && below_end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<String> {
+        (0..10).map(|i| format!("k{i}")).collect()
+    }
+
+    /// Pages through `keys` the way a store does, `size` entries at a time.
+    fn pages(range: (Bound<String>, Bound<String>), reverse: bool, size: usize) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let narrowed = resume(range.clone(), reverse, cursor.as_deref()).unwrap();
+            let mut matching: Vec<String> = keys().into_iter().filter(|k| contains(&narrowed, k)).collect();
+            if reverse {
+                matching.reverse();
+            }
+            let page: Vec<String> = matching.iter().take(size).cloned().collect();
+            cursor = (matching.len() > size).then(|| encode_cursor(page.last().unwrap()));
+            pages.push(page);
+            if cursor.is_none() {
+                return pages;
+            }
+        }
+    }
+
+    fn names(pages: &[Vec<String>]) -> Vec<Vec<&str>> {
+        pages.iter().map(|p| p.iter().map(String::as_str).collect()).collect()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for key in ["", "k1", "ключ", "a b/c"] {
+            assert_eq!(decode_cursor(&encode_cursor(key)).unwrap(), key);
+        }
+        assert!(decode_cursor("abc").is_err());
+        assert!(decode_cursor("zz").is_err());
+        assert!(decode_cursor("ff").is_err(), "not UTF-8");
+    }
+
+    #[test]
+    fn forward_pages_cover_an_exclusive_range_once() {
+        let range = (Bound::Excluded("k1".to_string()), Bound::Excluded("k8".to_string()));
+        assert_eq!(
+            names(&pages(range, false, 2)),
+            vec![vec!["k2", "k3"], vec!["k4", "k5"], vec!["k6", "k7"]]
+        );
+    }
+
+    #[test]
+    fn reverse_pages_cover_an_exclusive_range_once() {
+        let range = (Bound::Excluded("k1".to_string()), Bound::Included("k8".to_string()));
+        assert_eq!(
+            names(&pages(range, true, 3)),
+            vec![vec!["k8", "k7", "k6"], vec!["k5", "k4", "k3"], vec!["k2"]]
+        );
+        let range = (Bound::Unbounded, Bound::Excluded("k3".to_string()));
+        assert_eq!(names(&pages(range, true, 2)), vec![vec!["k2", "k1"], vec!["k0"]]);
+    }
+
+    #[test]
+    fn a_cursor_never_widens_the_range() {
+        let range = (Bound::Included("k3".to_string()), Bound::Excluded("k6".to_string()));
+        // forged to point before the start, or past the end when reversed
+        let before = encode_cursor("k0");
+        assert_eq!(resume(range.clone(), false, Some(&before)).unwrap(), range);
+        let after = encode_cursor("k9");
+        assert_eq!(resume(range.clone(), true, Some(&after)).unwrap(), range);
+        assert!(resume(range, false, Some("not hex")).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! src/quota.rs
+//!
+//! Per-user storage quotas: how many keys a user may hold, how many bytes they may take up and
+//! how long a key may be.
+//!
+//! Every user gets the default quota from `RocsConfig::default_quota` unless an admin has set one
+//! for them with `SetQuota`. Quotas cover every keyspace of the user together, and are checked by
+//! the store actor before a write is logged, using the usage counters the storage engine keeps up
+//! to date with every write (`storage::UsageCounters`, so expired keys stop counting once they
+//! are purged). Only writes that add to what a user holds
+//! can go over a quota; deletes and shrinking writes always go through, even for users who are
+//! over a quota that was lowered.
+
+use crate::error::StoreError;
+use serde::{Deserialize, Serialize};
+
+/// Limits on what one user may store. `0` means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Live keys, across all keyspaces.
+    pub max_keys: u64,
+    /// Estimated bytes of keys and values, as counted for `Stats`.
+    pub max_bytes: u64,
+    /// Bytes in one key.
+    pub max_key_len: u64,
+}
+
+/// The limit of a `Quota` that a write would exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaKind {
+    Keys,
+    Bytes,
+    KeyLength,
+}
+
+impl Quota {
+    /// Checks a user going from `before` to `after` (keys, bytes), writing a key of
+    /// `longest_key` bytes at most. Only limits the user would grow past are enforced.
+    pub fn check(&self, before: (u64, u64), after: (u64, u64), longest_key: usize) -> Result<(), StoreError> {
+        let exceeds = |limit: u64, before: u64, after: u64| limit > 0 && after > before && after > limit;
+        if self.max_key_len > 0 && longest_key as u64 > self.max_key_len {
+            return Err(StoreError::QuotaExceeded { kind: QuotaKind::KeyLength, limit: self.max_key_len });
+        }
+        if exceeds(self.max_keys, before.0, after.0) {
+            return Err(StoreError::QuotaExceeded { kind: QuotaKind::Keys, limit: self.max_keys });
+        }
+        if exceeds(self.max_bytes, before.1, after.1) {
+            return Err(StoreError::QuotaExceeded { kind: QuotaKind::Bytes, limit: self.max_bytes });
+        }
+        Ok(())
+    }
+}
+
+/// What `Info` reports to a user about themselves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub user_id: String,
+    /// Named keyspaces, besides the default one.
+    pub keyspaces: u64,
+    /// Live keys, across all keyspaces.
+    pub keys: u64,
+    pub bytes: u64,
+    pub quota: Quota,
+    /// Whether `quota` was set by an admin rather than being the default.
+    pub custom_quota: bool,
+}
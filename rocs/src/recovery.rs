@@ -0,0 +1,571 @@
+//! src/recovery.rs
+//!
+//! Point-in-time recovery: rebuilding the store as it was at an earlier sequence number or time.
+//!
+//! Recovery runs at startup, before any actor, when `ROCS_RECOVER_TO` is set. For every shard it
+//! loads the newest snapshot from before the target and replays the WAL on top of it, up to and
+//! including record `seq:N`, or up to the last record written before a time. What happens then
+//! depends on `ROCS_RECOVER_MODE`:
+//!
+//! - `dry-run` (the default): report what would be restored, compared to the current state,
+//!   and exit without touching anything.
+//! - `write`: write the recovered state as a new store file under `ROCS_RECOVER_OUTPUT` and
+//!   exit. The file has the format of `store_state.bin` and of snapshots.
+//! - `serve`: replace the store with the recovered state and start serving it. The WAL and
+//!   snapshots of the discarded history are moved aside to `<dir>.before-recovery-<millis>`
+//!   next to where they were, so nothing is lost. The recovered state takes the place of the
+//!   latest one under the latest sequence number, and numbering goes on from there; sequence
+//!   numbers are never reused, but CDC consumers and anything else that follows the WAL have to
+//!   start over.
+//!
+//! With several shards a sequence number does not name one point in time (see `crate::cdc`),
+//! so only times are accepted as targets.
+
+use crate::actors::logger_actor::{self, now_millis, WalRecord};
+use crate::actors::snapshot_actor::{self, SnapshotMeta};
+use crate::actors::store_actor::{self, StoreState};
+use crate::config::RocsConfig;
+use crate::shard::{ShardDirs, ShardId};
+use crate::storage::{self, memory::MemoryEngine, Key, StorageEngine};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Keys listed per category in a dry-run report; the rest are only counted.
+const REPORT_KEYS: usize = 20;
+
+/// The point to recover to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTarget {
+    /// Up to and including WAL record `N`. Written `seq:N`.
+    Seq(u64),
+    /// Up to the last WAL record written before this time, in milliseconds since the unix
+    /// epoch. Written `ts:MILLIS`, or as an RFC 3339 time such as `2026-10-18T14:03:00Z`.
+    Time(u64),
+}
+
+impl RecoveryTarget {
+    fn includes(&self, record: &WalRecord) -> bool {
+        match *self {
+            RecoveryTarget::Seq(seq) => record.seq <= seq,
+            RecoveryTarget::Time(ts) => record.ts < ts,
+        }
+    }
+
+    /// Whether everything in the snapshot lies before the target.
+    fn covers(&self, meta: &SnapshotMeta) -> bool {
+        match *self {
+            RecoveryTarget::Seq(seq) => meta.wal_seq <= seq,
+            RecoveryTarget::Time(ts) => meta.created_at < ts,
+        }
+    }
+}
+
+impl FromStr for RecoveryTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(seq) = s.strip_prefix("seq:") {
+            return seq.parse().map(RecoveryTarget::Seq).map_err(|e| format!("bad sequence number {seq:?}: {e}"));
+        }
+        if let Some(ts) = s.strip_prefix("ts:") {
+            return ts.parse().map(RecoveryTarget::Time).map_err(|e| format!("bad timestamp {ts:?}: {e}"));
+        }
+        parse_rfc3339(s).map(RecoveryTarget::Time).ok_or_else(|| {
+            format!("unknown recovery target {s:?} (expected \"seq:N\", \"ts:MILLIS\" or a time like \"2026-10-18T14:03:00Z\")")
+        })
+    }
+}
+
+impl fmt::Display for RecoveryTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RecoveryTarget::Seq(seq) => write!(f, "wal_seq {seq}"),
+            RecoveryTarget::Time(ts) => f.write_str(&format_millis(ts)),
+        }
+    }
+}
+
+/// What to do with the recovered state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Only report what would be restored.
+    DryRun,
+    /// Write the recovered state to a new store file.
+    Write,
+    /// Replace the store with the recovered state and start serving it.
+    Serve,
+}
+
+impl FromStr for RecoveryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dry-run" => Ok(RecoveryMode::DryRun),
+            "write" => Ok(RecoveryMode::Write),
+            "serve" => Ok(RecoveryMode::Serve),
+            other => Err(format!("unknown recovery mode {other:?} (expected \"dry-run\", \"write\" or \"serve\")")),
+        }
+    }
+}
+
+/// The state of one shard at the target.
+struct Recovered {
+    /// The snapshot the state was built from; `None` if it was built from the WAL alone.
+    snapshot: Option<SnapshotMeta>,
+    /// How many WAL records were replayed on top of the snapshot.
+    replayed: usize,
+    /// When the last record in the state was written, if any was replayed.
+    ts: Option<u64>,
+    state: StoreState,
+    /// The records after the target that are still in the WAL.
+    discarded: Vec<WalRecord>,
+    /// The last sequence number the shard logged.
+    latest_seq: u64,
+}
+
+/// Runs the recovery `config` asks for, if any.
+///
+/// Returns if there is nothing to recover or the recovered state is to be served; exits the
+/// process otherwise, and on every error.
+pub fn run(config: &RocsConfig) {
+    let Some(target) = config.recover_to else { return };
+    if matches!(target, RecoveryTarget::Seq(_)) && config.shards > 1 {
+        refuse("with several shards, recover to a time instead of a sequence number");
+    }
+
+    let shards: Vec<(ShardId, ShardDirs)> = (0..config.shards)
+        .map(|index| {
+            let shard = ShardId { index, count: config.shards };
+            (shard, shard.dirs())
+        })
+        .collect();
+    // every shard must be recoverable before any of them is touched
+    let recovered: Vec<Recovered> = shards
+        .iter()
+        .map(|(shard, dirs)| {
+            recover_shard(dirs, target).unwrap_or_else(|e| refuse(&format!("shard {}: {e}", shard.index)))
+        })
+        .collect();
+
+    println!("Recovering to {target} ({})", match config.recover_mode {
+        RecoveryMode::DryRun => "dry run",
+        RecoveryMode::Write => "write",
+        RecoveryMode::Serve => "serve",
+    });
+    for ((shard, dirs), recovered) in shards.iter().zip(recovered) {
+        let res = match config.recover_mode {
+            RecoveryMode::DryRun => report(shard, recovered),
+            RecoveryMode::Write => write(shard, recovered, &config.recover_output),
+            RecoveryMode::Serve => install(config, dirs, recovered),
+        };
+        if let Err(e) = res {
+            refuse(&format!("shard {}: {e}", shard.index));
+        }
+    }
+
+    if config.recover_mode != RecoveryMode::Serve {
+        std::process::exit(0);
+    }
+    println!("Recovery done; unset ROCS_RECOVER_TO before the next restart");
+}
+
+/// Builds the state of the shard in `dirs` at `target` from its snapshots and WAL.
+fn recover_shard(dirs: &ShardDirs, target: RecoveryTarget) -> Result<Recovered, String> {
+    let snapshot = snapshot_actor::load_manifest(&dirs.snaps)
+        .into_iter()
+        .rev()
+        .filter(|meta| target.covers(meta))
+        .find_map(|meta| match snapshot_actor::load_snapshot(&dirs.snaps, &meta) {
+            Ok(state) => Some((meta, state)),
+            Err(e) => {
+                eprintln!("Skipping snapshot {}: {e}", meta.seq);
+                None
+            }
+        });
+    let (snapshot, state) = match snapshot {
+        Some((meta, state)) => (Some(meta), state),
+        None => (None, StoreState::default()),
+    };
+    let base = state.wal_seq;
+    let latest_seq = (logger_actor::next_seq(&dirs.wal) - 1).max(base);
+
+    let mut records = logger_actor::read_wal(&dirs.wal, base).into_iter().peekable();
+    let first = records.peek().map_or(latest_seq + 1, |r| r.seq);
+    // which side of a time the missing records fall on is unknown
+    let needs_missing = match target {
+        RecoveryTarget::Seq(seq) => seq > base,
+        RecoveryTarget::Time(_) => true,
+    };
+    if first > base + 1 && needs_missing {
+        return Err(match &snapshot {
+            Some(meta) => format!(
+                "the WAL after snapshot {} (wal_seq {base}) starts at record {first}; the records in between are missing",
+                meta.seq
+            ),
+            None => format!("there is no snapshot from before {target} and the WAL starts at record {first}, not 1"),
+        });
+    }
+
+    let mut engine = MemoryEngine::detached();
+    engine.restore(state).map_err(|e| e.to_string())?;
+    let mut replayed = 0;
+    let mut ts = None;
+    while let Some(record) = records.next_if(|r| target.includes(r)) {
+        let seq = record.seq;
+        ts = Some(record.ts);
+        store_actor::apply(&mut engine, seq, record.op).map_err(|e| format!("failed to replay WAL record {seq}: {e}"))?;
+        replayed += 1;
+    }
+    if let RecoveryTarget::Seq(seq) = target {
+        if engine.wal_seq() < seq {
+            return Err(format!("the WAL ends at record {}, before {target}", engine.wal_seq()));
+        }
+    }
+
+    Ok(Recovered {
+        snapshot,
+        replayed,
+        ts,
+        state: engine.export().map_err(|e| e.to_string())?,
+        discarded: records.collect(),
+        latest_seq,
+    })
+}
+
+/// Prints what recovering the shard would restore, compared to its current state.
+fn report(shard: &ShardId, recovered: Recovered) -> Result<(), String> {
+    let Recovered { snapshot, replayed, ts, state, discarded, latest_seq } = recovered;
+    let source = match &snapshot {
+        Some(meta) => format!("snapshot {} (wal_seq {}, taken {})", meta.seq, meta.wal_seq, format_millis(meta.created_at)),
+        None => "an empty store".to_string(),
+    };
+    let written = ts.map(|ts| format!(", last written {}", format_millis(ts))).unwrap_or_default();
+    println!(
+        "Shard {}: {source} + {replayed} WAL record(s) -> wal_seq {}{written}",
+        shard.index, state.wal_seq
+    );
+    println!(
+        "  {} user(s), {} key(s); {} later record(s) would be discarded (the WAL is at {latest_seq})",
+        state.users.len(),
+        state.kv.len(),
+        discarded.len()
+    );
+
+    // the current state is the recovered one with the discarded records on top
+    let mut current = MemoryEngine::detached();
+    current.restore(state.clone()).map_err(|e| e.to_string())?;
+    for record in discarded {
+        let seq = record.seq;
+        store_actor::apply(&mut current, seq, record.op).map_err(|e| format!("failed to replay WAL record {seq}: {e}"))?;
+    }
+    let current = current.export().map_err(|e| e.to_string())?;
+
+    let mut back = Vec::new();
+    let mut changed = Vec::new();
+    for (key, entry) in &state.kv {
+        match current.kv.get(key) {
+            None => back.push(key),
+            Some(now) if now.value != entry.value || now.expires_at != entry.expires_at => changed.push(key),
+            Some(_) => {}
+        }
+    }
+    let gone: Vec<&Key> = current.kv.keys().filter(|key| !state.kv.contains_key(*key)).collect();
+    print_keys("come back", '+', &back);
+    print_keys("get their old value back", '~', &changed);
+    print_keys("go away", '-', &gone);
+    Ok(())
+}
+
+fn print_keys(what: &str, mark: char, keys: &[&Key]) {
+    if keys.is_empty() {
+        return;
+    }
+    println!("  {} key(s) would {what}:", keys.len());
+    for (owner, key) in keys.iter().take(REPORT_KEYS) {
+        println!("    {mark} {owner} {key}");
+    }
+    if keys.len() > REPORT_KEYS {
+        println!("    ... and {} more", keys.len() - REPORT_KEYS);
+    }
+}
+
+/// Writes the recovered state of the shard to a new store file under `dir`.
+fn write(shard: &ShardId, recovered: Recovered, dir: &Path) -> Result<(), String> {
+    let dir = match shard.count {
+        1 => dir.to_path_buf(),
+        _ => dir.join(format!("shard-{}", shard.index)),
+    };
+    let path = dir.join("store_state.bin");
+    let bytes = store_actor::encode_state(&recovered.state).map_err(|e| e.to_string())?;
+    fs::create_dir_all(&dir).map_err(|e| format!("failed to create {}: {e}", dir.display()))?;
+    let mut file = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| format!("failed to create {}: {e}", path.display()))?;
+    file.write_all(&bytes)
+        .and_then(|()| file.sync_all())
+        .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    println!("Shard {}: wrote the state at wal_seq {} to {}", shard.index, recovered.state.wal_seq, path.display());
+    Ok(())
+}
+
+/// Makes the recovered state the current state of the shard in `dirs`.
+///
+/// The store goes first: until the WAL is replaced, a crash leaves a store that is already at
+/// the latest sequence number, so nothing from the WAL is replayed over it.
+fn install(config: &RocsConfig, dirs: &ShardDirs, recovered: Recovered) -> Result<(), String> {
+    let mut state = recovered.state;
+    let recovered_seq = state.wal_seq;
+    state.wal_seq = recovered.latest_seq;
+
+    let kind = config.storage_engine;
+    let mut engine = match storage::open_engine(config, &dirs.store) {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("Failed to open the {kind} store ({e}); moving it aside");
+            storage::quarantine(kind, &dirs.store).map_err(|e| format!("failed to move the store aside: {e}"))?;
+            storage::open_engine(config, &dirs.store).map_err(|e| format!("failed to open a fresh {kind} store: {e}"))?
+        }
+    };
+    engine.restore(state).map_err(|e| format!("failed to restore the store: {e}"))?;
+    engine.flush()?;
+
+    let stamp = now_millis();
+    set_aside(&dirs.snaps, stamp)?;
+    // the new WAL is ready before the old one goes, so it always continues the numbering
+    let fresh = sibling(&dirs.wal, "recovering");
+    let _ = fs::remove_dir_all(&fresh);
+    logger_actor::create_empty(&fresh, recovered.latest_seq + 1)
+        .map_err(|e| format!("failed to start a new WAL: {e}"))?;
+    set_aside(&dirs.wal, stamp)?;
+    fs::rename(&fresh, &dirs.wal).map_err(|e| format!("failed to move the new WAL in place: {e}"))?;
+
+    println!(
+        "Restored the state at wal_seq {recovered_seq} as wal_seq {}; the old WAL and snapshots were moved to {} and {}",
+        recovered.latest_seq,
+        sibling(&dirs.wal, &format!("before-recovery-{stamp}")).display(),
+        sibling(&dirs.snaps, &format!("before-recovery-{stamp}")).display()
+    );
+    Ok(())
+}
+
+/// Moves `dir`, if it exists, to `<dir>.before-recovery-<stamp>`.
+fn set_aside(dir: &Path, stamp: u64) -> Result<(), String> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let dest = sibling(dir, &format!("before-recovery-{stamp}"));
+    fs::rename(dir, &dest).map_err(|e| format!("failed to move {} aside: {e}", dir.display()))
+}
+
+/// `dir` with `.<suffix>` appended to its name.
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{suffix}"));
+    dir.with_file_name(name)
+}
+
+fn refuse(reason: &str) -> ! {
+    eprintln!("Refusing to recover: {reason}");
+    std::process::exit(1);
+}
+
+/// Parses an RFC 3339 time such as `2026-10-18T14:03:00Z` or `2026-10-18 14:03:00.5+02:00` into
+/// milliseconds since the unix epoch. Seconds may be left out.
+fn parse_rfc3339(s: &str) -> Option<u64> {
+    let (date, rest) = s.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: u32 = date.next()?.parse().ok()?;
+    let day: u32 = date.next()?.parse().ok()?;
+
+    let (time, offset_secs) = match rest.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let (time, zone) = rest.split_at(rest.rfind(['+', '-'])?);
+            let (hours, minutes) = zone[1..].split_once(':')?;
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            (time, if zone.starts_with('-') { -offset } else { offset })
+        }
+    };
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut hms = hms.split(':');
+    let hour: i64 = hms.next()?.parse().ok()?;
+    let minute: i64 = hms.next()?.parse().ok()?;
+    let second: i64 = hms.next().map_or(Some(0), |s| s.parse().ok())?;
+    if hms.next().is_some() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let millis: i64 = format!("{fraction:0<3}")[..3].parse().ok()?;
+
+    let secs = days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second - offset_secs;
+    u64::try_from(secs * 1000 + millis).ok()
+}
+
+/// `ms` milliseconds since the unix epoch as an RFC 3339 time in UTC.
+fn format_millis(ms: u64) -> String {
+    let secs = (ms / 1000) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        ms % 1000
+    )
+}
+
+// Conversions between days since the unix epoch and proleptic Gregorian dates, after
+// http://howardhinnant.github.io/date_algorithms.html
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actors::logger_actor::WalOp;
+    use crate::value::Value;
+
+    fn put(seq: u64, ts: u64, key: &str) -> WalRecord {
+        let op = WalOp::Put { user_id: "u".into(), key: key.into(), value: Value::Int(seq as i64), expires_at: None };
+        WalRecord { seq, ts, op }
+    }
+
+    /// A shard whose WAL holds `records` and that has no snapshots.
+    fn shard(records: &[WalRecord]) -> (tempfile::TempDir, ShardDirs) {
+        let dir = tempfile::tempdir().unwrap();
+        let dirs = ShardDirs {
+            wal: dir.path().join("wal"),
+            snaps: dir.path().join("snaps"),
+            store: dir.path().join("store"),
+        };
+        logger_actor::create_empty(&dirs.wal, 1).unwrap();
+        let segment = fs::read_dir(&dirs.wal).unwrap().next().unwrap().unwrap().path();
+        let mut file = File::options().append(true).open(segment).unwrap();
+        for record in records {
+            writeln!(file, "{}", serde_json::to_string(record).unwrap()).unwrap();
+        }
+        (dir, dirs)
+    }
+
+    fn keys(recovered: &Recovered) -> Vec<&str> {
+        recovered.state.kv.keys().map(|(_, key)| key.as_str()).collect()
+    }
+
+    #[test]
+    fn rfc3339_times_are_parsed() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2026-10-18T14:03:00Z"), Some(1_792_332_180_000));
+        assert_eq!(parse_rfc3339("2026-10-18t14:03z"), Some(1_792_332_180_000), "seconds left out");
+        assert_eq!(parse_rfc3339("2026-10-18 16:03:00.5+02:00"), Some(1_792_332_180_500));
+        assert_eq!(parse_rfc3339("2026-10-18T13:33:00.123456-00:30"), Some(1_792_332_180_123));
+        assert_eq!(parse_rfc3339("2024-02-29T00:00:00Z"), Some(1_709_164_800_000), "leap day");
+        for bad in [
+            "2026-10-18",
+            "2026-10-18T14:03:00",
+            "2026-13-01T00:00Z",
+            "2026-10-18T24:00Z",
+            "2026-10-18T14:03:00.5xZ",
+            "2026-10-18T14:03:00:00Z",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert_eq!(parse_rfc3339(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn formatted_times_parse_back() {
+        for ms in [0, 1_709_164_800_000, 1_792_332_180_123, 4_102_444_799_999] {
+            assert_eq!(parse_rfc3339(&format_millis(ms)), Some(ms), "{}", format_millis(ms));
+        }
+        assert_eq!(format_millis(1_792_332_180_500), "2026-10-18T14:03:00.500Z");
+    }
+
+    #[test]
+    fn targets_are_parsed() {
+        assert_eq!("seq:42".parse(), Ok(RecoveryTarget::Seq(42)));
+        assert_eq!("ts:1792332180000".parse(), Ok(RecoveryTarget::Time(1_792_332_180_000)));
+        assert_eq!("2026-10-18T14:03:00Z".parse(), Ok(RecoveryTarget::Time(1_792_332_180_000)));
+        for bad in ["seq:", "seq:-1", "ts:soon", "42", "yesterday"] {
+            assert!(bad.parse::<RecoveryTarget>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn a_sequence_number_includes_its_own_record() {
+        let target = RecoveryTarget::Seq(2);
+        assert!(target.includes(&put(2, 0, "a")));
+        assert!(!target.includes(&put(3, 0, "a")));
+        let meta = |wal_seq| SnapshotMeta { seq: 1, wal_seq, created_at: 0, file: String::new(), size: 0, checksum: 0 };
+        assert!(target.covers(&meta(2)));
+        assert!(!target.covers(&meta(3)));
+    }
+
+    #[test]
+    fn a_time_excludes_records_written_at_it() {
+        let target = RecoveryTarget::Time(2000);
+        assert!(target.includes(&put(1, 1999, "a")));
+        assert!(!target.includes(&put(2, 2000, "a")));
+        let meta = |created_at| SnapshotMeta { seq: 1, wal_seq: 1, created_at, file: String::new(), size: 0, checksum: 0 };
+        assert!(target.covers(&meta(1999)));
+        assert!(!target.covers(&meta(2000)));
+    }
+
+    #[test]
+    fn the_wal_is_replayed_up_to_the_target() {
+        let (_dir, dirs) = shard(&[put(1, 1000, "a"), put(2, 2000, "b"), put(3, 3000, "c")]);
+
+        let recovered = recover_shard(&dirs, RecoveryTarget::Seq(2)).unwrap();
+        assert_eq!(keys(&recovered), ["a", "b"]);
+        assert_eq!((recovered.state.wal_seq, recovered.replayed, recovered.ts), (2, 2, Some(2000)));
+        assert_eq!(recovered.discarded.iter().map(|r| r.seq).collect::<Vec<_>>(), [3]);
+        assert_eq!(recovered.latest_seq, 3);
+
+        let recovered = recover_shard(&dirs, RecoveryTarget::Time(3000)).unwrap();
+        assert_eq!(keys(&recovered), ["a", "b"]);
+        assert_eq!(recovered.ts, Some(2000));
+
+        let recovered = recover_shard(&dirs, RecoveryTarget::Time(500)).unwrap();
+        assert!(recovered.state.kv.is_empty());
+        assert_eq!((recovered.replayed, recovered.discarded.len()), (0, 3));
+    }
+
+    #[test]
+    fn targets_past_the_end_or_before_a_gap_are_refused() {
+        let (_dir, dirs) = shard(&[put(1, 1000, "a"), put(2, 2000, "b")]);
+        assert!(recover_shard(&dirs, RecoveryTarget::Seq(5)).is_err());
+
+        let (_dir, dirs) = shard(&[put(3, 3000, "c")]);
+        assert!(recover_shard(&dirs, RecoveryTarget::Seq(3)).is_err(), "records 1 and 2 are missing");
+        assert!(recover_shard(&dirs, RecoveryTarget::Time(4000)).is_err());
+    }
+}
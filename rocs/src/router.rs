@@ -7,8 +7,10 @@ use std::collections::HashMap;
 use crate::command::Command;
 use crate::actors::{
     user_actor::{UserCommandHandler, spawn_user_actor},
-    store_actor::StoreCommandHandler,
+    admin_actor::AdminCommandHandler,
+    pubsub_actor::PubSubCommandHandler,
 };
+use crate::shard::Shards;
 use std::sync::{Arc, Mutex};
 use uuid;
 
@@ -17,7 +19,12 @@ pub type ActorHandle = mpsc::Sender<Command>;
 #[derive(Clone)]
 pub struct ActorChannels {
     pub user_actors: Arc<Mutex<HashMap<String, UserCommandHandler>>>,
-    pub store_actor: StoreCommandHandler,
+    /// The store actor of every shard.
+    pub shards: Shards,
+    pub admin_actor: AdminCommandHandler,
+    pub pubsub_actor: PubSubCommandHandler,
+    /// What admin commands must carry to be accepted (`RocsConfig::admin_token`).
+    pub admin_token: Option<Arc<str>>,
 }
 
 pub async fn route_cmd(cmd: Command, actors: &ActorChannels) {
@@ -25,25 +32,72 @@ pub async fn route_cmd(cmd: Command, actors: &ActorChannels) {
     match &cmd {
         Command::Set { user_id, .. }
         | Command::Get { user_id, .. }
+        | Command::MGet { user_id, .. }
+        | Command::MSet { user_id, .. }
+        | Command::MDel { user_id, .. }
+        | Command::Cas { user_id, .. }
+        | Command::SetNx { user_id, .. }
+        | Command::DelIfVersion { user_id, .. }
         | Command::Del { user_id, .. }
         | Command::List { user_id, .. }
         | Command::Update { user_id, ..}
+        | Command::Upsert { user_id, .. }
+        | Command::IncrBy { user_id, .. }
+        | Command::DecrBy { user_id, .. }
         | Command::Range { user_id, .. }
+        | Command::Ttl { user_id, .. }
+        | Command::Expire { user_id, .. }
+        | Command::PersistKey { user_id, .. }
         | Command::Exit { user_id, .. }
+        | Command::Begin { user_id, .. }
+        | Command::Commit { user_id, .. }
+        | Command::Rollback { user_id, .. }
+        | Command::CreateKeyspace { user_id, .. }
+        | Command::ListKeyspaces { user_id, .. }
+        | Command::SelectKeyspace { user_id, .. }
+        | Command::DropKeyspace { user_id, .. }
+        | Command::CreateIndex { user_id, .. }
+        | Command::DropIndex { user_id, .. }
+        | Command::Query { user_id, .. }
+        | Command::Aggregate { user_id, .. }
+        | Command::Info { user_id, .. }
+        | Command::Watch { user_id, .. }
+        | Command::Unwatch { user_id, .. }
+        | Command::OpenReadSnapshot { user_id, .. }
+        | Command::CloseReadSnapshot { user_id, .. }
         | Command::Ping { user_id, ..} => {
             let user_actor = {
                 let mut users = actors.user_actors.lock().unwrap();
                 users.entry(user_id.clone())
-                    .or_insert_with(|| spawn_user_actor(actors.store_actor.clone()))
+                    .or_insert_with(|| spawn_user_actor(actors.shards.for_user(user_id).clone()))
                     .clone()
             };
             // 2. Await outside the lock!
             let _ = user_actor.send(cmd).await;
         }
         Command::Hi { user_id, respond_to } => {
-            let store_actor = actors.store_actor.clone();
+            // A known id goes to the shard holding its data; new users are spread over the shards.
+            let store_actor = match user_id {
+                Some(id) => actors.shards.for_user(id).clone(),
+                None => actors.shards.for_new_user().clone(),
+            };
             let _ = store_actor.send(cmd).await;
         }
+        Command::Publish { .. }
+        | Command::Subscribe { .. }
+        | Command::PSubscribe { .. }
+        | Command::Unsubscribe { .. } => {
+            let _ = actors.pubsub_actor.send(cmd).await;
+        }
+        Command::Shutdown { .. }
+        | Command::Crash { .. }
+        | Command::Snapshot { .. }
+        | Command::Stats { .. }
+        | Command::SetQuota { .. }
+        | Command::Cdc { .. }
+        | Command::ClearWal { .. } => {
+            let _ = actors.admin_actor.send(cmd).await;
+        }
         _ => {
             eprintln!("Route not yet implemented!");
         }
@@ -0,0 +1,148 @@
+//! src/shard.rs
+//!
+//! Partitioning of the keyspace across several store actors.
+//!
+//! Each shard is a complete storage stack of its own: a store actor with its engine, a logger
+//! actor with its WAL, and a snapshot actor. Users are assigned to shards by a CRC32 of their
+//! user id, so every key, transaction and batch of one user lives in exactly one shard and stays
+//! atomic, and `List`/`Range` never have to merge results across shards. Different users run on
+//! different shards in parallel.
+//!
+//! With a single shard (the default) the on-disk layout is the same as before sharding:
+//! `logs/wal/`, `snaps/` and `~/.roc_server/`. With `n > 1` shards, shard `i` uses
+//! `logs/shard-<i>/wal/`, `snaps/shard-<i>/` and `~/.roc_server/shard-<i>/`. The shard count
+//! is recorded in `logs/SHARDS`, and the server refuses to start with a different count, since
+//! that would send users to shards that do not hold their data.
+
+use crate::actors::store_actor::StoreCommandHandler;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// One shard out of `count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardId {
+    pub index: usize,
+    pub count: usize,
+}
+
+/// Where a shard keeps its files.
+#[derive(Debug, Clone)]
+pub struct ShardDirs {
+    /// WAL segments.
+    pub wal: PathBuf,
+    /// Snapshots and their manifest.
+    pub snaps: PathBuf,
+    /// Storage engine files.
+    pub store: PathBuf,
+}
+
+impl ShardId {
+    /// Whether `user_id` belongs to this shard.
+    pub fn owns(&self, user_id: &str) -> bool {
+        shard_of(user_id, self.count) == self.index
+    }
+
+    pub fn dirs(&self) -> ShardDirs {
+        let roc_dir = dirs::home_dir().expect("Could not find home directory").join(".roc_server");
+        if self.count == 1 {
+            return ShardDirs {
+                wal: PathBuf::from("logs").join("wal"),
+                snaps: PathBuf::from("snaps"),
+                store: roc_dir,
+            };
+        }
+        let name = format!("shard-{}", self.index);
+        ShardDirs {
+            wal: PathBuf::from("logs").join(&name).join("wal"),
+            snaps: PathBuf::from("snaps").join(&name),
+            store: roc_dir.join(&name),
+        }
+    }
+}
+
+/// The shard `user_id` belongs to, out of `count`.
+pub fn shard_of(user_id: &str, count: usize) -> usize {
+    crc32fast::hash(user_id.as_bytes()) as usize % count.max(1)
+}
+
+/// The store actors of every shard, indexed by shard.
+#[derive(Clone)]
+pub struct Shards {
+    stores: Arc<Vec<StoreCommandHandler>>,
+    /// Round-robin position for users that do not have an id yet.
+    next: Arc<AtomicUsize>,
+}
+
+impl Shards {
+    pub fn new(stores: Vec<StoreCommandHandler>) -> Self {
+        assert!(!stores.is_empty(), "at least one shard is required");
+        Self { stores: Arc::new(stores), next: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// The store actor holding the data of `user_id`.
+    pub fn for_user(&self, user_id: &str) -> &StoreCommandHandler {
+        &self.stores[shard_of(user_id, self.stores.len())]
+    }
+
+    /// The store actor that should hand out the next new user id, spreading users evenly.
+    pub fn for_new_user(&self) -> &StoreCommandHandler {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.stores.len();
+        &self.stores[i]
+    }
+
+    pub fn all(&self) -> &[StoreCommandHandler] {
+        &self.stores
+    }
+}
+
+/// Checks the shard count recorded in `logs/SHARDS` against `count`, recording it on first start
+/// if `record` is set. A recovery dry run passes `false`, as it must leave the disk untouched.
+///
+/// Data written before shard counts were recorded is single-shard data.
+pub fn check_layout(count: usize, record: bool) -> Result<(), String> {
+    let marker = PathBuf::from("logs").join("SHARDS");
+    let recorded = match fs::read_to_string(&marker) {
+        Ok(raw) => Some(
+            raw.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("{} is unreadable: {e}", marker.display()))?,
+        ),
+        Err(_) if has_single_shard_data() => Some(1),
+        Err(_) => None,
+    };
+
+    match recorded {
+        Some(recorded) if recorded != count => Err(format!(
+            "the data on disk was written with {recorded} shard(s) but ROCS_SHARDS is {count}; \
+             start with ROCS_SHARDS={recorded}"
+        )),
+        Some(_) if marker.exists() => Ok(()),
+        _ if !record => Ok(()),
+        _ => {
+            fs::create_dir_all("logs").map_err(|e| e.to_string())?;
+            fs::write(&marker, format!("{count}\n")).map_err(|e| format!("failed to write {}: {e}", marker.display()))
+        }
+    }
+}
+
+fn has_single_shard_data() -> bool {
+    let dirs = ShardId { index: 0, count: 1 }.dirs();
+    let non_empty = |dir: &PathBuf| fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some());
+    // an empty manifest (`[]`) is what a fresh checkout has, not data
+    let snapshots = fs::read(dirs.snaps.join("snapshots.json"))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<Vec<serde_json::Value>>(&bytes).ok())
+        .is_some_and(|manifest| !manifest.is_empty());
+    let snapshot_files = fs::read_dir(&dirs.snaps).is_ok_and(|entries| {
+        entries.flatten().any(|e| e.file_name().to_string_lossy().starts_with("snapshot-"))
+    });
+    non_empty(&dirs.wal)
+        || PathBuf::from("logs").join("wal.log").exists()
+        || snapshots
+        || snapshot_files
+        || dirs.store.join("store_state.bin").exists()
+        || non_empty(&dirs.store.join("deltas"))
+        || dirs.store.join("lsm").exists()
+}
@@ -0,0 +1,646 @@
+//! src/storage/bounded.rs
+//!
+//! Memory budgets and eviction, for running the store as a bounded cache.
+//!
+//! Before a write is logged, the store actor asks `make_room` whether it fits. If it would go
+//! over the shard's budget (`RocsConfig::max_memory_bytes`, split evenly across the shards) or
+//! over the budget of the user it writes to (`RocsConfig::max_memory_per_user_bytes`, shared by
+//! all keyspaces of the user), `make_room` picks the keys to evict according to
+//! `RocsConfig::eviction_policy`, or rejects the write with `StoreError::OutOfMemory` under
+//! `noeviction` or when not enough can be evicted. Evictions are logged as deletes in the same WAL
+//! record as the write, so replay ends up with the same keys without tracking anything.
+//!
+//! The size of an entry is an estimate, see `storage::entry_size`. The totals per user and for
+//! the whole shard are the engine's `UsageCounters`; `check_quotas` checks writes against the
+//! quotas of `crate::quota` with the same counters.
+//!
+//! To pick victims, `Bounded` also keeps the size of every entry and how recently and how often
+//! it was used, but only when there is a budget and the policy can evict. This is kept in memory
+//! only: it is built with a scan of the engine when the server starts and after a snapshot
+//! restore. Writes count as a use, and so do the reads of clients, which the store actor reports
+//! with `touch` for `Get`, `MGet` and the entries of a `Range` page. Reads through `get` do not
+//! count, so that conditional writes, watches and read snapshots can look at keys freely.
+
+use super::{entry_size, EntryIter, Key, KeyedEntryIter, StorageEngine, UsageCounters};
+use crate::actors::logger_actor::WalOp;
+use crate::actors::store_actor::{Entry, StoreState};
+use crate::config::{EvictionPolicy, RocsConfig};
+use crate::error::StoreError;
+use crate::keyspace;
+use crate::quota::Quota;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::ops::{Bound, Deref};
+
+/// Where a key stands in the eviction order: lower ranks are evicted first.
+type Rank = (u64, u64);
+
+/// Memory use and eviction counters of a shard, or of all shards summed up.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemoryStats {
+    /// Live keys, including ones that have expired but were not swept yet.
+    pub keys: u64,
+    pub used_memory_bytes: u64,
+    /// `0` means no limit.
+    pub max_memory_bytes: u64,
+    /// `0` means no limit.
+    pub max_memory_per_user_bytes: u64,
+    pub eviction_policy: String,
+    /// Keys evicted to make room for writes since the server started.
+    pub evicted_keys: u64,
+    /// Writes rejected for lack of memory since the server started.
+    pub rejected_writes: u64,
+}
+
+impl MemoryStats {
+    /// Adds the counters of another shard.
+    pub fn merge(&mut self, other: MemoryStats) {
+        self.max_memory_per_user_bytes = other.max_memory_per_user_bytes;
+        self.eviction_policy = other.eviction_policy;
+        self.keys += other.keys;
+        self.used_memory_bytes += other.used_memory_bytes;
+        self.max_memory_bytes += other.max_memory_bytes;
+        self.evicted_keys += other.evicted_keys;
+        self.rejected_writes += other.rejected_writes;
+    }
+}
+
+/// What is known about one entry.
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    size: u64,
+    last_access: u64,
+    hits: u64,
+    expires_at: Option<u64>,
+}
+
+struct Tracker {
+    policy: EvictionPolicy,
+    /// Whether to keep `by_user`.
+    per_user_order: bool,
+    keys: HashMap<Key, Usage>,
+    /// Every key the policy may evict, in eviction order. Empty under `noeviction`.
+    order: BTreeSet<(Rank, Key)>,
+    /// `order` split by user id, kept only with a per-user budget.
+    by_user: HashMap<String, BTreeSet<(Rank, Key)>>,
+    /// Counts accesses, standing in for a clock.
+    clock: u64,
+}
+
+impl Tracker {
+    fn new(policy: EvictionPolicy, per_user_order: bool) -> Self {
+        Self {
+            policy,
+            per_user_order,
+            keys: HashMap::new(),
+            order: BTreeSet::new(),
+            by_user: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Where `usage` stands in the eviction order of the policy, or `None` if it is never evicted.
+    fn rank(&self, usage: &Usage) -> Option<Rank> {
+        match self.policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => Some((usage.last_access, 0)),
+            EvictionPolicy::AllKeysLfu => Some((usage.hits, usage.last_access)),
+            EvictionPolicy::VolatileTtl => usage.expires_at.map(|at| (at, 0)),
+        }
+    }
+
+    /// Records a write of `entry` under `key`.
+    fn track(&mut self, key: Key, entry: &Entry) {
+        let hits = self.forget(&key).map_or(0, |u| u.hits);
+        self.clock += 1;
+        let usage = Usage {
+            size: entry_size(&key, entry),
+            last_access: self.clock,
+            hits: hits + 1,
+            expires_at: entry.expires_at,
+        };
+        self.insert(key, usage);
+    }
+
+    /// Records a read of `key`.
+    fn touch(&mut self, key: &Key) {
+        if let Some(mut usage) = self.forget(key) {
+            self.clock += 1;
+            usage.last_access = self.clock;
+            usage.hits += 1;
+            self.insert(key.clone(), usage);
+        }
+    }
+
+    fn insert(&mut self, key: Key, usage: Usage) {
+        let user = keyspace::user_of(&key.0);
+        if let Some(rank) = self.rank(&usage) {
+            if self.per_user_order {
+                self.by_user.entry(user.to_string()).or_default().insert((rank, key.clone()));
+            }
+            self.order.insert((rank, key.clone()));
+        }
+        self.keys.insert(key, usage);
+    }
+
+    fn forget(&mut self, key: &Key) -> Option<Usage> {
+        let usage = self.keys.remove(key)?;
+        let user = keyspace::user_of(&key.0);
+        if let Some(rank) = self.rank(&usage) {
+            let row = (rank, key.clone());
+            self.order.remove(&row);
+            if let Some(rows) = self.by_user.get_mut(user) {
+                rows.remove(&row);
+                if rows.is_empty() {
+                    self.by_user.remove(user);
+                }
+            }
+        }
+        Some(usage)
+    }
+}
+
+/// Picks keys from `order` until they add up to `need` bytes, skipping `protected` keys and
+/// the ones already in `victims`. Returns how many bytes the picked keys free.
+fn pick(
+    order: Option<&BTreeSet<(Rank, Key)>>,
+    need: u64,
+    tracker: Option<&Tracker>,
+    protected: &HashMap<Key, u64>,
+    victims: &mut Vec<Key>,
+    chosen: &mut HashSet<Key>,
+) -> u64 {
+    let mut freed = 0;
+    for (_, key) in order.into_iter().flatten() {
+        if freed >= need {
+            break;
+        }
+        if protected.contains_key(key) || !chosen.insert(key.clone()) {
+            continue;
+        }
+        freed += tracker.map_or(0, |t| t.keys[key].size);
+        victims.push(key.clone());
+    }
+    freed
+}
+
+/// A `StorageEngine` that accounts for the memory its entries use and picks keys to evict.
+pub struct Bounded<E> {
+    engine: E,
+    /// Budget of this shard in bytes, `0` for none.
+    max_bytes: u64,
+    /// Budget of each user in bytes, `0` for none.
+    max_user_bytes: u64,
+    /// The quota of users an admin has not set one for.
+    default_quota: Quota,
+    policy: EvictionPolicy,
+    /// Only kept when there is a budget and the policy can evict; behind a `RefCell` so that
+    /// reads can count as accesses.
+    tracker: Option<RefCell<Tracker>>,
+    evicted_keys: u64,
+    rejected_writes: u64,
+}
+
+impl<E: StorageEngine> Bounded<E> {
+    /// Wraps `engine` with the budgets in `config`, this shard being one of `shards`.
+    pub fn new(engine: E, config: &RocsConfig, shards: usize) -> io::Result<Self> {
+        let max_bytes = config.max_memory_bytes / shards.max(1) as u64;
+        let max_user_bytes = config.max_memory_per_user_bytes;
+        let evicts = (max_bytes > 0 || max_user_bytes > 0) && config.eviction_policy != EvictionPolicy::NoEviction;
+        let mut bounded = Self {
+            engine,
+            max_bytes,
+            max_user_bytes,
+            default_quota: config.default_quota,
+            policy: config.eviction_policy,
+            tracker: evicts.then(|| RefCell::new(Tracker::new(config.eviction_policy, max_user_bytes > 0))),
+            evicted_keys: 0,
+            rejected_writes: 0,
+        };
+        bounded.rebuild()?;
+        Ok(bounded)
+    }
+
+    fn rebuild(&mut self) -> io::Result<()> {
+        let Some(old) = &mut self.tracker else { return Ok(()) };
+        let mut tracker = Tracker::new(old.get_mut().policy, old.get_mut().per_user_order);
+        // every entry, including those of owners the engine has no user for
+        for record in self.engine.scan_all() {
+            let (key, entry) = record?;
+            tracker.track(key, &entry);
+        }
+        *old.get_mut() = tracker;
+        Ok(())
+    }
+
+    /// Counts a read of `key` by a client, for the eviction policy. Reads through `get` do not
+    /// count, so that commands can look at keys for their own purposes.
+    pub fn touch(&self, key: &Key) {
+        if let Some(tracker) = &self.tracker {
+            tracker.borrow_mut().touch(key);
+        }
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let total = self.engine.usage().total();
+        MemoryStats {
+            keys: total.keys,
+            used_memory_bytes: total.bytes,
+            max_memory_bytes: self.max_bytes,
+            max_memory_per_user_bytes: self.max_user_bytes,
+            eviction_policy: self.policy.to_string(),
+            evicted_keys: self.evicted_keys,
+            rejected_writes: self.rejected_writes,
+        }
+    }
+
+    /// The quota of `user_id`: the one an admin set, or the default one.
+    pub fn quota_of(&self, user_id: &str) -> Quota {
+        self.engine.quota(user_id).unwrap_or(self.default_quota)
+    }
+
+    /// The size of the entry under each key `op` writes, as it is now, `None` for no entry.
+    fn sizes_before(&self, after: &HashMap<Key, u64>) -> Result<HashMap<Key, Option<u64>>, StoreError> {
+        after
+            .keys()
+            .map(|key| {
+                let entry = self.engine.get(key).map_err(|e| format!("failed to read {key:?}: {e}"))?;
+                Ok((key.clone(), entry.map(|e| entry_size(key, &e))))
+            })
+            .collect()
+    }
+
+    /// Checks `op` against the quotas of the users it writes to.
+    pub fn check_quotas(&self, op: &WalOp) -> Result<(), StoreError> {
+        let mut after = HashMap::new();
+        sizes_after(op, &mut after);
+        let before = self.sizes_before(&after)?;
+
+        // (keys, bytes) growth and the longest key written, per user
+        let mut growth: HashMap<&str, (i64, i64, usize)> = HashMap::new();
+        for (key, size) in &after {
+            let old = before[key];
+            let user = growth.entry(keyspace::user_of(&key.0)).or_default();
+            user.0 += i64::from(*size > 0) - i64::from(old.is_some());
+            user.1 += *size as i64 - old.unwrap_or(0) as i64;
+            if *size > 0 {
+                user.2 = user.2.max(key.1.len());
+            }
+        }
+        for (user, (keys, bytes, longest_key)) in growth {
+            let usage = self.engine.usage().user(user);
+            let before = (usage.keys, usage.bytes);
+            let after = (before.0.saturating_add_signed(keys), before.1.saturating_add_signed(bytes));
+            self.quota_of(user).check(before, after, longest_key)?;
+        }
+        Ok(())
+    }
+
+    /// Counts `evicted` keys as evicted, once the write that evicts them has been applied.
+    pub fn count_evicted(&mut self, evicted: usize) {
+        self.evicted_keys += evicted as u64;
+    }
+
+    /// The keys to evict so that `op` fits in the budgets, none if it fits as it is.
+    ///
+    /// Keys written by `op` are never picked. Users over their own budget are evicted from
+    /// first, then the whole shard. Writes that do not grow the store always fit, so deletes
+    /// and overwrites with smaller values go through even over budget.
+    pub fn make_room(&mut self, op: &WalOp) -> Result<Vec<Key>, StoreError> {
+        if self.max_bytes == 0 && self.max_user_bytes == 0 {
+            return Ok(Vec::new());
+        }
+        let mut after = HashMap::new();
+        sizes_after(op, &mut after);
+        let before = self.sizes_before(&after)?;
+
+        let usage = self.engine.usage();
+        let tracker = self.tracker.as_mut().map(|t| &*t.get_mut());
+        let mut growth: i64 = 0;
+        let mut user_growth: HashMap<&str, i64> = HashMap::new();
+        for (key, size) in &after {
+            let old = before[key].unwrap_or(0);
+            let delta = *size as i64 - old as i64;
+            growth += delta;
+            *user_growth.entry(keyspace::user_of(&key.0)).or_default() += delta;
+        }
+
+        let mut victims = Vec::new();
+        let mut chosen = HashSet::new();
+        let mut freed = 0;
+        let mut exceeded = None;
+        if self.max_user_bytes > 0 {
+            for (user, growth) in user_growth.into_iter().filter(|(_, g)| *g > 0) {
+                let used = usage.user(user).bytes;
+                let need = (used + growth as u64).saturating_sub(self.max_user_bytes);
+                if need == 0 {
+                    continue;
+                }
+                let got = pick(tracker.and_then(|t| t.by_user.get(user)), need, tracker, &after, &mut victims, &mut chosen);
+                freed += got;
+                if got < need {
+                    exceeded = Some(self.max_user_bytes);
+                }
+            }
+        }
+        if exceeded.is_none() && self.max_bytes > 0 && growth > 0 {
+            let need = (usage.total().bytes + growth as u64).saturating_sub(self.max_bytes + freed);
+            if need > 0 && pick(tracker.map(|t| &t.order), need, tracker, &after, &mut victims, &mut chosen) < need {
+                exceeded = Some(self.max_bytes);
+            }
+        }
+
+        if let Some(limit) = exceeded {
+            self.rejected_writes += 1;
+            return Err(StoreError::OutOfMemory { limit });
+        }
+        Ok(victims)
+    }
+}
+
+/// The size each key written by `op` will have afterwards, `0` for deleted keys.
+fn sizes_after(op: &WalOp, after: &mut HashMap<Key, u64>) {
+    match op {
+        WalOp::Put { user_id, key, value, expires_at } => {
+            let entry = Entry { value: value.clone(), expires_at: *expires_at, version: 0 };
+            let key = (user_id.clone(), key.clone());
+            let size = entry_size(&key, &entry);
+            after.insert(key, size);
+        }
+        WalOp::Delete { user_id, key } => {
+            after.insert((user_id.clone(), key.clone()), 0);
+        }
+        WalOp::Batch(ops) => {
+            for op in ops {
+                sizes_after(op, after);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl<E> Deref for Bounded<E> {
+    type Target = E;
+
+    fn deref(&self) -> &E {
+        &self.engine
+    }
+}
+
+impl<E: StorageEngine> StorageEngine for Bounded<E> {
+    fn get(&self, key: &Key) -> io::Result<Option<Entry>> {
+        self.engine.get(key)
+    }
+
+    fn scan<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        self.engine.scan(user_id, range)
+    }
+
+    fn scan_rev<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        self.engine.scan_rev(user_id, range)
+    }
+
+    fn scan_all(&self) -> KeyedEntryIter<'_> {
+        self.engine.scan_all()
+    }
+
+    fn put(&mut self, key: Key, entry: Entry) -> io::Result<()> {
+        if let Some(tracker) = &mut self.tracker {
+            tracker.get_mut().track(key.clone(), &entry);
+        }
+        self.engine.put(key, entry)
+    }
+
+    fn delete(&mut self, key: &Key) -> io::Result<()> {
+        if let Some(tracker) = &mut self.tracker {
+            tracker.get_mut().forget(key);
+        }
+        self.engine.delete(key)
+    }
+
+    fn has_user(&self, user_id: &str) -> bool {
+        self.engine.has_user(user_id)
+    }
+
+    fn add_user(&mut self, user_id: String) {
+        self.engine.add_user(user_id)
+    }
+
+    fn remove_user(&mut self, user_id: &str) {
+        self.engine.remove_user(user_id)
+    }
+
+    fn users_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.engine.users_with_prefix(prefix)
+    }
+
+    fn indexed_users(&self) -> Vec<String> {
+        self.engine.indexed_users()
+    }
+
+    fn set_indexed(&mut self, user_id: &str, indexed: bool) -> io::Result<()> {
+        self.engine.set_indexed(user_id, indexed)
+    }
+
+    fn quota(&self, user_id: &str) -> Option<Quota> {
+        self.engine.quota(user_id)
+    }
+
+    fn set_quota(&mut self, user_id: &str, quota: Option<Quota>) -> io::Result<()> {
+        self.engine.set_quota(user_id, quota)
+    }
+
+    fn wal_seq(&self) -> u64 {
+        self.engine.wal_seq()
+    }
+
+    fn set_wal_seq(&mut self, seq: u64) {
+        self.engine.set_wal_seq(seq)
+    }
+
+    fn purge_expired(&mut self, now: u64) -> Vec<Key> {
+        let purged = self.engine.purge_expired(now);
+        if let Some(tracker) = &mut self.tracker {
+            let tracker = tracker.get_mut();
+            for key in &purged {
+                tracker.forget(key);
+            }
+        }
+        purged
+    }
+
+    fn usage(&self) -> &UsageCounters {
+        self.engine.usage()
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.engine.is_dirty()
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.engine.flush()
+    }
+
+    fn export(&self) -> io::Result<StoreState> {
+        self.engine.export()
+    }
+
+    fn restore(&mut self, state: StoreState) -> io::Result<()> {
+        self.engine.restore(state)?;
+        self.rebuild()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryEngine;
+    use crate::value::Value;
+
+    fn key(k: &str) -> Key {
+        ("u".to_string(), k.to_string())
+    }
+
+    fn put(k: &str, expires_at: Option<u64>) -> WalOp {
+        WalOp::Put { user_id: "u".into(), key: k.into(), value: Value::Int(0), expires_at }
+    }
+
+    /// What `put(k, None)` takes up.
+    fn size() -> u64 {
+        entry_size(&key("a"), &Entry { value: Value::Int(0), expires_at: None, version: 0 })
+    }
+
+    /// What `put(k, Some(_))` takes up.
+    fn expiring_size() -> u64 {
+        entry_size(&key("a"), &Entry { value: Value::Int(0), expires_at: Some(0), version: 0 })
+    }
+
+    /// A single shard with room for `keys` entries without an expiry.
+    fn bounded(policy: EvictionPolicy, keys: u64) -> Bounded<MemoryEngine> {
+        with_budget(policy, keys * size())
+    }
+
+    fn with_budget(policy: EvictionPolicy, max_memory_bytes: u64) -> Bounded<MemoryEngine> {
+        let config = RocsConfig { max_memory_bytes, eviction_policy: policy, ..RocsConfig::default() };
+        Bounded::new(MemoryEngine::detached(), &config, 1).unwrap()
+    }
+
+    /// Applies `op` the way the store actor does, evictions first. Returns the evicted keys.
+    fn write(engine: &mut Bounded<MemoryEngine>, op: WalOp) -> Result<Vec<Key>, StoreError> {
+        let victims = engine.make_room(&op)?;
+        for victim in &victims {
+            engine.delete(victim).unwrap();
+        }
+        let WalOp::Put { user_id, key, value, expires_at } = op else { unreachable!() };
+        engine.put((user_id, key), Entry { value, expires_at, version: 0 }).unwrap();
+        engine.count_evicted(victims.len());
+        Ok(victims)
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_key() {
+        let mut engine = bounded(EvictionPolicy::AllKeysLru, 3);
+        for k in ["a", "b", "c"] {
+            write(&mut engine, put(k, None)).unwrap();
+        }
+        engine.touch(&key("a"));
+        // an internal read must not save b
+        engine.get(&key("b")).unwrap();
+
+        assert_eq!(write(&mut engine, put("d", None)).unwrap(), vec![key("b")]);
+        assert_eq!(write(&mut engine, put("e", None)).unwrap(), vec![key("c")]);
+        assert_eq!(write(&mut engine, put("f", None)).unwrap(), vec![key("a")]);
+        let stats = engine.stats();
+        assert_eq!((stats.keys, stats.used_memory_bytes, stats.evicted_keys), (3, 3 * size(), 3));
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used_key() {
+        let mut engine = bounded(EvictionPolicy::AllKeysLfu, 3);
+        for k in ["a", "b", "c"] {
+            write(&mut engine, put(k, None)).unwrap();
+        }
+        engine.touch(&key("a"));
+        engine.touch(&key("a"));
+        engine.touch(&key("c"));
+
+        assert_eq!(write(&mut engine, put("d", None)).unwrap(), vec![key("b")]);
+        // d was used once, like nothing else, and is older than nothing
+        assert_eq!(write(&mut engine, put("e", None)).unwrap(), vec![key("d")]);
+    }
+
+    #[test]
+    fn volatile_ttl_only_evicts_keys_with_an_expiry() {
+        let mut engine = with_budget(EvictionPolicy::VolatileTtl, 2 * size() + expiring_size());
+        write(&mut engine, put("a", None)).unwrap();
+        write(&mut engine, put("b", None)).unwrap();
+        write(&mut engine, put("c", Some(u64::MAX))).unwrap();
+
+        let evicted = write(&mut engine, put("d", None)).unwrap();
+        assert_eq!(evicted, vec![key("c")]);
+        assert!(matches!(write(&mut engine, put("e", None)), Err(StoreError::OutOfMemory { .. })));
+    }
+
+    #[test]
+    fn volatile_ttl_evicts_the_key_expiring_soonest() {
+        let mut engine = with_budget(EvictionPolicy::VolatileTtl, 3 * expiring_size());
+        write(&mut engine, put("a", Some(u64::MAX))).unwrap();
+        write(&mut engine, put("b", Some(u64::MAX - 2))).unwrap();
+        write(&mut engine, put("c", Some(u64::MAX - 1))).unwrap();
+
+        assert_eq!(write(&mut engine, put("d", Some(u64::MAX))).unwrap(), vec![key("b")]);
+        assert_eq!(write(&mut engine, put("e", Some(u64::MAX))).unwrap(), vec![key("c")]);
+    }
+
+    #[test]
+    fn writes_over_budget_are_rejected_under_noeviction() {
+        let mut engine = bounded(EvictionPolicy::NoEviction, 2);
+        assert!(engine.tracker.is_none(), "nothing to evict, so nothing to track");
+        write(&mut engine, put("a", None)).unwrap();
+        write(&mut engine, put("b", None)).unwrap();
+
+        let err = write(&mut engine, put("c", None)).unwrap_err();
+        assert!(matches!(err, StoreError::OutOfMemory { limit } if limit == 2 * size()));
+        assert_eq!(engine.stats().rejected_writes, 1);
+        // overwrites that do not grow the store and deletes still go through
+        assert!(engine.make_room(&put("a", None)).unwrap().is_empty());
+        let delete = WalOp::Delete { user_id: "u".into(), key: "a".into() };
+        assert!(engine.make_room(&delete).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keys_being_written_are_never_evicted() {
+        let mut engine = bounded(EvictionPolicy::AllKeysLru, 2);
+        write(&mut engine, put("a", None)).unwrap();
+        write(&mut engine, put("b", None)).unwrap();
+        let batch = WalOp::Batch(vec![put("a", None), put("c", None), put("d", None)]);
+        assert!(matches!(engine.make_room(&batch), Err(StoreError::OutOfMemory { .. })));
+        let batch = WalOp::Batch(vec![put("a", None), put("c", None)]);
+        assert_eq!(engine.make_room(&batch).unwrap(), vec![key("b")]);
+    }
+
+    #[test]
+    fn nothing_is_tracked_without_a_budget() {
+        let config = RocsConfig { eviction_policy: EvictionPolicy::AllKeysLru, ..RocsConfig::default() };
+        let engine = Bounded::new(MemoryEngine::detached(), &config, 1).unwrap();
+        assert!(engine.tracker.is_none());
+    }
+
+    #[test]
+    fn entries_already_in_the_engine_are_tracked() {
+        let mut inner = MemoryEngine::detached();
+        for k in ["a", "b"] {
+            inner.put(key(k), Entry { value: Value::Int(0), expires_at: None, version: 0 }).unwrap();
+        }
+        let config = RocsConfig {
+            max_memory_bytes: 2 * size(),
+            eviction_policy: EvictionPolicy::AllKeysLru,
+            ..RocsConfig::default()
+        };
+        let mut engine = Bounded::new(inner, &config, 1).unwrap();
+        assert_eq!(engine.stats().keys, 2);
+        assert_eq!(write(&mut engine, put("c", None)).unwrap(), vec![key("a")]);
+    }
+}
@@ -0,0 +1,339 @@
+//! src/storage/index.rs
+//!
+//! Secondary indexes over values, for finding the keys of a user by what they hold.
+//!
+//! Indexes are optional and per user (per owner id, so every keyspace has its own). The engine
+//! only persists which users are indexed, not the rows: those live in memory in `Indexed`, which
+//! wraps the engine, keeps the rows in step with every `put`, `delete` and `purge_expired` (live
+//! writes, WAL replay and batches alike) and rebuilds them with a scan of each indexed user when
+//! the engine is opened and after a `restore`.
+//!
+//! Only scalar values are indexed. Numbers sort together, ints and floats by their numeric value,
+//! then strings, then bytes. Lists and maps are left out of the index.
+//!
+//! A key that has expired keeps its row until the engine purges it, so lookups must check every
+//! key they get against the engine.
+
+use super::{EntryIter, Key, KeyedEntryIter, StorageEngine, UsageCounters};
+use crate::actors::store_actor::{Entry, StoreState};
+use crate::quota::Quota;
+use crate::value::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::ops::Bound;
+
+/// The part of a value an index orders by.
+#[derive(Debug, Clone)]
+pub enum IndexKey {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl IndexKey {
+    /// The index key of `value`, or `None` for values that are not indexed.
+    pub fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Int(n) => Some(IndexKey::Int(*n)),
+            Value::Float(f) => Some(IndexKey::Float(*f)),
+            Value::Str(s) => Some(IndexKey::Str(s.clone())),
+            Value::Bytes(b) => Some(IndexKey::Bytes(b.clone())),
+            Value::List(_) | Value::Map(_) => None,
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexKey::Int(_) | IndexKey::Float(_) => 0,
+            IndexKey::Str(_) => 1,
+            IndexKey::Bytes(_) => 2,
+        }
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Int(a), IndexKey::Int(b)) => a.cmp(b),
+            (IndexKey::Float(a), IndexKey::Float(b)) => cmp_floats(*a, *b),
+            (IndexKey::Int(a), IndexKey::Float(b)) => cmp_int_float(*a, *b),
+            (IndexKey::Float(a), IndexKey::Int(b)) => cmp_int_float(*b, *a).reverse(),
+            (IndexKey::Str(a), IndexKey::Str(b)) => a.cmp(b),
+            (IndexKey::Bytes(a), IndexKey::Bytes(b)) => a.cmp(b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexKey {}
+
+/// Orders floats numerically, with NaN after every number.
+fn cmp_floats(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+}
+
+/// Compares an int with a float exactly, with NaN after every number.
+fn cmp_int_float(i: i64, f: f64) -> Ordering {
+    // 2^63: every i64 is below it, and every float at or above it
+    const LIMIT: f64 = 9_223_372_036_854_775_808.0;
+    if f.is_nan() || f >= LIMIT {
+        return Ordering::Less;
+    }
+    if f < -LIMIT {
+        return Ordering::Greater;
+    }
+    let whole = f.trunc();
+    // `whole` is in i64 range here, so the cast is exact
+    i.cmp(&(whole as i64)).then(cmp_floats(whole, f))
+}
+
+/// The index rows of one user.
+#[derive(Default)]
+struct UserIndex {
+    /// `(value, key)`, in value order.
+    rows: BTreeSet<(IndexKey, String)>,
+    /// The row of each indexed key, to remove it when the key changes.
+    keys: HashMap<String, IndexKey>,
+}
+
+impl UserIndex {
+    fn insert(&mut self, key: &str, value: &Value) {
+        self.remove(key);
+        if let Some(index_key) = IndexKey::of(value) {
+            self.rows.insert((index_key.clone(), key.to_string()));
+            self.keys.insert(key.to_string(), index_key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.keys.remove(key) {
+            self.rows.remove(&(old, key.to_string()));
+        }
+    }
+}
+
+/// A `StorageEngine` that maintains the value indexes of the users the engine marks as indexed.
+pub struct Indexed {
+    engine: Box<dyn StorageEngine>,
+    users: HashMap<String, UserIndex>,
+}
+
+impl Indexed {
+    /// Wraps `engine`, building the indexes it has marked.
+    pub fn new(engine: Box<dyn StorageEngine>) -> io::Result<Self> {
+        let mut indexed = Self { engine, users: HashMap::new() };
+        indexed.rebuild()?;
+        Ok(indexed)
+    }
+
+    fn rebuild(&mut self) -> io::Result<()> {
+        self.users.clear();
+        for user_id in self.engine.indexed_users() {
+            self.build(user_id)?;
+        }
+        Ok(())
+    }
+
+    fn build(&mut self, user_id: String) -> io::Result<()> {
+        let mut index = UserIndex::default();
+        for record in self.engine.scan(&user_id, (Bound::Unbounded, Bound::Unbounded)) {
+            let (key, entry) = record?;
+            index.insert(&key, &entry.value);
+        }
+        self.users.insert(user_id, index);
+        Ok(())
+    }
+
+    pub fn is_indexed(&self, user_id: &str) -> bool {
+        self.users.contains_key(user_id)
+    }
+
+    /// How many keys of `user_id` the index holds, including expired ones not purged yet.
+    pub fn indexed_keys(&self, user_id: &str) -> usize {
+        self.users.get(user_id).map_or(0, |index| index.keys.len())
+    }
+
+    /// The keys of `user_id` whose value lies in `range`, in value order, or `None` if the user
+    /// has no index. The keys may have expired or changed since; check them against the engine.
+    pub fn lookup(&self, user_id: &str, (start, end): (Bound<IndexKey>, Bound<IndexKey>)) -> Option<Vec<String>> {
+        let index = self.users.get(user_id)?;
+        let first = match &start {
+            Bound::Included(v) | Bound::Excluded(v) => Bound::Included((v.clone(), String::new())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let keys = index
+            .rows
+            .range((first, Bound::Unbounded))
+            .skip_while(|(v, _)| matches!(&start, Bound::Excluded(s) if v == s))
+            .take_while(|(v, _)| match &end {
+                Bound::Included(e) => v <= e,
+                Bound::Excluded(e) => v < e,
+                Bound::Unbounded => true,
+            })
+            .map(|(_, key)| key.clone())
+            .collect();
+        Some(keys)
+    }
+}
+
+impl StorageEngine for Indexed {
+    fn get(&self, key: &Key) -> io::Result<Option<Entry>> {
+        self.engine.get(key)
+    }
+
+    fn scan<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        self.engine.scan(user_id, range)
+    }
+
+    fn scan_rev<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        self.engine.scan_rev(user_id, range)
+    }
+
+    fn scan_all(&self) -> KeyedEntryIter<'_> {
+        self.engine.scan_all()
+    }
+
+    fn put(&mut self, key: Key, entry: Entry) -> io::Result<()> {
+        if let Some(index) = self.users.get_mut(&key.0) {
+            index.insert(&key.1, &entry.value);
+        }
+        self.engine.put(key, entry)
+    }
+
+    fn delete(&mut self, key: &Key) -> io::Result<()> {
+        if let Some(index) = self.users.get_mut(&key.0) {
+            index.remove(&key.1);
+        }
+        self.engine.delete(key)
+    }
+
+    fn has_user(&self, user_id: &str) -> bool {
+        self.engine.has_user(user_id)
+    }
+
+    fn add_user(&mut self, user_id: String) {
+        self.engine.add_user(user_id)
+    }
+
+    fn remove_user(&mut self, user_id: &str) {
+        self.engine.remove_user(user_id)
+    }
+
+    fn users_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.engine.users_with_prefix(prefix)
+    }
+
+    fn indexed_users(&self) -> Vec<String> {
+        self.engine.indexed_users()
+    }
+
+    fn set_indexed(&mut self, user_id: &str, indexed: bool) -> io::Result<()> {
+        self.engine.set_indexed(user_id, indexed)?;
+        match indexed {
+            true if !self.is_indexed(user_id) => self.build(user_id.to_string()),
+            true => Ok(()),
+            false => {
+                self.users.remove(user_id);
+                Ok(())
+            }
+        }
+    }
+
+    fn quota(&self, user_id: &str) -> Option<Quota> {
+        self.engine.quota(user_id)
+    }
+
+    fn set_quota(&mut self, user_id: &str, quota: Option<Quota>) -> io::Result<()> {
+        self.engine.set_quota(user_id, quota)
+    }
+
+    fn wal_seq(&self) -> u64 {
+        self.engine.wal_seq()
+    }
+
+    fn set_wal_seq(&mut self, seq: u64) {
+        self.engine.set_wal_seq(seq)
+    }
+
+    fn purge_expired(&mut self, now: u64) -> Vec<Key> {
+        let purged = self.engine.purge_expired(now);
+        for (user_id, key) in &purged {
+            if let Some(index) = self.users.get_mut(user_id) {
+                index.remove(key);
+            }
+        }
+        purged
+    }
+
+    fn usage(&self) -> &UsageCounters {
+        self.engine.usage()
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.engine.is_dirty()
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.engine.flush()
+    }
+
+    fn export(&self) -> io::Result<StoreState> {
+        self.engine.export()
+    }
+
+    fn restore(&mut self, state: StoreState) -> io::Result<()> {
+        self.engine.restore(state)?;
+        self.rebuild()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::lsm::LsmEngine;
+    use crate::storage::memory::MemoryEngine;
+
+    fn entry(value: Value, expires_at: Option<u64>) -> Entry {
+        Entry { value, expires_at, version: 1 }
+    }
+
+    fn purged_keys_leave_the_index(engine: Box<dyn StorageEngine>) {
+        let mut indexed = Indexed::new(engine).unwrap();
+        indexed.set_indexed("u", true).unwrap();
+        let key = |k: &str| ("u".to_string(), k.to_string());
+        indexed.put(key("a"), entry(Value::Int(1), Some(10))).unwrap();
+        indexed.put(key("b"), entry(Value::Int(1), None)).unwrap();
+        indexed.put(key("c"), entry(Value::Int(1), Some(30))).unwrap();
+
+        assert_eq!(indexed.purge_expired(20), vec![key("a")]);
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(indexed.lookup("u", all).unwrap(), vec!["b", "c"]);
+        assert_eq!(indexed.indexed_keys("u"), 2);
+    }
+
+    #[test]
+    fn purged_keys_leave_the_index_of_a_memory_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        purged_keys_leave_the_index(Box::new(MemoryEngine::open(dir.path().to_path_buf(), 8).unwrap()));
+    }
+
+    #[test]
+    fn purged_keys_leave_the_index_of_an_lsm_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        purged_keys_leave_the_index(Box::new(LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap()));
+    }
+}
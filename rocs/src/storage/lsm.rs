@@ -0,0 +1,1303 @@
+//! src/storage/lsm.rs
+//!
+//! A small log-structured merge tree over files in `lsm/` in the engine directory (by default
+//! `~/.roc_server/lsm/`, see `crate::shard`).
+//!
+//! Writes go to an in-memory memtable; the WAL already makes them durable. When the memtable
+//! grows past `memtable_bytes`, or on `flush`, it is written out as an immutable sorted table
+//! (`table-<id>.sst`) and `MANIFEST` is rewritten to list it. Both are written to a temporary
+//! file, fsynced, renamed into place and followed by an fsync of the directory. Reads check the
+//! memtable and then the tables from newest to oldest; scans merge them, and reverse scans walk
+//! the tables back from the end of the range one index block at a time. Only a sparse index of each table is kept
+//! in memory, so the keyspace does not have to fit in RAM and opening the engine reads no entries.
+//!
+//! Deletes are written as tombstones. Once there are more than `MAX_TABLES` tables, they are
+//! merged into one and tombstones and expired entries are dropped. `purge_expired` turns expired
+//! memtable entries into tombstones and compacts early once enough of the tables has expired.
+//!
+//! Table layout:
+//!
+//! ```text
+//! header    TABLE_MAGIC, then u32 LE TABLE_FORMAT_VERSION
+//! record*   u32 LE length, u32 LE CRC32 of the body, then bincode (Key, Option<Entry>)
+//!           sorted by key, None = tombstone
+//! meta      bincode TableMeta: the sparse index, the expiry of every record that has one and
+//!           the record count
+//! footer    u64 LE offset of the meta, u32 LE CRC32 of the meta, then TABLE_MAGIC
+//! ```
+//!
+//! The header and the meta are checked when a table is opened, every record when it is read.
+//! Tables written before the format had a version (footer `ROCSSST1`, no header and no CRCs) are
+//! still read. The manifest is `MANIFEST_MAGIC`, a u32 LE version and the CRC32 of the JSON body
+//! that follows; a `MANIFEST.json` from before is read once and replaced.
+
+use super::{EntryIter, Key, KeyRange, KeyedEntryIter, StorageEngine, UsageCounters};
+use crate::actors::logger_actor::{now_millis, sync_dir};
+use crate::actors::store_actor::{Entry, StoreState};
+use crate::quota::Quota;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+const TABLE_MAGIC: &[u8; 4] = b"RSST";
+const TABLE_FORMAT_VERSION: u32 = 2;
+/// Footer magic of version 1 tables, which had no header and no checksums.
+const LEGACY_TABLE_MAGIC: &[u8; 8] = b"ROCSSST1";
+const MANIFEST_MAGIC: &[u8; 4] = b"RMAN";
+const MANIFEST_FORMAT_VERSION: u32 = 1;
+/// One index row per this many records.
+const INDEX_INTERVAL: usize = 64;
+/// Merge all tables into one when there are more than this many.
+const MAX_TABLES: usize = 8;
+
+/// What survives a restart, besides the tables themselves.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    /// Every WAL record up to this one is contained in `tables`.
+    wal_seq: u64,
+    next_table_id: u64,
+    /// Live tables, oldest first.
+    tables: Vec<u64>,
+    users: BTreeSet<String>,
+    /// Users whose values are indexed.
+    #[serde(default)]
+    indexed: BTreeSet<String>,
+    /// Quotas set by an admin.
+    #[serde(default)]
+    quotas: BTreeMap<String, Quota>,
+    /// What every owner holds in `tables`; counted from the tables when missing.
+    #[serde(default)]
+    usage: Option<UsageCounters>,
+}
+
+pub struct LsmEngine {
+    dir: PathBuf,
+    /// Recent writes; `None` is a tombstone.
+    memtable: BTreeMap<Key, Option<Entry>>,
+    memtable_size: u64,
+    memtable_bytes: u64,
+    /// Oldest first, like `Manifest::tables`.
+    tables: Vec<Table>,
+    next_table_id: u64,
+    users: BTreeSet<String>,
+    indexed: BTreeSet<String>,
+    quotas: BTreeMap<String, Quota>,
+    /// What every owner holds, memtable included. Every write reads the entry it replaces to
+    /// keep this up to date.
+    usage: UsageCounters,
+    wal_seq: u64,
+    /// Whether the users, the indexed users, the quotas or `wal_seq` changed since the manifest
+    /// was written.
+    dirty: bool,
+    /// Compaction drops entries that expired at or before this time, the `now` of the last
+    /// `purge_expired`.
+    purged_until: u64,
+    /// Keys removed because they expired that the next `purge_expired` has yet to report.
+    purged: BTreeSet<Key>,
+}
+
+impl LsmEngine {
+    pub fn open(dir: PathBuf, memtable_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        let manifest = read_manifest(&dir)?;
+
+        let tables = manifest
+            .tables
+            .iter()
+            .map(|&id| match Table::open(id, table_path(&dir, id)) {
+                // the manifest only ever lists tables that were fully written
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    Err(invalid_data(format!("MANIFEST lists missing table {id}")))
+                }
+                other => other,
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        remove_stray_tables(&dir, &manifest.tables);
+
+        let (usage, dirty) = match manifest.usage {
+            Some(usage) => (usage, false),
+            None if tables.is_empty() => (UsageCounters::default(), false),
+            None => (count_usage(&tables)?, true),
+        };
+
+        Ok(Self {
+            dir,
+            memtable: BTreeMap::new(),
+            memtable_size: 0,
+            memtable_bytes,
+            tables,
+            next_table_id: manifest.next_table_id,
+            users: manifest.users,
+            indexed: manifest.indexed,
+            quotas: manifest.quotas,
+            usage,
+            wal_seq: manifest.wal_seq,
+            dirty,
+            purged_until: 0,
+            purged: BTreeSet::new(),
+        })
+    }
+
+    fn write_to_memtable(&mut self, key: Key, entry: Option<Entry>) -> io::Result<()> {
+        let old = self.get(&key)?;
+        self.usage.replace(&key, old.as_ref(), entry.as_ref());
+        self.memtable_size += (key.0.len() + key.1.len()) as u64
+            + entry.as_ref().map_or(0, |e| bincode::serialized_size(e).unwrap_or(0));
+        // a new value or tombstone supersedes an expired one that was dropped
+        self.purged.remove(&key);
+        self.memtable.insert(key, entry);
+        if self.memtable_size >= self.memtable_bytes {
+            self.flush_memtable()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the memtable out as a new table and records it, with `wal_seq`, in the manifest.
+    ///
+    /// The memtable may hold part of a batch whose record number is not in `wal_seq` yet; replay
+    /// applies the whole record again, which is harmless.
+    fn flush_memtable(&mut self) -> io::Result<()> {
+        if !self.memtable.is_empty() {
+            let id = self.next_table_id;
+            let now = self.purged_until;
+            let mut expired = Vec::new();
+            let records = self.memtable.iter().map(|(k, e)| match e {
+                // written as a tombstone, as a table may hold an older value
+                Some(e) if e.is_expired(now) => {
+                    expired.push((k.clone(), e.clone()));
+                    Ok((k.clone(), None))
+                }
+                _ => Ok((k.clone(), e.clone())),
+            });
+            let table = Table::write(id, table_path(&self.dir, id), records)?;
+            for (key, entry) in expired {
+                self.usage.replace(&key, Some(&entry), None);
+                self.purged.insert(key);
+            }
+            self.next_table_id += 1;
+            self.tables.push(table);
+            self.memtable.clear();
+            self.memtable_size = 0;
+        }
+        self.write_manifest()?;
+
+        if self.tables.len() > MAX_TABLES {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Merges every table into one, dropping tombstones and expired entries.
+    fn compact(&mut self) -> io::Result<()> {
+        let id = self.next_table_id;
+        let now = self.purged_until;
+        let mut expired = Vec::new();
+        let merged = MergeIter::new(self.table_sources(&KeyRange::all()), KeyRange::all()).filter(|r| match r {
+            Ok((_, None)) => false,
+            Ok((key, Some(e))) if e.is_expired(now) => {
+                expired.push((key.clone(), e.clone()));
+                false
+            }
+            _ => true,
+        });
+        let table = Table::write(id, table_path(&self.dir, id), merged)?;
+        self.next_table_id += 1;
+        for (key, entry) in expired {
+            // a newer value in the memtable means the key is still there
+            if !self.memtable.contains_key(&key) {
+                self.usage.replace(&key, Some(&entry), None);
+                self.purged.insert(key);
+            }
+        }
+
+        let old = std::mem::replace(&mut self.tables, vec![table]);
+        self.write_manifest()?;
+        for t in old {
+            fs::remove_file(&t.path)?;
+        }
+        sync_dir(&self.dir)?;
+        println!("Compacted LSM tables into table {id}");
+        Ok(())
+    }
+
+    /// How many table records expired at or before `now`, and how many records there are.
+    fn expired_in_tables(&self, now: u64) -> (u64, u64) {
+        self.tables.iter().fold((0, 0), |(expired, total), t| {
+            (expired + t.expiries.partition_point(|&at| at <= now) as u64, total + t.records)
+        })
+    }
+
+    fn write_manifest(&mut self) -> io::Result<()> {
+        let manifest = Manifest {
+            wal_seq: self.wal_seq,
+            next_table_id: self.next_table_id,
+            tables: self.tables.iter().map(|t| t.id).collect(),
+            users: self.users.clone(),
+            indexed: self.indexed.clone(),
+            quotas: self.quotas.clone(),
+            usage: Some(self.usage.clone()),
+        };
+        let body = serde_json::to_vec(&manifest).map_err(io::Error::other)?;
+        let mut bytes = Vec::with_capacity(body.len() + 12);
+        bytes.extend_from_slice(MANIFEST_MAGIC);
+        bytes.extend_from_slice(&MANIFEST_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        bytes.extend_from_slice(&body);
+
+        let path = self.dir.join("MANIFEST");
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        match fs::remove_file(self.dir.join("MANIFEST.json")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        sync_dir(&self.dir)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// One iterator per table covering `range`, newest first.
+    fn table_sources(&self, range: &KeyRange) -> Vec<RecordIter<'static>> {
+        self.tables
+            .iter()
+            .rev()
+            .map(|t| -> RecordIter<'static> {
+                match t.iter_from(&range.first_key()) {
+                    Ok(iter) => Box::new(iter),
+                    Err(e) => Box::new(std::iter::once(Err(e))),
+                }
+            })
+            .collect()
+    }
+
+    /// One iterator per table going backwards from the end of `range`, newest first.
+    fn table_sources_rev<'a>(&'a self, range: &KeyRange) -> Vec<RecordIter<'a>> {
+        let end = range.upper_bound();
+        self.tables.iter().rev().map(|t| -> RecordIter<'a> { Box::new(t.iter_back_from(&end)) }).collect()
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &Key) -> io::Result<Option<Entry>> {
+        if let Some(entry) = self.memtable.get(key) {
+            return Ok(entry.clone());
+        }
+        for table in self.tables.iter().rev() {
+            if let Some(entry) = table.get(key)? {
+                return Ok(entry);
+            }
+        }
+        Ok(None)
+    }
+
+    fn scan<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        let range = KeyRange::user(user_id, range);
+        let memtable: RecordIter<'a> = Box::new(
+            self.memtable
+                .range(range.first_key()..)
+                .map(|(k, e)| Ok((k.clone(), e.clone()))),
+        );
+        let mut sources = vec![memtable];
+        sources.extend(self.table_sources(&range));
+
+        let iter = MergeIter::new(sources, range).filter_map(|r| match r {
+            Ok(((_, k), Some(e))) => Some(Ok((k, e))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        });
+        Box::new(iter)
+    }
+
+    fn scan_rev<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        let range = KeyRange::user(user_id, range);
+        let memtable: RecordIter<'a> = Box::new(
+            self.memtable
+                .range((Bound::Unbounded, range.upper_bound()))
+                .rev()
+                .map(|(k, e)| Ok((k.clone(), e.clone()))),
+        );
+        let mut sources = vec![memtable];
+        sources.extend(self.table_sources_rev(&range));
+
+        let iter = MergeIter::new_rev(sources, range).filter_map(|r| match r {
+            Ok(((_, k), Some(e))) => Some(Ok((k, e))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        });
+        Box::new(iter)
+    }
+
+    fn scan_all(&self) -> KeyedEntryIter<'_> {
+        let mut sources: Vec<RecordIter<'_>> =
+            vec![Box::new(self.memtable.iter().map(|(k, e)| Ok((k.clone(), e.clone()))))];
+        sources.extend(self.table_sources(&KeyRange::all()));
+        let iter = MergeIter::new(sources, KeyRange::all()).filter_map(|r| match r {
+            Ok((key, Some(e))) => Some(Ok((key, e))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        });
+        Box::new(iter)
+    }
+
+    fn put(&mut self, key: Key, entry: Entry) -> io::Result<()> {
+        self.write_to_memtable(key, Some(entry))
+    }
+
+    fn delete(&mut self, key: &Key) -> io::Result<()> {
+        self.write_to_memtable(key.clone(), None)
+    }
+
+    fn has_user(&self, user_id: &str) -> bool {
+        self.users.contains(user_id)
+    }
+
+    fn add_user(&mut self, user_id: String) {
+        self.dirty |= self.users.insert(user_id);
+    }
+
+    fn remove_user(&mut self, user_id: &str) {
+        self.dirty |= self.users.remove(user_id);
+    }
+
+    fn users_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.users.range(prefix.to_string()..).take_while(|u| u.starts_with(prefix)).cloned().collect()
+    }
+
+    fn indexed_users(&self) -> Vec<String> {
+        self.indexed.iter().cloned().collect()
+    }
+
+    fn set_indexed(&mut self, user_id: &str, indexed: bool) -> io::Result<()> {
+        self.dirty |= if indexed { self.indexed.insert(user_id.to_string()) } else { self.indexed.remove(user_id) };
+        Ok(())
+    }
+
+    fn quota(&self, user_id: &str) -> Option<Quota> {
+        self.quotas.get(user_id).copied()
+    }
+
+    fn set_quota(&mut self, user_id: &str, quota: Option<Quota>) -> io::Result<()> {
+        let old = match quota {
+            Some(quota) => self.quotas.insert(user_id.to_string(), quota),
+            None => self.quotas.remove(user_id),
+        };
+        self.dirty |= old != quota;
+        Ok(())
+    }
+
+    fn wal_seq(&self) -> u64 {
+        self.wal_seq
+    }
+
+    fn set_wal_seq(&mut self, seq: u64) {
+        self.dirty |= seq != self.wal_seq;
+        self.wal_seq = seq;
+    }
+
+    fn purge_expired(&mut self, now: u64) -> Vec<Key> {
+        self.purged_until = now;
+        let expired: Vec<Key> = self
+            .memtable
+            .iter()
+            .filter(|(_, e)| e.as_ref().is_some_and(|e| e.is_expired(now)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            // a tombstone, not a removal: a table may hold an older value
+            if let Some(Some(old)) = self.memtable.insert(key.clone(), None) {
+                self.usage.replace(&key, Some(&old), None);
+            }
+            self.purged.insert(key);
+        }
+
+        // Expired records in tables are invisible to readers; drop them once they are a
+        // quarter of all records rather than waiting for the next compaction. The memtable goes
+        // first, as the manifest written by a compaction claims everything up to `wal_seq`.
+        let (expired, total) = self.expired_in_tables(now);
+        if expired > 0 && expired * 4 >= total {
+            // unless the flush compacted already
+            let compacted = self.flush_memtable().and_then(|()| match self.expired_in_tables(now).0 {
+                0 => Ok(()),
+                _ => self.compact(),
+            });
+            if let Err(e) = compacted {
+                eprintln!("Failed to compact expired LSM entries: {e}");
+            }
+        }
+        std::mem::take(&mut self.purged).into_iter().collect()
+    }
+
+    fn usage(&self) -> &UsageCounters {
+        &self.usage
+    }
+
+    fn is_dirty(&self) -> bool {
+        !self.memtable.is_empty() || self.dirty
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        self.flush_memtable().map_err(|e| e.to_string())
+    }
+
+    fn export(&self) -> io::Result<StoreState> {
+        let mut state = StoreState::default();
+        state.users = self.users.clone();
+        state.indexed = self.indexed.clone();
+        state.quotas = self.quotas.clone();
+        state.wal_seq = self.wal_seq;
+        for record in self.scan_all() {
+            let (key, entry) = record?;
+            state.insert_entry(key, entry);
+        }
+        Ok(state)
+    }
+
+    fn restore(&mut self, state: StoreState) -> io::Result<()> {
+        let usage = state.usage().clone();
+        let id = self.next_table_id;
+        let records = state.kv.into_iter().map(|(k, e)| Ok((k, Some(e))));
+        let table = Table::write(id, table_path(&self.dir, id), records)?;
+        self.next_table_id += 1;
+
+        let old = std::mem::replace(&mut self.tables, vec![table]);
+        self.memtable.clear();
+        self.memtable_size = 0;
+        self.users = state.users;
+        self.indexed = state.indexed;
+        self.quotas = state.quotas;
+        self.usage = usage;
+        self.wal_seq = state.wal_seq;
+        self.write_manifest()?;
+        for t in old {
+            fs::remove_file(&t.path)?;
+        }
+        sync_dir(&self.dir)
+    }
+}
+
+type Record = (Key, Option<Entry>);
+type RecordIter<'a> = Box<dyn Iterator<Item = io::Result<Record>> + 'a>;
+
+/// An immutable sorted table on disk.
+struct Table {
+    id: u64,
+    path: PathBuf,
+    /// `TABLE_FORMAT_VERSION`, or 1 for a table without header and checksums.
+    version: u32,
+    /// First key and file offset of every `INDEX_INTERVAL`-th record.
+    index: Vec<(Key, u64)>,
+    /// Where the records begin, after the header.
+    data_start: u64,
+    /// Where the records end and the meta begins.
+    data_end: u64,
+    /// Expiry of every record that has one, sorted. Empty for version 1 tables.
+    expiries: Vec<u64>,
+    /// Number of records, tombstones included. 0 for version 1 tables.
+    records: u64,
+}
+
+/// What follows the records of a table.
+#[derive(Serialize, Deserialize)]
+struct TableMeta {
+    index: Vec<(Key, u64)>,
+    expiries: Vec<u64>,
+    records: u64,
+}
+
+impl Table {
+    /// Writes `records`, which must be sorted by key, to `path` through a temporary file, and
+    /// fsyncs it and the directory.
+    fn write(id: u64, path: PathBuf, records: impl Iterator<Item = io::Result<Record>>) -> io::Result<Table> {
+        let tmp = path.with_extension("sst.tmp");
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(TABLE_MAGIC)?;
+        out.write_all(&TABLE_FORMAT_VERSION.to_le_bytes())?;
+        let data_start = (TABLE_MAGIC.len() + 4) as u64;
+        let mut meta = TableMeta { index: Vec::new(), expiries: Vec::new(), records: 0 };
+        let mut offset = data_start;
+
+        for record in records {
+            let record = record?;
+            let bytes = bincode::serialize(&record).map_err(invalid_data)?;
+            if meta.records % INDEX_INTERVAL as u64 == 0 {
+                meta.index.push((record.0.clone(), offset));
+            }
+            if let Some(at) = record.1.as_ref().and_then(|e| e.expires_at) {
+                meta.expiries.push(at);
+            }
+            out.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            out.write_all(&crc32fast::hash(&bytes).to_le_bytes())?;
+            out.write_all(&bytes)?;
+            offset += 8 + bytes.len() as u64;
+            meta.records += 1;
+        }
+        meta.expiries.sort_unstable();
+
+        let meta_bytes = bincode::serialize(&meta).map_err(invalid_data)?;
+        out.write_all(&meta_bytes)?;
+        out.write_all(&offset.to_le_bytes())?;
+        out.write_all(&crc32fast::hash(&meta_bytes).to_le_bytes())?;
+        out.write_all(TABLE_MAGIC)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        if let Some(dir) = path.parent() {
+            sync_dir(dir)?;
+        }
+
+        Ok(Table {
+            id,
+            path,
+            version: TABLE_FORMAT_VERSION,
+            index: meta.index,
+            data_start,
+            data_end: offset,
+            expiries: meta.expiries,
+            records: meta.records,
+        })
+    }
+
+    /// Reads the meta of an existing table, checking its header and checksum.
+    fn open(id: u64, path: PathBuf) -> io::Result<Table> {
+        let corrupt = |what: &str| invalid_data(format!("{}: {what}", path.display()));
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        if len < 16 {
+            return Err(corrupt("truncated table"));
+        }
+
+        let mut footer = [0u8; 16];
+        file.seek(SeekFrom::Start(len - 16))?;
+        file.read_exact(&mut footer)?;
+        if &footer[8..] == LEGACY_TABLE_MAGIC {
+            return Self::open_v1(id, path, file, len, &footer);
+        }
+        if &footer[12..] != TABLE_MAGIC {
+            return Err(corrupt("not a table"));
+        }
+
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if &header[..4] != TABLE_MAGIC {
+            return Err(corrupt("not a table"));
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+        if version != TABLE_FORMAT_VERSION {
+            return Err(corrupt(&format!(
+                "table format version {version} is not supported (this server writes {TABLE_FORMAT_VERSION})"
+            )));
+        }
+
+        let data_end = u64::from_le_bytes(footer[..8].try_into().expect("8 bytes"));
+        let crc = u32::from_le_bytes(footer[8..12].try_into().expect("4 bytes"));
+        if data_end < header.len() as u64 || data_end > len - 16 {
+            return Err(corrupt("meta offset out of range"));
+        }
+        let mut meta = vec![0u8; (len - 16 - data_end) as usize];
+        file.seek(SeekFrom::Start(data_end))?;
+        file.read_exact(&mut meta)?;
+        if crc32fast::hash(&meta) != crc {
+            return Err(corrupt("meta checksum mismatch"));
+        }
+        let meta: TableMeta = bincode::deserialize(&meta).map_err(invalid_data)?;
+
+        Ok(Table {
+            id,
+            path,
+            version,
+            index: meta.index,
+            data_start: header.len() as u64,
+            data_end,
+            expiries: meta.expiries,
+            records: meta.records,
+        })
+    }
+
+    /// Reads the index of a version 1 table: records from offset 0, then the index, then the
+    /// index offset and `LEGACY_TABLE_MAGIC`.
+    fn open_v1(id: u64, path: PathBuf, mut file: File, len: u64, footer: &[u8; 16]) -> io::Result<Table> {
+        let data_end = u64::from_le_bytes(footer[..8].try_into().expect("8 bytes"));
+        let mut index = vec![0u8; (len - 16).saturating_sub(data_end) as usize];
+        file.seek(SeekFrom::Start(data_end))?;
+        file.read_exact(&mut index)?;
+        let index = bincode::deserialize(&index).map_err(invalid_data)?;
+
+        Ok(Table { id, path, version: 1, index, data_start: 0, data_end, expiries: Vec::new(), records: 0 })
+    }
+
+    /// `Some(entry)` if the table has a record for `key` (`Some(None)` for a tombstone).
+    fn get(&self, key: &Key) -> io::Result<Option<Option<Entry>>> {
+        let block = self.index.partition_point(|(first, _)| first <= key);
+        if block == 0 {
+            return Ok(None); // before the first key
+        }
+        let end = self.index.get(block).map_or(self.data_end, |(_, offset)| *offset);
+        for record in self.records(self.index[block - 1].1, end)? {
+            let (k, entry) = record?;
+            if k == *key {
+                return Ok(Some(entry));
+            }
+            if k > *key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// The records from the block that may contain `start` to the end of the table.
+    fn iter_from(&self, start: &Key) -> io::Result<TableIter> {
+        let block = self.index.partition_point(|(first, _)| first <= start).saturating_sub(1);
+        let offset = self.index.get(block).map_or(self.data_end, |(_, offset)| *offset);
+        self.records(offset, self.data_end)
+    }
+
+    /// The records up to `end`, last first. Reads one index block at a time.
+    fn iter_back_from(&self, end: &Bound<Key>) -> TableRevIter<'_> {
+        let blocks = match end {
+            Bound::Included(k) | Bound::Excluded(k) => self.index.partition_point(|(first, _)| first <= k),
+            Bound::Unbounded => self.index.len(),
+        };
+        TableRevIter { table: self, blocks, block: Vec::new() }
+    }
+
+    fn records(&self, start: u64, end: u64) -> io::Result<TableIter> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(TableIter { reader: BufReader::new(file), checked: self.version > 1, pos: start, end })
+    }
+}
+
+/// Reads the records between two offsets of a table.
+struct TableIter {
+    reader: BufReader<File>,
+    /// Whether records carry a CRC32.
+    checked: bool,
+    pos: u64,
+    end: u64,
+}
+
+impl Iterator for TableIter {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+        let mut read = || -> io::Result<Record> {
+            let mut len = [0u8; 4];
+            self.reader.read_exact(&mut len)?;
+            let mut crc = [0u8; 4];
+            if self.checked {
+                self.reader.read_exact(&mut crc)?;
+            }
+            let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+            self.reader.read_exact(&mut bytes)?;
+            if self.checked && crc32fast::hash(&bytes) != u32::from_le_bytes(crc) {
+                return Err(invalid_data(format!("table record at offset {} fails its checksum", self.pos)));
+            }
+            self.pos += if self.checked { 8 } else { 4 } + bytes.len() as u64;
+            bincode::deserialize(&bytes).map_err(invalid_data)
+        };
+        let record = read();
+        if record.is_err() {
+            self.pos = self.end; // a broken table yields one error, not garbage
+        }
+        Some(record)
+    }
+}
+
+/// Reads a table backwards: the records of one index block at a time, last block first.
+struct TableRevIter<'a> {
+    table: &'a Table,
+    /// Blocks not read yet, from the start of the table.
+    blocks: usize,
+    /// The rest of the block being read, in file order.
+    block: Vec<Record>,
+}
+
+impl Iterator for TableRevIter<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.block.is_empty() {
+            if self.blocks == 0 {
+                return None;
+            }
+            self.blocks -= 1;
+            let start = self.table.index[self.blocks].1;
+            let end = self.table.index.get(self.blocks + 1).map_or(self.table.data_end, |(_, offset)| *offset);
+            let block = self.table.records(start, end).and_then(|records| records.collect());
+            match block {
+                Ok(records) => self.block = records,
+                Err(e) => {
+                    self.blocks = 0; // a broken table yields one error, not garbage
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.block.pop().map(Ok)
+    }
+}
+
+/// Merges sorted sources (newest first) into one sorted stream restricted to `range`, or into
+/// one stream in reverse order from sources that are all in reverse order.
+/// When several sources hold the same key, the newest wins.
+struct MergeIter<'a> {
+    sources: Vec<RecordIter<'a>>,
+    heads: Vec<Option<Record>>,
+    range: KeyRange,
+    reverse: bool,
+    error: Option<io::Error>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<RecordIter<'a>>, range: KeyRange) -> Self {
+        Self::with_order(sources, range, false)
+    }
+
+    fn new_rev(sources: Vec<RecordIter<'a>>, range: KeyRange) -> Self {
+        Self::with_order(sources, range, true)
+    }
+
+    fn with_order(sources: Vec<RecordIter<'a>>, range: KeyRange, reverse: bool) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        let mut merge = Self { sources, heads, range, reverse, error: None };
+        for i in 0..merge.sources.len() {
+            merge.advance(i);
+        }
+        merge
+    }
+
+    /// Whether `key` has not been reached yet, going in the merge's direction.
+    fn not_reached(&self, key: &Key) -> bool {
+        if self.reverse { self.range.is_after(key) } else { self.range.is_before(key) }
+    }
+
+    /// Whether `key` lies beyond the range, going in the merge's direction.
+    fn passed(&self, key: &Key) -> bool {
+        if self.reverse { self.range.is_before(key) } else { self.range.is_after(key) }
+    }
+
+    /// Moves source `i` to its next record inside the range.
+    fn advance(&mut self, i: usize) {
+        self.heads[i] = loop {
+            match self.sources[i].next() {
+                Some(Ok(record)) if self.not_reached(&record.0) => continue,
+                Some(Ok(record)) if self.passed(&record.0) => break None,
+                Some(Ok(record)) => break Some(record),
+                Some(Err(e)) => {
+                    self.error.get_or_insert(e);
+                    break None;
+                }
+                None => break None,
+            }
+        };
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        // first key in the merge's order; on ties the lowest index, i.e. the newest source
+        let reverse = self.reverse;
+        let newest = (0..self.heads.len())
+            .filter_map(|i| self.heads[i].as_ref().map(|(k, _)| (k, i)))
+            .reduce(|first, other| {
+                let ahead = if reverse { other.0 > first.0 } else { other.0 < first.0 };
+                if ahead { other } else { first }
+            })?
+            .1;
+        let record = self.heads[newest].take().expect("head is set");
+        for i in 0..self.heads.len() {
+            if i == newest || self.heads[i].as_ref().is_some_and(|(k, _)| *k == record.0) {
+                self.advance(i);
+            }
+        }
+        Some(Ok(record))
+    }
+}
+
+/// Moves the engine directory `dir` into `dest`, leaving no store behind.
+pub fn quarantine(dir: &Path, dest: &Path) -> io::Result<()> {
+    if dir.exists() {
+        fs::rename(dir, dest.join("lsm"))?;
+        if let Some(parent) = dir.parent() {
+            sync_dir(parent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts what every owner holds in `tables`, for a manifest written before the counters were kept.
+fn count_usage(tables: &[Table]) -> io::Result<UsageCounters> {
+    let sources = tables
+        .iter()
+        .rev()
+        .map(|t| -> io::Result<RecordIter<'static>> { Ok(Box::new(t.records(t.data_start, t.data_end)?)) })
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut usage = UsageCounters::default();
+    for record in MergeIter::new(sources, KeyRange::all()) {
+        if let (key, Some(entry)) = record? {
+            usage.replace(&key, None, Some(&entry));
+        }
+    }
+    Ok(usage)
+}
+
+/// Reads `MANIFEST`, or a `MANIFEST.json` written before the manifest had a header.
+fn read_manifest(dir: &Path) -> io::Result<Manifest> {
+    let path = dir.join("MANIFEST");
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return match fs::read(dir.join("MANIFEST.json")) {
+                Ok(bytes) => serde_json::from_slice(&bytes).map_err(invalid_data),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+                Err(e) => Err(e),
+            };
+        }
+        Err(e) => return Err(e),
+    };
+
+    let corrupt = |what: String| invalid_data(format!("{}: {what}", path.display()));
+    let rest = bytes.strip_prefix(MANIFEST_MAGIC).ok_or_else(|| corrupt("not an LSM manifest".into()))?;
+    let (version, rest) = rest.split_at_checked(4).ok_or_else(|| corrupt("truncated header".into()))?;
+    let version = u32::from_le_bytes(version.try_into().expect("4 bytes"));
+    if version != MANIFEST_FORMAT_VERSION {
+        return Err(corrupt(format!(
+            "manifest format version {version} is not supported (this server writes {MANIFEST_FORMAT_VERSION})"
+        )));
+    }
+    let (crc, body) = rest.split_at_checked(4).ok_or_else(|| corrupt("truncated header".into()))?;
+    let crc = u32::from_le_bytes(crc.try_into().expect("4 bytes"));
+    let actual = crc32fast::hash(body);
+    if crc != actual {
+        return Err(corrupt(format!("checksum mismatch (header says {crc:#010x}, contents hash to {actual:#010x})")));
+    }
+    serde_json::from_slice(body).map_err(|e| corrupt(e.to_string()))
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("table-{id:08}.sst"))
+}
+
+/// Deletes tables left behind by a flush or compaction that crashed before the manifest update,
+/// including ones that were never completely written.
+fn remove_stray_tables(dir: &Path, live: &[u64]) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(id) = name
+            .strip_prefix("table-")
+            .and_then(|n| n.strip_suffix(".sst").or_else(|| n.strip_suffix(".sst.tmp")))
+        else {
+            continue;
+        };
+        if id.parse().is_ok_and(|id: u64| !live.contains(&id)) {
+            match fs::remove_file(entry.path()) {
+                Ok(()) => println!("Removed stray LSM table {name}"),
+                Err(e) => eprintln!("Failed to remove stray LSM table {name}: {e}"),
+            }
+        }
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{entry_size, Usage};
+    use crate::value::Value;
+
+    fn key(k: &str) -> Key {
+        ("u".to_string(), k.to_string())
+    }
+
+    fn entry(n: i64) -> Entry {
+        Entry { value: Value::Int(n), expires_at: None, version: n as u64 }
+    }
+
+    fn all(engine: &LsmEngine) -> Vec<(String, i64)> {
+        engine
+            .scan("u", (Bound::Unbounded, Bound::Unbounded))
+            .map(|r| {
+                let (k, e) = r.unwrap();
+                match e.value {
+                    Value::Int(n) => (k, n),
+                    other => panic!("unexpected value {other:?}"),
+                }
+            })
+            .collect()
+    }
+
+    /// Every record of every table, tombstones included, oldest table first.
+    fn records(engine: &LsmEngine) -> Vec<Record> {
+        engine
+            .tables
+            .iter()
+            .flat_map(|t| t.records(t.data_start, t.data_end).unwrap())
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn newest_version_wins_across_memtable_and_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        for (k, n) in [("a", 1), ("b", 2), ("c", 3)] {
+            engine.put(key(k), entry(n)).unwrap();
+        }
+        engine.flush_memtable().unwrap();
+        engine.put(key("b"), entry(20)).unwrap();
+        engine.flush_memtable().unwrap();
+        engine.put(key("c"), entry(30)).unwrap();
+        engine.put(key("d"), entry(4)).unwrap();
+
+        assert_eq!(engine.tables.len(), 2);
+        assert_eq!(all(&engine), vec![("a".into(), 1), ("b".into(), 20), ("c".into(), 30), ("d".into(), 4)]);
+        assert_eq!(engine.get(&key("b")).unwrap(), Some(entry(20)));
+        assert_eq!(engine.get(&key("a")).unwrap(), Some(entry(1)));
+        let rev: Vec<String> = engine
+            .scan_rev("u", (Bound::Excluded("a".into()), Bound::Included("c".into())))
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(rev, vec!["c", "b"]);
+    }
+
+    #[test]
+    fn scans_stay_within_the_user_and_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.put(("t".into(), "a".into()), entry(1)).unwrap();
+        engine.put(key("a"), entry(2)).unwrap();
+        engine.put(key("b"), entry(3)).unwrap();
+        engine.flush_memtable().unwrap();
+        engine.put(("v".into(), "a".into()), entry(4)).unwrap();
+
+        let keys: Vec<String> = engine
+            .scan("u", (Bound::Included("a".into()), Bound::Excluded("b".into())))
+            .map(|r| r.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec!["a"]);
+        assert_eq!(all(&engine).len(), 2);
+    }
+
+    #[test]
+    fn reverse_scans_mirror_forward_scans_across_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        let name = |i: usize| format!("k{i:03}");
+        engine.put(("t".into(), "z".into()), entry(1)).unwrap();
+        for i in 0..200 {
+            engine.put(key(&name(i)), entry(i as i64)).unwrap();
+        }
+        engine.flush_memtable().unwrap();
+        for i in (0..200).step_by(3) {
+            engine.put(key(&name(i)), entry(1000 + i as i64)).unwrap();
+        }
+        engine.flush_memtable().unwrap();
+        for i in (0..200).step_by(5) {
+            engine.delete(&key(&name(i))).unwrap();
+        }
+        engine.put(("v".into(), "a".into()), entry(2)).unwrap();
+
+        let ranges = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(name(10)), Bound::Excluded(name(150))),
+            (Bound::Excluded(name(63)), Bound::Included(name(64))),
+            (Bound::Included(name(199)), Bound::Unbounded),
+            (Bound::Excluded(name(199)), Bound::Unbounded),
+        ];
+        for range in ranges {
+            let mut forward: Vec<(String, Entry)> = engine.scan("u", range.clone()).map(|r| r.unwrap()).collect();
+            forward.reverse();
+            let backward: Vec<(String, Entry)> = engine.scan_rev("u", range.clone()).map(|r| r.unwrap()).collect();
+            assert_eq!(backward, forward, "{range:?}");
+        }
+    }
+
+    #[test]
+    fn a_short_reverse_scan_reads_only_the_last_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        for i in 0..(4 * INDEX_INTERVAL) {
+            engine.put(key(&format!("k{i:04}")), entry(i as i64)).unwrap();
+        }
+        engine.flush_memtable().unwrap();
+        let table = &engine.tables[0];
+        assert_eq!(table.index.len(), 4);
+
+        let mut iter = table.iter_back_from(&Bound::Unbounded);
+        let last: Vec<String> = iter.by_ref().take(3).map(|r| r.unwrap().0 .1).collect();
+        assert_eq!(last, ["k0255", "k0254", "k0253"]);
+        assert_eq!(iter.blocks, 3);
+    }
+
+    #[test]
+    fn tombstones_hide_older_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.put(key("a"), entry(1)).unwrap();
+        engine.put(key("b"), entry(2)).unwrap();
+        engine.flush_memtable().unwrap();
+        engine.delete(&key("a")).unwrap();
+
+        assert_eq!(engine.get(&key("a")).unwrap(), None);
+        assert_eq!(all(&engine), vec![("b".into(), 2)]);
+
+        // the tombstone keeps hiding the value once it is in a table of its own
+        engine.flush_memtable().unwrap();
+        assert_eq!(engine.get(&key("a")).unwrap(), None);
+        assert_eq!(all(&engine), vec![("b".into(), 2)]);
+        assert!(records(&engine).contains(&(key("a"), None)));
+    }
+
+    #[test]
+    fn compaction_merges_tables_and_drops_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        for i in 0..=MAX_TABLES as i64 {
+            engine.put(key(&format!("k{i:02}")), entry(i)).unwrap();
+            engine.put(key("shared"), entry(100 + i)).unwrap();
+            if i == 3 {
+                engine.delete(&key("k01")).unwrap();
+            }
+            engine.flush_memtable().unwrap();
+        }
+
+        assert_eq!(engine.tables.len(), 1, "more than MAX_TABLES tables are merged into one");
+        let records = records(&engine);
+        assert!(records.iter().all(|(_, e)| e.is_some()), "no tombstones survive a compaction");
+        assert!(!records.iter().any(|(k, _)| *k == key("k01")));
+        assert_eq!(engine.get(&key("shared")).unwrap(), Some(entry(100 + MAX_TABLES as i64)));
+        assert_eq!(all(&engine).len(), MAX_TABLES + 1);
+    }
+
+    #[test]
+    fn flushed_data_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.add_user("u".into());
+        engine.put(key("a"), entry(1)).unwrap();
+        engine.put(key("b"), entry(2)).unwrap();
+        engine.set_wal_seq(2);
+        engine.flush().unwrap();
+        engine.delete(&key("a")).unwrap();
+        engine.set_wal_seq(3);
+        engine.flush().unwrap();
+        // not flushed: the WAL would bring it back
+        engine.put(key("c"), entry(3)).unwrap();
+        drop(engine);
+
+        let engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.wal_seq(), 3);
+        assert!(engine.has_user("u"));
+        assert_eq!(all(&engine), vec![("b".into(), 2)]);
+    }
+
+    #[test]
+    fn a_full_memtable_is_written_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), 64).unwrap();
+        for i in 0..10 {
+            engine.put(key(&format!("k{i}")), entry(i)).unwrap();
+        }
+        assert!(!engine.tables.is_empty());
+        assert_eq!(all(&engine).len(), 10);
+    }
+
+    fn expiring(n: i64, at: u64) -> Entry {
+        Entry { expires_at: Some(at), ..entry(n) }
+    }
+
+    #[test]
+    fn purge_drops_expired_entries_from_tables_and_memtable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.put(key("a"), expiring(1, 10)).unwrap();
+        engine.put(key("b"), entry(2)).unwrap();
+        engine.put(key("c"), expiring(3, 30)).unwrap();
+        engine.flush_memtable().unwrap();
+        engine.put(key("d"), expiring(4, 10)).unwrap();
+        engine.put(key("e"), expiring(5, 30)).unwrap();
+
+        let mut purged = engine.purge_expired(20);
+        purged.sort();
+        assert_eq!(purged, vec![key("a"), key("d")]);
+        assert!(engine.purge_expired(20).is_empty(), "each key is reported once");
+
+        // one of three table records expired, so the memtable was flushed and the tables compacted
+        assert!(engine.memtable.is_empty());
+        assert_eq!(engine.tables.len(), 1);
+        assert!(!records(&engine).iter().any(|(k, _)| *k == key("a") || *k == key("d")));
+        assert_eq!(all(&engine), vec![("b".into(), 2), ("c".into(), 3), ("e".into(), 5)]);
+    }
+
+    #[test]
+    fn flush_writes_expired_entries_as_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.put(key("a"), entry(1)).unwrap();
+        engine.flush_memtable().unwrap();
+        engine.purge_expired(20);
+        // replayed from the WAL after the purge, with an expiry already past
+        engine.put(key("a"), expiring(2, 10)).unwrap();
+        engine.flush_memtable().unwrap();
+
+        assert!(records(&engine).contains(&(key("a"), None)));
+        assert_eq!(engine.get(&key("a")).unwrap(), None);
+        assert_eq!(engine.purge_expired(20), vec![key("a")]);
+    }
+
+    #[test]
+    fn keys_written_again_after_a_purge_are_not_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.purge_expired(20);
+        for i in 0..=MAX_TABLES as i64 {
+            engine.put(key(&format!("k{i}")), expiring(i, 10)).unwrap();
+            engine.flush_memtable().unwrap();
+        }
+        // k0 was dropped by the compaction and then written again
+        engine.put(key("k0"), entry(100)).unwrap();
+
+        let purged = engine.purge_expired(20);
+        assert_eq!(purged.len(), MAX_TABLES);
+        assert!(!purged.contains(&key("k0")));
+        assert_eq!(all(&engine), vec![("k0".into(), 100)]);
+    }
+
+    #[test]
+    fn corrupt_tables_and_manifests_are_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        engine.put(key("a"), entry(1)).unwrap();
+        engine.flush_memtable().unwrap();
+        let table = engine.tables[0].path.clone();
+        drop(engine);
+
+        // a flipped bit in the record fails its checksum when read
+        let mut bytes = fs::read(&table).unwrap();
+        bytes[20] ^= 1;
+        fs::write(&table, &bytes).unwrap();
+        let engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.get(&key("a")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(engine);
+
+        // and one in the meta when the table is opened
+        bytes[20] ^= 1;
+        let meta = bytes.len() - 20;
+        bytes[meta] ^= 1;
+        fs::write(&table, &bytes).unwrap();
+        assert!(LsmEngine::open(dir.path().to_path_buf(), u64::MAX).is_err());
+        bytes[meta] ^= 1;
+        fs::write(&table, &bytes).unwrap();
+
+        let manifest = dir.path().join("MANIFEST");
+        let mut bytes = fs::read(&manifest).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        fs::write(&manifest, &bytes).unwrap();
+        assert!(LsmEngine::open(dir.path().to_path_buf(), u64::MAX).is_err());
+    }
+
+    #[test]
+    fn a_legacy_manifest_is_read_and_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let legacy = Manifest { wal_seq: 7, users: BTreeSet::from(["u".to_string()]), ..Manifest::default() };
+        fs::write(dir.path().join("MANIFEST.json"), serde_json::to_vec(&legacy).unwrap()).unwrap();
+
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.wal_seq(), 7);
+        assert!(engine.has_user("u"));
+        engine.put(key("a"), entry(1)).unwrap();
+        engine.flush().unwrap();
+        assert!(!dir.path().join("MANIFEST.json").exists());
+        drop(engine);
+
+        let engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.wal_seq(), 7);
+        assert_eq!(all(&engine), vec![("a".into(), 1)]);
+    }
+
+    #[test]
+    fn version_1_tables_are_still_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bytes = Vec::new();
+        let mut index = Vec::new();
+        for record in [(key("a"), Some(entry(1))), (key("b"), None)] {
+            if index.is_empty() {
+                index.push((record.0.clone(), 0u64));
+            }
+            let body = bincode::serialize(&record).unwrap();
+            bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&body);
+        }
+        let data_end = bytes.len() as u64;
+        bytes.extend_from_slice(&bincode::serialize(&index).unwrap());
+        bytes.extend_from_slice(&data_end.to_le_bytes());
+        bytes.extend_from_slice(LEGACY_TABLE_MAGIC);
+        fs::write(table_path(dir.path(), 0), &bytes).unwrap();
+        let manifest = Manifest { next_table_id: 1, tables: vec![0], ..Manifest::default() };
+        fs::write(dir.path().join("MANIFEST.json"), serde_json::to_vec(&manifest).unwrap()).unwrap();
+
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.get(&key("a")).unwrap(), Some(entry(1)));
+        assert_eq!(engine.tables[0].get(&key("b")).unwrap(), Some(None));
+        engine.put(key("c"), entry(3)).unwrap();
+        engine.flush_memtable().unwrap();
+        assert_eq!(all(&engine), vec![("a".into(), 1), ("c".into(), 3)]);
+    }
+
+    #[test]
+    fn usage_follows_writes_and_purges_and_is_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        let other = ("u/ks".to_string(), "x".to_string());
+        engine.put(key("a"), entry(1)).unwrap();
+        engine.put(key("b"), expiring(2, 10)).unwrap();
+        engine.put(other.clone(), entry(3)).unwrap();
+        engine.flush_memtable().unwrap();
+        engine.put(key("a"), entry(10)).unwrap();
+        engine.put(key("a"), entry(11)).unwrap();
+        engine.delete(&key("c")).unwrap();
+        assert_eq!(engine.usage().owner("u").keys, 2);
+        assert_eq!(engine.usage().user("u").keys, 3);
+
+        engine.purge_expired(20);
+        let expected = Usage { keys: 1, bytes: entry_size(&key("a"), &entry(11)) };
+        assert_eq!(engine.usage().owner("u"), expected);
+        engine.delete(&other).unwrap();
+        assert_eq!(engine.usage().total(), expected);
+        engine.flush().unwrap();
+        drop(engine);
+
+        let engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.usage().total(), expected);
+
+        // a manifest from before the counters is counted from the tables
+        let mut manifest = read_manifest(dir.path()).unwrap();
+        manifest.usage = None;
+        fs::remove_file(dir.path().join("MANIFEST")).unwrap();
+        fs::write(dir.path().join("MANIFEST.json"), serde_json::to_vec(&manifest).unwrap()).unwrap();
+        let engine = LsmEngine::open(dir.path().to_path_buf(), u64::MAX).unwrap();
+        assert_eq!(engine.usage().total(), expected);
+        assert!(engine.is_dirty());
+    }
+}
@@ -0,0 +1,639 @@
+//! src/storage/memory.rs
+//!
+//! The in-memory engine: the whole `StoreState` lives in RAM.
+//!
+//! Persisting does not rewrite the whole state. Each `flush` writes only the keys and users that
+//! changed since the previous one, plus the indexed users and the quotas if they changed, to a delta file under `deltas/` in the engine directory (by
+//! default `~/.roc_server/`, see `crate::shard`), named after the
+//! WAL sequence number it brings the state up to. Changes that no WAL record stands for are only
+//! written along with the next one that does. Once `merge_after` deltas have piled up, the
+//! full state is written to `store_state.bin` and the deltas are deleted. Opening
+//! the engine loads `store_state.bin` and applies the deltas on top, in order.
+//!
+//! Every file is written to a temporary name, fsynced, renamed into place, and then its directory
+//! is fsynced, so a crash leaves either the old or the new file and never a torn one.
+//!
+//! Delta layout:
+//!
+//! ```text
+//! DELTA_MAGIC, u32 LE DELTA_FORMAT_VERSION, u32 LE CRC32 of the rest, bincode Delta
+//! ```
+
+use super::{EntryIter, Key, KeyRange, KeyedEntryIter, StorageEngine, UsageCounters};
+use crate::actors::logger_actor::sync_dir;
+use crate::actors::store_actor::{checked_body, decode_state, encode_state, Entry, StoreState};
+use crate::quota::Quota;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+const DELTA_MAGIC: &[u8; 4] = b"RDLT";
+const DELTA_FORMAT_VERSION: u32 = 5;
+
+/// The changes between two persisted states.
+#[derive(Debug, Serialize, Deserialize)]
+struct Delta {
+    /// The `wal_seq` of the state this delta applies to.
+    base_seq: u64,
+    /// The `wal_seq` after applying it.
+    wal_seq: u64,
+    users: Vec<String>,
+    removed_users: Vec<String>,
+    /// `None` removes the key.
+    changes: Vec<(Key, Option<Entry>)>,
+    /// The full set of indexed users, if it changed; `None` leaves it as it was.
+    indexed: Option<BTreeSet<String>>,
+    /// Every quota set by an admin, if they changed; `None` leaves them as they were.
+    quotas: Option<BTreeMap<String, Quota>>,
+}
+
+/// Delta format version 4, before quotas could be set per user.
+#[derive(Deserialize)]
+struct DeltaV4 {
+    base_seq: u64,
+    wal_seq: u64,
+    users: Vec<String>,
+    removed_users: Vec<String>,
+    changes: Vec<(Key, Option<Entry>)>,
+    indexed: Option<BTreeSet<String>>,
+}
+
+impl From<DeltaV4> for Delta {
+    fn from(old: DeltaV4) -> Self {
+        Self {
+            base_seq: old.base_seq,
+            wal_seq: old.wal_seq,
+            users: old.users,
+            removed_users: old.removed_users,
+            changes: old.changes,
+            indexed: old.indexed,
+            quotas: None,
+        }
+    }
+}
+
+/// Delta format version 3, before values could be indexed.
+#[derive(Deserialize)]
+struct DeltaV3 {
+    base_seq: u64,
+    wal_seq: u64,
+    users: Vec<String>,
+    removed_users: Vec<String>,
+    changes: Vec<(Key, Option<Entry>)>,
+}
+
+impl From<DeltaV3> for DeltaV4 {
+    fn from(old: DeltaV3) -> Self {
+        Self {
+            base_seq: old.base_seq,
+            wal_seq: old.wal_seq,
+            users: old.users,
+            removed_users: old.removed_users,
+            changes: old.changes,
+            indexed: None,
+        }
+    }
+}
+
+/// Delta format versions 1 and 2, before users could be removed.
+#[derive(Deserialize)]
+struct DeltaV2 {
+    base_seq: u64,
+    wal_seq: u64,
+    users: Vec<String>,
+    changes: Vec<(Key, Option<Entry>)>,
+}
+
+impl From<DeltaV2> for DeltaV3 {
+    fn from(old: DeltaV2) -> Self {
+        Self {
+            base_seq: old.base_seq,
+            wal_seq: old.wal_seq,
+            users: old.users,
+            removed_users: Vec::new(),
+            changes: old.changes,
+        }
+    }
+}
+
+pub struct MemoryEngine {
+    dir: PathBuf,
+    state: StoreState,
+    /// Keys written or deleted since the last flush.
+    dirty_keys: BTreeSet<Key>,
+    /// Users added or removed since the last flush.
+    dirty_users: BTreeSet<String>,
+    /// Whether the set of indexed users changed since the last flush.
+    dirty_indexed: bool,
+    /// Whether the quotas changed since the last flush.
+    dirty_quotas: bool,
+    /// `wal_seq` as of the last flush.
+    persisted_seq: u64,
+    /// Delta files written since `store_state.bin` was last rewritten.
+    deltas: Vec<PathBuf>,
+    /// Rewrite `store_state.bin` once this many deltas exist.
+    merge_after: usize,
+    /// The next flush must write the full state, e.g. after `restore`.
+    needs_merge: bool,
+}
+
+impl MemoryEngine {
+    /// Loads `store_state.bin` plus any deltas, starting empty if there is no store file yet.
+    ///
+    /// A store file or delta that fails its checksum or cannot be decoded is an `InvalidData`
+    /// error. Deltas that do not follow on from the state loaded so far are ignored; the WAL still
+    /// holds what they contained.
+    pub fn open(dir: PathBuf, merge_after: usize) -> io::Result<Self> {
+        fs::create_dir_all(deltas_dir(&dir))?;
+        let path = store_path(&dir);
+        let mut state = match fs::read(&path) {
+            Ok(bytes) => decode_state(&bytes).map_err(|e| corrupt(&path, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => StoreState::default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut deltas = Vec::new();
+        let mut needs_merge = false;
+        for path in delta_paths(&dir) {
+            let delta = decode_delta(&fs::read(&path)?).map_err(|e| corrupt(&path, e))?;
+            if delta.wal_seq <= state.wal_seq {
+                // left behind by a merge that was interrupted before the deltas were deleted
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if delta.base_seq != state.wal_seq {
+                eprintln!(
+                    "Ignoring {} onwards: it starts at wal_seq {}, the store is at {}",
+                    path.display(),
+                    delta.base_seq,
+                    state.wal_seq
+                );
+                needs_merge = true;
+                break;
+            }
+            apply_delta(&mut state, delta);
+            deltas.push(path);
+        }
+
+        Ok(Self {
+            dir,
+            persisted_seq: state.wal_seq,
+            state,
+            dirty_keys: BTreeSet::new(),
+            dirty_users: BTreeSet::new(),
+            dirty_indexed: false,
+            dirty_quotas: false,
+            deltas,
+            merge_after: merge_after.max(1),
+            // replace the unusable deltas before new ones are written after them
+            needs_merge,
+        })
+    }
+
+    /// An empty engine that lives in memory only, for working on a state outside of the store
+    /// actor (e.g. replaying the WAL into a snapshot). It must never be flushed.
+    pub fn detached() -> Self {
+        Self {
+            dir: PathBuf::new(),
+            state: StoreState::default(),
+            dirty_keys: BTreeSet::new(),
+            dirty_users: BTreeSet::new(),
+            dirty_indexed: false,
+            dirty_quotas: false,
+            persisted_seq: 0,
+            deltas: Vec::new(),
+            merge_after: 1,
+            needs_merge: false,
+        }
+    }
+
+    fn write_delta(&mut self) -> io::Result<()> {
+        let delta = Delta {
+            base_seq: self.persisted_seq,
+            wal_seq: self.state.wal_seq,
+            users: self.dirty_users.iter().filter(|u| self.state.users.contains(*u)).cloned().collect(),
+            removed_users: self.dirty_users.iter().filter(|u| !self.state.users.contains(*u)).cloned().collect(),
+            changes: self
+                .dirty_keys
+                .iter()
+                .map(|k| (k.clone(), self.state.kv.get(k).cloned()))
+                .collect(),
+            indexed: self.dirty_indexed.then(|| self.state.indexed.clone()),
+            quotas: self.dirty_quotas.then(|| self.state.quotas.clone()),
+        };
+        let body = bincode::serialize(&delta).map_err(io::Error::other)?;
+        let mut bytes = Vec::with_capacity(12 + body.len());
+        bytes.extend_from_slice(DELTA_MAGIC);
+        bytes.extend_from_slice(&DELTA_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        bytes.extend_from_slice(&body);
+
+        let path = deltas_dir(&self.dir).join(format!("delta-{:020}.bin", delta.wal_seq));
+        write_durably(&path, &bytes)?;
+        self.deltas.push(path);
+        Ok(())
+    }
+
+    /// Rewrites `store_state.bin` from the full state and drops the deltas it now contains.
+    fn merge(&mut self) -> io::Result<()> {
+        let bytes = encode_state(&self.state).map_err(io::Error::other)?;
+        write_durably(&store_path(&self.dir), &bytes)?;
+        // deltas left over from before a `restore` must go too, or `open` would skip past them
+        for path in self.deltas.drain(..).chain(delta_paths(&self.dir)) {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        sync_dir(&deltas_dir(&self.dir))
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &Key) -> io::Result<Option<Entry>> {
+        Ok(self.state.kv.get(key).cloned())
+    }
+
+    fn scan<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        let range = KeyRange::user(user_id, range);
+        let end = range.clone();
+        let iter = self
+            .state
+            .kv
+            .range(range.first_key()..)
+            .skip_while(move |(k, _)| range.is_before(k))
+            .take_while(move |(k, _)| !end.is_after(k))
+            .map(|((_, k), e)| Ok((k.clone(), e.clone())));
+        Box::new(iter)
+    }
+
+    fn scan_rev<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        let range = KeyRange::user(user_id, range);
+        let start = range.clone();
+        let iter = self
+            .state
+            .kv
+            .range((Bound::Unbounded, range.upper_bound()))
+            .rev()
+            .skip_while(move |(k, _)| range.is_after(k))
+            .take_while(move |(k, _)| !start.is_before(k))
+            .map(|((_, k), e)| Ok((k.clone(), e.clone())));
+        Box::new(iter)
+    }
+
+    fn scan_all(&self) -> KeyedEntryIter<'_> {
+        Box::new(self.state.kv.iter().map(|(k, e)| Ok((k.clone(), e.clone()))))
+    }
+
+    fn put(&mut self, key: Key, entry: Entry) -> io::Result<()> {
+        self.dirty_keys.insert(key.clone());
+        self.state.insert_entry(key, entry);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &Key) -> io::Result<()> {
+        if self.state.remove_entry(key).is_some() {
+            self.dirty_keys.insert(key.clone());
+        }
+        Ok(())
+    }
+
+    fn has_user(&self, user_id: &str) -> bool {
+        self.state.users.contains(user_id)
+    }
+
+    fn add_user(&mut self, user_id: String) {
+        if self.state.users.insert(user_id.clone()) {
+            self.dirty_users.insert(user_id);
+        }
+    }
+
+    fn remove_user(&mut self, user_id: &str) {
+        if self.state.users.remove(user_id) {
+            self.dirty_users.insert(user_id.to_string());
+        }
+    }
+
+    fn users_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.state.users.range(prefix.to_string()..).take_while(|u| u.starts_with(prefix)).cloned().collect()
+    }
+
+    fn indexed_users(&self) -> Vec<String> {
+        self.state.indexed.iter().cloned().collect()
+    }
+
+    fn set_indexed(&mut self, user_id: &str, indexed: bool) -> io::Result<()> {
+        let changed = if indexed {
+            self.state.indexed.insert(user_id.to_string())
+        } else {
+            self.state.indexed.remove(user_id)
+        };
+        self.dirty_indexed |= changed;
+        Ok(())
+    }
+
+    fn quota(&self, user_id: &str) -> Option<Quota> {
+        self.state.quotas.get(user_id).copied()
+    }
+
+    fn set_quota(&mut self, user_id: &str, quota: Option<Quota>) -> io::Result<()> {
+        let old = match quota {
+            Some(quota) => self.state.quotas.insert(user_id.to_string(), quota),
+            None => self.state.quotas.remove(user_id),
+        };
+        self.dirty_quotas |= old != quota;
+        Ok(())
+    }
+
+    fn wal_seq(&self) -> u64 {
+        self.state.wal_seq
+    }
+
+    fn set_wal_seq(&mut self, seq: u64) {
+        self.state.wal_seq = seq;
+    }
+
+    fn purge_expired(&mut self, now: u64) -> Vec<Key> {
+        // Purged keys are not marked dirty: the persisted copies carry the same expiry and get
+        // purged again after a restart.
+        self.state.purge_expired(now)
+    }
+
+    fn usage(&self) -> &UsageCounters {
+        self.state.usage()
+    }
+
+    fn is_dirty(&self) -> bool {
+        // Changes made without a WAL record (a lazily reclaimed key, a user whose `AddUser` could
+        // not be logged) wait for the next delta that moves `wal_seq`: a delta that does not
+        // would carry the name of the one before it.
+        self.needs_merge || self.state.wal_seq != self.persisted_seq
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        if !self.is_dirty() {
+            return Ok(());
+        }
+        let res = if self.needs_merge || self.deltas.len() + 1 >= self.merge_after {
+            self.merge()
+        } else {
+            self.write_delta()
+        };
+        res.map_err(|e| e.to_string())?;
+
+        self.dirty_keys.clear();
+        self.dirty_users.clear();
+        self.dirty_indexed = false;
+        self.dirty_quotas = false;
+        self.persisted_seq = self.state.wal_seq;
+        self.needs_merge = false;
+        Ok(())
+    }
+
+    fn export(&self) -> io::Result<StoreState> {
+        Ok(self.state.clone())
+    }
+
+    fn restore(&mut self, state: StoreState) -> io::Result<()> {
+        self.state = state;
+        self.dirty_keys.clear();
+        self.dirty_users.clear();
+        self.dirty_indexed = false;
+        self.dirty_quotas = false;
+        self.needs_merge = true;
+        Ok(())
+    }
+}
+
+fn decode_delta(bytes: &[u8]) -> Result<Delta, String> {
+    let rest = bytes.strip_prefix(DELTA_MAGIC).ok_or("not a delta file")?;
+    let (version, body) = rest.split_at_checked(4).ok_or("truncated delta header")?;
+    match u32::from_le_bytes(version.try_into().expect("4 bytes")) {
+        DELTA_FORMAT_VERSION => bincode::deserialize(checked_body(body)?).map_err(|e| e.to_string()),
+        4 => bincode::deserialize::<DeltaV4>(checked_body(body)?).map(Delta::from).map_err(|e| e.to_string()),
+        3 => bincode::deserialize::<DeltaV3>(checked_body(body)?)
+            .map(|v3| DeltaV4::from(v3).into())
+            .map_err(|e| e.to_string()),
+        2 => bincode::deserialize::<DeltaV2>(checked_body(body)?)
+            .map(|v2| DeltaV4::from(DeltaV3::from(v2)).into())
+            .map_err(|e| e.to_string()),
+        // version 1 had no checksum
+        1 => bincode::deserialize::<DeltaV2>(body)
+            .map(|v2| DeltaV4::from(DeltaV3::from(v2)).into())
+            .map_err(|e| e.to_string()),
+        other => Err(format!("unsupported delta format version {other}")),
+    }
+}
+
+fn corrupt(path: &Path, e: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display()))
+}
+
+fn apply_delta(state: &mut StoreState, delta: Delta) {
+    state.users.extend(delta.users);
+    for user_id in &delta.removed_users {
+        state.users.remove(user_id);
+    }
+    if let Some(indexed) = delta.indexed {
+        state.indexed = indexed;
+    }
+    if let Some(quotas) = delta.quotas {
+        state.quotas = quotas;
+    }
+    for (key, entry) in delta.changes {
+        match entry {
+            Some(entry) => state.insert_entry(key, entry),
+            None => {
+                state.remove_entry(&key);
+            }
+        }
+    }
+    state.wal_seq = delta.wal_seq;
+}
+
+/// Moves `store_state.bin` and the deltas in `dir` into `dest`, leaving no store behind.
+pub fn quarantine(dir: &Path, dest: &Path) -> io::Result<()> {
+    let path = store_path(dir);
+    if path.exists() {
+        fs::rename(&path, dest.join("store_state.bin"))?;
+    }
+    if deltas_dir(dir).exists() {
+        fs::rename(deltas_dir(dir), dest.join("deltas"))?;
+    }
+    sync_dir(dir)
+}
+
+/// Writes `bytes` to `path` so that it survives a crash: temp file, fsync, rename, fsync the
+/// directory.
+fn write_durably(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?; // atomic replace
+    sync_dir(path.parent().expect("store files live in a directory"))
+}
+
+fn store_path(dir: &Path) -> PathBuf {
+    dir.join("store_state.bin")
+}
+
+fn deltas_dir(dir: &Path) -> PathBuf {
+    dir.join("deltas")
+}
+
+/// Delta files on disk, oldest first. The zero-padded names sort by sequence number.
+fn delta_paths(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(deltas_dir(dir)) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("delta-") && n.ends_with(".bin"))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    fn key(k: &str) -> Key {
+        ("u".to_string(), k.to_string())
+    }
+
+    /// Puts `k` the way the store actor applies a WAL record `seq`.
+    fn put(engine: &mut MemoryEngine, seq: u64, k: &str) {
+        engine.put(key(k), Entry { value: Value::Int(seq as i64), expires_at: None, version: seq }).unwrap();
+        engine.set_wal_seq(seq);
+    }
+
+    fn keys(engine: &MemoryEngine) -> Vec<&str> {
+        engine.state.kv.keys().map(|(_, k)| k.as_str()).collect()
+    }
+
+    #[test]
+    fn flushes_write_deltas_that_are_applied_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        engine.add_user("u".to_string());
+        put(&mut engine, 1, "a");
+        engine.flush().unwrap();
+        put(&mut engine, 2, "b");
+        engine.delete(&key("a")).unwrap();
+        engine.set_wal_seq(3);
+        engine.flush().unwrap();
+        assert!(!engine.is_dirty());
+        assert_eq!(delta_paths(dir.path()).len(), 2);
+        assert!(!store_path(dir.path()).exists());
+
+        let engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        assert_eq!(engine.wal_seq(), 3);
+        assert_eq!(keys(&engine), ["b"]);
+        assert!(engine.has_user("u"));
+        assert_eq!(engine.deltas.len(), 2);
+    }
+
+    #[test]
+    fn enough_deltas_are_merged_into_the_store_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MemoryEngine::open(dir.path().to_path_buf(), 3).unwrap();
+        for (seq, k) in [(1, "a"), (2, "b"), (3, "c")] {
+            put(&mut engine, seq, k);
+            engine.flush().unwrap();
+        }
+        assert!(delta_paths(dir.path()).is_empty());
+        assert!(store_path(dir.path()).exists());
+        put(&mut engine, 4, "d");
+        engine.flush().unwrap();
+
+        let engine = MemoryEngine::open(dir.path().to_path_buf(), 3).unwrap();
+        assert_eq!(engine.wal_seq(), 4);
+        assert_eq!(keys(&engine), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn deltas_left_behind_by_an_interrupted_merge_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        put(&mut engine, 1, "a");
+        engine.flush().unwrap();
+        put(&mut engine, 2, "b");
+        // the store file is written, the deltas are not deleted yet
+        fs::write(store_path(dir.path()), encode_state(&engine.state).unwrap()).unwrap();
+
+        let engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        assert_eq!(engine.wal_seq(), 2);
+        assert_eq!(keys(&engine), ["a", "b"]);
+        assert!(delta_paths(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn changes_without_a_wal_record_do_not_break_the_chain_of_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        put(&mut engine, 1, "k1");
+        engine.flush().unwrap();
+        put(&mut engine, 2, "k2");
+        engine.flush().unwrap();
+        // e.g. a lazily reclaimed expired key
+        engine.delete(&key("k1")).unwrap();
+        assert!(!engine.is_dirty());
+        engine.flush().unwrap();
+        assert_eq!(delta_paths(dir.path()).len(), 2);
+
+        let reopened = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        assert_eq!(reopened.wal_seq(), 2);
+        assert_eq!(keys(&reopened), ["k1", "k2"]);
+
+        // the change goes out with the next delta
+        put(&mut engine, 3, "k3");
+        engine.flush().unwrap();
+        let reopened = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        assert_eq!(reopened.wal_seq(), 3);
+        assert_eq!(keys(&reopened), ["k2", "k3"]);
+    }
+
+    #[test]
+    fn a_corrupt_delta_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        put(&mut engine, 1, "a");
+        engine.flush().unwrap();
+        let path = delta_paths(dir.path()).pop().unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let err = MemoryEngine::open(dir.path().to_path_buf(), 16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn a_restored_state_replaces_the_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        put(&mut engine, 1, "a");
+        engine.flush().unwrap();
+        let mut state = StoreState::default();
+        state.insert_entry(key("z"), Entry { value: Value::Int(0), expires_at: None, version: 5 });
+        state.wal_seq = 5;
+        engine.restore(state).unwrap();
+        engine.flush().unwrap();
+        assert!(delta_paths(dir.path()).is_empty());
+
+        let engine = MemoryEngine::open(dir.path().to_path_buf(), 16).unwrap();
+        assert_eq!(engine.wal_seq(), 5);
+        assert_eq!(keys(&engine), ["z"]);
+    }
+}
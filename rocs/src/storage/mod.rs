@@ -0,0 +1,304 @@
+//! src/storage/mod.rs
+//!
+//! Storage engines behind the store actor.
+//!
+//! The store actor decides *what* to write (it owns the WAL, expiry rules, versions and command
+//! semantics); a `StorageEngine` decides *where* the entries live. Two engines exist:
+//!
+//! - `memory`: the whole keyspace in a `BTreeMap`, persisted as `store_state.bin` plus delta files
+//!   holding the keys changed since.
+//! - `lsm`: a log-structured merge tree under `lsm/` that only keeps recent writes
+//!   and a sparse index in memory.
+//!
+//! The engine is picked with `RocsConfig::storage_engine` (`ROCS_STORAGE_ENGINE`).
+
+pub mod memory;
+pub mod lsm;
+pub mod index;
+pub mod bounded;
+
+use crate::actors::logger_actor::now_millis;
+use crate::actors::store_actor::{Entry, StoreState};
+use crate::config::RocsConfig;
+use crate::keyspace;
+use crate::quota::Quota;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Every entry is stored under `(user_id, key)`.
+pub type Key = (String, String);
+
+/// Entries in key order, as returned by `StorageEngine::scan`.
+pub type EntryIter<'a> = Box<dyn Iterator<Item = io::Result<(String, Entry)>> + 'a>;
+
+/// Entries of every user in key order, as returned by `StorageEngine::scan_all`.
+pub type KeyedEntryIter<'a> = Box<dyn Iterator<Item = io::Result<(Key, Entry)>> + 'a>;
+
+/// Estimated memory used by `entry` under `key`: the length of the owner id and key plus the
+/// encoded size of the entry.
+pub fn entry_size(key: &Key, entry: &Entry) -> u64 {
+    let encoded = bincode::serialized_size(entry).unwrap_or(0);
+    (key.0.len() + key.1.len()) as u64 + encoded
+}
+
+/// Live keys and their estimated bytes (see `entry_size`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// What every owner id holds, kept up to date by the engine as entries are written, deleted and
+/// purged, so that quotas and `Stats` never have to go through the entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageCounters {
+    owners: BTreeMap<String, Usage>,
+    total: Usage,
+}
+
+impl UsageCounters {
+    /// Counts `entry` under `key` replacing `old`, either of them `None` for no entry.
+    pub fn replace(&mut self, key: &Key, old: Option<&Entry>, new: Option<&Entry>) {
+        if old.is_none() && new.is_none() {
+            return;
+        }
+        let owner = self.owners.entry(key.0.clone()).or_default();
+        for usage in [&mut *owner, &mut self.total] {
+            if let Some(old) = old {
+                usage.keys -= 1;
+                usage.bytes -= entry_size(key, old);
+            }
+            if let Some(new) = new {
+                usage.keys += 1;
+                usage.bytes += entry_size(key, new);
+            }
+        }
+        if owner.keys == 0 {
+            self.owners.remove(&key.0);
+        }
+    }
+
+    /// What `owner_id` holds.
+    pub fn owner(&self, owner_id: &str) -> Usage {
+        self.owners.get(owner_id).copied().unwrap_or_default()
+    }
+
+    /// What `user_id` holds across all of their keyspaces.
+    pub fn user(&self, user_id: &str) -> Usage {
+        let prefix = keyspace::owner_prefix(user_id);
+        self.owners
+            .range(prefix.clone()..)
+            .take_while(|(owner, _)| owner.starts_with(&prefix))
+            .fold(self.owner(user_id), |sum, (_, usage)| Usage {
+                keys: sum.keys + usage.keys,
+                bytes: sum.bytes + usage.bytes,
+            })
+    }
+
+    /// What the whole engine holds.
+    pub fn total(&self) -> Usage {
+        self.total
+    }
+}
+
+/// Which `StorageEngine` the server runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageEngineKind {
+    Memory,
+    Lsm,
+}
+
+impl FromStr for StorageEngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(StorageEngineKind::Memory),
+            "lsm" => Ok(StorageEngineKind::Lsm),
+            other => Err(format!("unknown storage engine {other:?} (expected \"memory\" or \"lsm\")")),
+        }
+    }
+}
+
+impl fmt::Display for StorageEngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StorageEngineKind::Memory => "memory",
+            StorageEngineKind::Lsm => "lsm",
+        })
+    }
+}
+
+/// Where the store actor keeps its entries, users and WAL position.
+///
+/// Engines store entries as they are given, including expired ones; filtering by expiry is up
+/// to the caller. Writes do not need to be durable until `flush`, because everything written
+/// through the store actor is in the WAL first. After `flush`, the engine must come back with
+/// every change up to `wal_seq()` when it is opened again.
+pub trait StorageEngine: Send {
+    /// The entry under `key`, expired or not.
+    fn get(&self, key: &Key) -> io::Result<Option<Entry>>;
+
+    /// The entries of `user_id` whose key lies in `range`, in key order.
+    fn scan<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a>;
+
+    /// The entries of `user_id` whose key lies in `range`, in reverse key order.
+    ///
+    /// The default collects the forward scan first; engines that can seek backwards override it.
+    fn scan_rev<'a>(&'a self, user_id: &str, range: (Bound<String>, Bound<String>)) -> EntryIter<'a> {
+        match self.scan(user_id, range).collect::<io::Result<Vec<_>>>() {
+            Ok(entries) => Box::new(entries.into_iter().rev().map(Ok)),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    /// Every entry, including those of owners the engine has no user for, in key order.
+    fn scan_all(&self) -> KeyedEntryIter<'_>;
+
+    fn put(&mut self, key: Key, entry: Entry) -> io::Result<()>;
+
+    fn delete(&mut self, key: &Key) -> io::Result<()>;
+
+    fn has_user(&self, user_id: &str) -> bool;
+
+    fn add_user(&mut self, user_id: String);
+
+    /// Forgets `user_id`. Its entries are not touched.
+    fn remove_user(&mut self, user_id: &str);
+
+    /// Every user id starting with `prefix`, in order.
+    fn users_with_prefix(&self, prefix: &str) -> Vec<String>;
+
+    /// Every user whose values are indexed, in order.
+    fn indexed_users(&self) -> Vec<String>;
+
+    /// Records whether the values of `user_id` are indexed. The engine only remembers the
+    /// setting; the index itself is kept by `index::Indexed`.
+    fn set_indexed(&mut self, user_id: &str, indexed: bool) -> io::Result<()>;
+
+    /// The quota an admin set for `user_id`, if any.
+    fn quota(&self, user_id: &str) -> Option<Quota>;
+
+    /// Records the quota an admin set for `user_id`; `None` goes back to the default quota.
+    fn set_quota(&mut self, user_id: &str, quota: Option<Quota>) -> io::Result<()>;
+
+    /// Sequence number of the last WAL record applied to this engine.
+    fn wal_seq(&self) -> u64;
+
+    fn set_wal_seq(&mut self, seq: u64);
+
+    /// Drops entries whose expiry is at or before `now`, if the engine can do so cheaply.
+    /// Returns the keys removed, including expired keys that background work such as compaction
+    /// dropped since the last call and that have not been written again. Entries expiring after
+    /// `now` must be kept, also by background work, as a read snapshot may still see them.
+    fn purge_expired(&mut self, now: u64) -> Vec<Key>;
+
+    /// What every owner holds, counting expired entries until they are purged.
+    fn usage(&self) -> &UsageCounters;
+
+    /// Whether anything changed since the last `flush`.
+    fn is_dirty(&self) -> bool;
+
+    /// Makes everything applied so far durable. Does nothing if the engine is not dirty.
+    fn flush(&mut self) -> Result<(), String>;
+
+    /// A full in-memory copy of the engine's contents, e.g. for a snapshot.
+    fn export(&self) -> io::Result<StoreState>;
+
+    /// Replaces the engine's contents with `state`.
+    fn restore(&mut self, state: StoreState) -> io::Result<()>;
+}
+
+/// Opens the engine selected in `config`, keeping its files in `dir`.
+///
+/// Files that fail their checksum or cannot be decoded are reported as `io::ErrorKind::InvalidData`.
+pub fn open_engine(config: &RocsConfig, dir: &Path) -> io::Result<Box<dyn StorageEngine>> {
+    Ok(match config.storage_engine {
+        StorageEngineKind::Memory => Box::new(memory::MemoryEngine::open(dir.to_path_buf(), config.delta_merge_count)?),
+        StorageEngineKind::Lsm => Box::new(lsm::LsmEngine::open(dir.join("lsm"), config.lsm_memtable_bytes)?),
+    })
+}
+
+/// Moves the files of engine `kind` in `dir` to `dir/corrupt/<kind>-<millis>/`, so that a fresh
+/// engine can be opened while the damaged files are kept for inspection. Returns the directory.
+pub fn quarantine(kind: StorageEngineKind, dir: &Path) -> io::Result<PathBuf> {
+    let dest = dir.join("corrupt").join(format!("{kind}-{}", now_millis()));
+    fs::create_dir_all(&dest)?;
+    match kind {
+        StorageEngineKind::Memory => memory::quarantine(dir, &dest)?,
+        StorageEngineKind::Lsm => lsm::quarantine(&dir.join("lsm"), &dest)?,
+    }
+    Ok(dest)
+}
+
+/// The keys of one user (or of everyone) between two bounds.
+#[derive(Debug, Clone)]
+pub(crate) struct KeyRange {
+    /// `None` covers every user, in which case the bounds are ignored.
+    pub user_id: Option<String>,
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+}
+
+impl KeyRange {
+    pub fn all() -> Self {
+        Self { user_id: None, start: Bound::Unbounded, end: Bound::Unbounded }
+    }
+
+    pub fn user(user_id: &str, (start, end): (Bound<String>, Bound<String>)) -> Self {
+        Self { user_id: Some(user_id.to_string()), start, end }
+    }
+
+    /// The smallest key that can be in the range.
+    pub fn first_key(&self) -> Key {
+        match (&self.user_id, &self.start) {
+            (None, _) => (String::new(), String::new()),
+            (Some(u), Bound::Included(k) | Bound::Excluded(k)) => (u.clone(), k.clone()),
+            (Some(u), Bound::Unbounded) => (u.clone(), String::new()),
+        }
+    }
+
+    /// The bound above every key in the range, for seeking backwards.
+    pub fn upper_bound(&self) -> Bound<Key> {
+        match (&self.user_id, &self.end) {
+            (None, _) => Bound::Unbounded,
+            (Some(u), Bound::Included(k) | Bound::Excluded(k)) => Bound::Included((u.clone(), k.clone())),
+            // "\0" is the smallest suffix, so no key of a user after `u` sorts below this one
+            (Some(u), Bound::Unbounded) => Bound::Excluded((format!("{u}\0"), String::new())),
+        }
+    }
+
+    /// `key` sorts before every key in the range.
+    pub fn is_before(&self, key: &Key) -> bool {
+        let Some(user_id) = &self.user_id else { return false };
+        match key.0.cmp(user_id) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Greater => false,
+            std::cmp::Ordering::Equal => match &self.start {
+                Bound::Included(s) => key.1 < *s,
+                Bound::Excluded(s) => key.1 <= *s,
+                Bound::Unbounded => false,
+            },
+        }
+    }
+
+    /// `key` sorts after every key in the range.
+    pub fn is_after(&self, key: &Key) -> bool {
+        let Some(user_id) = &self.user_id else { return false };
+        match key.0.cmp(user_id) {
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Equal => match &self.end {
+                Bound::Included(e) => key.1 > *e,
+                Bound::Excluded(e) => key.1 >= *e,
+                Bound::Unbounded => false,
+            },
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! src/value.rs
+//!
+//! The value type stored under every key.
+//!
+//! Values travel over the wire as externally tagged JSON (`{"Int":5}`, `{"Str":"alice"}`,
+//! `{"List":[{"Int":1},{"Float":2.5}]}`) and are stored with bincode in the store file.
+
+use serde::{Serialize, Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+/// A value together with the version of the entry holding it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Versioned {
+    pub value: Value,
+    pub version: u64,
+}
+
+impl Value {
+    /// Converts a value stored by a server that only knew `usize` values.
+    ///
+    /// Counters beyond `i64::MAX` cannot be represented exactly and become floats.
+    pub fn from_legacy(v: usize) -> Self {
+        i64::try_from(v).map(Value::Int).unwrap_or(Value::Float(v as f64))
+    }
+
+    /// Short name of the variant, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::Bytes(_) => "bytes",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+}
+
+/// Deserializes a `Value`, also accepting the bare integers written before values were typed.
+///
+/// Only usable with self-describing formats (the JSON WAL), not with bincode.
+pub fn deserialize_with_legacy<'de, D>(deserializer: D) -> Result<Value, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MaybeLegacy {
+        Legacy(usize),
+        Typed(Value),
+    }
+
+    Ok(match MaybeLegacy::deserialize(deserializer)? {
+        MaybeLegacy::Legacy(v) => Value::from_legacy(v),
+        MaybeLegacy::Typed(v) => v,
+    })
+}
@@ -0,0 +1,138 @@
+//! src/watch.rs
+//!
+//! Watches: live notifications of the changes made to a key, a prefix or a range.
+//!
+//! A `Watch` command registers a watch with the store actor of the user's shard and returns its
+//! id. The connection handler then opens a QUIC unidirectional stream to the client, writes a
+//! `{"watch_id":N}` line and then one JSON `WatchEvent` per line, for every change the store
+//! actor makes to a watched key: writes, deletes, commits of transactions (one event per key)
+//! and evictions. Keys that expire are not reported, as no write removes them.
+//!
+//! Watches belong to the keyspace that was current when they were created. They end when the
+//! client sends `Unwatch`, when the keyspace is dropped, when the connection goes away, or when
+//! the client falls `WATCH_BUFFER` events behind; in every case the server finishes the stream.
+//! The store actor never waits on a watcher.
+
+use crate::value::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+/// Events a watcher may fall behind by before its watch is dropped.
+pub const WATCH_BUFFER: usize = 1024;
+
+/// The keys a watch covers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WatchScope {
+    Key(String),
+    /// The keys starting with the prefix.
+    Prefix(String),
+    /// The keys from `start` to `end`, inclusive.
+    Range { start: String, end: String },
+}
+
+impl WatchScope {
+    pub fn contains(&self, key: &str) -> bool {
+        match self {
+            WatchScope::Key(k) => key == k,
+            WatchScope::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            WatchScope::Range { start, end } => start.as_str() <= key && key <= end.as_str(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ChangeKind {
+    /// The key did not exist before.
+    Set,
+    /// The key existed and got a new value or expiry.
+    Update,
+    Delete,
+}
+
+/// One change to a watched key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub key: String,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+    /// Sequence number of the WAL record that made the change, i.e. the new version of the key.
+    pub version: u64,
+}
+
+impl WatchEvent {
+    pub fn new(key: String, old: Option<Value>, new: Option<Value>, version: u64) -> Self {
+        let kind = match (&old, &new) {
+            (None, _) => ChangeKind::Set,
+            (Some(_), Some(_)) => ChangeKind::Update,
+            (Some(_), None) => ChangeKind::Delete,
+        };
+        Self { key, kind, old, new, version }
+    }
+}
+
+pub type EventSender = mpsc::Sender<WatchEvent>;
+
+struct Watch {
+    id: u64,
+    scope: WatchScope,
+    events: EventSender,
+}
+
+/// The watches of one shard, by owner id.
+#[derive(Default)]
+pub struct Watches {
+    next_id: u64,
+    by_owner: HashMap<String, Vec<Watch>>,
+}
+
+impl Watches {
+    /// Registers a watch on the keys of `owner` in `scope`. Returns its id.
+    pub fn add(&mut self, owner: String, scope: WatchScope, events: EventSender) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.by_owner.entry(owner).or_default().push(Watch { id, scope, events });
+        id
+    }
+
+    /// Drops watch `id` if it belongs to `user_id` (in any of their keyspaces). Dropping the
+    /// sender ends the event stream. Returns whether there was such a watch.
+    pub fn remove(&mut self, user_id: &str, id: u64) -> bool {
+        let mut found = false;
+        self.by_owner.retain(|owner, watches| {
+            if crate::keyspace::user_of(owner) == user_id {
+                let before = watches.len();
+                watches.retain(|w| w.id != id);
+                found |= watches.len() != before;
+            }
+            !watches.is_empty()
+        });
+        found
+    }
+
+    /// Ends every watch on the keys of `owner`, e.g. when its keyspace is dropped.
+    pub fn remove_owner(&mut self, owner: &str) {
+        self.by_owner.remove(owner);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_owner.is_empty()
+    }
+
+    pub fn is_watched(&self, owner: &str) -> bool {
+        self.by_owner.contains_key(owner)
+    }
+
+    /// Hands `event` on a key of `owner` to every watch covering the key, dropping the watches
+    /// whose client went away or fell too far behind.
+    pub fn publish(&mut self, owner: &str, event: &WatchEvent) {
+        let Some(watches) = self.by_owner.get_mut(owner) else { return };
+        watches.retain(|w| !w.scope.contains(&event.key) || w.events.try_send(event.clone()).is_ok());
+        if watches.is_empty() {
+            self.by_owner.remove(owner);
+        }
+    }
+}
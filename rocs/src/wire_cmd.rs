@@ -1,23 +1,190 @@
 use serde::{Deserialize, Serialize};
-use crate::command::{Command, UserId};
-use tokio::sync::oneshot;
+use crate::command::{Command, Expiry, UserId, ValueFilter};
+use crate::value::{Value, Versioned};
+use crate::error::StoreError;
+use crate::page::Page;
+use crate::quota::{Quota, UserInfo};
+use crate::storage::bounded::MemoryStats;
+use crate::aggregate::{Aggregate, KeyScope};
+use crate::watch::{WatchEvent, WatchScope, WATCH_BUFFER};
+use crate::actors::pubsub_actor::Subscribed;
+use crate::cdc::{CdcEvent, CDC_BUFFER};
+use crate::mvcc::ReadSnapshot;
+use std::ops::Bound;
+use tokio::sync::{mpsc, oneshot};
 
-/// The wire-format for user-accessible commands. Only user commands included.
+/// The wire-format for user-accessible commands. Only user commands included,
+/// plus the admin commands that have no user attached (`Snapshot`, `ClearWal`, `Stats`, `SetQuota`,
+/// `Cdc`). Admin commands carry the server's admin token, see `WireCommand::authorize`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "command", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum WireCommand {
     Hi { user_id: Option<UserId> },
     Ping { user_id: UserId },
-    Set { user_id: UserId, key: String, value: usize },
-    Get { user_id: UserId, key: String },
+    Set {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    Get {
+        user_id: UserId,
+        key: String,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
     Del { user_id: UserId, key: String },
-    Update { user_id: UserId, key: String, value: usize },
-    Range { user_id: UserId, start: String, end: String },
-    List { user_id: UserId },
+    MGet {
+        user_id: UserId,
+        keys: Vec<String>,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
+    MSet { user_id: UserId, pairs: Vec<(String, Value)> },
+    MDel { user_id: UserId, keys: Vec<String> },
+    Cas {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        version: u64,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    SetNx {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    DelIfVersion { user_id: UserId, key: String, version: u64 },
+    Update {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    Upsert {
+        user_id: UserId,
+        key: String,
+        value: Value,
+        #[serde(default)]
+        expiry: Option<Expiry>,
+    },
+    Incr { user_id: UserId, key: String },
+    Decr { user_id: UserId, key: String },
+    IncrBy { user_id: UserId, key: String, by: i64 },
+    DecrBy { user_id: UserId, key: String, by: i64 },
+    Range {
+        user_id: UserId,
+        start: String,
+        end: String,
+        #[serde(default)]
+        start_exclusive: bool,
+        #[serde(default)]
+        end_exclusive: bool,
+        #[serde(default)]
+        reverse: bool,
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
+    List {
+        user_id: UserId,
+        #[serde(default)]
+        prefix: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        cursor: Option<String>,
+        #[serde(default)]
+        snapshot: Option<u64>,
+    },
     Exit { user_id: UserId },
+    Ttl { user_id: UserId, key: String },
+    Expire { user_id: UserId, key: String, expiry: Expiry },
+    Persist { user_id: UserId, key: String },
+    Begin { user_id: UserId },
+    Commit { user_id: UserId },
+    Rollback { user_id: UserId },
+    Aggregate {
+        user_id: UserId,
+        function: Aggregate,
+        #[serde(default)]
+        scope: KeyScope,
+    },
+    CreateIndex { user_id: UserId },
+    DropIndex { user_id: UserId },
+    Query { user_id: UserId, filter: ValueFilter },
+    CreateKeyspace { user_id: UserId, name: String },
+    ListKeyspaces { user_id: UserId },
+    SelectKeyspace { user_id: UserId, name: String },
+    DropKeyspace { user_id: UserId, name: String },
+    Snapshot {
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    ClearWal {
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    Stats,
+    /// `quota: null` (or leaving it out) goes back to the default quota.
+    SetQuota {
+        user_id: UserId,
+        #[serde(default)]
+        quota: Option<Quota>,
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
+    Info { user_id: UserId },
+    /// The events arrive on a unidirectional stream the server opens, see `crate::watch`.
+    Watch { user_id: UserId, scope: WatchScope },
+    Unwatch { user_id: UserId, watch_id: u64 },
+    /// Pass the returned `id` as `snapshot` to `Get`, `MGet`, `Range` or `List` to read as
+    /// of the moment it was opened. See `crate::mvcc`.
+    OpenReadSnapshot { user_id: UserId },
+    CloseReadSnapshot { user_id: UserId, snapshot_id: u64 },
+    Publish { user_id: UserId, channel: String, message: Value },
+    /// The messages arrive on a unidirectional stream the server opens, see
+    /// `crate::actors::pubsub_actor`. So do those of `PSubscribe`.
+    Subscribe { user_id: UserId, channels: Vec<String> },
+    #[serde(rename = "PSUBSCRIBE")]
+    PSubscribe { user_id: UserId, patterns: Vec<String> },
+    Unsubscribe { user_id: UserId, subscription_id: u64 },
+    /// Every committed mutation after `from` (one sequence number per shard, or none to start at
+    /// the beginning), then live ones, on a unidirectional stream the server opens. See
+    /// `crate::cdc`.
+    Cdc {
+        #[serde(default)]
+        from: Vec<u64>,
+        #[serde(default)]
+        admin_token: Option<String>,
+    },
 }
 
 impl WireCommand {
+    /// Checks the token of an admin command against `server_token` (`ROCS_ADMIN_TOKEN`). User
+    /// commands always pass; admin commands fail if the server has no token.
+    pub fn authorize(&self, server_token: Option<&str>) -> Result<(), StoreError> {
+        let given = match self {
+            WireCommand::Snapshot { admin_token }
+            | WireCommand::ClearWal { admin_token }
+            | WireCommand::SetQuota { admin_token, .. }
+            | WireCommand::Cdc { admin_token, .. } => admin_token.as_deref(),
+            _ => return Ok(()),
+        };
+        match (server_token, given) {
+            (Some(expected), Some(given)) if tokens_match(expected, given) => Ok(()),
+            _ => Err(StoreError::Unauthorized),
+        }
+    }
+
     /// Converts a WireCommand sent by the user into an internal Command, attaching a oneshot responder.
     /// Returns (Command, WireResponseReceiver).
     pub fn into_internal(self) -> (Command, WireResponseReceiver) {
@@ -39,46 +206,149 @@ impl WireCommand {
                     WireResponseReceiver::String(rx),
                 )
             }
-            WireCommand::Set { user_id, key, value } => {
+            WireCommand::Set { user_id, key, value, expiry } => {
                 let (tx, rx) = oneshot::channel();
                 (
-                    Command::Set { user_id, key, value, respond_to: tx },
+                    Command::Set { user_id, key, value, expiry, respond_to: tx },
                     WireResponseReceiver::ResultUnit(rx),
                 )
             }
-            WireCommand::Get { user_id, key } => {
+            WireCommand::Get { user_id, key, snapshot } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Get { user_id, key, snapshot, respond_to: tx },
+                    WireResponseReceiver::ResultOptVersioned(rx),
+                )
+            }
+            WireCommand::MGet { user_id, keys, snapshot } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::MGet { user_id, keys, snapshot, respond_to: tx },
+                    WireResponseReceiver::ResultOptVersionedVec(rx),
+                )
+            }
+            WireCommand::MSet { user_id, pairs } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::MSet { user_id, pairs, respond_to: tx },
+                    WireResponseReceiver::ResultU64Vec(rx),
+                )
+            }
+            WireCommand::MDel { user_id, keys } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::MDel { user_id, keys, respond_to: tx },
+                    WireResponseReceiver::ResultOptValueVec(rx),
+                )
+            }
+            WireCommand::Cas { user_id, key, value, version, expiry } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Cas { user_id, key, value, version, expiry, respond_to: tx },
+                    WireResponseReceiver::StoreResultU64(rx),
+                )
+            }
+            WireCommand::SetNx { user_id, key, value, expiry } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::SetNx { user_id, key, value, expiry, respond_to: tx },
+                    WireResponseReceiver::StoreResultU64(rx),
+                )
+            }
+            WireCommand::DelIfVersion { user_id, key, version } => {
                 let (tx, rx) = oneshot::channel();
                 (
-                    Command::Get { user_id, key, respond_to: tx },
-                    WireResponseReceiver::ResultOptUsize(rx),
+                    Command::DelIfVersion { user_id, key, version, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
                 )
             }
             WireCommand::Del { user_id, key } => {
                 let (tx, rx) = oneshot::channel();
                 (
                     Command::Del { user_id, key, respond_to: tx },
-                    WireResponseReceiver::ResultUnit(rx),
+                    WireResponseReceiver::ResultOptValue(rx),
+                )
+            }
+            WireCommand::Update { user_id, key, value, expiry } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Update { user_id, key, value, expiry, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
                 )
             }
-            WireCommand::Update { user_id, key, value } => {
+            WireCommand::Upsert { user_id, key, value, expiry } => {
                 let (tx, rx) = oneshot::channel();
                 (
-                    Command::Update { user_id, key, value, respond_to: tx },
+                    Command::Upsert { user_id, key, value, expiry, respond_to: tx },
                     WireResponseReceiver::ResultUnit(rx),
                 )
             }
-            WireCommand::Range { user_id, start, end } => {
+            WireCommand::Incr { user_id, key } => WireCommand::IncrBy { user_id, key, by: 1 }.into_internal(),
+            WireCommand::Decr { user_id, key } => WireCommand::DecrBy { user_id, key, by: 1 }.into_internal(),
+            WireCommand::IncrBy { user_id, key, by } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::IncrBy { user_id, key, delta: by, respond_to: tx },
+                    WireResponseReceiver::StoreResultI64(rx),
+                )
+            }
+            WireCommand::DecrBy { user_id, key, by } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::DecrBy { user_id, key, delta: by, respond_to: tx },
+                    WireResponseReceiver::StoreResultI64(rx),
+                )
+            }
+            WireCommand::Range { user_id, start, end, start_exclusive, end_exclusive, reverse, limit, cursor, snapshot } => {
+                let bound = |key, exclusive| if exclusive { Bound::Excluded(key) } else { Bound::Included(key) };
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Range {
+                        user_id,
+                        start: bound(start, start_exclusive),
+                        end: bound(end, end_exclusive),
+                        reverse,
+                        limit,
+                        cursor,
+                        snapshot,
+                        respond_to: tx,
+                    },
+                    WireResponseReceiver::ResultPage(rx),
+                )
+            }
+            WireCommand::List { user_id, prefix, limit, cursor, snapshot } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::List { user_id, prefix, limit, cursor, snapshot, respond_to: tx },
+                    WireResponseReceiver::ResultPage(rx),
+                )
+            }
+            WireCommand::Aggregate { user_id, function, scope } => {
                 let (tx, rx) = oneshot::channel();
                 (
-                    Command::Range { user_id, start, end, respond_to: tx },
-                    WireResponseReceiver::ResultKvVec(rx),
+                    Command::Aggregate { user_id, function, scope, respond_to: tx },
+                    WireResponseReceiver::StoreResultOptValue(rx),
                 )
             }
-            WireCommand::List { user_id } => {
+            WireCommand::CreateIndex { user_id } => {
                 let (tx, rx) = oneshot::channel();
                 (
-                    Command::List { user_id, respond_to: tx },
-                    WireResponseReceiver::ResultKvVec(rx),
+                    Command::CreateIndex { user_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultU64(rx),
+                )
+            }
+            WireCommand::DropIndex { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::DropIndex { user_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::Query { user_id, filter } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Query { user_id, filter, respond_to: tx },
+                    WireResponseReceiver::StoreResultPairs(rx),
                 )
             }
             WireCommand::Exit { user_id } => {
@@ -88,16 +358,249 @@ impl WireCommand {
                     WireResponseReceiver::ResultUnit(rx),
                 )
             }
+            WireCommand::Ttl { user_id, key } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Ttl { user_id, key, respond_to: tx },
+                    WireResponseReceiver::ResultOptU64(rx),
+                )
+            }
+            WireCommand::Expire { user_id, key, expiry } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Expire { user_id, key, expiry, respond_to: tx },
+                    WireResponseReceiver::ResultBool(rx),
+                )
+            }
+            WireCommand::Persist { user_id, key } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::PersistKey { user_id, key, respond_to: tx },
+                    WireResponseReceiver::ResultBool(rx),
+                )
+            }
+            WireCommand::Begin { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Begin { user_id, respond_to: tx },
+                    WireResponseReceiver::ResultUnit(rx),
+                )
+            }
+            WireCommand::Commit { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Commit { user_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::Rollback { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Rollback { user_id, respond_to: tx },
+                    WireResponseReceiver::ResultUnit(rx),
+                )
+            }
+            WireCommand::CreateKeyspace { user_id, name } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::CreateKeyspace { user_id, name, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::ListKeyspaces { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::ListKeyspaces { user_id, respond_to: tx },
+                    WireResponseReceiver::ResultStringVec(rx),
+                )
+            }
+            WireCommand::SelectKeyspace { user_id, name } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::SelectKeyspace { user_id, name, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::DropKeyspace { user_id, name } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::DropKeyspace { user_id, name, respond_to: tx },
+                    WireResponseReceiver::StoreResultU64(rx),
+                )
+            }
+            WireCommand::Snapshot { .. } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Snapshot { respond_to: tx },
+                    WireResponseReceiver::ResultU64(rx),
+                )
+            }
+            WireCommand::ClearWal { .. } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::ClearWal { respond_to: tx },
+                    WireResponseReceiver::ResultU64(rx),
+                )
+            }
+            WireCommand::SetQuota { user_id, quota, .. } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::SetQuota { user_id, quota, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::Info { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Info { user_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultUserInfo(rx),
+                )
+            }
+            WireCommand::Watch { user_id, scope } => {
+                let (tx, rx) = oneshot::channel();
+                let (events, events_rx) = mpsc::channel(WATCH_BUFFER);
+                (
+                    Command::Watch { user_id, scope, events, respond_to: tx },
+                    WireResponseReceiver::Watch(rx, events_rx),
+                )
+            }
+            WireCommand::Unwatch { user_id, watch_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Unwatch { user_id, watch_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::OpenReadSnapshot { user_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::OpenReadSnapshot { user_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultReadSnapshot(rx),
+                )
+            }
+            WireCommand::CloseReadSnapshot { user_id, snapshot_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::CloseReadSnapshot { user_id, snapshot_id, respond_to: tx },
+                    WireResponseReceiver::StoreResultUnit(rx),
+                )
+            }
+            WireCommand::Publish { user_id, channel, message } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Publish { user_id, channel, message, respond_to: tx },
+                    WireResponseReceiver::ResultU64(rx),
+                )
+            }
+            WireCommand::Subscribe { user_id, channels } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Subscribe { user_id, channels, respond_to: tx },
+                    WireResponseReceiver::Subscribe(rx),
+                )
+            }
+            WireCommand::PSubscribe { user_id, patterns } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::PSubscribe { user_id, patterns, respond_to: tx },
+                    WireResponseReceiver::Subscribe(rx),
+                )
+            }
+            WireCommand::Unsubscribe { user_id, subscription_id } => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Unsubscribe { user_id, subscription_id, respond_to: tx },
+                    WireResponseReceiver::ResultUnit(rx),
+                )
+            }
+            WireCommand::Cdc { from, .. } => {
+                let (tx, rx) = oneshot::channel();
+                let (events, events_rx) = mpsc::channel(CDC_BUFFER);
+                (
+                    Command::Cdc { from, events, respond_to: tx },
+                    WireResponseReceiver::Cdc(rx, events_rx),
+                )
+            }
+            WireCommand::Stats => {
+                let (tx, rx) = oneshot::channel();
+                (
+                    Command::Stats { respond_to: tx },
+                    WireResponseReceiver::ResultMemoryStats(rx),
+                )
+            }
         }
     }
 }
 
+/// Compares the tokens in time that does not depend on where they differ.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 /// This is the receiver since we hand out the sender to the actor and await their response here
 /// Enum for all possible response receiver types.
 pub enum WireResponseReceiver {
     UserId(oneshot::Receiver<String>),
     String(oneshot::Receiver<String>),
     ResultUnit(oneshot::Receiver<Result<(), String>>),
-    ResultOptUsize(oneshot::Receiver<Result<Option<usize>, String>>),
-    ResultKvVec(oneshot::Receiver<Result<Vec<((String, String), usize)>, String>>),
+    ResultOptValue(oneshot::Receiver<Result<Option<Value>, String>>),
+    ResultOptVersioned(oneshot::Receiver<Result<Option<Versioned>, String>>),
+    ResultOptVersionedVec(oneshot::Receiver<Result<Vec<Option<Versioned>>, String>>),
+    ResultU64Vec(oneshot::Receiver<Result<Vec<u64>, String>>),
+    ResultOptValueVec(oneshot::Receiver<Result<Vec<Option<Value>>, String>>),
+    ResultPage(oneshot::Receiver<Result<Page, String>>),
+    ResultU64(oneshot::Receiver<Result<u64, String>>),
+    ResultOptU64(oneshot::Receiver<Result<Option<u64>, String>>),
+    ResultBool(oneshot::Receiver<Result<bool, String>>),
+    ResultStringVec(oneshot::Receiver<Result<Vec<String>, String>>),
+    ResultMemoryStats(oneshot::Receiver<Result<MemoryStats, String>>),
+    StoreResultU64(oneshot::Receiver<Result<u64, StoreError>>),
+    StoreResultUnit(oneshot::Receiver<Result<(), StoreError>>),
+    StoreResultI64(oneshot::Receiver<Result<i64, StoreError>>),
+    StoreResultOptValue(oneshot::Receiver<Result<Option<Value>, StoreError>>),
+    StoreResultPairs(oneshot::Receiver<Result<Vec<(String, Value)>, StoreError>>),
+    StoreResultUserInfo(oneshot::Receiver<Result<UserInfo, StoreError>>),
+    StoreResultReadSnapshot(oneshot::Receiver<Result<ReadSnapshot, StoreError>>),
+    /// The watch id, and the events to stream to the client once the watch is registered.
+    Watch(oneshot::Receiver<Result<u64, StoreError>>, mpsc::Receiver<WatchEvent>),
+    /// The subscription, whose messages are streamed to the client.
+    Subscribe(oneshot::Receiver<Result<Subscribed, String>>),
+    /// Whether the feed started, and its events to stream to the client.
+    Cdc(oneshot::Receiver<Result<(), StoreError>>, mpsc::Receiver<CdcEvent>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> WireCommand {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn admin_commands_need_the_servers_token() {
+        let admin = [
+            r#"{"command":"SNAPSHOT","admin_token":"secret"}"#,
+            r#"{"command":"CLEAR_WAL","admin_token":"secret"}"#,
+            r#"{"command":"SET_QUOTA","user_id":"u","admin_token":"secret"}"#,
+            r#"{"command":"CDC","from":[3],"admin_token":"secret"}"#,
+        ];
+        for json in admin {
+            let cmd = parse(json);
+            assert_eq!(cmd.authorize(Some("secret")), Ok(()), "{json}");
+            assert_eq!(cmd.authorize(Some("secreT")), Err(StoreError::Unauthorized), "{json}");
+            assert_eq!(cmd.authorize(Some("secret2")), Err(StoreError::Unauthorized), "{json}");
+            assert_eq!(cmd.authorize(None), Err(StoreError::Unauthorized), "{json}");
+        }
+        let without_token = parse(r#"{"command":"SET_QUOTA","user_id":"u","quota":null}"#);
+        assert_eq!(without_token.authorize(Some("secret")), Err(StoreError::Unauthorized));
+    }
+
+    #[test]
+    fn user_commands_need_no_token() {
+        for json in [r#"{"command":"GET","user_id":"u","key":"k"}"#, r#"{"command":"STATS"}"#] {
+            assert_eq!(parse(json).authorize(None), Ok(()), "{json}");
+        }
+    }
 }